@@ -0,0 +1,10 @@
+#![no_main]
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate calc;
+
+fuzz_target!(|data: &[u8]| {
+    let input = String::from_utf8_lossy(data);
+    let _ = calc::eval(&input);
+    let _ = calc::eval_polish(&input);
+});
@@ -0,0 +1,58 @@
+/* C interface to the calc library, available with the `ffi` cargo feature. */
+
+#ifndef CALC_H
+#define CALC_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define CALC_OK 0
+#define CALC_ERR_NULL_POINTER -1
+#define CALC_ERR_INVALID_UTF8 -2
+#define CALC_ERR_PANIC -3
+
+/* Positive return values of calc_eval identify the kind of evaluation error.
+ * These codes are stable across releases. */
+#define CALC_ERR_BAD_TYPES 1
+#define CALC_ERR_DIVIDE_BY_ZERO 2
+#define CALC_ERR_INVALID_NUMBER 3
+#define CALC_ERR_INVALID_OPERATOR 4
+#define CALC_ERR_UNRECOGNIZED_TOKEN 5
+#define CALC_ERR_UNEXPECTED_TOKEN 6
+#define CALC_ERR_UNKNOWN_ATOM 7
+#define CALC_ERR_UNEXPECTED_END_OF_INPUT 8
+#define CALC_ERR_UNMATCHED_PARENTHESIS 9
+#define CALC_ERR_WOULD_OVERFLOW 10
+#define CALC_ERR_WOULD_TRUNCATE 11
+#define CALC_ERR_BUDGET_EXCEEDED 12
+#define CALC_ERR_INPUT_TOO_LARGE 13
+#define CALC_ERR_NESTING_TOO_DEEP 14
+#define CALC_ERR_WRONG_ARITY 15
+#define CALC_ERR_CANNOT_DIFFERENTIATE 16
+#define CALC_ERR_UNSOLVABLE 17
+#define CALC_ERR_IO 18
+#define CALC_ERR_LITERAL_OUT_OF_RANGE 19
+#define CALC_ERR_INVALID_ARGUMENT 20
+#define CALC_ERR_INVALID_JSON 21
+#define CALC_ERR_CIRCULAR_REFERENCE 22
+#define CALC_ERR_NO_CONVERGENCE 23
+#define CALC_ERR_MAGNITUDE_EXCEEDED 24
+#define CALC_ERR_NOT_AN_INTEGER 25
+
+/* Evaluates the NUL-terminated UTF-8 string `input` and stores the result in
+ * `out`. Returns CALC_OK on success; `out` is untouched on failure. */
+int calc_eval(const char *input, double *out);
+
+/* Returns a description of the last failure on the calling thread, or NULL
+ * if the last call succeeded. Release the string with calc_string_free. */
+char *calc_last_error_message(void);
+
+/* Releases a string returned by this library. NULL is ignored. */
+void calc_string_free(char *s);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* CALC_H */
@@ -0,0 +1,1580 @@
+//! A syntax tree for expressions.
+//!
+//! `Expr::parse` builds the tree with the same grammar that `eval` uses,
+//! while the constructors and operator overloads build it in code. Both
+//! produce the same tree for equivalent expressions, so
+//! `Expr::var("x") * Expr::num(2) + Expr::num(1)` equals the result of
+//! parsing `x * 2 + 1`.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::ops;
+
+use error::CalcError;
+use number::CalcNum;
+use parse::{
+    self, Assoc, DefaultEnvironment, Environment, IntegerMode, NumericOptions,
+    Operator, MAX_DEPTH,
+};
+use token::TokenStream;
+use value::Value;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+}
+
+impl UnaryOp {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            UnaryOp::Neg => "-",
+            UnaryOp::Not => "~",
+        }
+    }
+
+    pub fn apply<N: CalcNum>(self, arg: N) -> Result<N, CalcError> {
+        match self {
+            UnaryOp::Neg => arg.neg(),
+            UnaryOp::Not => arg.not(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Pow,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
+}
+
+impl BinaryOp {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Rem => "%",
+            BinaryOp::Pow => "**",
+            BinaryOp::BitAnd => "&",
+            BinaryOp::BitOr => "|",
+            BinaryOp::BitXor => "^",
+            BinaryOp::Shl => "<<",
+            BinaryOp::Shr => ">>",
+        }
+    }
+
+    pub fn apply<N: CalcNum>(self, lhs: N, rhs: N) -> Result<N, CalcError> {
+        match self {
+            BinaryOp::Add => lhs.add(rhs),
+            BinaryOp::Sub => lhs.sub(rhs),
+            BinaryOp::Mul => lhs.mul(rhs),
+            BinaryOp::Div => lhs.div(rhs),
+            BinaryOp::Rem => lhs.rem(rhs),
+            BinaryOp::Pow => lhs.powf(rhs),
+            BinaryOp::BitAnd => lhs.bitand(rhs),
+            BinaryOp::BitOr => lhs.bitor(rhs),
+            BinaryOp::BitXor => lhs.bitxor(rhs),
+            BinaryOp::Shl => lhs.shl(rhs),
+            BinaryOp::Shr => lhs.shr(rhs),
+        }
+    }
+
+    /// How tightly the operator binds, from the bitwise operators (1) to
+    /// exponentiation (4). All operators but `**` are left associative.
+    pub fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::BitAnd
+            | BinaryOp::BitOr
+            | BinaryOp::BitXor
+            | BinaryOp::Shl
+            | BinaryOp::Shr => 1,
+            BinaryOp::Add | BinaryOp::Sub => 2,
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem => 3,
+            BinaryOp::Pow => 4,
+        }
+    }
+
+    /// How a chain of the operator groups: `2 ** 3 ** 2` is
+    /// `2 ** (3 ** 2)`, while `8 - 4 - 2` is `(8 - 4) - 2`.
+    pub fn assoc(self) -> Assoc {
+        match self {
+            BinaryOp::Pow => Assoc::Right,
+            _ => Assoc::Left,
+        }
+    }
+}
+
+/// The most values `sum` and `prod` may range over. Longer ranges fail
+/// with `CalcError::InvalidArgument` before anything is evaluated.
+pub const MAX_RANGE: u64 = 1_000_000;
+
+/// A function that binds a variable in its body, such as
+/// `sum(i, 1, 10, i ** 2)` or `solve(x ** 2 - 2, x, 1)`. The body is
+/// kept as a tree and evaluated for different values of the variable, while
+/// the remaining arguments are numbers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum Binder {
+    Sum,
+    Prod,
+    Integrate,
+    Solve,
+    Ddx,
+}
+
+impl Binder {
+    pub(crate) fn from_name(name: &str) -> Option<Binder> {
+        match name {
+            "sum" => Some(Binder::Sum),
+            "prod" => Some(Binder::Prod),
+            "integrate" => Some(Binder::Integrate),
+            "solve" => Some(Binder::Solve),
+            "ddx" => Some(Binder::Ddx),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Binder::Sum => "sum",
+            Binder::Prod => "prod",
+            Binder::Integrate => "integrate",
+            Binder::Solve => "solve",
+            Binder::Ddx => "ddx",
+        }
+    }
+
+    /// The positions of the variable and of the body among the arguments.
+    pub(crate) fn layout(self) -> (usize, usize) {
+        match self {
+            Binder::Sum | Binder::Prod => (0, 3),
+            Binder::Integrate | Binder::Solve | Binder::Ddx => (1, 0),
+        }
+    }
+
+    /// The least and the most arguments, counting the variable and the
+    /// body.
+    fn arity(self) -> (usize, usize) {
+        match self {
+            Binder::Solve => (3, 4),
+            Binder::Ddx => (3, 3),
+            _ => (4, 4),
+        }
+    }
+
+    /// Checks the number of arguments, counting the variable and the body.
+    pub(crate) fn check_arity(self, count: usize) -> Result<(), CalcError> {
+        let (min, max) = self.arity();
+        if min <= count && count <= max {
+            Ok(())
+        } else {
+            Err(self.wrong_arity(count))
+        }
+    }
+
+    pub(crate) fn wrong_arity(self, count: usize) -> CalcError {
+        let (min, max) = self.arity();
+        CalcError::WrongArity {
+            atom: self.name().into(),
+            expected: if count < min { min } else { max },
+            actual: count,
+        }
+    }
+
+    /// Puts the variable, the body and the numeric arguments back into the
+    /// order in which they are written.
+    pub(crate) fn arguments(
+        self,
+        var: &str,
+        body: Expr,
+        args: Vec<Expr>,
+    ) -> Vec<Expr> {
+        let (var_at, body_at) = self.layout();
+        let mut body = Some(body);
+        let mut args = args.into_iter();
+        (0..args.len() + 2)
+            .map(|i| {
+                if i == var_at {
+                    Expr::var(var)
+                } else if i == body_at {
+                    body.take().expect("the body is used once")
+                } else {
+                    args.next().expect("counted above")
+                }
+            })
+            .collect()
+    }
+
+    /// Applies the function to `body`, with `var` bound by `env`, given
+    /// the numeric arguments in order.
+    pub(crate) fn apply<N, E>(
+        self,
+        env: &mut E,
+        var: &str,
+        body: &Expr,
+        args: &[N],
+    ) -> Result<N, CalcError>
+    where
+        N: CalcNum,
+        E: Environment,
+    {
+        // Every nested binder wraps the environment once more, so erase its
+        // type to keep the instantiations of `evaluate` finite.
+        let env: &mut dyn Environment = env;
+        match self {
+            Binder::Sum | Binder::Prod => {
+                fold(self, env, var, body, &args[0], &args[1])
+            }
+            Binder::Integrate => integrate(env, var, body, &args[0], &args[1]),
+            Binder::Solve if args.len() == 1 => solve(env, var, body, &args[0]),
+            Binder::Solve => bisect(env, var, body, &args[0], &args[1]),
+            Binder::Ddx => derivative(env, var, body, &args[0]),
+        }
+    }
+
+    /// Splits the arguments of a call into the variable, the body and the
+    /// numeric arguments.
+    pub(crate) fn parts(
+        self,
+        args: &[Expr],
+    ) -> Result<(&str, &Expr, Vec<&Expr>), CalcError> {
+        self.check_arity(args.len())?;
+        let (var_at, body_at) = self.layout();
+        let var = match args[var_at] {
+            Expr::Var(ref var) => var,
+            _ => {
+                return Err(CalcError::InvalidArgument(format!(
+                    "{} expects a name as argument {}",
+                    self.name(),
+                    var_at + 1
+                )))
+            }
+        };
+        let values = args
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| i != var_at && i != body_at)
+            .map(|(_, arg)| arg)
+            .collect();
+        Ok((var, &args[body_at], values))
+    }
+
+    /// Evaluates the arguments of a call like `sum(i, 1, 10, i ** 2)`.
+    fn call<N, E>(self, args: &[Expr], env: &mut E) -> Result<N, CalcError>
+    where
+        N: CalcNum,
+        E: Environment,
+    {
+        let (var, body, values) = self.parts(args)?;
+        let values = values
+            .into_iter()
+            .map(|arg| evaluate(arg, env))
+            .collect::<Result<Vec<N>, _>>()?;
+        self.apply(env, var, body, &values)
+    }
+}
+
+/// Evaluates `body` with `var` bound to `value`.
+fn sample<N: CalcNum>(
+    env: &mut dyn Environment,
+    var: &str,
+    body: &Expr,
+    value: Value,
+) -> Result<N, CalcError> {
+    let mut scope = Bound {
+        name: var,
+        value,
+        env,
+    };
+    evaluate(body, &mut scope)
+}
+
+/// Evaluates `body` with `var` bound to `x` in `f64`, for the numeric
+/// methods. Values that are not finite stop the method, as it could not
+/// converge anyway.
+fn sample_f64<N: CalcNum>(
+    binder: Binder,
+    env: &mut dyn Environment,
+    var: &str,
+    body: &Expr,
+    x: f64,
+) -> Result<f64, CalcError> {
+    let y = sample::<N>(env, var, body, Value::from_f64(x))?;
+    let y = y.to_value().as_f64();
+    if y.is_finite() {
+        Ok(y)
+    } else {
+        Err(CalcError::NoConvergence(binder.name().into()))
+    }
+}
+
+/// Evaluates `body` for `var` from `lo` to `hi`, inclusive, and adds or
+/// multiplies the results. Empty ranges give 0 or 1.
+fn fold<N: CalcNum>(
+    binder: Binder,
+    env: &mut dyn Environment,
+    var: &str,
+    body: &Expr,
+    lo: &N,
+    hi: &N,
+) -> Result<N, CalcError> {
+    let bound = |n: &N| {
+        n.to_bits_integer().ok_or_else(|| {
+            CalcError::InvalidArgument(format!(
+                "the bounds of {} must be integers, got {}",
+                binder.name(),
+                n
+            ))
+        })
+    };
+    let (lo, hi) = (bound(lo)?, bound(hi)?);
+    let mut acc = N::from_bits_integer(match binder {
+        Binder::Prod => 1,
+        _ => 0,
+    });
+    if lo > hi {
+        return Ok(acc);
+    }
+    let len = (i128::from(hi) - i128::from(lo) + 1) as u128;
+    if len > u128::from(MAX_RANGE) {
+        return Err(CalcError::InvalidArgument(format!(
+            "{} over {} values exceeds the limit of {}",
+            binder.name(),
+            len,
+            MAX_RANGE
+        )));
+    }
+    // Sums carry the rounding error of each step along, and add it back at
+    // the end.
+    let mut comp: Option<N> = None;
+    for i in lo..=hi {
+        let term = sample(env, var, body, Value::dec(i))?;
+        acc = match binder {
+            Binder::Prod => acc.mul(term)?,
+            _ => {
+                let sum = acc.clone().add(term.clone())?;
+                if let Some(error) = acc.sum_error(&term, &sum) {
+                    comp = Some(match comp {
+                        Some(comp) => comp.add(error)?,
+                        None => error,
+                    });
+                }
+                sum
+            }
+        };
+    }
+    match comp {
+        Some(comp) => acc.add(comp),
+        None => Ok(acc),
+    }
+}
+
+/// Integrates `body` over `var` from `a` to `b` with adaptive Simpson
+/// quadrature in `f64`, to the tolerance of the environment's
+/// `NumericOptions`. A singularity fails with `CalcError::NoConvergence`,
+/// whether `body` is infinite or divides by zero at a sample.
+fn integrate<N: CalcNum>(
+    env: &mut dyn Environment,
+    var: &str,
+    body: &Expr,
+    a: &N,
+    b: &N,
+) -> Result<N, CalcError> {
+    let options = env.numeric_options();
+    let (a, b) = (a.to_value().as_f64(), b.to_value().as_f64());
+    let mut f = |x| {
+        sample_f64::<N>(Binder::Integrate, env, var, body, x).map_err(|error| {
+            match error {
+                CalcError::DivideByZero => {
+                    CalcError::NoConvergence(Binder::Integrate.name().into())
+                }
+                error => error,
+            }
+        })
+    };
+    let area = if a < b {
+        simpson(&mut f, a, b, &options)?
+    } else if a > b {
+        -simpson(&mut f, b, a, &options)?
+    } else {
+        0.0
+    };
+    N::from_value(&Value::from_f64(area))
+}
+
+/// A part of the interval being integrated by `simpson`, with `f` sampled
+/// at its ends and middle, and its share of the tolerance.
+struct Interval {
+    a: f64,
+    b: f64,
+    fa: f64,
+    fm: f64,
+    fb: f64,
+    area: f64,
+    tolerance: f64,
+}
+
+impl Interval {
+    fn new(a: f64, b: f64, fa: f64, fm: f64, fb: f64, tolerance: f64) -> Self {
+        let area = (b - a) / 6.0 * (fa + 4.0 * fm + fb);
+        Interval {
+            a,
+            b,
+            fa,
+            fm,
+            fb,
+            area,
+            tolerance,
+        }
+    }
+}
+
+/// Adaptive Simpson quadrature of `f` from `a` to `b`, where `a < b`. An
+/// interval is split in two until Simpson's rule on both halves agrees with
+/// the whole, with a stack instead of recursion.
+fn simpson<F>(
+    f: &mut F,
+    a: f64,
+    b: f64,
+    options: &NumericOptions,
+) -> Result<f64, CalcError>
+where
+    F: FnMut(f64) -> Result<f64, CalcError>,
+{
+    let fail = || CalcError::NoConvergence("integrate".into());
+    let m = a + (b - a) / 2.0;
+    let whole = Interval::new(a, b, f(a)?, f(m)?, f(b)?, options.tolerance);
+    let mut stack = vec![whole];
+    let mut total = 0.0;
+    let mut subdivisions = 0;
+    while let Some(part) = stack.pop() {
+        let m = part.a + (part.b - part.a) / 2.0;
+        if m <= part.a || m >= part.b {
+            return Err(fail());
+        }
+        let fl = f(part.a + (m - part.a) / 2.0)?;
+        let fr = f(m + (part.b - m) / 2.0)?;
+        let tolerance = part.tolerance / 2.0;
+        let left = Interval::new(part.a, m, part.fa, fl, part.fm, tolerance);
+        let right = Interval::new(m, part.b, part.fm, fr, part.fb, tolerance);
+        let delta = left.area + right.area - part.area;
+        if delta.abs() <= 15.0 * part.tolerance {
+            // Richardson extrapolation of the two estimates.
+            total += left.area + right.area + delta / 15.0;
+        } else {
+            subdivisions += 1;
+            if subdivisions > options.max_subdivisions {
+                return Err(fail());
+            }
+            stack.push(right);
+            stack.push(left);
+        }
+    }
+    Ok(total)
+}
+
+/// Finds a root of `body` near `guess` with Newton's method, estimating the
+/// derivative with a central difference. Once two samples have opposite
+/// signs, steps that leave the interval between them are replaced by
+/// bisection.
+fn solve<N: CalcNum>(
+    env: &mut dyn Environment,
+    var: &str,
+    body: &Expr,
+    guess: &N,
+) -> Result<N, CalcError> {
+    let options = env.numeric_options();
+    let fail = || CalcError::NoConvergence("solve".into());
+    let mut f = |x| sample_f64::<N>(Binder::Solve, env, var, body, x);
+    let mut x = guess.to_value().as_f64();
+    let mut fx = f(x)?;
+    // Two points whose values have opposite signs, once they are known,
+    // and whether the value at the first is negative.
+    let mut bracket: Option<(f64, f64, bool)> = None;
+    for _ in 0..options.max_iterations {
+        if fx == 0.0 {
+            return N::from_value(&Value::from_f64(x));
+        }
+        let h = f64::EPSILON.sqrt() * x.abs().max(1.0);
+        let slope = (f(x + h)? - f(x - h)?) / (2.0 * h);
+        // A slope this small sends the step far beyond what its error
+        // allows, as at the bottom of `x**2 - 2`.
+        let flat = slope.abs() <= options.tolerance * fx.abs().max(1.0);
+        let newton = x - fx / slope;
+        let next = match bracket {
+            Some((a, b, _)) if !(a.min(b) < newton && newton < a.max(b)) => {
+                a + (b - a) / 2.0
+            }
+            Some(_) => newton,
+            None if !flat && newton.is_finite() => newton,
+            None => return Err(fail()),
+        };
+        let fnext = f(next)?;
+        let negative = fnext < 0.0;
+        bracket = match bracket {
+            Some((a, _, na)) if na != negative => Some((a, next, na)),
+            Some((_, b, _)) => Some((next, b, negative)),
+            None if negative != (fx < 0.0) => Some((x, next, fx < 0.0)),
+            None => None,
+        };
+        let step = next - x;
+        x = next;
+        fx = fnext;
+        let small = options.tolerance * x.abs().max(1.0);
+        if fx.abs() <= options.tolerance && step.abs() <= small {
+            return N::from_value(&Value::from_f64(x));
+        }
+    }
+    Err(fail())
+}
+
+/// Finds a root of `body` between `lo` and `hi` by bisection. The values at
+/// the bounds must have opposite signs.
+fn bisect<N: CalcNum>(
+    env: &mut dyn Environment,
+    var: &str,
+    body: &Expr,
+    lo: &N,
+    hi: &N,
+) -> Result<N, CalcError> {
+    let options = env.numeric_options();
+    let mut f = |x| sample_f64::<N>(Binder::Solve, env, var, body, x);
+    let (mut lo, mut hi) = (lo.to_value().as_f64(), hi.to_value().as_f64());
+    let (flo, fhi) = (f(lo)?, f(hi)?);
+    if flo == 0.0 {
+        return N::from_value(&Value::from_f64(lo));
+    }
+    if fhi == 0.0 {
+        return N::from_value(&Value::from_f64(hi));
+    }
+    if (flo < 0.0) == (fhi < 0.0) {
+        return Err(CalcError::InvalidArgument(format!(
+            "the values of solve at {} and {} must have opposite signs",
+            lo, hi
+        )));
+    }
+    for _ in 0..options.max_iterations {
+        let mid = lo + (hi - lo) / 2.0;
+        let fmid = f(mid)?;
+        let small = options.tolerance * mid.abs().max(1.0);
+        if fmid == 0.0 || (hi - lo).abs() / 2.0 <= small {
+            return N::from_value(&Value::from_f64(mid));
+        }
+        if (fmid < 0.0) == (flo < 0.0) {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    Err(CalcError::NoConvergence("solve".into()))
+}
+
+/// Differentiates `body` with respect to `var` at `at` with Ridders'
+/// method: central differences with shrinking steps, extrapolated towards a
+/// step of zero. The first step is a tenth of the magnitude of `at`, or of
+/// one if that is smaller. Fails with `CalcError::NoConvergence` if the
+/// estimates do not settle, as near a pole.
+fn derivative<N: CalcNum>(
+    env: &mut dyn Environment,
+    var: &str,
+    body: &Expr,
+    at: &N,
+) -> Result<N, CalcError> {
+    // The factor by which the step shrinks, and the number of steps.
+    const SHRINK: f64 = 1.4;
+    const STEPS: usize = 10;
+    // How far the estimates may still be apart at the end, relative to the
+    // result or the first difference quotient, whichever is larger.
+    const SETTLED: f64 = 1e-6;
+    let mut f = |x| sample_f64::<N>(Binder::Ddx, env, var, body, x);
+    let x = at.to_value().as_f64();
+    let mut h = 0.1 * x.abs().max(1.0);
+    let (above, below) = (f(x + h)?, f(x - h)?);
+    let scale = (above.abs() + below.abs()) / (2.0 * h);
+    let mut previous = vec![(above - below) / (2.0 * h)];
+    let (mut best, mut error) = (previous[0], f64::INFINITY);
+    for _ in 1..STEPS {
+        h /= SHRINK;
+        let mut column = vec![(f(x + h)? - f(x - h)?) / (2.0 * h)];
+        let mut factor = SHRINK * SHRINK;
+        for j in 1..=previous.len() {
+            let extrapolated =
+                (column[j - 1] * factor - previous[j - 1]) / (factor - 1.0);
+            factor *= SHRINK * SHRINK;
+            let estimate = (extrapolated - column[j - 1])
+                .abs()
+                .max((extrapolated - previous[j - 1]).abs());
+            if estimate <= error {
+                best = extrapolated;
+                error = estimate;
+            }
+            column.push(extrapolated);
+        }
+        // Stop once rounding errors outgrow the gains of extrapolation.
+        let last = column.len() - 1;
+        if (column[last] - previous[last - 1]).abs() >= 2.0 * error {
+            break;
+        }
+        previous = column;
+    }
+    if !best.is_finite() || error > SETTLED * best.abs().max(scale) {
+        return Err(CalcError::NoConvergence("ddx".into()));
+    }
+    N::from_value(&Value::from_f64(best))
+}
+
+/// An environment in which `name` is bound to `value`, shadowing `env`.
+pub(crate) struct Bound<'a> {
+    pub(crate) name: &'a str,
+    pub(crate) value: Value,
+    pub(crate) env: &'a mut dyn Environment,
+}
+
+impl<'a> Environment for Bound<'a> {
+    fn arity(&self, atom: &str) -> Option<usize> {
+        if atom == self.name {
+            Some(0)
+        } else {
+            self.env.arity(atom)
+        }
+    }
+
+    fn resolve(
+        &mut self,
+        atom: &str,
+        args: &[Value],
+    ) -> Result<Value, CalcError> {
+        if atom == self.name {
+            Ok(self.value.clone())
+        } else {
+            self.env.resolve(atom, args)
+        }
+    }
+
+    fn step(&mut self) -> Result<(), CalcError> {
+        self.env.step()
+    }
+
+    fn max_magnitude(&self) -> Option<f64> {
+        self.env.max_magnitude()
+    }
+
+    fn integer_mode(&self) -> IntegerMode {
+        self.env.integer_mode()
+    }
+
+    fn lenient_parens(&self) -> bool {
+        self.env.lenient_parens()
+    }
+
+    fn compensated_sum(&self) -> bool {
+        self.env.compensated_sum()
+    }
+
+    fn operators(&self) -> Vec<Operator> {
+        self.env.operators()
+    }
+
+    fn precedence(&self, op: BinaryOp) -> u8 {
+        self.env.precedence(op)
+    }
+
+    fn assoc(&self, op: BinaryOp) -> Assoc {
+        self.env.assoc(op)
+    }
+
+    fn warn(&mut self, message: String) {
+        self.env.warn(message)
+    }
+
+    fn names(&self) -> Vec<String> {
+        let mut names = self.env.names();
+        names.push(self.name.to_owned());
+        names
+    }
+
+    fn max_depth(&self) -> Option<usize> {
+        self.env.max_depth()
+    }
+
+    fn right_shift(&self) -> parse::RightShift {
+        self.env.right_shift()
+    }
+
+    fn numeric_options(&self) -> NumericOptions {
+        self.env.numeric_options()
+    }
+}
+
+/// An expression tree.
+///
+/// `Display` renders the expression as source text that parses back into
+/// the same tree.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Num(Value),
+    /// An atom without arguments, such as a variable or `pi`.
+    Var(String),
+    /// An atom applied to arguments, such as `log 100`.
+    Call(String, Vec<Expr>),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    /// Creates a literal. Integers become integral values and floats become
+    /// decimal floats, just like `2` and `2.0` in source text.
+    pub fn num<T: Into<Value>>(value: T) -> Self {
+        Expr::Num(value.into())
+    }
+
+    pub fn var<S: Into<String>>(name: S) -> Self {
+        Expr::Var(name.into())
+    }
+
+    pub fn call<S: Into<String>>(name: S, args: Vec<Expr>) -> Self {
+        Expr::Call(name.into(), args)
+    }
+
+    /// Applies a unary operator. Like the parser, negating a literal
+    /// produces a negative literal.
+    pub fn unary(op: UnaryOp, arg: Expr) -> Self {
+        match (op, arg) {
+            (UnaryOp::Neg, Expr::Num(value)) => Expr::Num(-value),
+            (op, arg) => Expr::Unary(op, Box::new(arg)),
+        }
+    }
+
+    pub fn binary(op: BinaryOp, lhs: Expr, rhs: Expr) -> Self {
+        Expr::Binary(op, Box::new(lhs), Box::new(rhs))
+    }
+
+    pub fn pow(self, that: Expr) -> Self {
+        Expr::binary(BinaryOp::Pow, self, that)
+    }
+
+    /// Parses an expression. Atoms that the default environment does not
+    /// define are treated as variables.
+    pub fn parse(input: &str) -> Result<Self, CalcError> {
+        Expr::parse_with(input, &mut DefaultEnvironment)
+    }
+
+    /// Parses an expression, taking the arity of functions from `env`.
+    /// Atoms that `env` does not define are treated as variables.
+    pub fn parse_with<E>(input: &str, env: &mut E) -> Result<Self, CalcError>
+    where
+        E: Environment,
+    {
+        let operators = env.operators();
+        let tokens = TokenStream::with_operators(input, &operators)?;
+        parse::build(&tokens, &mut Symbolic(env))
+    }
+
+    /// Iterates over the nodes of the tree in pre-order: every node comes
+    /// before its children, which are visited from left to right.
+    pub fn iter(&self) -> Iter<'_> {
+        Iter { stack: vec![self] }
+    }
+
+    /// The number of nodes on the longest path from the root to a leaf.
+    pub(crate) fn depth(&self) -> usize {
+        match *self {
+            Expr::Num(_) | Expr::Var(_) => 1,
+            Expr::Call(_, ref args) => {
+                1 + args.iter().map(Expr::depth).max().unwrap_or(0)
+            }
+            Expr::Unary(_, ref arg) => 1 + arg.depth(),
+            Expr::Binary(_, ref lhs, ref rhs) => {
+                1 + lhs.depth().max(rhs.depth())
+            }
+        }
+    }
+
+    /// Rebuilds the tree bottom-up: the children of a node are mapped
+    /// first, then `f` is applied to the node with its new children.
+    pub fn map<F>(self, mut f: F) -> Expr
+    where
+        F: FnMut(Expr) -> Expr,
+    {
+        self.map_with(&mut f)
+    }
+
+    fn map_with<F>(self, f: &mut F) -> Expr
+    where
+        F: FnMut(Expr) -> Expr,
+    {
+        let node = match self {
+            Expr::Call(name, args) => Expr::Call(
+                name,
+                args.into_iter().map(|arg| arg.map_with(f)).collect(),
+            ),
+            Expr::Unary(op, arg) => Expr::unary(op, arg.map_with(f)),
+            Expr::Binary(op, lhs, rhs) => {
+                Expr::binary(op, lhs.map_with(f), rhs.map_with(f))
+            }
+            leaf => leaf,
+        };
+        f(node)
+    }
+
+    /// Replaces every occurrence of the variable `name` with `with`.
+    /// Nothing is evaluated, and `with` itself is inserted as is, even if
+    /// it mentions `name`.
+    pub fn substitute(&self, name: &str, with: &Expr) -> Expr {
+        self.clone().map(|node| match node {
+            Expr::Var(ref var) if var == name => with.clone(),
+            node => node,
+        })
+    }
+
+    /// Replaces all variables that appear in `vars` at once.
+    pub fn substitute_all(&self, vars: &HashMap<String, Expr>) -> Expr {
+        self.clone().map(|node| {
+            if let Expr::Var(ref var) = node {
+                if let Some(with) = vars.get(var) {
+                    return with.clone();
+                }
+            }
+            node
+        })
+    }
+
+    /// Folds every subtree that only depends on literals and atoms known
+    /// to `env` into a literal, leaving the rest of the tree symbolic.
+    ///
+    /// Subtrees whose evaluation fails, such as `1 / 0`, are kept as they
+    /// are, so the error surfaces once the whole expression is evaluated.
+    pub fn partial_eval<E: Environment>(&self, env: &mut E) -> Expr {
+        self.clone().map(|node| {
+            let foldable = match node {
+                Expr::Num(_) => false,
+                Expr::Var(_) => true,
+                Expr::Call(_, ref args) => args.iter().all(Expr::is_num),
+                Expr::Unary(_, ref arg) => arg.is_num(),
+                Expr::Binary(_, ref lhs, ref rhs) => {
+                    lhs.is_num() && rhs.is_num()
+                }
+            };
+            if foldable {
+                if let Ok(value) = evaluate(&node, env) {
+                    return Expr::Num(value);
+                }
+            }
+            node
+        })
+    }
+
+    pub(crate) fn is_num(&self) -> bool {
+        matches!(*self, Expr::Num(_))
+    }
+
+    /// Binding strength of the root node; atoms bind tightest and unary
+    /// operators, which swallow the rest of the input, the loosest.
+    pub(crate) fn precedence(&self) -> u8 {
+        match *self {
+            Expr::Call(ref name, _) if name == "let" => 0,
+            Expr::Num(_) | Expr::Var(_) | Expr::Call(..) => 5,
+            Expr::Unary(..) => 0,
+            Expr::Binary(op, ..) => op.precedence(),
+        }
+    }
+}
+
+/// Evaluates an expression tree in any numeric type.
+pub fn evaluate<N, E>(expr: &Expr, env: &mut E) -> Result<N, CalcError>
+where
+    N: CalcNum,
+    E: Environment,
+{
+    env.step()?;
+    let (n, op) = match *expr {
+        Expr::Num(ref value) => {
+            if let Some(warning) = N::literal_warning(value) {
+                env.warn(warning);
+            }
+            (N::from_literal(value), "literal")
+        }
+        Expr::Var(ref name) => (resolve(name, &[], env), &name[..]),
+        Expr::Call(ref name, ref args) if name == "cond" => {
+            (cond(args, env), "cond")
+        }
+        Expr::Call(ref name, ref args) if name == "let" => {
+            (let_in(args, env), "let")
+        }
+        Expr::Call(ref name, ref args) => match Binder::from_name(name) {
+            Some(binder) => (binder.call(args, env), binder.name()),
+            None => (resolve(name, args, env), &name[..]),
+        },
+        Expr::Unary(op, ref arg) => {
+            (op.apply(evaluate(arg, env)?), op.symbol())
+        }
+        Expr::Binary(..) => {
+            // Chains such as `1 + 2 + ... + n` nest to the left, so walk
+            // down the left operands iteratively instead of recursing.
+            let mut rest = Vec::new();
+            let mut node = expr;
+            while let Expr::Binary(op, ref lhs, ref rhs) = *node {
+                rest.push((op, rhs));
+                node = lhs;
+            }
+            let mut acc = evaluate(node, env)?;
+            for (op, rhs) in rest.into_iter().rev() {
+                env.step()?;
+                let rhs = evaluate(rhs, env)?;
+                acc = parse::binary(&*env, op, acc, rhs)?;
+                parse::check_result(&*env, op.symbol(), &acc)?;
+            }
+            return Ok(acc);
+        }
+    };
+    let n = n?;
+    parse::check_result(&*env, op, &n)?;
+    Ok(n)
+}
+
+/// Evaluates the conditions of a `cond` in order, and only the value of the
+/// first one that holds, or else the default.
+fn cond<N, E>(args: &[Expr], env: &mut E) -> Result<N, CalcError>
+where
+    N: CalcNum,
+    E: Environment,
+{
+    parse::check_cond_arity(args.len())?;
+    for pair in args.chunks(2) {
+        match *pair {
+            [ref condition, ref value] => {
+                if parse::truth(&evaluate::<N, E>(condition, env)?) {
+                    return evaluate(value, env);
+                }
+            }
+            _ => return evaluate(&pair[0], env),
+        }
+    }
+    unreachable!("cond has an odd number of arguments")
+}
+
+/// The name, the value and the body of `let NAME = VALUE in BODY`, which
+/// is recorded as a call of `let` with these arguments.
+pub(crate) fn let_parts(
+    args: &[Expr],
+) -> Result<(&str, &Expr, &Expr), CalcError> {
+    match *args {
+        [Expr::Var(ref name), ref value, ref body] => Ok((name, value, body)),
+        [_, _, _] => Err(CalcError::InvalidArgument(
+            "let expects a name as argument 1".into(),
+        )),
+        _ => Err(CalcError::WrongArity {
+            atom: "let".into(),
+            expected: 3,
+            actual: args.len(),
+        }),
+    }
+}
+
+/// Evaluates the value of a `let` once, and the body with the name bound to
+/// it.
+fn let_in<N, E>(args: &[Expr], env: &mut E) -> Result<N, CalcError>
+where
+    N: CalcNum,
+    E: Environment,
+{
+    let (name, value, body) = let_parts(args)?;
+    let value = evaluate::<N, E>(value, env)?.to_value();
+    sample(env, name, body, value)
+}
+
+fn resolve<N, E>(name: &str, args: &[Expr], env: &mut E) -> Result<N, CalcError>
+where
+    N: CalcNum,
+    E: Environment,
+{
+    match env.arity(name) {
+        Some(arity) if arity == args.len() => {}
+        Some(arity) => {
+            return Err(CalcError::WrongArity {
+                atom: name.to_owned(),
+                expected: arity,
+                actual: args.len(),
+            })
+        }
+        None => return Err(CalcError::unknown_atom(name, &*env)),
+    }
+    let mut values = Vec::with_capacity(args.len());
+    for arg in args {
+        values.push(evaluate::<N, E>(arg, env)?);
+    }
+    N::call(name, values, |args| env.resolve(name, args))
+}
+
+/// Wraps an environment while parsing into a tree, so that undefined atoms
+/// become variables instead of errors.
+pub(crate) struct Symbolic<'a, E: 'a + ?Sized>(pub(crate) &'a mut E);
+
+impl<'a, E: Environment + ?Sized> Environment for Symbolic<'a, E> {
+    fn arity(&self, atom: &str) -> Option<usize> {
+        self.0.arity(atom).or(Some(0))
+    }
+
+    fn resolve(
+        &mut self,
+        atom: &str,
+        args: &[Value],
+    ) -> Result<Value, CalcError> {
+        self.0.resolve(atom, args)
+    }
+
+    fn max_magnitude(&self) -> Option<f64> {
+        self.0.max_magnitude()
+    }
+
+    fn integer_mode(&self) -> IntegerMode {
+        self.0.integer_mode()
+    }
+
+    fn lenient_parens(&self) -> bool {
+        self.0.lenient_parens()
+    }
+
+    fn compensated_sum(&self) -> bool {
+        self.0.compensated_sum()
+    }
+
+    fn operators(&self) -> Vec<Operator> {
+        self.0.operators()
+    }
+
+    fn precedence(&self, op: BinaryOp) -> u8 {
+        self.0.precedence(op)
+    }
+
+    fn assoc(&self, op: BinaryOp) -> Assoc {
+        self.0.assoc(op)
+    }
+
+    fn warn(&mut self, message: String) {
+        self.0.warn(message)
+    }
+
+    fn names(&self) -> Vec<String> {
+        self.0.names()
+    }
+
+    fn step(&mut self) -> Result<(), CalcError> {
+        self.0.step()
+    }
+
+    fn max_depth(&self) -> Option<usize> {
+        let max_depth = self.0.max_depth().unwrap_or(MAX_DEPTH);
+        Some(max_depth.min(MAX_DEPTH))
+    }
+}
+
+/// Receives the nodes of an expression from `walk`.
+///
+/// The `visit_*` methods are called in pre-order; those of nodes with
+/// children return whether to walk into them. `leave` is called once all
+/// children of a node have been walked, or skipped.
+pub trait Visitor {
+    fn visit_num(&mut self, _value: &Value) {}
+
+    fn visit_var(&mut self, _name: &str) {}
+
+    fn visit_call(&mut self, _name: &str, _args: &[Expr]) -> bool {
+        true
+    }
+
+    fn visit_unary(&mut self, _op: UnaryOp, _arg: &Expr) -> bool {
+        true
+    }
+
+    fn visit_binary(
+        &mut self,
+        _op: BinaryOp,
+        _lhs: &Expr,
+        _rhs: &Expr,
+    ) -> bool {
+        true
+    }
+
+    fn leave(&mut self, _expr: &Expr) {}
+}
+
+/// Walks `expr` depth-first, calling `visitor` for every node.
+pub fn walk<V: Visitor>(expr: &Expr, visitor: &mut V) {
+    enum Step<'a> {
+        Enter(&'a Expr),
+        Leave(&'a Expr),
+    }
+
+    // An explicit stack keeps long operator chains from overflowing the
+    // call stack.
+    let mut stack = vec![Step::Enter(expr)];
+    while let Some(step) = stack.pop() {
+        let node = match step {
+            Step::Enter(node) => node,
+            Step::Leave(node) => {
+                visitor.leave(node);
+                continue;
+            }
+        };
+        stack.push(Step::Leave(node));
+        match *node {
+            Expr::Num(ref value) => visitor.visit_num(value),
+            Expr::Var(ref name) => visitor.visit_var(name),
+            Expr::Call(ref name, ref args) => {
+                if visitor.visit_call(name, args) {
+                    stack.extend(args.iter().rev().map(Step::Enter));
+                }
+            }
+            Expr::Unary(op, ref arg) => {
+                if visitor.visit_unary(op, arg) {
+                    stack.push(Step::Enter(arg));
+                }
+            }
+            Expr::Binary(op, ref lhs, ref rhs) => {
+                if visitor.visit_binary(op, lhs, rhs) {
+                    stack.push(Step::Enter(rhs));
+                    stack.push(Step::Enter(lhs));
+                }
+            }
+        }
+    }
+}
+
+/// A pre-order iterator over the nodes of an `Expr`, created by
+/// `Expr::iter`.
+pub struct Iter<'a> {
+    stack: Vec<&'a Expr>,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a Expr;
+
+    fn next(&mut self) -> Option<&'a Expr> {
+        let node = self.stack.pop()?;
+        match *node {
+            Expr::Num(_) | Expr::Var(_) => {}
+            Expr::Call(_, ref args) => self.stack.extend(args.iter().rev()),
+            Expr::Unary(_, ref arg) => self.stack.push(arg),
+            Expr::Binary(_, ref lhs, ref rhs) => {
+                self.stack.push(rhs);
+                self.stack.push(lhs);
+            }
+        }
+        Some(node)
+    }
+}
+
+fn write_operand(
+    f: &mut fmt::Formatter,
+    expr: &Expr,
+    parens: bool,
+) -> fmt::Result {
+    if parens {
+        write!(f, "({})", expr)
+    } else {
+        write!(f, "{}", expr)
+    }
+}
+
+/// Renders `value` as a literal that parses back into the same value. A
+/// float keeps its decimal point, so that it does not become an integer.
+pub(crate) fn literal(value: &Value) -> String {
+    let text = value.to_string();
+    match *value {
+        Value::Float(n)
+            if n.is_finite() && !text.contains(&['.', 'E', 'e'][..]) =>
+        {
+            text + "."
+        }
+        _ => text,
+    }
+}
+
+/// Whether `expr` has to be parenthesized as the base of a power, because
+/// a leading `-` would negate the power, as in `-2 ** 2`.
+pub(crate) fn negative_base(expr: &Expr) -> bool {
+    match *expr {
+        Expr::Num(ref value) => literal(value).starts_with('-'),
+        _ => false,
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Expr::Num(ref value) => write!(f, "{}", literal(value)),
+            Expr::Var(ref name) => write!(f, "{}", name),
+            Expr::Call(ref name, ref args)
+                if name == "let" && let_parts(args).is_ok() =>
+            {
+                let (name, value, body) = let_parts(args).expect("checked");
+                write!(f, "let {} = {} in {}", name, value, body)
+            }
+            Expr::Call(ref name, ref args)
+                if name == "cond"
+                    || name == "let"
+                    || Binder::from_name(name).is_some() =>
+            {
+                write!(f, "{}(", name)?;
+                for (i, arg) in args.iter().enumerate() {
+                    let sep = if i == 0 { "" } else { ", " };
+                    write!(f, "{}{}", sep, arg)?;
+                }
+                write!(f, ")")
+            }
+            Expr::Call(ref name, ref args) => {
+                write!(f, "{}", name)?;
+                for arg in args {
+                    write!(f, " ")?;
+                    write_operand(f, arg, arg.precedence() < 5)?;
+                }
+                Ok(())
+            }
+            Expr::Unary(op, ref arg) => {
+                write!(f, "{}", op.symbol())?;
+                write_operand(f, arg, arg.precedence() < 5)
+            }
+            Expr::Binary(op, ref lhs, ref rhs) => {
+                let prec = op.precedence();
+                let right_assoc = op == BinaryOp::Pow;
+                let (lhs_prec, rhs_prec) = (lhs.precedence(), rhs.precedence());
+                write_operand(
+                    f,
+                    lhs,
+                    lhs_prec < prec
+                        || (right_assoc && lhs_prec == prec)
+                        || (right_assoc && negative_base(lhs)),
+                )?;
+                write!(f, " {} ", op.symbol())?;
+                write_operand(
+                    f,
+                    rhs,
+                    rhs_prec < prec || (!right_assoc && rhs_prec == prec),
+                )
+            }
+        }
+    }
+}
+
+macro_rules! binary_op {
+    ($trait:ident, $method:ident, $op:expr) => {
+        impl ops::$trait for Expr {
+            type Output = Expr;
+
+            fn $method(self, that: Expr) -> Expr {
+                Expr::binary($op, self, that)
+            }
+        }
+    };
+}
+
+binary_op!(Add, add, BinaryOp::Add);
+binary_op!(Sub, sub, BinaryOp::Sub);
+binary_op!(Mul, mul, BinaryOp::Mul);
+binary_op!(Div, div, BinaryOp::Div);
+binary_op!(Rem, rem, BinaryOp::Rem);
+binary_op!(BitAnd, bitand, BinaryOp::BitAnd);
+binary_op!(BitOr, bitor, BinaryOp::BitOr);
+binary_op!(BitXor, bitxor, BinaryOp::BitXor);
+binary_op!(Shl, shl, BinaryOp::Shl);
+binary_op!(Shr, shr, BinaryOp::Shr);
+
+impl ops::Neg for Expr {
+    type Output = Expr;
+
+    fn neg(self) -> Expr {
+        Expr::unary(UnaryOp::Neg, self)
+    }
+}
+
+/// `!expr` builds the bitwise complement `~expr`.
+impl ops::Not for Expr {
+    type Output = Expr;
+
+    fn not(self) -> Expr {
+        Expr::unary(UnaryOp::Not, self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use calculator::Calculator;
+    use {eval_expr, eval_expr_with};
+
+    fn env() -> Calculator {
+        let mut env = Calculator::new();
+        env.set_var("x", Value::dec(3));
+        env.set_var("y", Value::from(0.5));
+        env
+    }
+
+    #[test]
+    fn builder_matches_parser() {
+        let x = || Expr::var("x");
+        let cases = vec![
+            (x() * Expr::num(2) + Expr::num(1), "x * 2 + 1"),
+            (Expr::num(1) + x() * Expr::num(2), "1 + x * 2"),
+            ((x() + Expr::num(1)) * Expr::var("y"), "(x + 1) * y"),
+            (x() - (Expr::num(1) - x()), "x - (1 - x)"),
+            (Expr::num(2.5) * x() % Expr::num(4), "2.5 * x % 4"),
+            (Expr::num(3).pow(Expr::num(2)) * x(), "3² * x"),
+            (Expr::num(2).pow(x().pow(Expr::num(2))), "2 ** x ** 2"),
+            (Expr::num(-2).pow(Expr::num(2)), "(-2) ** 2"),
+            (-Expr::num(2).pow(Expr::num(2)), "-2 ** 2"),
+            (-Expr::num(3) * x(), "-3 * x"),
+            (Expr::num(2) * -(x() + Expr::var("y")), "2 * (-(x + y))"),
+            (
+                Expr::num(Value::hex(0xFF)) & x() | Expr::num(1) << x(),
+                "0xFF & x | (1 << x)",
+            ),
+            (!(x() ^ Expr::num(1)) >> Expr::num(1), "(~(x ^ 1)) >> 1"),
+            (
+                Expr::call("log", vec![Expr::num(100)]) / Expr::var("pi"),
+                "log 100 / pi",
+            ),
+            (Expr::call("log", vec![x() + Expr::num(7)]), "log (x + 7)"),
+        ];
+        for (built, source) in cases {
+            let parsed = Expr::parse(source).unwrap();
+            assert_eq!(built, parsed, "{}", source);
+            assert_eq!(Expr::parse(&built.to_string()), Ok(built.clone()));
+            let expected = ::eval_with_env(source, &mut env());
+            assert_eq!(eval_expr_with(&built, &mut env()), expected);
+        }
+    }
+
+    #[test]
+    fn literal_round_trip() {
+        let cases = vec![
+            ("-0x1f", "-0x1F"),
+            ("1.", "1."),
+            ("1. & 1", "1. & 1"),
+            ("2.50 - 0x1f", "2.50 - 0x1F"),
+        ];
+        for (source, shown) in cases {
+            let expr = Expr::parse(source).unwrap();
+            assert_eq!(expr.to_string(), shown);
+            assert_eq!(Expr::parse(shown), Ok(expr));
+            assert_eq!(::eval(shown), ::eval(source));
+        }
+    }
+
+    #[test]
+    fn generic_evaluation() {
+        let e = Expr::var("x") * Expr::num(2) + Expr::num(1);
+        assert_eq!(evaluate::<f64, _>(&e, &mut env()), Ok(7.0));
+        assert_eq!(evaluate::<i128, _>(&e, &mut env()), Ok(7));
+    }
+
+    #[derive(Default)]
+    struct Counter {
+        leaves: usize,
+        operators: usize,
+        depth: usize,
+        max_depth: usize,
+    }
+
+    impl Counter {
+        fn enter(&mut self) -> bool {
+            self.operators += 1;
+            self.depth += 1;
+            self.max_depth = self.max_depth.max(self.depth);
+            true
+        }
+    }
+
+    impl Visitor for Counter {
+        fn visit_num(&mut self, _: &Value) {
+            self.leaves += 1;
+        }
+
+        fn visit_var(&mut self, _: &str) {
+            self.leaves += 1;
+        }
+
+        fn visit_call(&mut self, _: &str, _: &[Expr]) -> bool {
+            self.enter()
+        }
+
+        fn visit_unary(&mut self, _: UnaryOp, _: &Expr) -> bool {
+            self.enter()
+        }
+
+        fn visit_binary(&mut self, _: BinaryOp, _: &Expr, _: &Expr) -> bool {
+            self.enter()
+        }
+
+        fn leave(&mut self, expr: &Expr) {
+            match *expr {
+                Expr::Num(_) | Expr::Var(_) => {}
+                _ => self.depth -= 1,
+            }
+        }
+    }
+
+    #[test]
+    fn visitor() {
+        let e = Expr::parse("log (x + 1) * (-(y - 2 ** x)) + 3").unwrap();
+        let mut counter = Counter::default();
+        walk(&e, &mut counter);
+        assert_eq!((counter.leaves, counter.operators), (6, 7));
+        assert_eq!(counter.leaves + counter.operators, e.iter().count());
+        assert_eq!(counter.max_depth, 5);
+        assert_eq!(counter.depth, 0);
+
+        let flat = (1..10_000).fold(Expr::num(1), |acc, _| {
+            Expr::binary(BinaryOp::Add, acc, Expr::num(1))
+        });
+        let mut counter = Counter::default();
+        walk(&flat, &mut counter);
+        assert_eq!(counter.leaves, 10_000);
+    }
+
+    #[test]
+    fn pre_order() {
+        let e = Expr::parse("(1 - x) * log 2").unwrap();
+        let nodes: Vec<String> = e.iter().map(|n| n.to_string()).collect();
+        assert_eq!(
+            nodes,
+            vec!["(1 - x) * log 2", "1 - x", "1", "x", "log 2", "2"]
+        );
+    }
+
+    #[test]
+    fn transform() {
+        let e = Expr::parse("2 * x + log 2 ** 2").unwrap();
+        let twos = e.iter().filter(|n| **n == Expr::num(2)).count();
+        assert_eq!(twos, 3);
+        let mapped =
+            e.map(|n| if n == Expr::num(2) { Expr::num(3) } else { n });
+        assert_eq!(mapped, Expr::parse("3 * x + log 3 ** 3").unwrap());
+        let mut env = env();
+        assert_eq!(
+            eval_expr_with(&mapped, &mut env),
+            ::eval_with_env("3 * x + log 3 ** 3", &mut env)
+        );
+        // Negated literals are folded again when the tree is rebuilt.
+        let negated = Expr::parse("-(x)").unwrap().map(|n| match n {
+            Expr::Var(_) => Expr::num(2),
+            n => n,
+        });
+        assert_eq!(negated, Expr::num(-2));
+    }
+
+    #[test]
+    fn substitution() {
+        let template = Expr::parse("x ** 2 + log (x * y) - z").unwrap();
+        let cases = vec![
+            ("x", Expr::num(3), "3 ** 2 + log (3 * y) - z"),
+            (
+                "y",
+                Expr::var("x") + Expr::num(1),
+                "x ** 2 + log (x * (x + 1)) - z",
+            ),
+            (
+                "x",
+                Expr::var("x") * Expr::var("x"),
+                "(x * x) ** 2 + log (x * x * y) - z",
+            ),
+            ("w", Expr::num(1), "x ** 2 + log (x * y) - z"),
+        ];
+        for (name, with, expected) in cases {
+            let e = template.substitute(name, &with);
+            assert_eq!(e, Expr::parse(expected).unwrap());
+        }
+
+        let mut vars = HashMap::new();
+        vars.insert("x".to_owned(), Expr::num(4));
+        vars.insert("y".to_owned(), Expr::num(25));
+        vars.insert("z".to_owned(), Expr::var("x") / Expr::num(2));
+        let e = template.substitute_all(&vars);
+        assert_eq!(e, Expr::parse("4 ** 2 + log (4 * 25) - x / 2").unwrap());
+
+        let mut env = Calculator::new();
+        env.set_var("x", Value::dec(4));
+        let mut all = env.clone();
+        all.set_var("y", Value::dec(25));
+        all.set_var("z", Value::dec(2));
+        let expected = ::eval_with_env("x ** 2 + log (x * y) - z", &mut all);
+        assert_eq!(eval_expr_with(&e, &mut env), expected);
+    }
+
+    #[test]
+    fn partial_evaluation() {
+        let e = Expr::parse("(2 + 3) * x + (4 / 2)").unwrap();
+        let folded = e.partial_eval(&mut Calculator::new());
+        assert_eq!(folded, Expr::parse("5 * x + 2").unwrap());
+
+        let inputs = vec![
+            "(2 + 3) * x + 4 / 2",
+            "x * y - log 100",
+            "2 ** (y + 1) * x",
+            "(x + 1) * (y - 2) % 3 + (0x10 | 1)",
+            "y / 2 + tau * x ** 2",
+            "(~(x & 6)) << (y - 4)",
+        ];
+        let values = vec![("x", 3), ("y", 5)];
+        for input in inputs {
+            let e = Expr::parse(input).unwrap();
+            let mut all = Calculator::new();
+            for &(name, value) in &values {
+                all.set_var(name, Value::dec(value));
+            }
+            let expected = eval_expr_with(&e, &mut all);
+            assert_eq!(::eval_with_env(input, &mut all), expected);
+            for &(name, value) in &values {
+                let mut known = Calculator::new();
+                known.set_var(name, Value::dec(value));
+                let folded = e.partial_eval(&mut known);
+                assert!(folded.iter().all(|n| *n != Expr::var(name)));
+                let (other, value) = values
+                    .iter()
+                    .cloned()
+                    .find(|&(other, _)| other != name)
+                    .unwrap();
+                let e = folded.substitute(other, &Expr::num(value));
+                assert_eq!(eval_expr(&e), expected, "{}", input);
+            }
+        }
+
+        let e = Expr::parse("x + 1 / (1 - 1) + 2 * 3").unwrap();
+        let folded = e.partial_eval(&mut Calculator::new());
+        let expected = Expr::var("x") + Expr::num(1) / Expr::num(0);
+        assert_eq!(folded, expected + Expr::num(6));
+        let mut env = Calculator::new();
+        env.set_var("x", Value::dec(1));
+        assert_eq!(
+            eval_expr_with(&folded, &mut env),
+            Err(CalcError::DivideByZero)
+        );
+    }
+
+    #[test]
+    fn resolution_errors() {
+        let mut env = env();
+        assert_eq!(
+            eval_expr_with(&(Expr::var("z") + Expr::num(1)), &mut env),
+            Err(CalcError::UnknownAtom("z".into(), None))
+        );
+        assert_eq!(
+            eval_expr_with(&Expr::var("log"), &mut env),
+            Err(CalcError::WrongArity {
+                atom: "log".into(),
+                expected: 1,
+                actual: 0,
+            })
+        );
+        assert_eq!(Expr::parse("1 +"), Err(CalcError::UnexpectedEndOfInput));
+    }
+}
@@ -0,0 +1,369 @@
+//! A mode that reads and prints numbers like the POSIX `bc` calculator.
+
+use calculator::Calculator;
+use context;
+use error::CalcError;
+use num::{BigInt, BigRational, Signed, ToPrimitive, Zero};
+use value::{ops, Value};
+
+/// Evaluates statements the way `bc` does, governed by its three special
+/// variables:
+///
+/// - `scale`, the number of fractional digits of results, which are
+///   truncated rather than rounded. It starts at 0, so `10 / 3` is `3`.
+/// - `ibase`, the radix of the numbers in the input, from 2 to 16. Digits
+///   above 9 are written `A` to `F`, so after `ibase = 16`, `FF` is 255.
+///   `A` is always 10, so `ibase = A` goes back to decimal from any radix.
+/// - `obase`, the radix of printed results, from 2 to 16.
+///
+/// Statements are separated by `;` or newlines. Assignments, such as
+/// `scale = 4` or `x = 2 / 3`, print nothing and take effect for the
+/// statements that follow; every other statement prints its value. As in
+/// `bc`, `^` raises to a power, and results between -1 and 1 are printed
+/// without a leading zero, e.g. `.66`.
+///
+/// Unlike `bc`, numbers do not carry a scale of their own: non-integral
+/// results get `scale` digits, or as many as the longest fraction written
+/// in the statement if that is more. `1.5 * 3` is therefore `4.5` at any
+/// scale, but divisions that come out even, such as `8 / 2`, print as
+/// integers where `bc` prints `4.00` at a scale of 2.
+///
+/// ```
+/// # extern crate calc;
+/// # use calc::{Bc, CalcError};
+/// # fn main() -> Result<(), CalcError> {
+/// let mut bc = Bc::new();
+/// assert_eq!(bc.run("scale = 4; 10 / 3")?, vec!["3.3333"]);
+/// assert_eq!(bc.run("obase = 16; 255")?, vec!["FF"]);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Bc {
+    calculator: Calculator,
+    scale: u32,
+    ibase: u32,
+    obase: u32,
+}
+
+impl Default for Bc {
+    fn default() -> Self {
+        Bc::new()
+    }
+}
+
+impl Bc {
+    pub fn new() -> Self {
+        let mut bc = Bc {
+            calculator: Calculator::new(),
+            scale: 0,
+            ibase: 10,
+            obase: 10,
+        };
+        for &name in &["scale", "ibase", "obase"] {
+            let value = bc.special(name).expect("special variable");
+            bc.calculator.set_var(name, Value::dec(value));
+        }
+        bc
+    }
+
+    pub fn scale(&self) -> u32 {
+        self.scale
+    }
+
+    pub fn ibase(&self) -> u32 {
+        self.ibase
+    }
+
+    pub fn obase(&self) -> u32 {
+        self.obase
+    }
+
+    /// Sets the number of fractional digits of results.
+    pub fn set_scale(&mut self, scale: u32) {
+        self.scale = scale;
+        self.calculator.set_var("scale", Value::dec(scale));
+    }
+
+    /// Sets the radix of the input, which must be from 2 to 16.
+    pub fn set_ibase(&mut self, ibase: u32) -> Result<(), CalcError> {
+        self.ibase = check_base("ibase", ibase)?;
+        self.calculator.set_var("ibase", Value::dec(ibase));
+        Ok(())
+    }
+
+    /// Sets the radix of printed results, which must be from 2 to 16.
+    pub fn set_obase(&mut self, obase: u32) -> Result<(), CalcError> {
+        self.obase = check_base("obase", obase)?;
+        self.calculator.set_var("obase", Value::dec(obase));
+        Ok(())
+    }
+
+    /// Runs the statements of `input` and returns the printed results, one
+    /// per statement that is not an assignment. Stops at the first
+    /// statement that fails; the ones before it keep their effect.
+    pub fn run(&mut self, input: &str) -> Result<Vec<String>, CalcError> {
+        let mut output = Vec::new();
+        for statement in input.split(&[';', '\n'][..]) {
+            let statement = statement.trim();
+            if statement.is_empty() {
+                continue;
+            }
+            if let Some(equals) = statement.find('=') {
+                let name = statement[..equals].trim();
+                if context::is_name(name) {
+                    self.assign(name, &statement[equals + 1..])?;
+                    continue;
+                }
+            }
+            let (expr, digits) = self.translate(statement);
+            let value = self.calculator.eval(&expr)?;
+            output.push(self.format(&value, digits.max(self.scale))?);
+        }
+        Ok(output)
+    }
+
+    fn special(&self, name: &str) -> Option<u32> {
+        match name {
+            "scale" => Some(self.scale),
+            "ibase" => Some(self.ibase),
+            "obase" => Some(self.obase),
+            _ => None,
+        }
+    }
+
+    fn assign(&mut self, name: &str, input: &str) -> Result<(), CalcError> {
+        let (expr, _) = self.translate(input);
+        let value = self.calculator.eval(&expr)?;
+        if self.special(name).is_none() {
+            self.calculator.set_var(name, value);
+            return Ok(());
+        }
+        let n = match value {
+            Value::Integral(ref n, _) => n.to_u32(),
+            Value::Float(_) => None,
+        };
+        let n = n.ok_or_else(|| {
+            CalcError::InvalidArgument(format!(
+                "{} must be a non-negative integer, got {}",
+                name, value
+            ))
+        })?;
+        match name {
+            "scale" => self.set_scale(n),
+            "ibase" => self.set_ibase(n)?,
+            _ => self.set_obase(n)?,
+        }
+        Ok(())
+    }
+
+    /// Rewrites a statement into the syntax of `eval`, reading its numbers
+    /// in `ibase`. Also returns the length of the longest fraction among
+    /// the numbers.
+    fn translate(&self, statement: &str) -> (String, u32) {
+        let chars: Vec<char> = statement.chars().collect();
+        let mut out = String::with_capacity(statement.len());
+        let mut digits = 0;
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            let after_word = i > 0 && is_word(chars[i - 1]);
+            let starts_number = is_digit(c)
+                || c == '.'
+                    && matches!(chars.get(i + 1), Some(&c) if is_digit(c));
+            if after_word || !starts_number {
+                if c == '^' {
+                    out.push_str("**");
+                } else {
+                    out.push(c);
+                }
+                i += 1;
+                continue;
+            }
+            let start = i;
+            while i < chars.len() && is_digit(chars[i]) {
+                i += 1;
+            }
+            let point = i;
+            if i < chars.len() && chars[i] == '.' {
+                i += 1;
+                while i < chars.len() && is_digit(chars[i]) {
+                    i += 1;
+                }
+            }
+            let text: String = chars[start..i].iter().collect();
+            if i < chars.len() && is_word(chars[i]) {
+                // Part of a word such as `0x1F`, which `eval` reports.
+                out.push_str(&text);
+                continue;
+            }
+            let fraction = &chars[(point + 1).min(i)..i];
+            digits = digits.max(fraction.len() as u32);
+            if self.ibase == 10 && text.chars().all(|c| !c.is_ascii_uppercase())
+            {
+                if point == start {
+                    out.push('0');
+                }
+                out.push_str(&text);
+            } else {
+                out.push_str(&self.read_number(&chars[start..point], fraction));
+            }
+        }
+        (out, digits)
+    }
+
+    /// Converts a number written in `ibase` into decimal syntax.
+    fn read_number(&self, whole: &[char], fraction: &[char]) -> String {
+        let base = BigInt::from(self.ibase);
+        let value = |digits: &[char]| {
+            digits.iter().fold(BigInt::zero(), |acc, &c| {
+                acc * &base + BigInt::from(digit_value(c))
+            })
+        };
+        let whole = value(whole);
+        if fraction.is_empty() {
+            return whole.to_string();
+        }
+        let denominator = ops::int_powu(&base, &(fraction.len() as u64).into());
+        format!("({} + {} / {})", whole, value(fraction), denominator)
+    }
+
+    /// Renders `value` in `obase`, truncated to `digits` decimal digits of
+    /// fraction, or the equivalent number of `obase` digits.
+    fn format(&self, value: &Value, digits: u32) -> Result<String, CalcError> {
+        let n = match *value {
+            Value::Integral(ref n, _) => BigRational::from_integer(n.clone()),
+            Value::Float(f) => ops::to_rational(f).ok_or_else(|| {
+                CalcError::InvalidArgument(format!("{} has no bc form", value))
+            })?,
+        };
+        let base = BigInt::from(self.obase);
+        let whole = n.abs().trunc();
+        let mut rest = n.abs() - &whole;
+        let mut text = whole.to_integer().to_str_radix(self.obase);
+        if let Value::Float(_) = *value {
+            // As many digits in `obase` as it takes to resolve `digits`
+            // decimal ones.
+            let limit = ops::int_powu(&10.into(), &u64::from(digits).into());
+            let mut resolution = BigInt::from(1);
+            let mut fraction = String::new();
+            while resolution < limit {
+                resolution = resolution * &base;
+                rest = rest * BigRational::from_integer(base.clone());
+                let digit = rest.trunc();
+                rest = rest - &digit;
+                let digit = digit.to_integer().to_u32().expect("digit in base");
+                fraction.push_str(&digit_text(digit));
+            }
+            if !fraction.is_empty() {
+                if whole.is_zero() {
+                    text.clear();
+                }
+                text.push('.');
+                text.push_str(&fraction);
+            }
+        }
+        let text = text.to_uppercase();
+        if text.chars().all(|c| c == '0' || c == '.') {
+            return Ok("0".into());
+        }
+        Ok(if n.is_negative() {
+            format!("-{}", text)
+        } else {
+            text
+        })
+    }
+}
+
+fn check_base(name: &str, base: u32) -> Result<u32, CalcError> {
+    if !(2..=16).contains(&base) {
+        return Err(CalcError::InvalidArgument(format!(
+            "{} must be from 2 to 16, got {}",
+            name, base
+        )));
+    }
+    Ok(base)
+}
+
+fn is_digit(c: char) -> bool {
+    c.is_ascii_digit() || ('A'..='F').contains(&c)
+}
+
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn digit_value(c: char) -> u32 {
+    c.to_digit(16).expect("bc digit")
+}
+
+fn digit_text(digit: u32) -> String {
+    ::std::char::from_digit(digit, 16)
+        .expect("digit in base")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs each line in a fresh `Bc`, paired with what GNU bc 1.07 prints
+    /// for it.
+    const MATRIX: &[(&str, &[&str])] = &[
+        ("2 + 3 * 4", &["14"]),
+        ("2 ^ 10", &["1024"]),
+        ("7 % 3", &["1"]),
+        ("10 / 3", &["3"]),
+        ("scale = 4; 10 / 3", &["3.3333"]),
+        ("scale = 2; 2 / 3", &[".66"]),
+        ("scale = 2; -2 / 3", &["-.66"]),
+        ("scale = 5; 1 / 8", &[".12500"]),
+        ("scale = 1; 5 / 2", &["2.5"]),
+        ("scale = 2; 1 / 1000", &["0"]),
+        ("1.5 * 3", &["4.5"]),
+        (".5 + .25", &[".75"]),
+        ("scale", &["0"]),
+        ("scale = 3; scale", &["3"]),
+        ("1; 2", &["1", "2"]),
+        ("x = 5; x * 2", &["10"]),
+        ("ibase = 16; FF", &["255"]),
+        ("ibase = 2; 1010", &["10"]),
+        ("ibase = 16; ibase = A; 10", &["10"]),
+        ("ibase = 16; ibase = 10; 10", &["16"]),
+        ("ibase = 2; 1.1", &["1.5"]),
+        ("obase = 16; 255", &["FF"]),
+        ("obase = 16; -255", &["-FF"]),
+        ("obase = 2; 10", &["1010"]),
+        ("ibase = 16; obase = 2; A", &["1010"]),
+        ("obase = 16; 10 / 4", &["2"]),
+        ("obase = 2; scale = 1; 1 / 2", &[".1000"]),
+    ];
+
+    #[test]
+    fn matches_bc() {
+        for &(input, expected) in MATRIX {
+            let mut bc = Bc::new();
+            assert_eq!(bc.run(input).unwrap(), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn settings_persist() {
+        let mut bc = Bc::new();
+        assert_eq!(bc.run("scale = 2"), Ok(vec![]));
+        assert_eq!(bc.run("1 / 3"), Ok(vec![".33".to_owned()]));
+        assert_eq!(bc.scale(), 2);
+        bc.set_obase(16).unwrap();
+        assert_eq!(bc.run("obase"), Ok(vec!["10".to_owned()]));
+    }
+
+    #[test]
+    fn bad_settings() {
+        let mut bc = Bc::new();
+        assert!(bc.run("ibase = 1").is_err());
+        assert!(bc.run("obase = 17").is_err());
+        assert!(bc.run("scale = 1 / 2").is_err());
+        assert!(bc.run("scale = -1").is_err());
+        assert_eq!((bc.scale(), bc.ibase(), bc.obase()), (0, 10, 10));
+    }
+}
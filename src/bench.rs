@@ -1,5 +1,49 @@
-use super::eval;
+use super::{
+    ast, eval, eval_many, Calculator, Context, Expr, Expression, Value,
+};
+use parse::{self, DefaultEnvironment};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
 use test::Bencher;
+use token::{tokenize, TokenStream, Tokenizer};
+
+/// Counts the allocations made by each thread, so that tests running in
+/// parallel do not disturb each other's counts.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(
+        &self,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn count_allocations<T, F: FnOnce() -> T>(f: F) -> (T, usize) {
+    let before = ALLOCATIONS.with(Cell::get);
+    let result = f();
+    (result, ALLOCATIONS.with(Cell::get) - before)
+}
 
 const SEXPR: &str = "4 ** (88 * 9 / (59 >> 3))";
 const MEXPR: &str = "((((87))) - 73) + (97 + (((15 / 55 * ((31)) + 35))) + (15 - (9)) - (39 / 26) / 20 / 91 + 27 / (33 * 26 + 28 - (7) / 10 + 66 * 6) + 60 / 35 - ((29) - (69) / 44 / (92)) / (89) + 2 + 87 / 47 * ((2)) * 83 / 98 * 42 / (((67)) * ((97))) / (34 / 89 + 77) - 29 + 70 * (20)) + ((((((92))) + 23 * (98) / (95) + (((99) * (41))) + (5 + 41) + 10) - (36) / (6 + 80 * 52 + (90))))";
@@ -13,3 +57,256 @@ fn small_expr(bench: &mut Bencher) {
 fn medium_expr(bench: &mut Bencher) {
     bench.iter(|| eval(MEXPR));
 }
+
+fn many_inputs() -> Vec<String> {
+    (1..100_001)
+        .map(|i| format!("{} * ({} + 3) % 7", i, i))
+        .collect()
+}
+
+#[bench]
+fn many_serial(bench: &mut Bencher) {
+    let inputs = many_inputs();
+    bench.iter(|| inputs.iter().map(|s| eval(s)).collect::<Vec<_>>());
+}
+
+#[bench]
+fn many_batch(bench: &mut Bencher) {
+    let inputs = many_inputs();
+    let inputs: Vec<&str> = inputs.iter().map(|s| s.as_str()).collect();
+    bench.iter(|| eval_many(&inputs));
+}
+
+fn formulas() -> Vec<String> {
+    (1..1001)
+        .map(|i| {
+            format!(
+                "{} * (x + {}) - y / {} ** 2 + sin ({} * z) % (w - {})",
+                i,
+                i % 7 + 1,
+                i % 5 + 1,
+                i,
+                i % 3 + 1
+            )
+        })
+        .collect()
+}
+
+#[bench]
+fn compile_tree(bench: &mut Bencher) {
+    let inputs = formulas();
+    bench.iter(|| inputs.iter().map(|s| Expr::parse(s)).collect::<Vec<_>>());
+}
+
+#[bench]
+fn compile_flat(bench: &mut Bencher) {
+    let inputs = formulas();
+    bench.iter(|| {
+        inputs
+            .iter()
+            .map(|s| s.parse::<Expression>())
+            .collect::<Vec<_>>()
+    });
+}
+
+#[test]
+fn compile_allocations() {
+    let inputs = formulas();
+    let (trees, tree_allocations) = count_allocations(|| {
+        inputs
+            .iter()
+            .map(|s| Expr::parse(s).unwrap())
+            .collect::<Vec<_>>()
+    });
+    let (flat, flat_allocations) = count_allocations(|| {
+        inputs
+            .iter()
+            .map(|s| s.parse::<Expression>().unwrap())
+            .collect::<Vec<_>>()
+    });
+    assert_eq!(trees.len(), flat.len());
+    assert!(
+        flat_allocations < tree_allocations,
+        "{} allocations, not fewer than the {} of trees",
+        flat_allocations,
+        tree_allocations
+    );
+}
+
+fn long_expression() -> String {
+    (1..10_001)
+        .map(|i| format!("({} * x_{} + {}.25)", i, i, i))
+        .collect::<Vec<_>>()
+        .join(" ** ")
+}
+
+#[bench]
+fn tokenize_owned(bench: &mut Bencher) {
+    let input = long_expression();
+    bench.iter(|| tokenize(&input));
+}
+
+#[bench]
+fn tokenize_borrowed(bench: &mut Bencher) {
+    let input = long_expression();
+    let mut tokens = Vec::with_capacity(input.len());
+    bench.iter(|| {
+        tokens.clear();
+        for token in Tokenizer::new(&input) {
+            tokens.push(token.unwrap());
+        }
+        tokens.len()
+    });
+}
+
+#[test]
+fn tokenize_without_allocating() {
+    let input = long_expression();
+    let mut tokens = Vec::with_capacity(input.len());
+    let (result, allocations) = count_allocations(|| {
+        for token in Tokenizer::new(&input) {
+            tokens.push(token?);
+        }
+        Ok::<(), ::CalcError>(())
+    });
+    assert_eq!(result, Ok(()));
+    assert_eq!(allocations, 0);
+    assert_eq!(tokens.len(), tokenize(&input).unwrap().len());
+}
+
+/// About a megabyte of numeric literals.
+fn numbers() -> String {
+    (1..40_001)
+        .map(|i| format!("{}.{} + {}", i * 7919, i, i * 31))
+        .collect::<Vec<_>>()
+        .join(" - ")
+}
+
+#[bench]
+fn tokenize_numbers(bench: &mut Bencher) {
+    let input = numbers();
+    let mut tokens = Vec::with_capacity(input.len());
+    bench.iter(|| {
+        tokens.clear();
+        for token in Tokenizer::new(&input) {
+            tokens.push(token.unwrap());
+        }
+        tokens.len()
+    });
+}
+
+#[bench]
+fn tokenize_numbers_owned(bench: &mut Bencher) {
+    let input = numbers();
+    bench.iter(|| tokenize(&input).map(|tokens| tokens.len()));
+}
+
+#[bench]
+fn tokenize_numbers_packed(bench: &mut Bencher) {
+    let input = numbers();
+    bench.iter(|| TokenStream::new(&input).map(|tokens| tokens.len()));
+}
+
+#[bench]
+fn parse_numbers_owned(bench: &mut Bencher) {
+    let tokens = tokenize(&numbers()).unwrap();
+    bench.iter(|| parse::parse(&tokens, &mut DefaultEnvironment));
+}
+
+#[bench]
+fn parse_numbers_packed(bench: &mut Bencher) {
+    let input = numbers();
+    let tokens = TokenStream::new(&input).unwrap();
+    bench.iter(|| parse::build::<Value, _>(&tokens, &mut DefaultEnvironment));
+}
+
+const ROW_FORMULA: &str = "x * (y + 3) ** 2 / (1 + x) - sin y % 7 + 2 * pi";
+
+fn rows() -> Vec<[f64; 2]> {
+    (0..1000)
+        .map(|i| [f64::from(i), f64::from(i % 17) / 4.0])
+        .collect()
+}
+
+#[bench]
+fn rows_tree(bench: &mut Bencher) {
+    let expr = Expr::parse(ROW_FORMULA).unwrap();
+    let rows = rows();
+    let mut context = Context::new();
+    bench.iter(|| {
+        rows.iter()
+            .map(|row| {
+                context.set_var("x", Value::from_f64(row[0]));
+                context.set_var("y", Value::from_f64(row[1]));
+                ast::evaluate::<f64, _>(&expr, &mut &context).unwrap()
+            })
+            .sum::<f64>()
+    });
+}
+
+#[bench]
+fn rows_bytecode(bench: &mut Bencher) {
+    let e: Expression = ROW_FORMULA.parse().unwrap();
+    let compiled = e.compile(&["x", "y"]).unwrap();
+    let rows = rows();
+    bench.iter(|| {
+        rows.iter()
+            .map(|row| compiled.eval(row).unwrap())
+            .sum::<f64>()
+    });
+}
+
+// Inputs shaped to trip up a recursive descent parser. Each comes in two
+// sizes, whose timings should differ by about the same factor of ten.
+
+fn pow_chain(terms: usize) -> String {
+    vec!["a"; terms].join(" ** ")
+}
+
+fn alternating(terms: usize) -> String {
+    (1..terms)
+        .map(|i| format!("{} {} ", i % 10, if i % 2 == 0 { "+" } else { "*" }))
+        .collect::<String>()
+        + "1"
+}
+
+fn nested_parens(depth: usize) -> String {
+    format!("{}a{}", "(".repeat(depth), ")".repeat(depth))
+}
+
+fn bench_eval(bench: &mut Bencher, input: &str) {
+    let mut calc = Calculator::new();
+    calc.set_var("a", Value::dec(1));
+    assert!(calc.eval(input).is_ok());
+    bench.iter(|| calc.eval(input));
+}
+
+#[bench]
+fn pow_chain_1k(bench: &mut Bencher) {
+    bench_eval(bench, &pow_chain(1000));
+}
+
+#[bench]
+fn pow_chain_10k(bench: &mut Bencher) {
+    bench_eval(bench, &pow_chain(10_000));
+}
+
+#[bench]
+fn alternating_1k(bench: &mut Bencher) {
+    bench_eval(bench, &alternating(1000));
+}
+
+#[bench]
+fn alternating_10k(bench: &mut Bencher) {
+    bench_eval(bench, &alternating(10_000));
+}
+
+#[bench]
+fn nested_parens_1k(bench: &mut Bencher) {
+    bench_eval(bench, &nested_parens(1000));
+}
+
+#[bench]
+fn nested_parens_10k(bench: &mut Bencher) {
+    bench_eval(bench, &nested_parens(10_000));
+}
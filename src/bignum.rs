@@ -0,0 +1,295 @@
+//! Exact arbitrary-precision arithmetic, available with the `bignum` cargo
+//! feature.
+//!
+//! Every number is a rational with unbounded numerator and denominator, so
+//! division never rounds. Exponents must be integral, and the bitwise
+//! operators only accept integers.
+
+use decimal::d128;
+use num::{BigRational, Signed, ToPrimitive, Zero};
+use std::cmp::Ordering;
+use std::fmt;
+
+use error::{CalcError, PartialComp};
+use number::{check_shift, CalcNum};
+use value::{ops, Integral, Value};
+
+/// An exact rational number produced by `eval_big`.
+///
+/// `Display` renders the exact value, either as an integer or as a reduced
+/// `numerator/denominator` fraction. Use `to_decimal_string` for a decimal
+/// expansion.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BigNum(BigRational);
+
+impl BigNum {
+    pub fn from_integer<T: Into<Integral>>(n: T) -> Self {
+        BigNum(BigRational::from_integer(n.into()))
+    }
+
+    /// Creates the fraction `numer / denom`, or returns `None` if `denom`
+    /// is zero.
+    pub fn new<T: Into<Integral>, U: Into<Integral>>(
+        numer: T,
+        denom: U,
+    ) -> Option<Self> {
+        let denom = denom.into();
+        if denom.is_zero() {
+            None
+        } else {
+            Some(BigNum(BigRational::new(numer.into(), denom)))
+        }
+    }
+
+    pub fn is_integer(&self) -> bool {
+        self.0.is_integer()
+    }
+
+    pub fn numer(&self) -> &Integral {
+        self.0.numer()
+    }
+
+    pub fn denom(&self) -> &Integral {
+        self.0.denom()
+    }
+
+    /// Renders the number in decimal notation, rounded half away from zero
+    /// to `digits` fractional digits.
+    pub fn to_decimal_string(&self, digits: usize) -> String {
+        let scale = ops::int_powu(&10.into(), &digits.into());
+        let scaled = self.0.abs() * BigRational::from_integer(scale);
+        let rounded = (scaled + BigRational::new(1.into(), 2.into()))
+            .floor()
+            .to_integer()
+            .to_string();
+        let sign = if self.0.is_negative() && rounded.bytes().any(|b| b != b'0')
+        {
+            "-"
+        } else {
+            ""
+        };
+        if digits == 0 {
+            return format!("{}{}", sign, rounded);
+        }
+        let padded = format!("{:0>width$}", rounded, width = digits + 1);
+        let (int, frac) = padded.split_at(padded.len() - digits);
+        format!("{}{}.{}", sign, int, frac)
+    }
+
+    fn integral(&self, op: &str, that: &BigNum) -> Result<(), CalcError> {
+        if self.is_integer() && that.is_integer() {
+            Ok(())
+        } else {
+            Err(CalcError::BadTypes(PartialComp::binary(op, self, that)))
+        }
+    }
+
+    fn intmap<F>(self, that: BigNum, op: &str, f: F) -> Result<Self, CalcError>
+    where
+        F: Fn(&Integral, &Integral) -> Option<Integral>,
+    {
+        self.integral(op, &that)?;
+        match f(self.numer(), that.numer()) {
+            Some(n) => Ok(BigNum::from_integer(n)),
+            None => Err(CalcError::WouldOverflow(PartialComp::binary(
+                op, &self, &that,
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for BigNum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_integer() {
+            write!(f, "{}", self.numer())
+        } else {
+            write!(f, "{}/{}", self.numer(), self.denom())
+        }
+    }
+}
+
+impl CalcNum for BigNum {
+    fn from_value(value: &Value) -> Result<Self, CalcError> {
+        match *value {
+            Value::Integral(ref n, _) => Ok(BigNum::from_integer(n.clone())),
+            Value::Float(n) => ops::to_rational(n)
+                .map(BigNum)
+                .ok_or_else(|| CalcError::InvalidNumber(n.to_string())),
+        }
+    }
+
+    fn to_value(&self) -> Value {
+        if self.is_integer() {
+            Value::dec(self.numer().clone())
+        } else {
+            let n = self
+                .to_decimal_string(34)
+                .parse::<d128>()
+                .expect("decimal expansion is a valid decimal");
+            Value::Float(n)
+        }
+    }
+
+    fn add(self, that: Self) -> Result<Self, CalcError> {
+        Ok(BigNum(self.0 + that.0))
+    }
+
+    fn sub(self, that: Self) -> Result<Self, CalcError> {
+        Ok(BigNum(self.0 - that.0))
+    }
+
+    fn mul(self, that: Self) -> Result<Self, CalcError> {
+        Ok(BigNum(self.0 * that.0))
+    }
+
+    fn div(self, that: Self) -> Result<Self, CalcError> {
+        if that.0.is_zero() {
+            return Err(CalcError::DivideByZero);
+        }
+        Ok(BigNum(self.0 / that.0))
+    }
+
+    fn rem(self, that: Self) -> Result<Self, CalcError> {
+        if that.0.is_zero() {
+            return Err(CalcError::DivideByZero);
+        }
+        Ok(BigNum(self.0 % that.0))
+    }
+
+    fn neg(self) -> Result<Self, CalcError> {
+        Ok(BigNum(-self.0))
+    }
+
+    fn powf(self, that: Self) -> Result<Self, CalcError> {
+        if !that.is_integer() {
+            return Err(CalcError::BadTypes(PartialComp::binary(
+                "**", &self, &that,
+            )));
+        }
+        let exp = that.numer();
+        if exp.is_negative() && self.0.is_zero() {
+            return Err(CalcError::DivideByZero);
+        }
+        let exp = exp.abs();
+        let numer = ops::int_pow(self.numer(), &exp);
+        let denom = ops::int_pow(self.denom(), &exp);
+        match (numer, denom) {
+            (Some(numer), Some(denom)) => {
+                let res = BigRational::new(numer, denom);
+                if that.numer().is_negative() {
+                    Ok(BigNum(res.recip()))
+                } else {
+                    Ok(BigNum(res))
+                }
+            }
+            _ => Err(CalcError::WouldOverflow(PartialComp::binary(
+                "**", &self, &that,
+            ))),
+        }
+    }
+
+    fn floor(self) -> Result<Self, CalcError> {
+        Ok(BigNum(self.0.floor()))
+    }
+
+    fn compare(&self, that: &Self) -> Option<Ordering> {
+        Some(self.cmp(that))
+    }
+
+    fn to_bits_integer(&self) -> Option<i64> {
+        if self.is_integer() {
+            self.numer().to_i64()
+        } else {
+            None
+        }
+    }
+
+    fn from_bits_integer(n: i64) -> Self {
+        BigNum::from_integer(n)
+    }
+
+    fn bitand(self, that: Self) -> Result<Self, CalcError> {
+        self.intmap(that, "&", |n, m| Some(ops::and(n, m)))
+    }
+
+    fn bitor(self, that: Self) -> Result<Self, CalcError> {
+        self.intmap(that, "|", |n, m| Some(ops::or(n, m)))
+    }
+
+    fn bitxor(self, that: Self) -> Result<Self, CalcError> {
+        self.intmap(that, "^", |n, m| Some(ops::xor(n, m)))
+    }
+
+    fn shl(self, that: Self) -> Result<Self, CalcError> {
+        check_shift("<<", &that)?;
+        self.intmap(that, "<<", ops::shl)
+    }
+
+    fn shr(self, that: Self) -> Result<Self, CalcError> {
+        check_shift(">>", &that)?;
+        self.intmap(that, ">>", ops::shr)
+    }
+
+    fn logical_shr(self, that: Self, width: u32) -> Result<Self, CalcError> {
+        check_shift(">>", &that)?;
+        self.intmap(that, ">>", |n, m| ops::logical_shr(n, m, width))
+    }
+
+    fn not(self) -> Result<Self, CalcError> {
+        if self.is_integer() {
+            Ok(BigNum::from_integer(ops::not(self.numer().clone())))
+        } else {
+            Err(CalcError::BadTypes(PartialComp::unary("~", self)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eval_big;
+
+    #[test]
+    fn exact_results() {
+        let mersenne =
+            ops::int_powu(&2.into(), &521u32.into()) - Integral::from(1);
+        let cases = vec![
+            ("2 ** 521 - 1", mersenne.to_string()),
+            ("1 / 3", "1/3".into()),
+            ("1/3 + 1/6", "1/2".into()),
+            ("0.1 + 0.2", "3/10".into()),
+            ("(2/3) ** -2", "9/4".into()),
+            ("7.5 % 2", "3/2".into()),
+            ("(1 << 200) >> 199", "2".into()),
+            ("0xFF & 0x0F | 0x100", "271".into()),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(eval_big(input).map(|n| n.to_string()), Ok(expected));
+        }
+    }
+
+    #[test]
+    fn decimal_expansion() {
+        let third = eval_big("1 / 3").unwrap();
+        assert_eq!(third.to_decimal_string(10), "0.3333333333");
+        assert_eq!(eval_big("-2 / 3").unwrap().to_decimal_string(4), "-0.6667");
+        assert_eq!(eval_big("1 / 8").unwrap().to_decimal_string(0), "0");
+        assert_eq!(eval_big("5 / 8").unwrap().to_decimal_string(0), "1");
+        assert_eq!(eval_big("-1 / 1000").unwrap().to_decimal_string(2), "0.00");
+        assert_eq!(eval_big("123").unwrap().to_decimal_string(2), "123.00");
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(eval_big("1 / 0"), Err(CalcError::DivideByZero));
+        assert_eq!(
+            eval_big("2 ** (1 / 2)"),
+            Err(CalcError::BadTypes(PartialComp::binary("**", &2, &"1/2")))
+        );
+        assert_eq!(
+            eval_big("1.5 & 1"),
+            Err(CalcError::BadTypes(PartialComp::binary("&", &"3/2", &1)))
+        );
+        assert!(eval_big("9 ** 9 ** 9").is_err());
+    }
+}
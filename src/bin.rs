@@ -3,14 +3,16 @@ extern crate calc;
 extern crate clap;
 extern crate liner;
 
+use std::env;
 use std::fmt;
 use std::process::exit;
 
 use std::io::{self, stdout, BufRead, Write};
 
-use calc::{eval, eval_polish, CalcError};
+use calc::token::{tokenize, Token};
+use calc::{eval_polish, BaseView, Bc, CalcError, Calculator, Value};
 
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches};
 
 use liner::Context;
 
@@ -24,6 +26,8 @@ pub fn prompt<W: Write>(out: &mut W) -> io::Result<()> {
 pub enum RuntimeError {
     Calc(CalcError),
     IO(io::Error),
+    /// A reference to an environment variable that cannot be used.
+    Env(String),
 }
 
 impl From<CalcError> for RuntimeError {
@@ -38,20 +42,183 @@ impl From<io::Error> for RuntimeError {
     }
 }
 
+impl RuntimeError {
+    /// The error as a `CalcError`, with I/O errors as `CalcError::Io` and
+    /// unusable environment variables as `CalcError::InvalidArgument`.
+    fn to_calc_error(&self) -> CalcError {
+        match *self {
+            RuntimeError::Calc(ref c) => c.clone(),
+            RuntimeError::IO(ref e) => CalcError::Io(e.to_string()),
+            RuntimeError::Env(ref e) => CalcError::InvalidArgument(e.clone()),
+        }
+    }
+
+    /// Renders the error like `Display`, followed by a `help:` line with the
+    /// suggestion of the error if it has one. `color` makes the `calc:`
+    /// prefix bold red and the `help:` one bold.
+    fn render(&self, color: bool) -> String {
+        let message = match *self {
+            RuntimeError::Calc(ref c) => c.to_string(),
+            RuntimeError::IO(ref e) => e.to_string(),
+            RuntimeError::Env(ref e) => e.clone(),
+        };
+        let (error, help) = if color {
+            ("\x1b[1;31mcalc:\x1b[0m", "\x1b[1mhelp:\x1b[0m")
+        } else {
+            ("calc:", "help:")
+        };
+        match *self {
+            RuntimeError::Calc(ref c) => match c.suggestion() {
+                Some(hint) => {
+                    format!("{} {}\n{} {}", error, message, help, hint)
+                }
+                None => format!("{} {}", error, message),
+            },
+            _ => format!("{} {}", error, message),
+        }
+    }
+}
+
 impl fmt::Display for RuntimeError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            RuntimeError::Calc(ref c) => write!(f, "calc: {}", c),
-            RuntimeError::IO(ref e) => write!(f, "calc: {}", e),
+        f.write_str(&self.render(false))
+    }
+}
+
+/// Whether to color errors: only on a terminal, unless `CLICOLOR_FORCE` is
+/// set to anything but `0`, and never if `NO_COLOR` is set to anything.
+fn use_color() -> bool {
+    let var = |name: &str| env::var_os(name).filter(|v| !v.is_empty());
+    if var("NO_COLOR").is_some() {
+        return false;
+    }
+    var("CLICOLOR_FORCE").is_some_and(|v| v != "0")
+        || atty::is(atty::Stream::Stdout)
+}
+
+/// Replaces the references to environment variables in `input`, written
+/// `$NAME` or `${NAME}`, with `NAME`, and defines `NAME` in `calculator` to
+/// be the value of the environment variable. The values must be numbers,
+/// and are never pasted into the input, so they cannot inject operators.
+fn interpolate(
+    calculator: &mut Calculator,
+    input: &str,
+) -> Result<String, RuntimeError> {
+    let mut out = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(dollar) = rest.find('$') {
+        out.push_str(&rest[..dollar]);
+        rest = &rest[dollar + 1..];
+        let (name, len) = if rest.starts_with('{') {
+            match rest.find('}') {
+                Some(close) => (&rest[1..close], close + 1),
+                None => {
+                    return Err(RuntimeError::Env(
+                        "missing '}' after '${'".into(),
+                    ))
+                }
+            }
+        } else {
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            (&rest[..len], len)
+        };
+        let valid = name.starts_with(|c: char| c.is_ascii_alphabetic())
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid {
+            return Err(RuntimeError::Env(format!(
+                "expected the name of an environment variable after '$', \
+                 got {:?}",
+                name
+            )));
         }
+        let value = env::var(name).map_err(|e| {
+            let problem = match e {
+                env::VarError::NotPresent => "is not set",
+                env::VarError::NotUnicode(_) => "is not valid Unicode",
+            };
+            RuntimeError::Env(format!(
+                "environment variable {} {}",
+                name, problem
+            ))
+        })?;
+        let number = parse_number(&value).ok_or_else(|| {
+            RuntimeError::Env(format!(
+                "environment variable {} is not a number: {:?}",
+                name, value
+            ))
+        })?;
+        calculator.set_var(name, number);
+        out.push_str(name);
+        rest = &rest[len..];
     }
+    out.push_str(rest);
+    Ok(out)
 }
 
-pub fn calc() -> Result<(), RuntimeError> {
-    let stdout = stdout();
-    let mut stdout = stdout.lock();
+/// Reads a number, which may be negative.
+fn parse_number(text: &str) -> Option<Value> {
+    match tokenize(text).ok()?.as_slice() {
+        [Token::Number(n)] => Some(n.clone()),
+        [Token::Minus, Token::Number(n)] => Some(-n.clone()),
+        _ => None,
+    }
+}
+
+/// Runs a command of the interactive mode and returns what to print.
+/// The memory commands work with the last result like the memory keys of a
+/// pocket calculator, and print the memory. `:rpn` toggles whether lines
+/// are processed by `Calculator::rpn`, and `:vars` lists the variables.
+/// Returns `None` if `line` is not a command.
+fn command(
+    calculator: &mut Calculator,
+    rpn: &mut bool,
+    last: f64,
+    line: &str,
+) -> Option<String> {
+    match line {
+        ":rpn" => {
+            *rpn = !*rpn;
+            let mode = if *rpn { "on" } else { "off" };
+            return Some(format!("rpn mode {}", mode));
+        }
+        ":undo" if !calculator.undo() => return Some("nothing to undo".into()),
+        ":redo" if !calculator.redo() => return Some("nothing to redo".into()),
+        ":undo" | ":redo" => return Some(String::new()),
+        ":vars" => {
+            let vars: Vec<String> = calculator
+                .vars()
+                .map(|(name, value)| format!("{} = {}", name, value))
+                .collect();
+            return Some(vars.join("\n"));
+        }
+        ":m+" => calculator.memory_add(last),
+        ":m-" => calculator.memory_subtract(last),
+        ":ms" => calculator.memory_store(last),
+        ":mc" => calculator.memory_clear(),
+        ":mr" => {}
+        _ => return None,
+    }
+    Some(calculator.memory_recall().to_string())
+}
+
+/// Renders the RPN stack from the bottom up, on one line.
+fn show_stack(stack: &[f64]) -> String {
+    let values: Vec<String> = stack.iter().map(f64::to_string).collect();
+    format!("[{}]", values.join(" "))
+}
+
+/// Renders a result, as a table of bases at the given width if there is one.
+fn show(value: &Value, bases: Option<u32>) -> Result<String, CalcError> {
+    match bases {
+        Some(width) => BaseView::new(value, width).map(|view| view.to_string()),
+        None => Ok(value.to_string()),
+    }
+}
 
-    let matches = App::new("calc")
+fn app() -> App<'static, 'static> {
+    App::new("calc")
         .version(env!("CARGO_PKG_VERSION"))
         .author("Hunter Goldstein <hunter.d.goldstein@gmail.com>")
         .about("Floating point calculator")
@@ -60,32 +227,95 @@ pub fn calc() -> Result<(), RuntimeError> {
              .short("p")
              .long("polish")
              .help("Parse expressions using polish notation versus infix notation"))
+        .arg(Arg::with_name("bc")
+             .long("bc")
+             .conflicts_with("polish")
+             .help("Read and print numbers like bc, with its scale, ibase and obase variables"))
+        .arg(Arg::with_name("all-bases")
+             .long("all-bases")
+             .conflicts_with("bc")
+             .help("Print integer results in decimal, hex, octal and binary, and as signed and unsigned numbers"))
+        .arg(Arg::with_name("width")
+             .long("width")
+             .takes_value(true)
+             .value_name("BITS")
+             .default_value("64")
+             .possible_values(&["8", "16", "32", "64"])
+             .help("Bit width of the --all-bases view"))
+        .arg(Arg::with_name("json-errors")
+             .long("json-errors")
+             .help("Print errors to stderr as JSON objects, as described by CalcError::to_json"))
         .arg(Arg::with_name("expr")
              .help("Expression to evaluate by this program. If this argument is missing, enter interactive mode.")
              .multiple(true)
              .value_name("EXPR"))
-        .get_matches();
+}
+
+pub fn calc(matches: &ArgMatches) -> Result<(), RuntimeError> {
+    let stdout = stdout();
+    let mut stdout = stdout.lock();
 
     // Check if the polish notation flag was given.
     let polish = matches.is_present("polish");
+    let mut calculator = Calculator::new();
+    let mut bc = if matches.is_present("bc") {
+        Some(Bc::new())
+    } else {
+        None
+    };
+    let bases = if matches.is_present("all-bases") {
+        matches.value_of("width").and_then(|w| w.parse().ok())
+    } else {
+        None
+    };
+    let mut last = 0.0;
+    let mut rpn = false;
 
     macro_rules! eval {
         ($expr:expr) => {
             if polish {
                 eval_polish($expr)?
             } else {
-                eval($expr)?
+                let input = interpolate(&mut calculator, $expr)?;
+                calculator.eval(&input)?
+            }
+        };
+    }
+
+    // Prints the results of a line of input.
+    macro_rules! respond {
+        ($input:expr) => {
+            match bc {
+                Some(ref mut bc) => {
+                    for output in bc.run($input)? {
+                        writeln!(stdout, "{}", output)?;
+                    }
+                }
+                None if rpn => {
+                    calculator.rpn($input)?;
+                    last = calculator.stack().last().cloned().unwrap_or(0.0);
+                    writeln!(stdout, "{}", show_stack(calculator.stack()))?;
+                }
+                None => {
+                    let value = eval!($input);
+                    last = value.as_f64();
+                    writeln!(stdout, "{}", show(&value, bases)?)?;
+                }
             }
         };
     }
 
     match matches.values_of("expr") {
         Some(values) => {
-            writeln!(
-                stdout,
-                "{}",
-                eval!(&values.fold(String::new(), |acc, s| acc + s)),
-            )?;
+            let input = values.fold(String::new(), |acc, s| acc + s);
+            match bc {
+                Some(ref mut bc) => {
+                    for output in bc.run(&input)? {
+                        writeln!(stdout, "{}", output)?;
+                    }
+                }
+                None => writeln!(stdout, "{}", show(&eval!(&input), bases)?)?,
+            }
         }
         None => {
             if atty::is(atty::Stream::Stdin) {
@@ -94,8 +324,11 @@ pub fn calc() -> Result<(), RuntimeError> {
                     let line = con.read_line(PROMPT, &mut |_| {})?;
                     match line.trim() {
                         "" => (),
-                        "exit" => break,
-                        s => writeln!(stdout, "{}", eval!(s))?,
+                        "exit" | "quit" => break,
+                        s => match command(&mut calculator, &mut rpn, last, s) {
+                            Some(output) => writeln!(stdout, "{}", output)?,
+                            None => respond!(s),
+                        },
                     }
                     con.history.push(line.into())?;
                 }
@@ -103,7 +336,12 @@ pub fn calc() -> Result<(), RuntimeError> {
                 let stdin = io::stdin();
                 let mut lock = stdin.lock();
                 for line in lock.lines() {
-                    writeln!(stdout, "{}", eval!(&line?))?;
+                    let line = line?;
+                    match command(&mut calculator, &mut rpn, last, line.trim())
+                    {
+                        Some(output) => writeln!(stdout, "{}", output)?,
+                        None => respond!(&line),
+                    }
                 }
             }
         }
@@ -112,10 +350,15 @@ pub fn calc() -> Result<(), RuntimeError> {
 }
 
 fn main() {
-    let code = match calc() {
+    let matches = app().get_matches();
+    let code = match calc(&matches) {
         Ok(()) => 0,
         Err(e) => {
-            println!("{}", e);
+            if matches.is_present("json-errors") {
+                eprintln!("{}", e.to_calc_error().to_json());
+            } else {
+                println!("{}", e.render(use_color()));
+            }
             1
         }
     };
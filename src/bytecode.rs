@@ -0,0 +1,596 @@
+use ast::{BinaryOp, UnaryOp};
+use error::{closest, CalcError};
+use expression::Node;
+use number::CalcNum;
+use parse::{self, DefaultEnvironment, Environment};
+use std::collections::HashMap;
+use value::Value;
+
+/// An `Expression` compiled to bytecode for fast repeated evaluation in
+/// `f64`, e.g. once for every row of a table.
+///
+/// Compiling resolves every variable to a slot, so evaluating only takes
+/// the values of the slots, in the order the variables were named. The
+/// constants of the expression are converted once and kept in a pool.
+/// Results, including errors, are exactly those of evaluating the `Expr`
+/// of the expression in `f64`.
+///
+/// ```
+/// # extern crate calc;
+/// # use calc::{CalcError, Expression};
+/// # fn main() -> Result<(), CalcError> {
+/// let e: Expression = "x * (y + 1)".parse()?;
+/// let compiled = e.compile(&["x", "y"])?;
+/// assert_eq!(compiled.eval(&[2.0, 3.0])?, 8.0);
+/// assert_eq!(compiled.eval(&[0.5, 1.0])?, 1.0);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompiledExpr {
+    code: Vec<Op>,
+    consts: Vec<f64>,
+    functions: Vec<String>,
+    /// The names of the variables, in the order of their slots.
+    vars: Vec<String>,
+    /// The highest index of a placeholder, whose slots follow those of the
+    /// variables.
+    params: usize,
+    /// The number of slots for the names bound by `let`.
+    locals: usize,
+    max_stack: usize,
+}
+
+/// An instruction of the stack machine. Operators pop their operands and
+/// push their result.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    PushConst(u32),
+    LoadVar(u32),
+    /// Pops the value of a `let` into a local slot.
+    Store(u32),
+    LoadLocal(u32),
+    /// Pops a condition of `cond`, and continues at the given instruction
+    /// unless it holds.
+    JumpUnless(u32),
+    /// Continues at the given instruction.
+    Jump(u32),
+    /// Calls a function with the given number of arguments.
+    Call(u32, u32),
+    Neg,
+    Not,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Pow,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
+}
+
+impl CompiledExpr {
+    /// Evaluates the expression with `vars` as the values of the variables
+    /// it was compiled for, followed by those of its placeholders.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are fewer values than variables and placeholders.
+    pub fn eval(&self, vars: &[f64]) -> Result<f64, CalcError> {
+        assert!(
+            vars.len() >= self.vars.len() + self.params,
+            "missing variable values"
+        );
+        let mut stack: Vec<f64> = Vec::with_capacity(self.max_stack);
+        let mut locals = vec![0.0; self.locals];
+        let mut pc = 0;
+        while let Some(op) = self.code.get(pc) {
+            pc += 1;
+            let value = match *op {
+                Op::PushConst(index) => self.consts[index as usize],
+                Op::LoadVar(slot) => vars[slot as usize],
+                Op::Store(slot) => {
+                    let value = stack.pop().expect("let has a value");
+                    locals[slot as usize] = value;
+                    continue;
+                }
+                Op::LoadLocal(slot) => locals[slot as usize],
+                Op::JumpUnless(target) => {
+                    let condition = stack.pop().expect("cond has conditions");
+                    if !parse::truth(&condition) {
+                        pc = target as usize;
+                    }
+                    continue;
+                }
+                Op::Jump(target) => {
+                    pc = target as usize;
+                    continue;
+                }
+                Op::Call(index, count) => {
+                    let start = stack.len() - count as usize;
+                    let args: Vec<Value> =
+                        stack.drain(start..).map(|n| n.to_value()).collect();
+                    let name = &self.functions[index as usize];
+                    f64::from_value(&DefaultEnvironment.resolve(name, &args)?)?
+                }
+                Op::Neg | Op::Not => {
+                    let arg =
+                        stack.pop().expect("unary operators have an operand");
+                    if *op == Op::Neg {
+                        arg.neg()?
+                    } else {
+                        arg.not()?
+                    }
+                }
+                _ => {
+                    let rhs =
+                        stack.pop().expect("binary operators have operands");
+                    let lhs =
+                        stack.pop().expect("binary operators have operands");
+                    match *op {
+                        Op::Add => lhs.add(rhs)?,
+                        Op::Sub => lhs.sub(rhs)?,
+                        Op::Mul => lhs.mul(rhs)?,
+                        Op::Div => lhs.div(rhs)?,
+                        Op::Rem => lhs.rem(rhs)?,
+                        Op::Pow => CalcNum::powf(lhs, rhs)?,
+                        Op::BitAnd => lhs.bitand(rhs)?,
+                        Op::BitOr => lhs.bitor(rhs)?,
+                        Op::BitXor => lhs.bitxor(rhs)?,
+                        Op::Shl => CalcNum::shl(lhs, rhs)?,
+                        Op::Shr => CalcNum::shr(lhs, rhs)?,
+                        _ => unreachable!("operands are pushed above"),
+                    }
+                }
+            };
+            stack.push(value);
+        }
+        Ok(stack.pop().expect("expressions have a root"))
+    }
+
+    /// The names of the variables the expression was compiled for, in the
+    /// order `eval` takes their values.
+    pub fn vars(&self) -> &[String] {
+        &self.vars
+    }
+
+    /// The number of arguments the placeholders take: the highest index of
+    /// `?N`, where the `N`th bare `?` counts as `?N`.
+    pub fn param_count(&self) -> usize {
+        self.params
+    }
+
+    /// Evaluates the expression like `eval`, but fails with
+    /// `CalcError::WrongArity` unless there is exactly one value for every
+    /// variable and placeholder.
+    ///
+    /// ```
+    /// # extern crate calc;
+    /// # use calc::{CalcError, Expression};
+    /// # fn main() -> Result<(), CalcError> {
+    /// let e: Expression = "? * ? + ?".parse()?;
+    /// let compiled = e.compile(&[])?;
+    /// assert_eq!(compiled.param_count(), 3);
+    /// assert_eq!(compiled.eval_with_args(&[2.0, 3.0, 4.0])?, 10.0);
+    /// assert!(compiled.eval_with_args(&[2.0, 3.0]).is_err());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn eval_with_args(&self, args: &[f64]) -> Result<f64, CalcError> {
+        let expected = self.vars.len() + self.params;
+        if args.len() != expected {
+            return Err(CalcError::WrongArity {
+                atom: "?".into(),
+                expected,
+                actual: args.len(),
+            });
+        }
+        self.eval(args)
+    }
+}
+
+/// Compiles the post-order `nodes` of an expression. Atoms in `vars` and
+/// placeholders become slots; any other atom must be known to the
+/// `DefaultEnvironment`.
+pub(crate) fn compile(
+    nodes: &[Node],
+    vars: &[&str],
+) -> Result<CompiledExpr, CalcError> {
+    let mut compiled = CompiledExpr {
+        code: Vec::with_capacity(nodes.len()),
+        consts: Vec::new(),
+        functions: Vec::new(),
+        vars: vars.iter().map(|&var| var.to_owned()).collect(),
+        params: 0,
+        locals: 0,
+        max_stack: 0,
+    };
+    let mut pool = HashMap::new();
+    let mut depth = 0;
+    // The names bound by the enclosing `let`s, innermost last, with the
+    // index of the last node of their body. Each uses the slot of its
+    // position in this list.
+    let mut scopes: Vec<(&str, usize)> = Vec::new();
+    let mut conds: Vec<OpenCond> = Vec::new();
+    for (i, node) in nodes.iter().enumerate() {
+        let (op, popped) = match *node {
+            Node::Num(ref value) => {
+                let n = f64::from_literal(value)?;
+                (Op::PushConst(constant(&mut compiled, &mut pool, n)), 0)
+            }
+            Node::Var(ref name) => {
+                let local = scopes.iter().rposition(|scope| scope.0 == name);
+                let slot = vars.iter().position(|v| v == name);
+                match (local, slot, placeholder(name)) {
+                    (Some(local), ..) => (Op::LoadLocal(local as u32), 0),
+                    (None, Some(slot), _) => (Op::LoadVar(slot as u32), 0),
+                    (None, None, Some(index)) => {
+                        compiled.params = compiled.params.max(index);
+                        (Op::LoadVar((vars.len() + index - 1) as u32), 0)
+                    }
+                    (None, None, None) => {
+                        check_arity(name, 0, vars)?;
+                        let value = DefaultEnvironment.resolve(name, &[])?;
+                        let n = f64::from_value(&value)?;
+                        let index = constant(&mut compiled, &mut pool, n);
+                        (Op::PushConst(index), 0)
+                    }
+                }
+            }
+            Node::Let(ref name, len) => {
+                let slot = scopes.len();
+                scopes.push((name, i + len as usize));
+                compiled.locals = compiled.locals.max(scopes.len());
+                compiled.code.push(Op::Store(slot as u32));
+                depth -= 1;
+                continue;
+            }
+            Node::Cond(ref lens) => {
+                parse::check_cond_arity(lens.len())?;
+                let mut end = i;
+                let ends = lens
+                    .iter()
+                    .map(|&len| {
+                        end += len as usize;
+                        end
+                    })
+                    .collect();
+                conds.push(OpenCond {
+                    ends,
+                    arg: 0,
+                    test: 0,
+                    exits: Vec::new(),
+                });
+                continue;
+            }
+            Node::Call(ref name, count) => {
+                check_arity(name, count as usize, vars)?;
+                let index = compiled.functions.len() as u32;
+                compiled.functions.push(name.clone());
+                (Op::Call(index, count), count as usize)
+            }
+            Node::Unary(UnaryOp::Neg) => (Op::Neg, 1),
+            Node::Unary(UnaryOp::Not) => (Op::Not, 1),
+            Node::Binary(op) => {
+                let op = match op {
+                    BinaryOp::Add => Op::Add,
+                    BinaryOp::Sub => Op::Sub,
+                    BinaryOp::Mul => Op::Mul,
+                    BinaryOp::Div => Op::Div,
+                    BinaryOp::Rem => Op::Rem,
+                    BinaryOp::Pow => Op::Pow,
+                    BinaryOp::BitAnd => Op::BitAnd,
+                    BinaryOp::BitOr => Op::BitOr,
+                    BinaryOp::BitXor => Op::BitXor,
+                    BinaryOp::Shl => Op::Shl,
+                    BinaryOp::Shr => Op::Shr,
+                };
+                (op, 2)
+            }
+            Node::Binder(binder, ..) => {
+                return Err(CalcError::InvalidArgument(format!(
+                    "{} cannot be compiled",
+                    binder.name()
+                )))
+            }
+        };
+        depth = depth - popped + 1;
+        compiled.max_stack = compiled.max_stack.max(depth);
+        compiled.code.push(op);
+        close_conds(&mut conds, &mut compiled.code, &mut depth, i);
+        while scopes.last().is_some_and(|&(_, end)| end == i) {
+            scopes.pop();
+        }
+    }
+    Ok(compiled)
+}
+
+/// A `cond` whose arguments are being compiled.
+struct OpenCond {
+    /// The index of the last node of every argument.
+    ends: Vec<usize>,
+    /// The argument being compiled.
+    arg: usize,
+    /// The `JumpUnless` after the last condition.
+    test: usize,
+    /// The `Jump`s after the values, which continue after the `cond`.
+    exits: Vec<usize>,
+}
+
+/// Adds the jumps after the arguments of `conds` that end with node `i`.
+/// Only one value of a `cond` is computed, so the stack does not grow by
+/// the values that are jumped over.
+fn close_conds(
+    conds: &mut Vec<OpenCond>,
+    code: &mut Vec<Op>,
+    depth: &mut usize,
+    i: usize,
+) {
+    while let Some(cond) = conds.last_mut() {
+        if cond.ends[cond.arg] != i {
+            return;
+        }
+        let here = code.len() as u32;
+        if cond.arg + 1 == cond.ends.len() {
+            for &exit in &cond.exits {
+                code[exit] = Op::Jump(here);
+            }
+            conds.pop();
+            continue;
+        }
+        if cond.arg % 2 == 0 {
+            cond.test = code.len();
+            code.push(Op::JumpUnless(0));
+        } else {
+            cond.exits.push(code.len());
+            code.push(Op::Jump(0));
+            code[cond.test] = Op::JumpUnless(here + 1);
+        }
+        *depth -= 1;
+        cond.arg += 1;
+    }
+}
+
+/// The index of a placeholder, which the parser names `?N`.
+fn placeholder(name: &str) -> Option<usize> {
+    name.strip_prefix('?')?
+        .parse()
+        .ok()
+        .filter(|&index| index > 0)
+}
+
+/// Returns the index of `n` in the constant pool, adding it if needed.
+fn constant(
+    compiled: &mut CompiledExpr,
+    pool: &mut HashMap<u64, u32>,
+    n: f64,
+) -> u32 {
+    let consts = &mut compiled.consts;
+    *pool.entry(n.to_bits()).or_insert_with(|| {
+        consts.push(n);
+        consts.len() as u32 - 1
+    })
+}
+
+/// Checks that an atom exists and takes `count` arguments. Variables
+/// shadow the atoms of the `DefaultEnvironment`, just like in a `Context`.
+fn check_arity(
+    name: &str,
+    count: usize,
+    vars: &[&str],
+) -> Result<(), CalcError> {
+    let arity = if vars.contains(&name) {
+        Some(0)
+    } else {
+        DefaultEnvironment.arity(name)
+    };
+    match arity {
+        Some(arity) if arity == count => Ok(()),
+        Some(arity) => Err(CalcError::WrongArity {
+            atom: name.to_owned(),
+            expected: arity,
+            actual: count,
+        }),
+        None => {
+            let mut names = DefaultEnvironment.names();
+            names.extend(vars.iter().map(|&var| var.to_owned()));
+            Err(CalcError::UnknownAtom(
+                name.to_owned(),
+                closest(name, &names),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ast;
+    use context::Context;
+    use expression::Expression;
+
+    #[test]
+    fn constants() {
+        let e: Expression = "2 * x + 2 * y + 2 ** y".parse().unwrap();
+        let compiled = e.compile(&["x", "y"]).unwrap();
+        assert_eq!(compiled.consts, vec![2.0]);
+        assert_eq!(compiled.max_stack, 3);
+        assert_eq!(compiled.eval(&[1.0, 3.0]), Ok(16.0));
+
+        let e: Expression = "pi * 2".parse().unwrap();
+        assert_eq!(e.compile(&["pi"]).unwrap().eval(&[1.5]), Ok(3.0));
+        assert_eq!(e.compile(&[]).map(|c| c.consts.len()), Ok(2));
+        let e: Expression = "x + y".parse().unwrap();
+        assert_eq!(
+            e.compile(&["x"]),
+            Err(CalcError::UnknownAtom("y".into(), None))
+        );
+    }
+
+    #[test]
+    fn placeholders() {
+        use eval_with_args;
+        assert_eq!(eval_with_args("? * ? + ?", &[2.0, 3.0, 4.0]), Ok(10.0));
+        assert_eq!(eval_with_args("?2 - ?1", &[1.0, 5.0]), Ok(4.0));
+        assert_eq!(eval_with_args("?1 * ?1 + ?1", &[3.0]), Ok(12.0));
+        assert_eq!(eval_with_args("sin(?) + 2", &[0.0]), Ok(2.0));
+        assert_eq!(eval_with_args("?3", &[0.0, 0.0, 7.0]), Ok(7.0));
+        assert_eq!(
+            eval_with_args("? + ?", &[1.0]),
+            Err(CalcError::WrongArity {
+                atom: "?".into(),
+                expected: 2,
+                actual: 1,
+            })
+        );
+        assert_eq!(
+            eval_with_args("?1 * 2", &[1.0, 2.0]),
+            Err(CalcError::WrongArity {
+                atom: "?".into(),
+                expected: 1,
+                actual: 2,
+            })
+        );
+        assert_eq!(eval_with_args("1 + 2", &[]), Ok(3.0));
+    }
+
+    #[test]
+    fn compiled_placeholders() {
+        let e: Expression = "x * ? + ?2".parse().unwrap();
+        assert_eq!(e.to_string(), "x * ?1 + ?2");
+        let compiled = e.compile(&["x"]).unwrap();
+        assert_eq!(compiled.param_count(), 2);
+        let rows = [[1.0, 2.0, 3.0], [0.5, 4.0, -1.0], [-2.0, 0.0, 0.0]];
+        let results: Result<Vec<f64>, _> = rows
+            .iter()
+            .map(|row| compiled.eval_with_args(row))
+            .collect();
+        assert_eq!(results, Ok(vec![5.0, 1.0, 0.0]));
+        assert!(compiled.eval_with_args(&[1.0, 2.0]).is_err());
+        assert_eq!(
+            "? + 1".parse::<Expression>().unwrap().value(),
+            Err(CalcError::UnknownAtom("?1".into(), None))
+        );
+    }
+
+    #[test]
+    fn lazy_cond() {
+        let cases = vec![
+            ("cond(1, 2, 1 / 0)", vec![0.0, 5.0]),
+            ("cond(x, 1 / x, 0)", vec![0.0, 0.25, -2.0]),
+            ("cond(x - 1, cond(x, 1 / x, 7), 3) * 2", vec![0.0, 1.0, 4.0]),
+            ("let y = x * 2 in cond(y, y + 1, 0) + y", vec![0.0, 3.0]),
+            ("1 + cond(0, 1 / 0, x, x ** 2, -1)", vec![0.0, -3.0]),
+        ];
+        for (input, rows) in cases {
+            let e: Expression = input.parse().unwrap();
+            let expr = e.to_expr();
+            assert_eq!(expr, ast::Expr::parse(input).unwrap(), "{}", input);
+            let compiled = e.compile(&["x"]).unwrap();
+            for x in rows {
+                let mut context = Context::new();
+                context.set_var("x", Value::from_f64(x));
+                let tree = ast::evaluate::<f64, _>(&expr, &mut &context);
+                assert!(tree.is_ok(), "{} with x = {}: {:?}", input, x, tree);
+                assert_eq!(e.value_with(&context), tree, "{}", input);
+                assert_eq!(compiled.eval(&[x]), tree, "{}", input);
+            }
+        }
+        let e: Expression = "cond(1, 2, 1 / 0)".parse().unwrap();
+        assert_eq!(e.value(), Ok(2.0));
+        let args = vec![ast::Expr::var("x"), ast::Expr::num(1)];
+        let e = Expression::from(ast::Expr::call("cond", args));
+        assert_eq!(
+            e.compile(&["x"]),
+            Err(CalcError::WrongArity {
+                atom: "cond".into(),
+                expected: 3,
+                actual: 2,
+            })
+        );
+    }
+
+    /// Generates random source text from a small xorshift generator, so
+    /// that failures are reproducible.
+    struct Generator(u64);
+
+    impl Generator {
+        fn next(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+
+        fn expr(&mut self, depth: u32) -> String {
+            if depth == 0 {
+                return self.leaf();
+            }
+            match self.next(10) {
+                0 | 1 => self.leaf(),
+                2 => format!("-({})", self.expr(depth - 1)),
+                3 => {
+                    let function = ["log", "ln", "exp", "sin", "cos", "tan"]
+                        [self.next(6) as usize];
+                    format!("{} ({})", function, self.expr(depth - 1))
+                }
+                _ => {
+                    let op = [
+                        "+", "-", "*", "/", "%", "**", "&", "|", "^", "<<",
+                        ">>",
+                    ][self.next(11) as usize];
+                    let lhs = self.expr(depth - 1);
+                    let rhs = self.expr(depth - 1);
+                    format!("({} {} {})", lhs, op, rhs)
+                }
+            }
+        }
+
+        fn leaf(&mut self) -> String {
+            match self.next(6) {
+                0 => "x".to_owned(),
+                1 => "y".to_owned(),
+                2 => "pi".to_owned(),
+                3 => format!("{}.{}", self.next(10), self.next(100)),
+                4 => format!("-{}", self.next(5)),
+                _ => self.next(20).to_string(),
+            }
+        }
+    }
+
+    #[test]
+    fn differential() {
+        let mut generator = Generator(0x2545_f491_4f6c_dd1d);
+        let rows = [(0.0, 0.0), (1.0, -2.0), (2.5, 3.0), (-7.0, 0.125)];
+        for _ in 0..5000 {
+            let mut input = generator.expr(5);
+            if generator.next(8) == 0 {
+                input = format!("~{}", input);
+            }
+            let e: Expression = input.parse().unwrap();
+            let expr = e.to_expr();
+            let compiled = e.compile(&["x", "y"]).unwrap();
+            for &(x, y) in &rows {
+                let mut context = Context::new();
+                context.set_var("x", Value::from_f64(x));
+                context.set_var("y", Value::from_f64(y));
+                let tree = ast::evaluate::<f64, _>(&expr, &mut &context);
+                let bytecode = compiled.eval(&[x, y]);
+                let same = match (&tree, &bytecode) {
+                    (&Ok(a), &Ok(b)) => {
+                        a.to_bits() == b.to_bits() || a.is_nan() && b.is_nan()
+                    }
+                    _ => tree == bytecode,
+                };
+                assert!(
+                    same,
+                    "{} with x = {}, y = {}: {:?} != {:?}",
+                    input, x, y, tree, bytecode
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,2326 @@
+use ast::BinaryOp;
+use context::{self, Context};
+use error::CalcError;
+use gray;
+use json::{self, Json};
+use number::CalcNum;
+use parse::{
+    self, Assoc, Environment, IntegerMode, NumericOptions, Operator, RightShift,
+};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write as FmtWrite;
+use std::hash::{Hash, Hasher};
+use std::io::{Read, Write};
+use std::mem;
+use std::time::{Duration, Instant};
+use token::{RawToken, Token, TokenStream, Tokenizer, TokenizerOptions};
+use value::Value;
+
+/// A stateful evaluator that remembers variables between evaluations.
+///
+/// Variables shadow the functions defined with `define`, which in turn
+/// shadow the constants and functions of the `DefaultEnvironment`.
+///
+/// By default numbers are evaluated as `Value`s, whose fractional part is a
+/// 128-bit decimal, so `0.1 + 0.2` is exactly `0.3`. See `NumberBackend` for
+/// the alternatives.
+///
+/// The work spent on a single evaluation can be bounded with a step limit
+/// and a timeout, both of which are disabled by default. Exceeding either
+/// aborts the evaluation with `CalcError::BudgetExceeded`. The size of the
+/// input itself is bounded by the `TokenizerOptions`.
+///
+/// Results can be memoized with `with_cache`, for callers that evaluate the
+/// same inputs over and over.
+///
+/// Like a pocket calculator, it has a memory register that is changed with
+/// `memory_add` and friends, and read in expressions as `mem`. The result
+/// of the last successful evaluation is read as `ans`. Variables of either
+/// name shadow them.
+///
+/// Changes to the variables and the memory, including assignments like
+/// `x = 2 * y` passed to `eval`, can be reverted with `undo` and restored
+/// with `redo`.
+///
+/// Every call to `eval` is recorded in a transcript, which `transcript`
+/// returns and `export_transcript` writes out, e.g. at the end of a REPL
+/// session.
+///
+/// For reverse Polish notation, it also keeps a stack of numbers: `push`
+/// evaluates onto it, and `apply` replaces the values on top with the
+/// result of an operator or function, so `3 4 + 2 *` leaves `14`.
+#[derive(Clone, Debug, Default)]
+pub struct Calculator {
+    vars: HashMap<String, Value>,
+    memory: f64,
+    history: History,
+    /// The functions defined with `define`, and their sources in order.
+    functions: Context,
+    definitions: Vec<String>,
+    backend: NumberBackend,
+    tokenizer_options: TokenizerOptions,
+    step_limit: Option<u64>,
+    max_depth: Option<usize>,
+    max_magnitude: Option<f64>,
+    integer_mode: IntegerMode,
+    lenient_parens: bool,
+    compensated_sum: bool,
+    /// The builtin operators that bind or group unlike they do by default.
+    precedence: Vec<(BinaryOp, u8)>,
+    assoc: Vec<(BinaryOp, Assoc)>,
+    right_shift: RightShift,
+    numeric_options: NumericOptions,
+    timeout: Option<Duration>,
+    steps: u64,
+    deadline: Option<Instant>,
+    /// The notes of the last evaluation.
+    warnings: Vec<String>,
+    /// The result of the last successful evaluation.
+    ans: Option<Value>,
+    cache: Option<Cache>,
+    /// The RPN stack, with its top last.
+    stack: Vec<f64>,
+    /// The evaluations so far, oldest first.
+    transcript: Vec<TranscriptEntry>,
+    transcript_limit: Option<usize>,
+}
+
+/// The numeric representation a `Calculator` evaluates in.
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq)]
+pub enum NumberBackend {
+    /// Exact integers and 34-digit decimal floats. Base-10 fractions such
+    /// as `0.1` are represented exactly, which suits money math. Arithmetic,
+    /// comparisons and rounding stay in decimal, as do `log`, `ln` and
+    /// `sqrt`. `exp`, `sin`, `cos` and `tan` go through `f64`, so their
+    /// results only have about 16 significant digits.
+    #[default]
+    Decimal,
+    /// Binary `f64` arithmetic, matching what most other tools produce,
+    /// e.g. `0.1 + 0.2` is `0.30000000000000004`. Results are converted
+    /// back into a `Value`.
+    Float,
+}
+
+/// The version of the format written by `Calculator::save_session`.
+const SESSION_VERSION: u64 = 1;
+
+/// The number of changes a `Calculator` can undo by default.
+const DEFAULT_HISTORY_DEPTH: usize = 100;
+
+/// A change to a `Calculator`, recorded with the state it replaced.
+#[derive(Clone, Debug)]
+enum Change {
+    /// A variable and its previous value, if it had one.
+    Var(String, Option<Value>),
+    Memory(f64),
+}
+
+/// The changes that `undo` and `redo` revert, most recent last.
+#[derive(Clone, Debug)]
+struct History {
+    undo: VecDeque<Change>,
+    redo: Vec<Change>,
+    depth: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History {
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            depth: DEFAULT_HISTORY_DEPTH,
+        }
+    }
+}
+
+impl History {
+    /// Records a new change, which makes the undone changes unreachable.
+    fn record(&mut self, change: Change) {
+        self.redo.clear();
+        self.push(change);
+    }
+
+    fn push(&mut self, change: Change) {
+        self.undo.push_back(change);
+        self.truncate();
+    }
+
+    /// Forgets the oldest changes beyond the depth.
+    fn truncate(&mut self) {
+        while self.undo.len() > self.depth {
+            self.undo.pop_front();
+        }
+    }
+}
+
+/// An evaluation recorded in the transcript of a `Calculator`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TranscriptEntry {
+    /// The input passed to `eval`, without surrounding whitespace.
+    pub input: String,
+    /// The result as `Display` formats it, or the message of the error.
+    pub outcome: Result<String, String>,
+}
+
+/// The layouts `Calculator::export_transcript` writes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TranscriptFormat {
+    /// Each input after a `> ` prompt, with the result or error on the next
+    /// line, like the REPL shows them.
+    Text,
+    /// A list with an item for each evaluation, showing the input as a code
+    /// span followed by the result.
+    Markdown,
+}
+
+/// Counts the lookups made in the result cache of a `Calculator`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// A least recently used map from an input and the state of the calculator
+/// it was evaluated in to the result.
+#[derive(Clone, Debug)]
+struct Cache {
+    capacity: usize,
+    entries: HashMap<(String, u64), CacheEntry>,
+    clock: u64,
+    stats: CacheStats,
+}
+
+#[derive(Clone, Debug)]
+struct CacheEntry {
+    result: Result<Value, CalcError>,
+    last_used: u64,
+}
+
+impl Cache {
+    fn get(&mut self, key: &(String, u64)) -> Option<Result<Value, CalcError>> {
+        self.clock += 1;
+        match self.entries.get_mut(key) {
+            Some(entry) => {
+                entry.last_used = self.clock;
+                self.stats.hits += 1;
+                Some(entry.result.clone())
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn insert(&mut self, key: (String, u64), result: Result<Value, CalcError>) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() >= self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|&(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        let last_used = self.clock;
+        self.entries.insert(key, CacheEntry { result, last_used });
+    }
+}
+
+impl Calculator {
+    pub fn new() -> Self {
+        Calculator::default()
+    }
+
+    /// Memoizes the results of `eval`, keeping those of the `capacity`
+    /// most recently used inputs.
+    ///
+    /// Results are only reused while the variables and settings of the
+    /// calculator are the same as when they were computed, so a hit is
+    /// indistinguishable from evaluating the input again. Evaluations
+    /// aborted by the step limit or timeout are not cached.
+    pub fn with_cache(mut self, capacity: usize) -> Self {
+        self.cache = Some(Cache {
+            capacity,
+            entries: HashMap::new(),
+            clock: 0,
+            stats: CacheStats::default(),
+        });
+        self
+    }
+
+    /// The number of cache hits and misses so far. Both are zero if caching
+    /// is disabled.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache
+            .as_ref()
+            .map(|cache| cache.stats)
+            .unwrap_or_default()
+    }
+
+    /// Selects the numeric representation used by `eval`.
+    pub fn set_backend(&mut self, backend: NumberBackend) {
+        self.backend = backend;
+    }
+
+    /// Defines a variable, replacing any previous value of the same name.
+    pub fn set_var<S: Into<String>>(&mut self, name: S, value: Value) {
+        let name = name.into();
+        let previous = self.vars.insert(name.clone(), value);
+        self.history.record(Change::Var(name, previous));
+    }
+
+    /// Looks up the value of a variable, whether set with `set_var` or
+    /// assigned in `eval`.
+    pub fn var(&self, name: &str) -> Option<&Value> {
+        self.vars.get(name)
+    }
+
+    /// The variables, sorted by name.
+    pub fn vars(&self) -> impl Iterator<Item = (&str, &Value)> {
+        let mut vars: Vec<_> = self.vars.iter().collect();
+        vars.sort_by(|a, b| a.0.cmp(b.0));
+        vars.into_iter().map(|(name, value)| (name.as_str(), value))
+    }
+
+    /// The result of the last successful evaluation, read as `ans`.
+    pub fn ans(&self) -> Option<&Value> {
+        self.ans.as_ref()
+    }
+
+    /// Defines a function from its source, such as `f(x) = x ** 2 + 1`, as
+    /// `Context::define` does. The body sees its parameters, the functions
+    /// defined before it and the `DefaultEnvironment`, but not the
+    /// variables of the calculator.
+    pub fn define(&mut self, input: &str) -> Result<(), CalcError> {
+        self.functions.define(input)?;
+        self.definitions.push(input.trim().to_owned());
+        Ok(())
+    }
+
+    /// Defines a binary operator as `Context::register_operator` does.
+    /// Operators are closures, so `save_session` leaves them out.
+    pub fn register_operator<S, F>(
+        &mut self,
+        symbol: S,
+        precedence: u8,
+        assoc: Assoc,
+        f: F,
+    ) -> Result<(), CalcError>
+    where
+        S: Into<String>,
+        F: Fn(f64, f64) -> Result<f64, CalcError> + Send + Sync + 'static,
+    {
+        self.functions
+            .register_operator(symbol, precedence, assoc, f)?;
+        // The fingerprint cannot tell closures apart.
+        if let Some(ref mut cache) = self.cache {
+            cache.entries.clear();
+        }
+        Ok(())
+    }
+
+    /// The sources of the functions passed to `define`, oldest first.
+    pub fn definitions(&self) -> &[String] {
+        &self.definitions
+    }
+
+    /// Adds `value` to the memory register, like the M+ key.
+    pub fn memory_add(&mut self, value: f64) {
+        let memory = self.memory + value;
+        self.memory_store(memory);
+    }
+
+    /// Subtracts `value` from the memory register, like the M- key.
+    pub fn memory_subtract(&mut self, value: f64) {
+        let memory = self.memory - value;
+        self.memory_store(memory);
+    }
+
+    /// Replaces the memory register with `value`, like the MS key.
+    pub fn memory_store(&mut self, value: f64) {
+        let previous = mem::replace(&mut self.memory, value);
+        self.history.record(Change::Memory(previous));
+    }
+
+    /// The value of the memory register, like the MR key.
+    pub fn memory_recall(&self) -> f64 {
+        self.memory
+    }
+
+    /// Resets the memory register to zero, like the MC key.
+    pub fn memory_clear(&mut self) {
+        self.memory_store(0.0);
+    }
+
+    /// Reverts the most recent change to the variables or the memory that
+    /// has not been undone yet. Returns whether there was one.
+    pub fn undo(&mut self) -> bool {
+        match self.history.undo.pop_back() {
+            Some(change) => {
+                let redo = self.revert(change);
+                self.history.redo.push(redo);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Restores the most recently undone change, unless something changed
+    /// since. Returns whether there was one.
+    pub fn redo(&mut self) -> bool {
+        match self.history.redo.pop() {
+            Some(change) => {
+                let undo = self.revert(change);
+                self.history.push(undo);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Limits how many changes can be undone, forgetting the oldest ones.
+    /// The default is 100.
+    pub fn set_history_depth(&mut self, depth: usize) {
+        self.history.depth = depth;
+        self.history.truncate();
+    }
+
+    /// Limits how many evaluations the transcript keeps, forgetting the
+    /// oldest ones. A limit of zero stops recording. There is no limit by
+    /// default.
+    pub fn set_transcript_limit(&mut self, limit: Option<usize>) {
+        self.transcript_limit = limit;
+        self.truncate_transcript();
+    }
+
+    /// The evaluations recorded so far, oldest first, including the failed
+    /// ones.
+    pub fn transcript(&self) -> &[TranscriptEntry] {
+        &self.transcript
+    }
+
+    /// Writes the transcript to `writer` in the given format, e.g.
+    ///
+    /// ```text
+    /// > 1 + 2
+    /// 3
+    /// > 1 / 0
+    /// error: attempted to divide by zero
+    /// ```
+    ///
+    /// or, in Markdown,
+    ///
+    /// ```text
+    /// - `1 + 2` = 3
+    /// - `1 / 0` **error:** attempted to divide by zero
+    /// ```
+    pub fn export_transcript<W: Write>(
+        &self,
+        mut writer: W,
+        format: TranscriptFormat,
+    ) -> Result<(), CalcError> {
+        let mut out = String::new();
+        for entry in &self.transcript {
+            match (format, entry.outcome.as_ref()) {
+                (TranscriptFormat::Text, Ok(result)) => {
+                    writeln!(out, "> {}\n{}", entry.input, result)
+                }
+                (TranscriptFormat::Text, Err(message)) => {
+                    writeln!(out, "> {}\nerror: {}", entry.input, message)
+                }
+                (TranscriptFormat::Markdown, Ok(result)) => {
+                    writeln!(out, "- {} = {}", code_span(&entry.input), result)
+                }
+                (TranscriptFormat::Markdown, Err(message)) => writeln!(
+                    out,
+                    "- {} **error:** {}",
+                    code_span(&entry.input),
+                    message
+                ),
+            }
+            .expect("writing to a String succeeds");
+        }
+        writer.write_all(out.as_bytes())?;
+        Ok(())
+    }
+
+    /// Adds an evaluation to the transcript.
+    fn record(&mut self, input: &str, result: &Result<Value, CalcError>) {
+        if self.transcript_limit == Some(0) {
+            return;
+        }
+        self.transcript.push(TranscriptEntry {
+            input: input.trim().to_owned(),
+            outcome: match *result {
+                Ok(ref value) => Ok(value.to_string()),
+                Err(ref err) => Err(err.to_string()),
+            },
+        });
+        self.truncate_transcript();
+    }
+
+    /// Forgets the oldest evaluations beyond the transcript limit.
+    fn truncate_transcript(&mut self) {
+        if let Some(limit) = self.transcript_limit {
+            let excess = self.transcript.len().saturating_sub(limit);
+            self.transcript.drain(..excess);
+        }
+    }
+
+    /// Puts back the state recorded in `change`, and returns the change
+    /// that reverts this again.
+    fn revert(&mut self, change: Change) -> Change {
+        match change {
+            Change::Var(name, Some(value)) => {
+                let current = self.vars.insert(name.clone(), value);
+                Change::Var(name, current)
+            }
+            Change::Var(name, None) => {
+                let current = self.vars.remove(&name);
+                Change::Var(name, current)
+            }
+            Change::Memory(memory) => {
+                Change::Memory(mem::replace(&mut self.memory, memory))
+            }
+        }
+    }
+
+    /// Sets the limits applied when tokenizing the input.
+    pub fn set_tokenizer_options(&mut self, options: TokenizerOptions) {
+        self.tokenizer_options = options;
+    }
+
+    /// Limits the number of parser and evaluator operations a single
+    /// evaluation may perform.
+    pub fn set_step_limit(&mut self, limit: Option<u64>) {
+        self.step_limit = limit;
+    }
+
+    /// Limits how deeply groups, unary operators and exponents may nest.
+    /// Deeper inputs fail with `CalcError::NestingTooDeep`.
+    pub fn set_max_depth(&mut self, depth: Option<usize>) {
+        self.max_depth = depth;
+    }
+
+    /// Limits the absolute value of every literal, intermediate and final
+    /// result. Larger values fail with `CalcError::MagnitudeExceeded`.
+    pub fn set_max_magnitude(&mut self, limit: Option<f64>) {
+        self.max_magnitude = limit;
+    }
+
+    /// Selects whether values must be integers, and what `/` does then.
+    pub fn set_integer_mode(&mut self, mode: IntegerMode) {
+        self.integer_mode = mode;
+    }
+
+    /// Lets the end of the input close the parentheses that are still
+    /// open, so that `((5 + 3) * 2` is `16`. How many were closed is noted
+    /// in `warnings`.
+    pub fn set_lenient_parens(&mut self, lenient: bool) {
+        self.lenient_parens = lenient;
+    }
+
+    /// Carries the rounding error of each step of a chain of `+` and `-`
+    /// along, and adds it back at the end, so that long sums such as
+    /// `1 + 1e-8 + 1e-8 + ...` don't drift. Sums of two terms are unchanged.
+    /// The `sum` function always does this.
+    pub fn set_compensated_sum(&mut self, compensated: bool) {
+        self.compensated_sum = compensated;
+    }
+
+    /// Notes about the input of the last `eval` that was accepted, but
+    /// not as written, such as parentheses closed by
+    /// `set_lenient_parens`.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Makes the binary operator `token` bind like the builtin operators of
+    /// `precedence`, from 1 for the bitwise operators to 4 for `**`. With
+    /// `+` at 3, `2 + 3 * 4` is `(2 + 3) * 4`, that is `20`.
+    ///
+    /// Fails with `CalcError::InvalidArgument` for tokens other than the
+    /// builtin binary operators, and for precedences outside of 1 to 4.
+    /// Registered operators get theirs from `register_operator`.
+    pub fn set_precedence(
+        &mut self,
+        token: Token,
+        precedence: u8,
+    ) -> Result<(), CalcError> {
+        let op = binary_op(&token)?;
+        if !(1..=4).contains(&precedence) {
+            return Err(CalcError::InvalidArgument(format!(
+                "operator precedence must be between 1 and 4, got {}",
+                precedence
+            )));
+        }
+        set_override(&mut self.precedence, op, precedence);
+        Ok(())
+    }
+
+    /// Makes chains of the binary operator `token` group as `assoc` says.
+    /// With `**` left associative, `2 ** 3 ** 2` is `(2 ** 3) ** 2`, that is `64`.
+    ///
+    /// Fails like `set_precedence` for other tokens.
+    pub fn set_assoc(
+        &mut self,
+        token: Token,
+        assoc: Assoc,
+    ) -> Result<(), CalcError> {
+        let op = binary_op(&token)?;
+        set_override(&mut self.assoc, op, assoc);
+        Ok(())
+    }
+
+    /// Selects whether `>>` shifts arithmetically, the default, or
+    /// logically at a fixed width. That width also bounds the arguments of
+    /// `togray` and `fromgray`, which otherwise take up to 64 bits.
+    pub fn set_right_shift(&mut self, shift: RightShift) {
+        self.right_shift = shift;
+    }
+
+    /// Sets the tolerance and limits of numeric methods such as
+    /// `integrate` and `solve`.
+    pub fn set_numeric_options(&mut self, options: NumericOptions) {
+        self.numeric_options = options;
+    }
+
+    /// Limits the wall-clock time a single evaluation may take.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Evaluates an expression that may refer to the defined variables.
+    ///
+    /// An assignment like `x = 2 * y` evaluates the right-hand side and
+    /// defines the variable to be its value, which it returns. Nothing is
+    /// defined if the evaluation fails. A definition like `f(x) = x + 1`
+    /// defines a function as `define` does, and evaluates to 0.
+    ///
+    /// Like on a pocket calculator, input that starts with a binary
+    /// operator, such as `* 2`, continues from `ans`, the result of the
+    /// last successful evaluation. It fails with
+    /// `CalcError::InvalidArgument` if there is none yet. A leading `-`
+    /// negates instead, so `-5` is `-5`; type `ans - 5` to subtract. A
+    /// leading `+` adds to `ans` if there is one, and is a unary plus
+    /// otherwise.
+    pub fn eval(&mut self, input: &str) -> Result<Value, CalcError> {
+        let result = self.eval_line(input);
+        self.record(input, &result);
+        result
+    }
+
+    /// Evaluates an input for `eval`, continuing from `ans` if it starts
+    /// with a binary operator.
+    fn eval_line(&mut self, input: &str) -> Result<Value, CalcError> {
+        self.warnings.clear();
+        let continued;
+        let operators = (&self.functions).operators();
+        let input = if continues(input, &operators, self.ans.is_some()) {
+            if self.ans.is_none() {
+                return Err(CalcError::InvalidArgument(format!(
+                    "no previous result for '{}' to continue from",
+                    input.trim()
+                )));
+            }
+            continued = format!("ans {}", input);
+            &continued
+        } else {
+            input
+        };
+        let value = self.eval_statement(input)?;
+        self.ans = Some(value.clone());
+        Ok(value)
+    }
+
+    /// Evaluates an expression or assignment for `eval`.
+    fn eval_statement(&mut self, input: &str) -> Result<Value, CalcError> {
+        if let Some(equals) = input.find('=') {
+            let name = input[..equals].trim();
+            if context::is_name(name) {
+                let value = self.eval_statement(&input[equals + 1..])?;
+                self.set_var(name, value.clone());
+                return Ok(value);
+            }
+            if context::split_definition(input).is_some() {
+                self.define(input)?;
+                return Ok(Value::dec(0));
+            }
+        }
+        // Results that depend on `ans` change with every evaluation, so
+        // they are not worth caching.
+        if self.cache.is_none() || refers_to_ans(input) {
+            return self.eval_uncached(input);
+        }
+        let key = (input.to_owned(), self.fingerprint());
+        if let Some(result) = self.cache.as_mut().and_then(|c| c.get(&key)) {
+            return result;
+        }
+        let result = self.eval_uncached(input);
+        // A cached result would lose its warnings.
+        if result != Err(CalcError::BudgetExceeded) && self.warnings.is_empty()
+        {
+            if let Some(ref mut cache) = self.cache {
+                cache.insert(key, result.clone());
+            }
+        }
+        result
+    }
+
+    /// Hashes everything besides the input that the result of `eval`
+    /// depends on.
+    fn fingerprint(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.backend.hash(&mut hasher);
+        self.memory.to_bits().hash(&mut hasher);
+        self.definitions.hash(&mut hasher);
+        self.tokenizer_options.hash(&mut hasher);
+        self.step_limit.hash(&mut hasher);
+        self.max_depth.hash(&mut hasher);
+        self.max_magnitude.map(f64::to_bits).hash(&mut hasher);
+        self.integer_mode.hash(&mut hasher);
+        self.lenient_parens.hash(&mut hasher);
+        self.compensated_sum.hash(&mut hasher);
+        self.precedence.hash(&mut hasher);
+        self.assoc.hash(&mut hasher);
+        self.right_shift.hash(&mut hasher);
+        self.numeric_options.tolerance.to_bits().hash(&mut hasher);
+        self.numeric_options.max_subdivisions.hash(&mut hasher);
+        self.numeric_options.max_iterations.hash(&mut hasher);
+        self.timeout.hash(&mut hasher);
+        for (name, value) in self.vars() {
+            name.hash(&mut hasher);
+            mem::discriminant(value).hash(&mut hasher);
+            value.to_string().hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    fn eval_uncached(&mut self, input: &str) -> Result<Value, CalcError> {
+        let operators = (&self.functions).operators();
+        let tokens = TokenStream::tokenize_with(
+            input,
+            &self.tokenizer_options,
+            &operators,
+        )?;
+        self.eval_tokens(&tokens)
+    }
+
+    fn eval_tokens(
+        &mut self,
+        tokens: &TokenStream,
+    ) -> Result<Value, CalcError> {
+        self.steps = 0;
+        self.deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        match self.backend {
+            NumberBackend::Decimal => parse::build(tokens, self),
+            NumberBackend::Float => {
+                parse::build::<f64, _>(tokens, self).map(|n| n.to_value())
+            }
+        }
+    }
+}
+
+impl Calculator {
+    /// Evaluates `input` like `eval` and pushes the result onto the RPN
+    /// stack, which it returns.
+    pub fn push(&mut self, input: &str) -> Result<f64, CalcError> {
+        let n = self.eval(input)?.as_f64();
+        self.stack.push(n);
+        Ok(n)
+    }
+
+    /// Replaces the values on top of the RPN stack with the result of `op`,
+    /// which it returns. `op` is a binary operator, which takes the top
+    /// value as its right operand, one of `~`, `²` and `³`, or the name of
+    /// a function, which takes as many values as it has parameters.
+    ///
+    /// The operands are evaluated like literals passed to `eval`, so the
+    /// backend and limits of the calculator apply. The stack is left as it
+    /// was if this fails, e.g. when there are too few values on it.
+    pub fn apply(&mut self, op: &str) -> Result<f64, CalcError> {
+        let (token, arity) = self.stack_op(op)?;
+        let depth = self.stack.len();
+        if depth < arity {
+            return Err(underflow(op, arity, depth));
+        }
+        let args: Vec<Token> = self.stack[depth - arity..]
+            .iter()
+            .map(|n| Token::Number(n.to_value()))
+            .collect();
+        let tokens = match token {
+            Token::Atom(_) => {
+                let mut tokens = vec![token, Token::OpenParen];
+                for (i, arg) in args.into_iter().enumerate() {
+                    if i > 0 {
+                        tokens.push(Token::Comma);
+                    }
+                    tokens.push(arg);
+                }
+                tokens.push(Token::CloseParen);
+                tokens
+            }
+            Token::BitWiseNot => vec![token, args[0].clone()],
+            Token::Square | Token::Cube => vec![args[0].clone(), token],
+            _ => vec![args[0].clone(), token, args[1].clone()],
+        };
+        let n = self.eval_tokens(&TokenStream::from_tokens(&tokens))?;
+        let n = n.as_f64();
+        self.stack.truncate(depth - arity);
+        self.stack.push(n);
+        Ok(n)
+    }
+
+    /// The token of an operator or function for `apply`, and the number of
+    /// values it takes.
+    fn stack_op(&self, op: &str) -> Result<(Token, usize), CalcError> {
+        let not_an_op = || {
+            CalcError::InvalidArgument(format!(
+                "'{}' is not an operator or function",
+                op
+            ))
+        };
+        let operators = (&self.functions).operators();
+        let mut tokens = Tokenizer::with_operators(op, &operators);
+        let token = match (tokens.next(), tokens.next()) {
+            (Some(token), None) => token?.to_token()?,
+            _ => return Err(not_an_op()),
+        };
+        let arity = match token {
+            Token::BitWiseNot | Token::Square | Token::Cube => 1,
+            Token::Operator(_) => 2,
+            Token::Atom(ref name) => match self.arity(name) {
+                Some(arity) if arity > 0 => arity,
+                _ => return Err(not_an_op()),
+            },
+            ref token => {
+                binary_op(token).map_err(|_| not_an_op())?;
+                2
+            }
+        };
+        Ok((token, arity))
+    }
+
+    /// The RPN stack, with its top last.
+    pub fn stack(&self) -> &[f64] {
+        &self.stack
+    }
+
+    /// Removes the top value of the RPN stack and returns it.
+    pub fn pop(&mut self) -> Result<f64, CalcError> {
+        self.stack.pop().ok_or_else(|| underflow("pop", 1, 0))
+    }
+
+    /// Pushes another copy of the top value of the RPN stack.
+    pub fn dup(&mut self) -> Result<(), CalcError> {
+        let top = *self.stack.last().ok_or_else(|| underflow("dup", 1, 0))?;
+        self.stack.push(top);
+        Ok(())
+    }
+
+    /// Exchanges the two values on top of the RPN stack.
+    pub fn swap(&mut self) -> Result<(), CalcError> {
+        let depth = self.stack.len();
+        if depth < 2 {
+            return Err(underflow("swap", 2, depth));
+        }
+        self.stack.swap(depth - 2, depth - 1);
+        Ok(())
+    }
+
+    /// Empties the RPN stack.
+    pub fn drop_all(&mut self) {
+        self.stack.clear();
+    }
+
+    /// Processes the whitespace-separated words of `line` in order, like
+    /// the keys of an RPN calculator. `dup`, `swap`, `pop` and `clear` work
+    /// the stack, operators and functions taking arguments are applied with
+    /// `apply`, and anything else is pushed with `push`.
+    ///
+    /// Processing stops at the first word that fails, keeping the effects
+    /// of the words before it.
+    ///
+    /// ```
+    /// # extern crate calc;
+    /// # use calc::{CalcError, Calculator};
+    /// # fn main() -> Result<(), CalcError> {
+    /// let mut calculator = Calculator::new();
+    /// calculator.rpn("3 4 + 2 *")?;
+    /// assert_eq!(calculator.stack(), &[14.0]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn rpn(&mut self, line: &str) -> Result<(), CalcError> {
+        for word in line.split_whitespace() {
+            match word {
+                "dup" => self.dup()?,
+                "swap" => self.swap()?,
+                "pop" => {
+                    self.pop()?;
+                }
+                "clear" => self.drop_all(),
+                _ if self.stack_op(word).is_ok() => {
+                    self.apply(word)?;
+                }
+                _ => {
+                    self.push(word)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes `text` as a Markdown code span, delimited by more backticks than
+/// it contains in a row.
+fn code_span(text: &str) -> String {
+    let mut longest = 0;
+    let mut run = 0;
+    for c in text.chars() {
+        run = if c == '`' { run + 1 } else { 0 };
+        longest = longest.max(run);
+    }
+    let fence = "`".repeat(longest + 1);
+    // A span may not start or end with a backtick next to its delimiter.
+    if text.starts_with('`') || text.ends_with('`') {
+        format!("{} {} {}", fence, text, fence)
+    } else {
+        format!("{}{}{}", fence, text, fence)
+    }
+}
+
+/// The error of an RPN operation that needs more values than the stack
+/// holds.
+fn underflow(op: &str, needed: usize, depth: usize) -> CalcError {
+    CalcError::InvalidArgument(format!(
+        "'{}' needs {} value(s) on the stack, but it holds {}",
+        op, needed, depth
+    ))
+}
+
+impl Calculator {
+    /// Writes the variables, functions, memory and settings of the
+    /// calculator to `writer`, to be restored by `load_session`. The undo
+    /// history, the transcript and the contents of the cache are not saved.
+    ///
+    /// All functions of a calculator are defined from source, so every one
+    /// of them is saved. Operators added with `register_operator` are
+    /// closures, and have to be registered again after loading.
+    ///
+    /// The session is a JSON document like the following, holding version
+    /// 1 of the format. Variables are numbers as in the `json` module,
+    /// while other numbers that are not finite are written as `null` and
+    /// read back as NaN. Limits that are disabled are `null` as well, as is
+    /// the `shift_width` of an arithmetic `>>`. The overrides of
+    /// `set_precedence` and `set_assoc` are keyed by the operator names of
+    /// the `json` module.
+    ///
+    /// ```text
+    /// {
+    ///   "version": 1,
+    ///   "vars": {"rate": 0.25, "x": 3},
+    ///   "memory": 0.0,
+    ///   "functions": ["sq(x) = x * x"],
+    ///   "config": {
+    ///     "backend": "decimal",
+    ///     "integer_mode": "any",
+    ///     "lenient_parens": false,
+    ///     "compensated_sum": false,
+    ///     "decimal_comma": false,
+    ///     "thousands_point": false,
+    ///     "precedence": {"add": 3},
+    ///     "assoc": {"pow": "left"},
+    ///     "shift_width": null,
+    ///     "max_input_len": null,
+    ///     "max_tokens": null,
+    ///     "step_limit": null,
+    ///     "max_depth": null,
+    ///     "timeout_ms": null,
+    ///     "max_magnitude": null,
+    ///     "tolerance": 1e-10,
+    ///     "max_subdivisions": 10000,
+    ///     "max_iterations": 100,
+    ///     "history_depth": 100,
+    ///     "cache_capacity": null
+    ///   }
+    /// }
+    /// ```
+    pub fn save_session<W: Write>(
+        &self,
+        mut writer: W,
+    ) -> Result<(), CalcError> {
+        let mut out =
+            format!("{{\n  \"version\": {},\n  \"vars\": {{", SESSION_VERSION);
+        for (i, (name, value)) in self.vars().enumerate() {
+            out.push_str(if i == 0 { "" } else { ", " });
+            json::write_string(&mut out, name);
+            out.push_str(": ");
+            json::write_value(&mut out, value);
+        }
+        out.push_str("},\n  \"memory\": ");
+        write_f64(&mut out, self.memory);
+        out.push_str(",\n  \"functions\": [");
+        for (i, definition) in self.definitions.iter().enumerate() {
+            out.push_str(if i == 0 { "" } else { ", " });
+            json::write_string(&mut out, definition);
+        }
+        let backend = match self.backend {
+            NumberBackend::Decimal => "decimal",
+            NumberBackend::Float => "float",
+        };
+        let shift_width = match self.right_shift {
+            RightShift::Arithmetic => None,
+            RightShift::Logical(width) => Some(u64::from(width)),
+        };
+        let count = |n: Option<usize>| n.map(|n| n as u64);
+        let options = &self.numeric_options;
+        let integer_mode = match self.integer_mode {
+            IntegerMode::Any => "any",
+            IntegerMode::Strict => "strict",
+            IntegerMode::FloorDivision => "floor_division",
+        };
+        write!(
+            out,
+            "],\n  \"config\": {{\n    \"backend\": \"{}\",\n    \
+             \"integer_mode\": \"{}\",\n    \"lenient_parens\": {},\n    \
+             \"compensated_sum\": {},\n    \"decimal_comma\": {},\n    \
+             \"thousands_point\": {}",
+            backend,
+            integer_mode,
+            self.lenient_parens,
+            self.compensated_sum,
+            self.tokenizer_options.decimal_comma,
+            self.tokenizer_options.thousands_point
+        )
+        .expect("writing to a String succeeds");
+        out.push_str(",\n    \"precedence\": {");
+        for (i, &(op, precedence)) in self.precedence.iter().enumerate() {
+            out.push_str(if i == 0 { "" } else { ", " });
+            write!(out, "\"{}\": {}", json::binary_name(op), precedence)
+                .expect("writing to a String succeeds");
+        }
+        out.push_str("},\n    \"assoc\": {");
+        for (i, &(op, assoc)) in self.assoc.iter().enumerate() {
+            out.push_str(if i == 0 { "" } else { ", " });
+            let assoc = match assoc {
+                Assoc::Left => "left",
+                Assoc::Right => "right",
+            };
+            write!(out, "\"{}\": \"{}\"", json::binary_name(op), assoc)
+                .expect("writing to a String succeeds");
+        }
+        out.push('}');
+        let fields = [
+            ("shift_width", shift_width),
+            ("max_input_len", count(self.tokenizer_options.max_input_len)),
+            ("max_tokens", count(self.tokenizer_options.max_tokens)),
+            ("step_limit", self.step_limit),
+            ("max_depth", count(self.max_depth)),
+            ("timeout_ms", self.timeout.map(|t| t.as_millis() as u64)),
+        ];
+        for &(name, value) in &fields {
+            write!(out, ",\n    \"{}\": ", name)
+                .expect("writing to a String succeeds");
+            match value {
+                Some(n) => write!(out, "{}", n),
+                None => write!(out, "null"),
+            }
+            .expect("writing to a String succeeds");
+        }
+        out.push_str(",\n    \"max_magnitude\": ");
+        match self.max_magnitude {
+            Some(limit) => write_f64(&mut out, limit),
+            None => out.push_str("null"),
+        }
+        out.push_str(",\n    \"tolerance\": ");
+        write_f64(&mut out, options.tolerance);
+        write!(
+            out,
+            ",\n    \"max_subdivisions\": {},\n    \"max_iterations\": {},\n    \
+             \"history_depth\": {},\n    \"cache_capacity\": ",
+            options.max_subdivisions, options.max_iterations, self.history.depth
+        )
+        .expect("writing to a String succeeds");
+        match self.cache {
+            Some(ref cache) => write!(out, "{}", cache.capacity),
+            None => write!(out, "null"),
+        }
+        .expect("writing to a String succeeds");
+        out.push_str("\n  }\n}\n");
+        writer.write_all(out.as_bytes())?;
+        Ok(())
+    }
+
+    /// Reads a session written by `save_session`.
+    ///
+    /// Documents that are malformed, or of another version, fail with
+    /// `CalcError::InvalidJson`, while functions that no longer parse fail
+    /// as they did when they were defined.
+    pub fn load_session<R: Read>(mut reader: R) -> Result<Self, CalcError> {
+        let mut input = String::new();
+        reader.read_to_string(&mut input)?;
+        let mut fields = json::into_object(json::parse(&input)?, "session")?;
+        match json::take(&mut fields, "version") {
+            Some(Json::Number(ref version))
+                if *version == SESSION_VERSION.to_string() => {}
+            Some(Json::Number(version)) => {
+                return Err(json::invalid(format!(
+                    "unsupported session version {}",
+                    version
+                )))
+            }
+            _ => return Err(json::invalid("missing version")),
+        }
+        let mut calc = Calculator::new();
+        for (name, value) in
+            json::into_object(field(&mut fields, "vars")?, "vars")?
+        {
+            let value = match value {
+                Json::Number(text) => json::read_value(&text)?,
+                _ => {
+                    return Err(json::invalid(format!(
+                        "{} must be a number",
+                        name
+                    )))
+                }
+            };
+            calc.vars.insert(name, value);
+        }
+        calc.memory = read_f64(field(&mut fields, "memory")?, "memory")?;
+        match field(&mut fields, "functions")? {
+            Json::Array(functions) => {
+                for function in functions {
+                    calc.define(&json::into_string(function, "a function")?)?;
+                }
+            }
+            _ => return Err(json::invalid("functions must be an array")),
+        }
+        let mut config =
+            json::into_object(field(&mut fields, "config")?, "config")?;
+        json::no_more_fields(&fields, "session")?;
+
+        calc.backend = match field(&mut config, "backend")? {
+            Json::String(ref backend) if backend == "decimal" => {
+                NumberBackend::Decimal
+            }
+            Json::String(ref backend) if backend == "float" => {
+                NumberBackend::Float
+            }
+            _ => {
+                return Err(json::invalid(
+                    "backend must be \"decimal\" or \"float\"",
+                ))
+            }
+        };
+        // Sessions saved before integer modes existed lack one.
+        calc.integer_mode = match json::take(&mut config, "integer_mode") {
+            None => IntegerMode::Any,
+            Some(Json::String(ref mode)) if mode == "any" => IntegerMode::Any,
+            Some(Json::String(ref mode)) if mode == "strict" => {
+                IntegerMode::Strict
+            }
+            Some(Json::String(ref mode)) if mode == "floor_division" => {
+                IntegerMode::FloorDivision
+            }
+            _ => {
+                return Err(json::invalid(
+                    "integer_mode must be \"any\", \"strict\" or \
+                     \"floor_division\"",
+                ))
+            }
+        };
+        // Sessions saved before lenient parentheses existed lack the flag.
+        calc.lenient_parens = match json::take(&mut config, "lenient_parens") {
+            None => false,
+            Some(Json::Bool(lenient)) => lenient,
+            _ => return Err(json::invalid("lenient_parens must be a boolean")),
+        };
+        // Sessions saved before compensated sums existed lack the flag.
+        calc.compensated_sum = match json::take(&mut config, "compensated_sum")
+        {
+            None => false,
+            Some(Json::Bool(compensated)) => compensated,
+            _ => {
+                return Err(json::invalid("compensated_sum must be a boolean"))
+            }
+        };
+        // Sessions saved before number separators existed lack the flags.
+        let mut flag = |name| match json::take(&mut config, name) {
+            None => Ok(false),
+            Some(Json::Bool(flag)) => Ok(flag),
+            _ => Err(json::invalid(format!("{} must be a boolean", name))),
+        };
+        let decimal_comma = flag("decimal_comma")?;
+        let thousands_point = flag("thousands_point")?;
+        // Sessions saved before precedence overrides existed lack them.
+        if let Some(precedence) = json::take(&mut config, "precedence") {
+            for (name, value) in json::into_object(precedence, "precedence")? {
+                let op = session_op(&name)?;
+                let precedence = match value {
+                    Json::Number(ref text) => text.parse().ok(),
+                    _ => None,
+                };
+                match precedence {
+                    Some(precedence) if (1..=4).contains(&precedence) => {
+                        set_override(&mut calc.precedence, op, precedence)
+                    }
+                    _ => {
+                        return Err(json::invalid(format!(
+                            "precedence of {} must be between 1 and 4",
+                            name
+                        )))
+                    }
+                }
+            }
+        }
+        if let Some(assoc) = json::take(&mut config, "assoc") {
+            for (name, value) in json::into_object(assoc, "assoc")? {
+                let op = session_op(&name)?;
+                let assoc = match value {
+                    Json::String(ref assoc) if assoc == "left" => Assoc::Left,
+                    Json::String(ref assoc) if assoc == "right" => Assoc::Right,
+                    _ => {
+                        return Err(json::invalid(format!(
+                            "assoc of {} must be \"left\" or \"right\"",
+                            name
+                        )))
+                    }
+                };
+                set_override(&mut calc.assoc, op, assoc);
+            }
+        }
+        let mut count = |name: &str| -> Result<Option<u64>, CalcError> {
+            match field(&mut config, name)? {
+                Json::Null => Ok(None),
+                Json::Number(ref text) => {
+                    text.parse().map(Some).map_err(|_| {
+                        json::invalid(format!("invalid {} {}", name, text))
+                    })
+                }
+                _ => Err(json::invalid(format!("{} must be a number", name))),
+            }
+        };
+        let shift_width = count("shift_width")?;
+        let max_input_len = count("max_input_len")?;
+        let max_tokens = count("max_tokens")?;
+        let step_limit = count("step_limit")?;
+        let max_depth = count("max_depth")?;
+        let timeout_ms = count("timeout_ms")?;
+        let max_subdivisions = count("max_subdivisions")?;
+        let max_iterations = count("max_iterations")?;
+        let history_depth = count("history_depth")?;
+        let cache_capacity = count("cache_capacity")?;
+        let tolerance =
+            read_f64(field(&mut config, "tolerance")?, "tolerance")?;
+        // Sessions saved before the limit existed lack it.
+        let max_magnitude = match json::take(&mut config, "max_magnitude") {
+            None | Some(Json::Null) => None,
+            Some(limit) => Some(read_f64(limit, "max_magnitude")?),
+        };
+        json::no_more_fields(&config, "config")?;
+
+        calc.right_shift = match shift_width {
+            Some(width) => RightShift::Logical(narrow(width, "shift_width")?),
+            None => RightShift::Arithmetic,
+        };
+        calc.tokenizer_options = TokenizerOptions {
+            max_input_len: max_input_len.map(|n| n as usize),
+            max_tokens: max_tokens.map(|n| n as usize),
+            decimal_comma,
+            thousands_point,
+        };
+        calc.step_limit = step_limit;
+        calc.max_depth = max_depth.map(|n| n as usize);
+        calc.max_magnitude = max_magnitude;
+        calc.timeout = timeout_ms.map(Duration::from_millis);
+        calc.numeric_options = NumericOptions {
+            tolerance,
+            max_subdivisions: narrow(
+                required(max_subdivisions, "max_subdivisions")?,
+                "max_subdivisions",
+            )?,
+            max_iterations: narrow(
+                required(max_iterations, "max_iterations")?,
+                "max_iterations",
+            )?,
+        };
+        calc.history.depth = required(history_depth, "history_depth")? as usize;
+        if let Some(capacity) = cache_capacity {
+            calc = calc.with_cache(capacity as usize);
+        }
+        Ok(calc)
+    }
+}
+
+/// Writes a number that is not finite as `null`.
+fn write_f64(out: &mut String, n: f64) {
+    if n.is_finite() {
+        write!(out, "{:?}", n).expect("writing to a String succeeds");
+    } else {
+        out.push_str("null");
+    }
+}
+
+fn read_f64(json: Json, what: &str) -> Result<f64, CalcError> {
+    match json {
+        Json::Null => Ok(f64::NAN),
+        Json::Number(ref text) => text
+            .parse()
+            .map_err(|_| json::invalid(format!("invalid {} {}", what, text))),
+        _ => Err(json::invalid(format!("{} must be a number", what))),
+    }
+}
+
+fn field(
+    fields: &mut Vec<(String, Json)>,
+    key: &str,
+) -> Result<Json, CalcError> {
+    json::take(fields, key)
+        .ok_or_else(|| json::invalid(format!("missing {}", key)))
+}
+
+fn required(n: Option<u64>, what: &str) -> Result<u64, CalcError> {
+    n.ok_or_else(|| json::invalid(format!("{} must be a number", what)))
+}
+
+fn narrow(n: u64, what: &str) -> Result<u32, CalcError> {
+    if n <= u64::from(u32::MAX) {
+        Ok(n as u32)
+    } else {
+        Err(json::invalid(format!("{} {} is too large", what, n)))
+    }
+}
+
+/// Whether `input` mentions `ans`.
+fn refers_to_ans(input: &str) -> bool {
+    Tokenizer::new(input).any(|token| token == Ok(RawToken::Atom("ans")))
+}
+
+/// Whether `input` starts with a binary operator, builtin or one of
+/// `operators`, and so continues from the previous result. A `+` only
+/// does if there is a previous result, and is a unary plus otherwise.
+fn continues(input: &str, operators: &[Operator], ans: bool) -> bool {
+    match Tokenizer::with_operators(input, operators).next() {
+        Some(Ok(RawToken::Plus)) => ans,
+        Some(Ok(token)) => matches!(
+            token,
+            RawToken::Operator(_)
+                | RawToken::Multiply
+                | RawToken::Divide
+                | RawToken::Modulo
+                | RawToken::Exponent
+                | RawToken::BitWiseAnd
+                | RawToken::BitWiseOr
+                | RawToken::BitWiseXor
+                | RawToken::BitWiseLShift
+                | RawToken::BitWiseRShift
+        ),
+        _ => false,
+    }
+}
+
+/// The builtin binary operator `token` denotes, for `set_precedence` and
+/// `set_assoc`.
+fn binary_op(token: &Token) -> Result<BinaryOp, CalcError> {
+    Ok(match *token {
+        Token::Plus => BinaryOp::Add,
+        Token::Minus => BinaryOp::Sub,
+        Token::Multiply => BinaryOp::Mul,
+        Token::Divide => BinaryOp::Div,
+        Token::Modulo => BinaryOp::Rem,
+        Token::Exponent => BinaryOp::Pow,
+        Token::BitWiseAnd => BinaryOp::BitAnd,
+        Token::BitWiseOr => BinaryOp::BitOr,
+        Token::BitWiseXor => BinaryOp::BitXor,
+        Token::BitWiseLShift => BinaryOp::Shl,
+        Token::BitWiseRShift => BinaryOp::Shr,
+        Token::Operator(ref symbol) => {
+            return Err(CalcError::InvalidArgument(format!(
+                "'{}' is a registered operator, whose precedence and \
+                 associativity are given to register_operator",
+                symbol
+            )))
+        }
+        _ => {
+            return Err(CalcError::InvalidArgument(format!(
+                "{} is not a binary operator",
+                token
+            )))
+        }
+    })
+}
+
+/// The operator a session names `name`.
+fn session_op(name: &str) -> Result<BinaryOp, CalcError> {
+    json::binary_op(name)
+        .ok_or_else(|| json::invalid(format!("unknown operator '{}'", name)))
+}
+
+/// Overrides the setting of `op` in `overrides`, replacing an earlier
+/// override.
+fn set_override<T>(overrides: &mut Vec<(BinaryOp, T)>, op: BinaryOp, value: T) {
+    match overrides.iter_mut().find(|o| o.0 == op) {
+        Some(o) => o.1 = value,
+        None => overrides.push((op, value)),
+    }
+}
+
+impl Environment for Calculator {
+    fn arity(&self, atom: &str) -> Option<usize> {
+        if self.vars.contains_key(atom)
+            || atom == "mem"
+            || atom == "ans" && self.ans.is_some()
+        {
+            Some(0)
+        } else {
+            (&self.functions).arity(atom)
+        }
+    }
+
+    fn resolve(
+        &mut self,
+        atom: &str,
+        args: &[Value],
+    ) -> Result<Value, CalcError> {
+        match self.vars.get(atom) {
+            Some(value) => Ok(value.clone()),
+            None if atom == "mem" => Ok(Value::from_f64(self.memory)),
+            None if atom == "ans" && self.ans.is_some() => {
+                Ok(self.ans.clone().expect("checked to be some"))
+            }
+            None => match self.right_shift {
+                RightShift::Logical(width)
+                    if gray::is_gray(atom) && !self.functions.shadows(atom) =>
+                {
+                    gray::gray(atom, &args[0], width)
+                }
+                _ => match self.functions.function(atom) {
+                    // Defined functions run under the limits of the
+                    // calculator.
+                    Some(function) => function.call(self, args),
+                    None => (&self.functions).resolve(atom, args),
+                },
+            },
+        }
+    }
+
+    fn step(&mut self) -> Result<(), CalcError> {
+        self.steps += 1;
+        if let Some(limit) = self.step_limit {
+            if self.steps > limit {
+                return Err(CalcError::BudgetExceeded);
+            }
+        }
+        if let Some(deadline) = self.deadline {
+            if Instant::now() > deadline {
+                return Err(CalcError::BudgetExceeded);
+            }
+        }
+        Ok(())
+    }
+
+    fn max_depth(&self) -> Option<usize> {
+        self.max_depth
+    }
+
+    fn max_magnitude(&self) -> Option<f64> {
+        self.max_magnitude
+    }
+
+    fn integer_mode(&self) -> IntegerMode {
+        self.integer_mode
+    }
+
+    fn lenient_parens(&self) -> bool {
+        self.lenient_parens
+    }
+
+    fn compensated_sum(&self) -> bool {
+        self.compensated_sum
+    }
+
+    fn warn(&mut self, message: String) {
+        self.warnings.push(message);
+    }
+
+    fn right_shift(&self) -> RightShift {
+        self.right_shift
+    }
+
+    fn numeric_options(&self) -> NumericOptions {
+        self.numeric_options
+    }
+
+    fn operators(&self) -> Vec<Operator> {
+        (&self.functions).operators()
+    }
+
+    fn precedence(&self, op: BinaryOp) -> u8 {
+        match self.precedence.iter().find(|o| o.0 == op) {
+            Some(&(_, precedence)) => precedence,
+            None => op.precedence(),
+        }
+    }
+
+    fn assoc(&self, op: BinaryOp) -> Assoc {
+        match self.assoc.iter().find(|o| o.0 == op) {
+            Some(&(_, assoc)) => assoc,
+            None => op.assoc(),
+        }
+    }
+
+    fn names(&self) -> Vec<String> {
+        let mut names = (&self.functions).names();
+        names.extend(self.vars.keys().cloned());
+        names.push("mem".into());
+        if self.ans.is_some() {
+            names.push("ans".into());
+        }
+        names
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn variables() {
+        let mut calc = Calculator::new();
+        calc.set_var("x", Value::dec(4));
+        assert_eq!(calc.eval("x * x + 1"), Ok(Value::dec(17)));
+        calc.set_var("x", Value::dec(2));
+        assert_eq!(calc.eval("x << 3"), Ok(Value::dec(16)));
+        assert_eq!(
+            calc.eval("y"),
+            Err(CalcError::UnknownAtom("y".into(), None))
+        );
+    }
+
+    #[test]
+    fn accessors() {
+        let mut calc = Calculator::new();
+        assert_eq!(calc.ans(), None);
+        assert_eq!(calc.eval("x = 3 * 4"), Ok(Value::dec(12)));
+        assert_eq!(calc.eval("x² + 1"), Ok(Value::dec(145)));
+        assert_eq!(calc.eval("x = 1"), Ok(Value::dec(1)));
+        calc.set_var("a", Value::dec(2));
+        assert_eq!(calc.var("x"), Some(&Value::dec(1)));
+        assert_eq!(calc.var("y"), None);
+        assert_eq!(calc.ans(), Some(&Value::dec(1)));
+        let vars: Vec<_> = calc.vars().collect();
+        assert_eq!(vars, [("a", &Value::dec(2)), ("x", &Value::dec(1))]);
+    }
+
+    #[test]
+    fn step_limit() {
+        let input = vec!["1"; 100_000].join(" + ");
+        let mut calc = Calculator::new();
+        calc.set_step_limit(Some(1000));
+        assert_eq!(calc.eval(&input), Err(CalcError::BudgetExceeded));
+        assert_eq!(calc.eval("1 + 1"), Ok(Value::dec(2)));
+        calc.set_step_limit(None);
+        assert_eq!(calc.eval(&input), Ok(Value::dec(100_000)));
+    }
+
+    #[test]
+    fn step_limit_in_definitions() {
+        // Every definition calls the one before twice, so `f20 1` takes
+        // millions of steps inside the bodies.
+        let mut calc = Calculator::new();
+        calc.define("f0(x) = x + 1").unwrap();
+        for i in 1..=20 {
+            let source = format!("f{}(x) = f{}(f{}(x))", i, i - 1, i - 1);
+            calc.define(&source).unwrap();
+        }
+        calc.set_step_limit(Some(10_000));
+        assert_eq!(calc.eval("f20 1"), Err(CalcError::BudgetExceeded));
+        assert_eq!(calc.eval("f3 1"), Ok(Value::dec(9)));
+    }
+
+    #[test]
+    fn numeric_options() {
+        let input = "integrate(x ** 0.5, x, 0, 1)";
+        let mut calc = Calculator::new();
+        let value = calc.eval(input).unwrap().as_f64();
+        assert!((value - 2.0 / 3.0).abs() < 1e-9);
+        calc.set_numeric_options(NumericOptions {
+            tolerance: 1e-10,
+            max_subdivisions: 8,
+            ..NumericOptions::default()
+        });
+        assert_eq!(
+            calc.eval(input),
+            Err(CalcError::NoConvergence("integrate".into()))
+        );
+        calc.set_numeric_options(NumericOptions {
+            tolerance: 1e-3,
+            max_subdivisions: 8,
+            ..NumericOptions::default()
+        });
+        let value = calc.eval(input).unwrap().as_f64();
+        assert!((value - 2.0 / 3.0).abs() < 1e-2);
+    }
+
+    #[test]
+    fn iteration_limit() {
+        let input = "solve(x ** 2 - 2, x, 1000)";
+        let mut calc = Calculator::new();
+        let value = calc.eval(input).unwrap().as_f64();
+        assert!((value - 2f64.sqrt()).abs() < 1e-9);
+        calc.set_numeric_options(NumericOptions {
+            max_iterations: 5,
+            ..NumericOptions::default()
+        });
+        assert_eq!(
+            calc.eval(input),
+            Err(CalcError::NoConvergence("solve".into()))
+        );
+    }
+
+    #[test]
+    fn memory() {
+        let mut calc = Calculator::new();
+        assert_eq!(calc.memory_recall(), 0.0);
+        calc.memory_add(5.0);
+        calc.memory_add(2.5);
+        calc.memory_subtract(1.5);
+        calc.memory_add(4.0);
+        assert_eq!(calc.memory_recall(), 10.0);
+        assert_eq!(calc.eval("mem * 2").map(|v| v.as_f64()), Ok(20.0));
+        calc.memory_store(-3.0);
+        assert_eq!(calc.eval("mem + 1").map(|v| v.as_f64()), Ok(-2.0));
+        calc.memory_clear();
+        assert_eq!(calc.eval("mem").map(|v| v.as_f64()), Ok(0.0));
+
+        // The memory is separate from the variables.
+        calc.memory_store(7.0);
+        calc.set_var("x", Value::dec(1));
+        assert_eq!(calc.eval("x + mem").map(|v| v.as_f64()), Ok(8.0));
+        calc.set_var("mem", Value::dec(2));
+        assert_eq!(calc.eval("mem"), Ok(Value::dec(2)));
+        assert_eq!(calc.memory_recall(), 7.0);
+    }
+
+    #[test]
+    fn rpn_stack() {
+        let mut calc = Calculator::new();
+        assert_eq!(calc.push("3"), Ok(3.0));
+        assert_eq!(calc.push("4"), Ok(4.0));
+        assert_eq!(calc.apply("+"), Ok(7.0));
+        calc.push("2").unwrap();
+        assert_eq!(calc.apply("*"), Ok(14.0));
+        assert_eq!(calc.stack(), &[14.0]);
+
+        // The top value is the right operand.
+        calc.push("2 * 5").unwrap();
+        assert_eq!(calc.apply("-"), Ok(4.0));
+        calc.push("2").unwrap();
+        assert_eq!(calc.apply("**"), Ok(16.0));
+        assert_eq!(calc.apply("²"), Ok(256.0));
+        calc.dup().unwrap();
+        calc.push("-1").unwrap();
+        calc.swap().unwrap();
+        assert_eq!(calc.stack(), &[256.0, -1.0, 256.0]);
+        assert_eq!(calc.pop(), Ok(256.0));
+        assert_eq!(calc.apply("~"), Ok(0.0));
+        calc.drop_all();
+        assert_eq!(calc.stack(), &[] as &[f64]);
+
+        calc.set_var("x", Value::dec(10));
+        calc.define("avg(a, b) = (a + b) / 2").unwrap();
+        calc.rpn("x 0 sin  4 avg +").unwrap();
+        assert_eq!(calc.stack(), &[12.0]);
+        calc.rpn("clear 3 4 + 2 *").unwrap();
+        assert_eq!(calc.stack(), &[14.0]);
+    }
+
+    #[test]
+    fn rpn_errors() {
+        let mut calc = Calculator::new();
+        calc.push("1").unwrap();
+        assert_eq!(
+            calc.apply("+"),
+            Err(CalcError::InvalidArgument(
+                "'+' needs 2 value(s) on the stack, but it holds 1".into()
+            ))
+        );
+        assert_eq!(
+            calc.swap(),
+            Err(CalcError::InvalidArgument(
+                "'swap' needs 2 value(s) on the stack, but it holds 1".into()
+            ))
+        );
+        calc.push("0").unwrap();
+        assert_eq!(calc.apply("/"), Err(CalcError::DivideByZero));
+        assert_eq!(calc.stack(), &[1.0, 0.0]);
+        assert!(calc.apply("(").is_err());
+        assert!(calc.apply("pi").is_err());
+        assert_eq!(calc.rpn("pop pop pop").map_err(|e| e.to_string()), Err(
+            "invalid argument: 'pop' needs 1 value(s) on the stack, but it \
+             holds 0".into()
+        ));
+        assert_eq!(calc.stack(), &[] as &[f64]);
+        assert!(calc.dup().is_err());
+    }
+
+    #[test]
+    fn transcript() {
+        let mut calc = Calculator::new();
+        for input in &["x = 2", " x * 3 ", "1 / 0", "+ 1", "y", "`` + 1"] {
+            let _ = calc.eval(input);
+        }
+        assert_eq!(calc.transcript().len(), 6);
+        assert_eq!(
+            calc.transcript()[1],
+            TranscriptEntry {
+                input: "x * 3".into(),
+                outcome: Ok("6".into()),
+            }
+        );
+
+        let mut text = Vec::new();
+        calc.export_transcript(&mut text, TranscriptFormat::Text)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(text).unwrap(),
+            "> x = 2\n\
+             2\n\
+             > x * 3\n\
+             6\n\
+             > 1 / 0\n\
+             error: attempted to divide by zero\n\
+             > + 1\n\
+             7\n\
+             > y\n\
+             error: unknown variable or function 'y'\n\
+             > `` + 1\n\
+             error: unrecognized token: ``\n"
+        );
+        let mut markdown = Vec::new();
+        calc.export_transcript(&mut markdown, TranscriptFormat::Markdown)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(markdown).unwrap(),
+            "- `x = 2` = 2\n\
+             - `x * 3` = 6\n\
+             - `1 / 0` **error:** attempted to divide by zero\n\
+             - `+ 1` = 7\n\
+             - `y` **error:** unknown variable or function 'y'\n\
+             - ``` `` + 1 ``` **error:** unrecognized token: ``\n"
+        );
+    }
+
+    #[test]
+    fn transcript_limit() {
+        let mut calc = Calculator::new();
+        calc.set_transcript_limit(Some(2));
+        for input in &["1", "2", "3"] {
+            calc.eval(input).unwrap();
+        }
+        let inputs: Vec<&str> =
+            calc.transcript().iter().map(|e| e.input.as_str()).collect();
+        assert_eq!(inputs, vec!["2", "3"]);
+        calc.set_transcript_limit(Some(0));
+        assert_eq!(calc.transcript(), &[]);
+        calc.eval("4").unwrap();
+        assert_eq!(calc.transcript(), &[]);
+        calc.set_transcript_limit(None);
+        calc.eval("5").unwrap();
+        assert_eq!(calc.transcript().len(), 1);
+    }
+
+    #[test]
+    fn cache_sees_memory() {
+        let mut calc = Calculator::new().with_cache(4);
+        calc.memory_store(1.0);
+        assert_eq!(calc.eval("mem").map(|v| v.as_f64()), Ok(1.0));
+        calc.memory_add(1.0);
+        assert_eq!(calc.eval("mem").map(|v| v.as_f64()), Ok(2.0));
+    }
+
+    #[test]
+    fn assignments() {
+        let mut calc = Calculator::new();
+        assert_eq!(calc.eval("x = 3 * 4"), Ok(Value::dec(12)));
+        assert_eq!(calc.eval("y = x + 1"), Ok(Value::dec(13)));
+        assert_eq!(calc.eval("x * y"), Ok(Value::dec(156)));
+        assert_eq!(calc.eval("z = 1 / 0"), Err(CalcError::DivideByZero));
+        assert_eq!(
+            calc.eval("z"),
+            Err(CalcError::UnknownAtom("z".into(), None))
+        );
+        assert!(calc.eval("2 = 3").is_err());
+        calc.eval("sin = 3").unwrap();
+        assert!(calc.eval("sin(0)").is_err());
+    }
+
+    #[test]
+    fn undo_redo() {
+        let mut calc = Calculator::new();
+        let x = |calc: &mut Calculator| calc.eval("x").ok();
+        assert!(!calc.undo());
+        calc.eval("x = 1").unwrap();
+        calc.eval("x = 2").unwrap();
+        assert_eq!(x(&mut calc), Some(Value::dec(2)));
+        // A failed evaluation changes nothing, so there is nothing to undo.
+        assert!(calc.eval("x = y").is_err());
+        assert!(calc.undo());
+        assert_eq!(x(&mut calc), Some(Value::dec(1)));
+        assert!(calc.undo());
+        assert_eq!(x(&mut calc), None);
+        assert!(!calc.undo());
+        assert!(calc.redo());
+        assert_eq!(x(&mut calc), Some(Value::dec(1)));
+        assert!(calc.redo());
+        assert_eq!(x(&mut calc), Some(Value::dec(2)));
+        assert!(!calc.redo());
+
+        // A new change drops the changes that were undone.
+        calc.undo();
+        calc.memory_store(5.0);
+        assert!(!calc.redo());
+        assert_eq!(x(&mut calc), Some(Value::dec(1)));
+        assert!(calc.undo());
+        assert_eq!(calc.memory_recall(), 0.0);
+        assert_eq!(x(&mut calc), Some(Value::dec(1)));
+    }
+
+    #[test]
+    fn history_depth() {
+        let mut calc = Calculator::new();
+        calc.set_history_depth(2);
+        for i in 1..=4 {
+            calc.set_var("x", Value::dec(i));
+        }
+        assert!(calc.undo());
+        assert!(calc.undo());
+        assert!(!calc.undo());
+        assert_eq!(calc.eval("x"), Ok(Value::dec(2)));
+        calc.set_history_depth(0);
+        calc.set_var("x", Value::dec(5));
+        assert!(!calc.undo());
+    }
+
+    #[test]
+    fn definitions() {
+        let mut calc = Calculator::new().with_cache(4);
+        assert_eq!(
+            calc.eval("sq 3"),
+            Err(CalcError::UnknownAtom("sq".into(), None))
+        );
+        calc.define("sq(x) = x * x").unwrap();
+        calc.define("norm(a, b) = sq a + sq b").unwrap();
+        assert_eq!(calc.eval("sq 3"), Ok(Value::dec(9)));
+        assert_eq!(calc.eval("norm 3 4"), Ok(Value::dec(25)));
+        calc.set_var("sq", Value::dec(2));
+        assert_eq!(calc.eval("sq"), Ok(Value::dec(2)));
+        assert_eq!(
+            calc.definitions(),
+            &["sq(x) = x * x", "norm(a, b) = sq a + sq b"]
+        );
+
+        assert_eq!(calc.eval("k(x) = x + 1"), Ok(Value::dec(0)));
+        assert_eq!(calc.eval("k(2)"), Ok(Value::dec(3)));
+        assert_eq!(calc.eval("k(x) = x * 10"), Ok(Value::dec(0)));
+        assert_eq!(calc.eval("k(k(2))"), Ok(Value::dec(200)));
+        assert!(calc.eval("j(x) = j(x)").is_err());
+        assert_eq!(calc.definitions().len(), 4);
+    }
+
+    #[test]
+    fn max_magnitude() {
+        let mut calc = Calculator::new();
+        calc.set_max_magnitude(Some(1e15));
+        let exceeded =
+            |value| Err(CalcError::MagnitudeExceeded { value, limit: 1e15 });
+        assert_eq!(calc.eval("10 ** 15").map(|v| v.as_f64()), Ok(1e15));
+        assert_eq!(calc.eval("0 - 10 ** 15").map(|v| v.as_f64()), Ok(-1e15));
+        assert_eq!(calc.eval("10 ** 15 + 1"), exceeded(1e15 + 1.0));
+        assert_eq!(calc.eval("0 - 10 ** 15 - 1"), exceeded(-1e15 - 1.0));
+        assert_eq!(calc.eval("10 ** 100"), exceeded(1e100));
+        // Intermediate results are limited as well as final ones.
+        assert_eq!(calc.eval("10 ** 16 / 100"), exceeded(1e16));
+        assert_eq!(calc.eval("sum(i, 1, 3, 10 ** 16 * i) * 0"), exceeded(1e16));
+
+        calc.set_max_magnitude(None);
+        assert_eq!(
+            calc.eval("10 ** 16 / 100"),
+            Ok(Value::dec(100_000_000_000_000u64))
+        );
+    }
+
+    #[test]
+    fn continuation() {
+        let mut calc = Calculator::new();
+        assert_eq!(
+            calc.eval("* 2"),
+            Err(CalcError::InvalidArgument(
+                "no previous result for '* 2' to continue from".into()
+            ))
+        );
+        assert!(matches!(
+            calc.eval("ans"),
+            Err(CalcError::UnknownAtom(ref name, _)) if name == "ans"
+        ));
+        // Without a previous result, a leading plus is a unary one.
+        assert_eq!(calc.eval("+5"), Ok(Value::dec(5)));
+        assert_eq!(calc.eval("-10"), Ok(Value::dec(-10)));
+        assert_eq!(calc.eval("+5"), Ok(Value::dec(-5)));
+
+        assert_eq!(calc.eval("5 + 3"), Ok(Value::dec(8)));
+        assert_eq!(calc.eval("* 2"), Ok(Value::dec(16)));
+        assert_eq!(calc.eval("/ 4"), Ok(Value::dec(4)));
+        assert_eq!(calc.eval("+ 1"), Ok(Value::dec(5)));
+        assert_eq!(calc.eval("** 2"), Ok(Value::dec(25)));
+        assert_eq!(calc.eval("% 7"), Ok(Value::dec(4)));
+        assert_eq!(calc.eval("<< 2 | 1"), Ok(Value::dec(17)));
+        assert_eq!(calc.eval("ans - 7"), Ok(Value::dec(10)));
+        // A leading minus negates rather than subtracts.
+        assert_eq!(calc.eval("-5"), Ok(Value::dec(-5)));
+        assert_eq!(calc.eval("- 1"), Ok(Value::dec(-1)));
+
+        // Failures leave the previous result in place.
+        assert!(calc.eval("* nothing").is_err());
+        assert_eq!(calc.eval("* 3"), Ok(Value::dec(-3)));
+        assert_eq!(calc.eval("x = ans * 2"), Ok(Value::dec(-6)));
+        assert_eq!(calc.eval("* x"), Ok(Value::dec(36)));
+        calc.set_var("ans", Value::dec(1));
+        assert_eq!(calc.eval("+ 1"), Ok(Value::dec(2)));
+    }
+
+    #[test]
+    fn cache_follows_ans() {
+        let mut calc = Calculator::new().with_cache(8);
+        assert_eq!(calc.eval("2"), Ok(Value::dec(2)));
+        assert_eq!(calc.eval("* 2"), Ok(Value::dec(4)));
+        assert_eq!(calc.eval("* 2"), Ok(Value::dec(8)));
+        assert_eq!(calc.eval("* 2"), Ok(Value::dec(16)));
+    }
+
+    #[test]
+    fn lenient_parens() {
+        let mut calc = Calculator::new().with_cache(8);
+        assert_eq!(
+            calc.eval("((5 + 3) * 2"),
+            Err(CalcError::UnmatchedParenthesis)
+        );
+        assert_eq!(
+            calc.eval("2 * (1 + (3 - (1"),
+            Err(CalcError::UnmatchedParenthesis)
+        );
+        assert_eq!(calc.eval("(1+2))"), Err(CalcError::UnmatchedParenthesis));
+
+        calc.set_lenient_parens(true);
+        assert_eq!(calc.eval("((5 + 3) * 2"), Ok(Value::dec(16)));
+        assert_eq!(calc.warnings(), ["auto-closed 1 parenthesis"]);
+        assert_eq!(calc.eval("((5 + 3) * 2"), Ok(Value::dec(16)));
+        assert_eq!(calc.warnings(), ["auto-closed 1 parenthesis"]);
+        assert_eq!(calc.eval("2 * (1 + (3 - (1"), Ok(Value::dec(6)));
+        assert_eq!(calc.warnings(), ["auto-closed 3 parentheses"]);
+        assert_eq!(calc.eval("(1 + 2)"), Ok(Value::dec(3)));
+        assert!(calc.warnings().is_empty());
+        // Only missing parentheses are implied.
+        assert!(calc.eval("1 + ) * (2").is_err());
+        assert!(calc.eval(") + (1").is_err());
+        assert!(calc.eval("(1 +").is_err());
+        assert_eq!(calc.eval("(1+2))"), Err(CalcError::UnmatchedParenthesis));
+
+        let mut session = Vec::new();
+        calc.save_session(&mut session).unwrap();
+        let mut loaded = Calculator::load_session(&session[..]).unwrap();
+        assert_eq!(loaded.eval("(2 * (3"), Ok(Value::dec(6)));
+    }
+
+    #[test]
+    fn compensated_sum() {
+        let mut calc = Calculator::new().with_cache(8);
+        calc.set_backend(NumberBackend::Float);
+        // 1 + 100000 * 0.00000001 is 1.001, which each `+` misses by a little.
+        let input = format!("1{}", " + 0.00000001".repeat(100_000));
+        let naive = calc.eval(&input).unwrap().as_f64();
+        calc.set_compensated_sum(true);
+        let compensated = calc.eval(&input).unwrap().as_f64();
+        assert!((naive - 1.001).abs() > 1e-12, "{}", naive);
+        assert_eq!(compensated, 1.001);
+        let input = format!("1{}", " - 0.00000001".repeat(100_000));
+        assert_eq!(calc.eval(&input).map(|v| v.as_f64()), Ok(0.999));
+
+        // Short sums come out as they always did.
+        for input in
+            &["0.1 + 0.2", "10000000000000000 + 1", "2 * 0.1 - 0.7 * 3"]
+        {
+            let compensated = calc.eval(input);
+            calc.set_compensated_sum(false);
+            assert_eq!(calc.eval(input), compensated, "{}", input);
+            calc.set_compensated_sum(true);
+        }
+        // Longer ones may round differently, as they should.
+        assert_eq!(
+            calc.eval("10000000000000000 + 1 - 10000000000000000"),
+            Ok(Value::dec(1))
+        );
+        assert_eq!(calc.eval("(0.1 + 0.2 + 0.3) * 10"), Ok(Value::dec(6)));
+
+        // `sum` compensates either way.
+        calc.set_compensated_sum(false);
+        assert_eq!(
+            calc.eval("sum(i, 1, 100000, 0.1)").map(|v| v.as_f64()),
+            Ok(10000.0)
+        );
+
+        let mut session = Vec::new();
+        calc.set_compensated_sum(true);
+        calc.save_session(&mut session).unwrap();
+        let loaded = Calculator::load_session(&session[..]).unwrap();
+        assert!(loaded.compensated_sum);
+    }
+
+    #[test]
+    fn precedence_overrides() {
+        let mut calc = Calculator::new().with_cache(8);
+        assert_eq!(calc.eval("2 + 3 * 4"), Ok(Value::dec(14)));
+        assert_eq!(calc.eval("1 | 2 * 4"), Ok(Value::dec(9)));
+        assert_eq!(calc.eval("2 ** 3 ** 2"), Ok(Value::dec(512)));
+
+        calc.set_precedence(Token::Plus, 3).unwrap();
+        assert_eq!(calc.eval("2 + 3 * 4"), Ok(Value::dec(20)));
+        assert_eq!(calc.eval("2 * 3 + 4"), Ok(Value::dec(10)));
+        assert_eq!(calc.precedence(BinaryOp::Add), 3);
+        assert_eq!(calc.precedence(BinaryOp::Sub), 2);
+        calc.set_precedence(Token::BitWiseOr, 4).unwrap();
+        assert_eq!(calc.eval("1 | 2 * 4"), Ok(Value::dec(12)));
+        assert_eq!(calc.eval("4 * 1 | 2"), Ok(Value::dec(12)));
+        calc.set_assoc(Token::Exponent, Assoc::Left).unwrap();
+        assert_eq!(calc.eval("2 ** 3 ** 2"), Ok(Value::dec(64)));
+        assert_eq!(calc.assoc(BinaryOp::Pow), Assoc::Left);
+        calc.set_assoc(Token::Minus, Assoc::Right).unwrap();
+        assert_eq!(calc.eval("8 - 4 - 2"), Ok(Value::dec(6)));
+
+        let mut session = Vec::new();
+        calc.save_session(&mut session).unwrap();
+        let mut loaded = Calculator::load_session(&session[..]).unwrap();
+        assert_eq!(loaded.eval("2 + 3 * 4"), Ok(Value::dec(20)));
+        assert_eq!(loaded.eval("2 ** 3 ** 2"), Ok(Value::dec(64)));
+
+        let mut calc = Calculator::new();
+        assert_eq!(
+            calc.set_precedence(Token::Plus, 5),
+            Err(CalcError::InvalidArgument(
+                "operator precedence must be between 1 and 4, got 5".into()
+            ))
+        );
+        assert_eq!(
+            calc.set_assoc(Token::OpenParen, Assoc::Left),
+            Err(CalcError::InvalidArgument(
+                "OpenParen is not a binary operator".into()
+            ))
+        );
+        assert!(calc.set_precedence(Token::Square, 4).is_err());
+        assert!(calc
+            .set_precedence(Token::Operator("><".into()), 1)
+            .is_err());
+        assert_eq!(calc.eval("2 + 3 * 4"), Ok(Value::dec(14)));
+    }
+
+    #[test]
+    fn integer_mode() {
+        let mut calc = Calculator::new();
+        calc.set_integer_mode(IntegerMode::Strict);
+        let not_an_integer = |op: &str, value: &str| {
+            Err(CalcError::NotAnInteger {
+                op: op.into(),
+                value: value.into(),
+            })
+        };
+        assert_eq!(calc.eval("10 / 2"), Ok(Value::dec(5)));
+        assert_eq!(calc.eval("10 / 4"), not_an_integer("/", "2.5"));
+        assert_eq!(calc.eval("2 ** 0.5 * 0"), not_an_integer("literal", "0.5"));
+        assert_eq!(calc.eval("4.0 * 2").map(|v| v.as_f64()), Ok(8.0));
+        assert!(matches!(
+            calc.eval("pi"),
+            Err(CalcError::NotAnInteger { ref op, .. }) if op == "pi"
+        ));
+        assert!(calc.eval("2 ** -1").is_err());
+        assert_eq!(calc.eval("2 ** 10"), Ok(Value::dec(1024)));
+        assert_eq!(
+            calc.eval("sum(i, 1, 4, i / 2)"),
+            not_an_integer("/", "0.5")
+        );
+        for input in &["0xF0 & 0x3C | 1", "~5 ^ 3", "1 << 4 >> 2"] {
+            assert_eq!(calc.eval(input), ::eval(input));
+        }
+
+        calc.set_integer_mode(IntegerMode::FloorDivision);
+        assert_eq!(calc.eval("7 / 2"), Ok(Value::dec(3)));
+        assert_eq!(calc.eval("-7 / 2"), Ok(Value::dec(-4)));
+        assert_eq!(calc.eval("7 / 0"), Err(CalcError::DivideByZero));
+        assert_eq!(calc.eval("7.5 / 2"), not_an_integer("literal", "7.5"));
+        let mut saved = Vec::new();
+        calc.save_session(&mut saved).unwrap();
+        let loaded = Calculator::load_session(&saved[..]).unwrap();
+        assert_eq!(loaded.integer_mode, IntegerMode::FloorDivision);
+
+        calc.set_integer_mode(IntegerMode::Any);
+        assert_eq!(calc.eval("10 / 4").map(|v| v.as_f64()), Ok(2.5));
+    }
+
+    #[test]
+    fn sessions() {
+        let mut calc = Calculator::new().with_cache(8);
+        calc.set_var("x", Value::dec(3));
+        calc.set_var("rate", Value::from_f64(0.25));
+        calc.define("sq(x) = x * x").unwrap();
+        calc.memory_store(1.5);
+        calc.set_backend(NumberBackend::Float);
+        calc.set_right_shift(RightShift::Logical(8));
+        calc.set_step_limit(Some(1000));
+        calc.set_max_magnitude(Some(1e15));
+        calc.set_timeout(Some(Duration::from_millis(250)));
+        calc.set_numeric_options(NumericOptions {
+            tolerance: 1e-6,
+            ..NumericOptions::default()
+        });
+        calc.set_history_depth(3);
+
+        let mut saved = Vec::new();
+        calc.save_session(&mut saved).unwrap();
+        let mut loaded = Calculator::load_session(&saved[..]).unwrap();
+        assert_eq!(loaded.vars, calc.vars);
+        assert_eq!(loaded.definitions(), &["sq(x) = x * x"]);
+        assert_eq!(loaded.memory_recall(), 1.5);
+        assert_eq!(loaded.backend, NumberBackend::Float);
+        assert_eq!(loaded.right_shift, RightShift::Logical(8));
+        assert_eq!(loaded.step_limit, Some(1000));
+        assert_eq!(loaded.max_magnitude, Some(1e15));
+        assert_eq!(loaded.timeout, Some(Duration::from_millis(250)));
+        assert_eq!(loaded.numeric_options, calc.numeric_options);
+        assert_eq!(loaded.history.depth, 3);
+        assert_eq!(loaded.fingerprint(), calc.fingerprint());
+        assert_eq!(loaded.eval("sq x + mem").map(|v| v.as_f64()), Ok(10.5));
+        assert_eq!(loaded.eval("-8 >> 1").map(|v| v.as_f64()), Ok(124.0));
+        assert_eq!(loaded.cache_stats().misses, 2);
+
+        let mut resaved = Vec::new();
+        loaded.save_session(&mut resaved).unwrap();
+        assert_eq!(resaved, saved);
+    }
+
+    #[test]
+    fn bad_sessions() {
+        let load = |text: &str| Calculator::load_session(text.as_bytes());
+        let mut saved = Vec::new();
+        Calculator::new().save_session(&mut saved).unwrap();
+        let saved = String::from_utf8(saved).unwrap();
+        assert!(load(&saved).is_ok());
+
+        let invalid = |result: Result<Calculator, CalcError>| match result {
+            Err(CalcError::InvalidJson(_)) => {}
+            Err(e) => panic!("expected invalid JSON, got {:?}", e),
+            Ok(_) => panic!("expected invalid JSON, got a calculator"),
+        };
+        invalid(load(&saved[..saved.len() / 2]));
+        invalid(load(""));
+        invalid(load(&saved.replace("\"version\": 1", "\"version\": 2")));
+        invalid(load(&saved.replace("\"memory\"", "\"memo\"")));
+        invalid(load(&saved.replace("\"decimal\"", "\"binary\"")));
+        invalid(load(
+            &saved.replace("\"vars\": {}", "\"vars\": {\"x\": \"1\"}"),
+        ));
+        let broken =
+            saved.replace("\"functions\": []", "\"functions\": [\"f(x) = \"]");
+        assert_eq!(load(&broken).err(), Some(CalcError::UnexpectedEndOfInput));
+    }
+
+    #[test]
+    fn step_limit_bounds_sums() {
+        let mut calc = Calculator::new();
+        calc.set_step_limit(Some(10_000));
+        assert_eq!(calc.eval("sum(i, 1, 100, i)"), Ok(Value::dec(5050)));
+        assert_eq!(
+            calc.eval("sum(i, 1, 100000, i)"),
+            Err(CalcError::BudgetExceeded)
+        );
+    }
+
+    #[test]
+    fn max_depth() {
+        let input = format!("{}1{}", "(".repeat(100), ")".repeat(100));
+        let mut calc = Calculator::new();
+        assert_eq!(calc.eval(&input), Ok(Value::dec(1)));
+        calc.set_max_depth(Some(50));
+        assert_eq!(calc.eval(&input), Err(CalcError::NestingTooDeep));
+        assert_eq!(calc.eval("(1 + 1)"), Ok(Value::dec(2)));
+    }
+
+    #[test]
+    fn right_shift() {
+        let mut calc = Calculator::new();
+        assert_eq!(calc.eval("-8 >> 1"), Ok(Value::dec(-4)));
+        calc.set_right_shift(RightShift::Logical(8));
+        assert_eq!(calc.eval("-8 >> 1"), Ok(Value::dec(124)));
+        assert_eq!(calc.eval("255 >> 4"), Ok(Value::dec(15)));
+        assert!(calc.eval("-200 >> 1").is_err());
+        calc.set_right_shift(RightShift::Logical(64));
+        assert_eq!(calc.eval("-8 >> 1"), Ok(Value::dec(i64::MAX - 3)));
+        assert_eq!(
+            calc.eval("-8 >> 0").unwrap().to_string(),
+            "18446744073709551608"
+        );
+
+        calc.set_backend(NumberBackend::Float);
+        assert_eq!(calc.eval("-8 >> 60"), Ok(Value::dec(15)));
+        calc.set_right_shift(RightShift::Logical(8));
+        assert_eq!(calc.eval("-8 >> 1"), Ok(Value::dec(124)));
+        calc.set_right_shift(RightShift::Arithmetic);
+        assert_eq!(calc.eval("-8 >> 1"), Ok(Value::dec(-4)));
+
+        for &shift in &[
+            RightShift::Arithmetic,
+            RightShift::Logical(8),
+            RightShift::Logical(64),
+        ] {
+            calc.set_right_shift(shift);
+            assert_eq!(calc.eval("100 >> 2"), Ok(Value::dec(25)));
+            assert_eq!(
+                calc.eval("100 >> -1"),
+                Err(CalcError::InvalidArgument(
+                    "the amount of >> must not be negative, got -1".into()
+                ))
+            );
+        }
+    }
+
+    #[test]
+    fn timeout() {
+        let input = vec!["1"; 100_000].join(" + ");
+        let mut calc = Calculator::new();
+        calc.set_timeout(Some(Duration::new(0, 0)));
+        assert_eq!(calc.eval(&input), Err(CalcError::BudgetExceeded));
+        calc.set_timeout(Some(Duration::from_secs(60)));
+        assert_eq!(calc.eval("2 * 3"), Ok(Value::dec(6)));
+    }
+
+    #[test]
+    fn tokenizer_options() {
+        let mut calc = Calculator::new();
+        calc.set_tokenizer_options(TokenizerOptions {
+            max_input_len: Some(3),
+            ..TokenizerOptions::default()
+        });
+        assert_eq!(calc.eval("1+1"), Ok(Value::dec(2)));
+        assert_eq!(
+            calc.eval("1 + 1"),
+            Err(CalcError::InputTooLarge {
+                limit: 3,
+                actual: 5,
+            })
+        );
+
+        calc.set_tokenizer_options(TokenizerOptions {
+            decimal_comma: true,
+            thousands_point: true,
+            ..TokenizerOptions::default()
+        });
+        assert_eq!(calc.eval("1.000,5 * 2").map(|v| v.as_f64()), Ok(2001.0));
+        // A comma between digits belongs to the number.
+        assert_eq!(
+            calc.eval("1,"),
+            Err(CalcError::UnexpectedToken("Comma".into(), "operator"))
+        );
+        assert_eq!(
+            calc.eval("cond(1,2,3)"),
+            Err(CalcError::WrongArity {
+                atom: "cond".into(),
+                expected: 3,
+                actual: 2,
+            })
+        );
+        assert_eq!(calc.eval("cond(1, 2, 3)"), Ok(Value::dec(2)));
+        let mut saved = Vec::new();
+        calc.save_session(&mut saved).unwrap();
+        let loaded = Calculator::load_session(&saved[..]).unwrap();
+        assert_eq!(loaded.tokenizer_options, calc.tokenizer_options);
+    }
+
+    #[test]
+    fn backends() {
+        let mut calc = Calculator::new();
+        let sum = calc.eval("0.1 + 0.2").unwrap();
+        assert_eq!(sum.to_string(), "0.3");
+        assert_eq!(calc.eval("19.99 % 5").unwrap().to_string(), "4.99");
+
+        calc.set_backend(NumberBackend::Float);
+        assert_eq!(calc.eval("0.1 + 0.2"), Ok(Value::from_f64(0.1 + 0.2)));
+        assert_eq!(calc.eval("7 / 2"), Ok(Value::from_f64(3.5)));
+        assert_eq!(calc.eval("6 / 2"), Ok(Value::dec(3)));
+        assert!(calc.warnings().is_empty());
+        // Literals with more digits than an `f64` holds are rounded.
+        assert_eq!(
+            calc.eval("9007199254740993 - 1"),
+            Ok(Value::dec(9_007_199_254_740_991u64))
+        );
+        assert_eq!(
+            calc.warnings(),
+            ["9007199254740993 is rounded to 9007199254740992 as f64"]
+        );
+        assert!(calc.eval("0.1 + 1e300 + 9007199254740992").is_ok());
+        assert!(calc.warnings().is_empty());
+        assert!(calc.eval("0.12345678901234567").is_ok());
+        assert_eq!(calc.warnings().len(), 1);
+    }
+
+    #[test]
+    fn cache_hits() {
+        let mut calc = Calculator::new().with_cache(4);
+        assert_eq!(calc.eval("2 * 3"), Ok(Value::dec(6)));
+        assert_eq!(calc.eval("2 * 3"), Ok(Value::dec(6)));
+        assert_eq!(calc.eval("1 / 0"), Err(CalcError::DivideByZero));
+        assert_eq!(calc.eval("1 / 0"), Err(CalcError::DivideByZero));
+        assert_eq!(calc.cache_stats(), CacheStats { hits: 2, misses: 2 });
+        assert_eq!(Calculator::new().cache_stats(), CacheStats::default());
+    }
+
+    #[test]
+    fn cache_invalidation() {
+        let mut calc = Calculator::new().with_cache(4);
+        calc.set_var("x", Value::dec(2));
+        assert_eq!(calc.eval("x + 1"), Ok(Value::dec(3)));
+        calc.set_var("x", Value::dec(5));
+        assert_eq!(calc.eval("x + 1"), Ok(Value::dec(6)));
+        calc.set_var("x", Value::from_f64(2.0));
+        assert_eq!(calc.eval("x + 1"), Ok(Value::from_f64(3.0)));
+        calc.set_backend(NumberBackend::Float);
+        calc.set_var("x", Value::dec(5));
+        assert_eq!(calc.eval("x / 2"), Ok(Value::from_f64(2.5)));
+        calc.set_backend(NumberBackend::Decimal);
+        assert_eq!(calc.eval("x + 1"), Ok(Value::dec(6)));
+        assert_eq!(calc.cache_stats(), CacheStats { hits: 1, misses: 4 });
+
+        let input = vec!["1"; 1000].join(" + ");
+        calc.set_step_limit(Some(100));
+        assert_eq!(calc.eval(&input), Err(CalcError::BudgetExceeded));
+        calc.set_step_limit(None);
+        assert_eq!(calc.eval(&input), Ok(Value::dec(1000)));
+    }
+
+    #[test]
+    fn cache_eviction() {
+        let mut calc = Calculator::new().with_cache(2);
+        calc.eval("1").unwrap();
+        calc.eval("2").unwrap();
+        calc.eval("1").unwrap();
+        calc.eval("3").unwrap();
+        calc.eval("1").unwrap();
+        assert_eq!(calc.cache_stats(), CacheStats { hits: 2, misses: 3 });
+        calc.eval("2").unwrap();
+        assert_eq!(calc.cache_stats(), CacheStats { hits: 2, misses: 4 });
+    }
+}
@@ -0,0 +1,459 @@
+//! Complex numbers, such as `(2 + 3i) * (1 - i)`.
+//!
+//! `i` and `j` stand for the imaginary unit, and a number directly followed
+//! by either is an imaginary literal. A literal binds like a parenthesized
+//! product, so `2 ** 3i` raises `2` to the power of `3i`, and `3i²` is
+//! `-9`. Numbers are pairs of `f64`s.
+
+use error::{CalcError, PartialComp};
+use number::CalcNum;
+use parse::{DefaultEnvironment, Environment};
+use std::cmp::Ordering;
+use std::fmt;
+use token::Token;
+use value::Value;
+
+/// The names of the imaginary unit.
+const UNITS: [&str; 2] = ["i", "j"];
+
+/// The functions of the `DefaultEnvironment` that take complex arguments.
+const FUNCTIONS: [&str; 8] =
+    ["sqrt", "exp", "ln", "log", "sin", "cos", "tan", "abs"];
+
+/// The functions that only `ComplexEnvironment` has.
+const PARTS: [&str; 4] = ["re", "im", "arg", "conj"];
+
+/// A complex number, produced by `eval_complex`.
+///
+/// `Display` writes the real and imaginary parts like `f64`s, as in
+/// `5 + i` or `-2.5i`, leaving out a part that is zero.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Complex {
+    re: f64,
+    im: f64,
+}
+
+impl Complex {
+    pub fn new(re: f64, im: f64) -> Self {
+        Complex { re, im }
+    }
+
+    /// A number without an imaginary part.
+    pub fn real(re: f64) -> Self {
+        Complex::new(re, 0.0)
+    }
+
+    pub fn re(&self) -> f64 {
+        self.re
+    }
+
+    pub fn im(&self) -> f64 {
+        self.im
+    }
+
+    /// The absolute value, or modulus.
+    pub fn abs(&self) -> f64 {
+        self.re.hypot(self.im)
+    }
+
+    /// The angle to the positive real axis, in radians from `-pi` to `pi`.
+    pub fn arg(&self) -> f64 {
+        self.im.atan2(self.re)
+    }
+
+    pub fn conj(&self) -> Self {
+        Complex::new(self.re, -self.im)
+    }
+
+    fn is_real(&self) -> bool {
+        self.im == 0.0
+    }
+
+    fn is_zero(&self) -> bool {
+        self.re == 0.0 && self.im == 0.0
+    }
+
+    fn exp(self) -> Self {
+        let r = self.re.exp();
+        Complex::new(r * self.im.cos(), r * self.im.sin())
+    }
+
+    /// The principal logarithm, whose imaginary part is `arg`.
+    fn ln(self) -> Self {
+        Complex::new(self.abs().ln(), self.arg())
+    }
+
+    /// The principal square root, whose real part is not negative. It is
+    /// computed from the modulus rather than as a power, so that
+    /// `sqrt(-1)` is exactly `i`.
+    fn sqrt(self) -> Self {
+        let r = self.abs();
+        let re = ((r + self.re) / 2.0).sqrt();
+        let im = ((r - self.re) / 2.0).sqrt();
+        Complex::new(re, if self.im < 0.0 { -im } else { im })
+    }
+
+    fn sin(self) -> Self {
+        Complex::new(
+            self.re.sin() * self.im.cosh(),
+            self.re.cos() * self.im.sinh(),
+        )
+    }
+
+    fn cos(self) -> Self {
+        Complex::new(
+            self.re.cos() * self.im.cosh(),
+            -self.re.sin() * self.im.sinh(),
+        )
+    }
+
+    /// Raises the number to an integer power by repeated squaring, which
+    /// keeps `(1 + i) ** 2` exact.
+    fn powi(self, n: i64) -> Result<Self, CalcError> {
+        let mut result = Complex::real(1.0);
+        let mut base = self;
+        let mut exp = n.unsigned_abs();
+        while exp > 0 {
+            if exp & 1 == 1 {
+                result = result.mul(base)?;
+            }
+            base = base.mul(base)?;
+            exp >>= 1;
+        }
+        if n < 0 {
+            Complex::real(1.0).div(result)
+        } else {
+            Ok(result)
+        }
+    }
+
+    /// Applies one of `FUNCTIONS` or `PARTS`.
+    fn apply(name: &str, z: Self) -> Result<Self, CalcError> {
+        Ok(match name {
+            "sqrt" => z.sqrt(),
+            "exp" => z.exp(),
+            "ln" => z.ln(),
+            "log" => z.ln().div(Complex::real(10f64.ln()))?,
+            "sin" => z.sin(),
+            "cos" => z.cos(),
+            "tan" => z.sin().div(z.cos())?,
+            "abs" => Complex::real(z.abs()),
+            "re" => Complex::real(z.re),
+            "im" => Complex::real(z.im),
+            "arg" => Complex::real(z.arg()),
+            _ => z.conj(),
+        })
+    }
+}
+
+impl fmt::Display for Complex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_real() {
+            return write!(f, "{}", self.re);
+        }
+        let im = self.im.abs();
+        let sign = if self.im < 0.0 { "-" } else { "+" };
+        match (self.re == 0.0, im == 1.0) {
+            (true, true) if sign == "-" => write!(f, "-i"),
+            (true, true) => write!(f, "i"),
+            (true, false) => write!(f, "{}i", self.im),
+            (false, true) => write!(f, "{} {} i", self.re, sign),
+            (false, false) => write!(f, "{} {} {}i", self.re, sign, im),
+        }
+    }
+}
+
+impl CalcNum for Complex {
+    fn from_value(value: &Value) -> Result<Self, CalcError> {
+        f64::from_value(value).map(Complex::real)
+    }
+
+    fn from_literal(value: &Value) -> Result<Self, CalcError> {
+        f64::from_literal(value).map(Complex::real)
+    }
+
+    fn literal_warning(value: &Value) -> Option<String> {
+        f64::literal_warning(value)
+    }
+
+    /// Only the real part makes it into the `Value`.
+    fn to_value(&self) -> Value {
+        self.re.to_value()
+    }
+
+    /// Computes the imaginary unit, the functions that take complex
+    /// arguments and those of `PARTS` itself, and leaves the others to
+    /// the `DefaultEnvironment`, which only takes real arguments.
+    fn call<F>(
+        name: &str,
+        args: Vec<Self>,
+        mut resolve: F,
+    ) -> Result<Self, CalcError>
+    where
+        F: FnMut(&[Value]) -> Result<Value, CalcError>,
+    {
+        if UNITS.contains(&name) && args.is_empty() {
+            return Ok(Complex::new(0.0, 1.0));
+        }
+        if args.len() == 1
+            && (FUNCTIONS.contains(&name) || PARTS.contains(&name))
+        {
+            return Complex::apply(name, args[0]);
+        }
+        if let Some(arg) = args.iter().find(|arg| !arg.is_real()) {
+            return Err(CalcError::InvalidArgument(format!(
+                "'{}' takes real arguments, got {}",
+                name, arg
+            )));
+        }
+        let args: Vec<Value> = args.iter().map(CalcNum::to_value).collect();
+        Complex::from_value(&resolve(&args)?)
+    }
+
+    fn add(self, that: Self) -> Result<Self, CalcError> {
+        Ok(Complex::new(self.re + that.re, self.im + that.im))
+    }
+
+    fn sub(self, that: Self) -> Result<Self, CalcError> {
+        Ok(Complex::new(self.re - that.re, self.im - that.im))
+    }
+
+    fn mul(self, that: Self) -> Result<Self, CalcError> {
+        Ok(Complex::new(
+            self.re * that.re - self.im * that.im,
+            self.re * that.im + self.im * that.re,
+        ))
+    }
+
+    fn div(self, that: Self) -> Result<Self, CalcError> {
+        if that.is_zero() {
+            return Err(CalcError::DivideByZero);
+        }
+        let norm = that.re * that.re + that.im * that.im;
+        Ok(Complex::new(
+            (self.re * that.re + self.im * that.im) / norm,
+            (self.im * that.re - self.re * that.im) / norm,
+        ))
+    }
+
+    fn rem(self, that: Self) -> Result<Self, CalcError> {
+        if !self.is_real() || !that.is_real() {
+            return Err(CalcError::BadTypes(PartialComp::binary(
+                "%", &self, &that,
+            )));
+        }
+        self.re.rem(that.re).map(Complex::real)
+    }
+
+    /// Keeps a zero imaginary part positive, so that `ln(-1)` is `pi * i`
+    /// rather than its conjugate.
+    fn neg(self) -> Result<Self, CalcError> {
+        Ok(Complex::new(-self.re, 0.0 - self.im))
+    }
+
+    /// Real powers of numbers that are not negative stay real, integer
+    /// powers are exact, and the others are principal values, e.g.
+    /// `(-8) ** (1/3)` is `1 + 1.732...i`.
+    fn powf(self, that: Self) -> Result<Self, CalcError> {
+        if that.is_real()
+            && that.re.fract() == 0.0
+            && that.re.abs() <= f64::from(i32::MAX)
+        {
+            self.powi(that.re as i64)
+        } else if self.is_real() && that.is_real() && self.re >= 0.0 {
+            Ok(Complex::real(self.re.powf(that.re)))
+        } else if self.is_zero() {
+            if that.re > 0.0 {
+                Ok(self)
+            } else {
+                Err(CalcError::DivideByZero)
+            }
+        } else {
+            Ok(that.mul(self.ln())?.exp())
+        }
+    }
+
+    fn floor(self) -> Result<Self, CalcError> {
+        if !self.is_real() {
+            return Err(CalcError::BadTypes(PartialComp::unary("floor", self)));
+        }
+        Ok(Complex::real(self.re.floor()))
+    }
+
+    /// Only real numbers are ordered.
+    fn compare(&self, that: &Self) -> Option<Ordering> {
+        if self.is_real() && that.is_real() {
+            self.re.partial_cmp(&that.re)
+        } else {
+            None
+        }
+    }
+
+    fn to_bits_integer(&self) -> Option<i64> {
+        if self.is_real() {
+            self.re.to_bits_integer()
+        } else {
+            None
+        }
+    }
+
+    fn from_bits_integer(n: i64) -> Self {
+        Complex::real(n as f64)
+    }
+}
+
+/// Rewrites the imaginary literals among `tokens`, a number followed by `i`
+/// or `j`, into parenthesized products. A `-` in front of a literal that
+/// negates it moves into the parentheses, as `-(3 * i)` would negate the
+/// rest of the input.
+pub(crate) fn imaginary_literals(
+    tokens: Vec<Token>,
+) -> Result<Vec<Token>, CalcError> {
+    let mut out = Vec::with_capacity(tokens.len());
+    let mut tokens = tokens.into_iter().peekable();
+    while let Some(token) = tokens.next() {
+        let n = match token {
+            Token::Number(n) => n,
+            token => {
+                out.push(token);
+                continue;
+            }
+        };
+        match tokens.peek() {
+            Some(Token::Atom(name)) if UNITS.contains(&&**name) => {}
+            _ => {
+                out.push(Token::Number(n));
+                continue;
+            }
+        }
+        let negated = out.last() == Some(&Token::Minus)
+            && !matches!(
+                out.len().checked_sub(2).map(|prev| &out[prev]),
+                Some(Token::Number(_))
+                    | Some(Token::Atom(_))
+                    | Some(Token::Placeholder(_))
+                    | Some(Token::CloseParen)
+                    | Some(Token::Square)
+                    | Some(Token::Cube)
+            );
+        let n = if negated {
+            out.pop();
+            CalcNum::neg(n)?
+        } else {
+            n
+        };
+        out.push(Token::OpenParen);
+        out.push(Token::Number(n));
+        out.push(Token::Multiply);
+        out.extend(tokens.next());
+        out.push(Token::CloseParen);
+    }
+    Ok(out)
+}
+
+/// The `DefaultEnvironment` with the imaginary unit and the functions of
+/// `PARTS`, for `eval_complex`.
+pub(crate) struct ComplexEnvironment;
+
+impl Environment for ComplexEnvironment {
+    fn arity(&self, atom: &str) -> Option<usize> {
+        if UNITS.contains(&atom) {
+            Some(0)
+        } else if PARTS.contains(&atom) {
+            Some(1)
+        } else {
+            DefaultEnvironment.arity(atom)
+        }
+    }
+
+    /// Only called with real arguments, for the functions `Complex::call`
+    /// leaves to the `DefaultEnvironment`.
+    fn resolve(
+        &mut self,
+        atom: &str,
+        args: &[Value],
+    ) -> Result<Value, CalcError> {
+        DefaultEnvironment.resolve(atom, args)
+    }
+
+    fn names(&self) -> Vec<String> {
+        let mut names = DefaultEnvironment.names();
+        names.extend(UNITS.iter().chain(&PARTS).map(|&name| name.into()));
+        names
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eval_complex;
+
+    fn assert_close(input: &str, re: f64, im: f64) {
+        let z = eval_complex(input).unwrap();
+        assert!((z.re() - re).abs() < 1e-9, "{}: {}", input, z);
+        assert!((z.im() - im).abs() < 1e-9, "{}: {}", input, z);
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(eval_complex("(2+3i)*(1-i)"), Ok(Complex::new(5.0, 1.0)));
+        assert_eq!(eval_complex("i * i"), Ok(Complex::real(-1.0)));
+        assert_eq!(eval_complex("(1 + i) ** 2"), Ok(Complex::new(0.0, 2.0)));
+        assert_eq!(eval_complex("1 / i"), Ok(Complex::new(0.0, -1.0)));
+        assert_eq!(eval_complex("2j - 0.5"), Ok(Complex::new(-0.5, 2.0)));
+        assert_eq!(eval_complex("1 + 2 * 3"), Ok(Complex::real(7.0)));
+        assert_close("(3 + 4i) / (1 - 2i)", -1.0, 2.0);
+    }
+
+    #[test]
+    fn literals() {
+        assert_eq!(eval_complex("-3i + 2"), Ok(Complex::new(2.0, -3.0)));
+        assert_eq!(eval_complex("2 * -3i"), Ok(Complex::new(0.0, -6.0)));
+        assert_eq!(eval_complex("1 -2i"), Ok(Complex::new(1.0, -2.0)));
+        assert_eq!(eval_complex("3i²"), Ok(Complex::real(-9.0)));
+        assert_close(
+            "2 ** 3i",
+            (3.0 * 2f64.ln()).cos(),
+            (3.0 * 2f64.ln()).sin(),
+        );
+    }
+
+    #[test]
+    fn functions() {
+        assert_eq!(eval_complex("sqrt(-1)"), Ok(Complex::new(0.0, 1.0)));
+        assert_eq!(
+            eval_complex("sqrt(-4i)").unwrap().to_string(),
+            "1.4142135623730951 - 1.4142135623730951i"
+        );
+        assert_close("exp(i * pi)", -1.0, 0.0);
+        assert_close("ln(-1)", 0.0, std::f64::consts::PI);
+        assert_close("(-8) ** (1/3)", 1.0, 3f64.sqrt());
+        assert_close("abs(3 + 4i)", 5.0, 0.0);
+        assert_close("re(2 - i) + im(2 - i)", 1.0, 0.0);
+        assert_close("conj(2 - i) * 2", 4.0, 2.0);
+        assert_close("arg(-i)", -std::f64::consts::FRAC_PI_2, 0.0);
+        assert_close("sin(i)", 0.0, 1f64.sinh());
+        assert_close("floor(2.5) + cos 0", 3.0, 0.0);
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(Complex::new(5.0, 1.0).to_string(), "5 + i");
+        assert_eq!(Complex::new(2.0, -3.0).to_string(), "2 - 3i");
+        assert_eq!(Complex::new(0.0, -2.5).to_string(), "-2.5i");
+        assert_eq!(Complex::new(0.0, -1.0).to_string(), "-i");
+        assert_eq!(Complex::real(1.5).to_string(), "1.5");
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(eval_complex("1 / (i - i)"), Err(CalcError::DivideByZero));
+        assert!(matches!(eval_complex("i & 1"), Err(CalcError::BadTypes(_))));
+        assert!(matches!(
+            eval_complex("totient(2i)"),
+            Err(CalcError::InvalidArgument(_))
+        ));
+        assert!(eval_complex("i % 2").is_err());
+        assert!(eval_complex("floor i").is_err());
+    }
+}
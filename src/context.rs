@@ -0,0 +1,781 @@
+use ast::{self, BinaryOp, Binder, Expr};
+use error::CalcError;
+use parse::{
+    Assoc, DefaultEnvironment, Environment, IntegerMode, NumericOptions,
+    Operator, RightShift,
+};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use token::{RawToken, Tokenizer};
+use value::Value;
+
+/// A set of variable bindings and functions that expressions can be
+/// evaluated against.
+///
+/// Variables shadow functions, which in turn shadow the constants and
+/// functions of the `DefaultEnvironment`. Unlike a `Calculator`, a
+/// `Context` is not changed by evaluating an expression, so it can be
+/// shared by reference.
+///
+/// Registered functions must be `Send + Sync`, which makes `Context` both
+/// as well. To evaluate against the same constants and functions from
+/// several threads, put the `Context` into an `Arc` and hand a clone of it
+/// to every thread. A `Calculator` is mutated by every evaluation, so each
+/// thread should own its own.
+///
+/// ```
+/// # extern crate calc;
+/// # use calc::{CalcError, Context, Expression, Value};
+/// # use std::sync::Arc;
+/// # use std::thread;
+/// # fn main() -> Result<(), CalcError> {
+/// let mut context = Context::new();
+/// context.set_var("rate", Value::dec(3));
+/// context.register_fn("double", 1, |args| args[0].clone() * Value::dec(2));
+/// let context = Arc::new(context);
+///
+/// let e = Expression::parse_with("double rate + 1", &context)?;
+/// let shared = Arc::clone(&context);
+/// let result = thread::spawn(move || e.value_with(&shared)).join().unwrap();
+/// assert_eq!(result?, 7.0);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Context {
+    vars: HashMap<String, Value>,
+    /// The functions, including those applying the `operators` by their
+    /// symbols.
+    functions: HashMap<String, Function>,
+    operators: Vec<Operator>,
+}
+
+type Callback = dyn Fn(&[Value]) -> Result<Value, CalcError> + Send + Sync;
+
+/// A function registered with `Context::register_fn` or defined with
+/// `Context::define`.
+#[derive(Clone)]
+pub(crate) struct Function {
+    arity: usize,
+    body: Body,
+}
+
+#[derive(Clone)]
+enum Body {
+    Native(Arc<Callback>),
+    /// The parameters and the body of a definition, and the context it was
+    /// defined in.
+    Defined(Arc<(Vec<String>, Expr, Context)>),
+}
+
+impl Function {
+    /// Applies the function to `args`. The body of a definition is
+    /// evaluated under the limits of `caller`, such as its step budget.
+    pub(crate) fn call(
+        &self,
+        caller: &mut dyn Environment,
+        args: &[Value],
+    ) -> Result<Value, CalcError> {
+        match self.body {
+            Body::Native(ref fun) => fun(args),
+            Body::Defined(ref definition) => {
+                let (ref params, ref body, ref context) = **definition;
+                let mut scope = Scope {
+                    params,
+                    args,
+                    context,
+                    caller,
+                };
+                ast::evaluate(body, &mut scope)
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Function {{ arity: {} }}", self.arity)
+    }
+}
+
+impl Context {
+    pub fn new() -> Self {
+        Context::default()
+    }
+
+    /// Defines a variable, replacing any previous value of the same name.
+    pub fn set_var<S: Into<String>>(&mut self, name: S, value: Value) {
+        self.vars.insert(name.into(), value);
+    }
+
+    /// Looks up the value of a variable.
+    pub fn var(&self, name: &str) -> Option<&Value> {
+        self.vars.get(name)
+    }
+
+    /// Whether a variable or function of this name shadows the
+    /// `DefaultEnvironment`.
+    pub(crate) fn shadows(&self, name: &str) -> bool {
+        self.vars.contains_key(name) || self.functions.contains_key(name)
+    }
+
+    /// Defines a function taking `arity` arguments, replacing any previous
+    /// function of the same name. It is only called with exactly `arity`
+    /// arguments.
+    pub fn register_fn<S, F>(&mut self, name: S, arity: usize, fun: F)
+    where
+        S: Into<String>,
+        F: Fn(&[Value]) -> Result<Value, CalcError> + Send + Sync + 'static,
+    {
+        let body = Body::Native(Arc::new(fun));
+        self.functions.insert(name.into(), Function { arity, body });
+    }
+
+    /// Defines a function like `register_fn`, which is applied to its
+    /// arguments as `f64`s.
+    ///
+    /// ```
+    /// # extern crate calc;
+    /// # use calc::{eval_with_context, CalcError, Context};
+    /// # fn main() -> Result<(), CalcError> {
+    /// let mut context = Context::new();
+    /// context.register_f64_fn("hypot", 2, |args| Ok(args[0].hypot(args[1])));
+    /// let value = eval_with_context("hypot(3, 4) + 1", &context)?;
+    /// assert_eq!(value.as_f64(), 6.0);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn register_f64_fn<S, F>(&mut self, name: S, arity: usize, f: F)
+    where
+        S: Into<String>,
+        F: Fn(&[f64]) -> Result<f64, CalcError> + Send + Sync + 'static,
+    {
+        self.register_fn(name, arity, move |args| {
+            let args: Vec<f64> = args.iter().map(Value::as_f64).collect();
+            f(&args).map(Value::from_f64)
+        });
+    }
+
+    /// Defines a binary operator, replacing any previous operator of the
+    /// same symbol. It binds like the builtin operators of `precedence`,
+    /// from 1 for the bitwise operators to 4 for `**`, and is applied to
+    /// its operands as `f64`s.
+    ///
+    /// The symbol may not contain letters, digits, whitespace or any of
+    /// `_ . , ( ) $`, and may not be a builtin operator or consist of the
+    /// characters of builtin operators alone, like `*-`, which already
+    /// means something. Such symbols, and precedences outside of 1 to 4,
+    /// fail with `CalcError::InvalidArgument`. Where a symbol and a builtin
+    /// operator both match, the longer one is taken, so `<<<` is not
+    /// `<<` followed by `<`.
+    ///
+    /// Trees parsed with a context record a registered operator as a call
+    /// of its symbol with both operands.
+    ///
+    /// ```
+    /// # extern crate calc;
+    /// # use calc::{Assoc, CalcError, Context, Expression};
+    /// # fn main() -> Result<(), CalcError> {
+    /// let mut context = Context::new();
+    /// let mean = |a: f64, b: f64| Ok((a * b).sqrt());
+    /// context.register_operator("><", 3, Assoc::Left, mean)?;
+    ///
+    /// let e = Expression::parse_with("1 + 2 >< 8", &context)?;
+    /// assert_eq!(e.value_with(&context)?, 5.0);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn register_operator<S, F>(
+        &mut self,
+        symbol: S,
+        precedence: u8,
+        assoc: Assoc,
+        f: F,
+    ) -> Result<(), CalcError>
+    where
+        S: Into<String>,
+        F: Fn(f64, f64) -> Result<f64, CalcError> + Send + Sync + 'static,
+    {
+        let symbol = symbol.into();
+        let reserved = |c: char| {
+            c.is_alphanumeric() || c.is_whitespace() || "_.,()$".contains(c)
+        };
+        if symbol.is_empty() || symbol.chars().any(reserved) {
+            return Err(CalcError::InvalidArgument(format!(
+                "operator symbol {:?} may not contain letters, digits, \
+                 whitespace or any of _ . , ( ) $",
+                symbol
+            )));
+        }
+        let builtin = |c: char| "+-−/÷*×^²³&|~%=√".contains(c);
+        if symbol.chars().all(builtin) || symbol == "<<" || symbol == ">>" {
+            return Err(CalcError::InvalidArgument(format!(
+                "operator symbol {:?} collides with the builtin operators",
+                symbol
+            )));
+        }
+        if !(1..=4).contains(&precedence) {
+            return Err(CalcError::InvalidArgument(format!(
+                "operator precedence must be between 1 and 4, got {}",
+                precedence
+            )));
+        }
+        self.operators.retain(|op| op.symbol != symbol);
+        self.operators.push(Operator {
+            symbol: symbol.clone(),
+            precedence,
+            assoc,
+        });
+        self.register_fn(symbol, 2, move |args| {
+            f(args[0].as_f64(), args[1].as_f64()).map(Value::from_f64)
+        });
+        Ok(())
+    }
+
+    /// Defines a function from its source, such as `f(x) = x ** 2 + 1`,
+    /// replacing any previous function of the same name. Parameters are
+    /// separated by commas, while calls either list the arguments the same
+    /// way, as in `hyp(3, 4)`, or pass them side by side, as in `f 3` or
+    /// `hyp 3 4`.
+    ///
+    /// The body sees the parameters, and the variables and functions of the
+    /// context as they are when the function is defined. So a function
+    /// cannot call itself: a name in the body that is neither a parameter
+    /// nor defined yet fails with `CalcError::UnknownAtom`, and a
+    /// redefinition that refers to its own name calls the previous
+    /// definition.
+    pub fn define(&mut self, input: &str) -> Result<(), CalcError> {
+        let (name, params, body) =
+            split_definition(input).ok_or_else(|| {
+                CalcError::InvalidArgument(format!(
+                    "expected a definition like `f(x) = x + 1`, got {:?}",
+                    input.trim()
+                ))
+            })?;
+        for (i, param) in params.iter().enumerate() {
+            if params[..i].contains(param) {
+                return Err(CalcError::InvalidArgument(format!(
+                    "parameter {} is repeated",
+                    param
+                )));
+            }
+        }
+
+        let captured = self.clone();
+        let params: Vec<String> =
+            params.into_iter().map(String::from).collect();
+        let body = {
+            let mut scope = Scope {
+                params: &params,
+                args: &[],
+                context: &captured,
+                caller: &mut DefaultEnvironment,
+            };
+            let body = Expr::parse_with(body, &mut scope)
+                .map_err(|error| unknown_call(body, &scope).unwrap_or(error))?;
+            for node in body.iter() {
+                if let Expr::Var(ref var) = *node {
+                    if scope.arity(var).is_none() {
+                        return Err(CalcError::unknown_atom(var, &scope));
+                    }
+                }
+            }
+            body
+        };
+        let function = Function {
+            arity: params.len(),
+            body: Body::Defined(Arc::new((params, body, captured))),
+        };
+        self.functions.insert(name.to_owned(), function);
+        Ok(())
+    }
+
+    /// The function `atom` resolves to, unless a variable shadows it.
+    pub(crate) fn function(&self, atom: &str) -> Option<Function> {
+        if self.vars.contains_key(atom) {
+            None
+        } else {
+            self.functions.get(atom).cloned()
+        }
+    }
+}
+
+/// Splits a definition like `f(x, y) = x * y` into the name, the
+/// parameters and the body.
+pub(crate) fn split_definition(input: &str) -> Option<(&str, Vec<&str>, &str)> {
+    let equals = input.find('=')?;
+    let (head, body) = (input[..equals].trim(), &input[equals + 1..]);
+    let open = head.find('(')?;
+    let name = head[..open].trim();
+    let params = head[open + 1..].strip_suffix(')')?.trim();
+    let params: Vec<&str> = if params.is_empty() {
+        Vec::new()
+    } else {
+        params.split(',').map(str::trim).collect()
+    };
+    if is_name(name) && params.iter().all(|param| is_name(param)) {
+        Some((name, params, body))
+    } else {
+        None
+    }
+}
+
+/// The error for the first name in `body` that is called like a function
+/// but unknown to `scope`, such as the name being defined. The body parses
+/// it as a variable followed by stray parentheses.
+fn unknown_call(body: &str, scope: &Scope) -> Option<CalcError> {
+    let tokens: Vec<RawToken> =
+        Tokenizer::new(body).collect::<Result<_, _>>().ok()?;
+    tokens.windows(2).find_map(|pair| match (pair[0], pair[1]) {
+        (RawToken::Atom(name), RawToken::OpenParen)
+            if scope.arity(name).is_none()
+                && name != "cond"
+                && Binder::from_name(name).is_none() =>
+        {
+            Some(CalcError::unknown_atom(name, scope))
+        }
+        _ => None,
+    })
+}
+
+/// Whether `text` is tokenized as a single atom.
+pub(crate) fn is_name(text: &str) -> bool {
+    let mut tokens = Tokenizer::new(text);
+    match (tokens.next(), tokens.next()) {
+        (Some(Ok(RawToken::Atom(atom))), None) => atom == text,
+        _ => false,
+    }
+}
+
+/// The environment of a call to a function defined with `Context::define`:
+/// its parameters, bound to the arguments, shadow the captured context.
+/// The limits are those of the caller.
+struct Scope<'a> {
+    params: &'a [String],
+    args: &'a [Value],
+    context: &'a Context,
+    caller: &'a mut dyn Environment,
+}
+
+impl<'a> Environment for Scope<'a> {
+    fn arity(&self, atom: &str) -> Option<usize> {
+        if self.params.iter().any(|param| param == atom) {
+            Some(0)
+        } else {
+            self.context.arity(atom)
+        }
+    }
+
+    fn resolve(
+        &mut self,
+        atom: &str,
+        args: &[Value],
+    ) -> Result<Value, CalcError> {
+        if let Some(index) = self.params.iter().position(|p| p == atom) {
+            return Ok(self.args[index].clone());
+        }
+        match self.context.function(atom) {
+            Some(function) => function.call(&mut *self.caller, args),
+            None => (&mut self.context).resolve(atom, args),
+        }
+    }
+
+    fn step(&mut self) -> Result<(), CalcError> {
+        self.caller.step()
+    }
+
+    fn max_depth(&self) -> Option<usize> {
+        self.caller.max_depth()
+    }
+
+    fn right_shift(&self) -> RightShift {
+        self.caller.right_shift()
+    }
+
+    fn numeric_options(&self) -> NumericOptions {
+        self.caller.numeric_options()
+    }
+
+    fn max_magnitude(&self) -> Option<f64> {
+        self.caller.max_magnitude()
+    }
+
+    fn integer_mode(&self) -> IntegerMode {
+        self.caller.integer_mode()
+    }
+
+    fn lenient_parens(&self) -> bool {
+        self.caller.lenient_parens()
+    }
+
+    fn compensated_sum(&self) -> bool {
+        self.caller.compensated_sum()
+    }
+
+    fn warn(&mut self, message: String) {
+        self.caller.warn(message)
+    }
+
+    fn operators(&self) -> Vec<Operator> {
+        self.context.operators()
+    }
+
+    fn precedence(&self, op: BinaryOp) -> u8 {
+        self.caller.precedence(op)
+    }
+
+    fn assoc(&self, op: BinaryOp) -> Assoc {
+        self.caller.assoc(op)
+    }
+
+    fn names(&self) -> Vec<String> {
+        let mut names = self.context.names();
+        names.extend(self.params.iter().cloned());
+        names
+    }
+}
+
+impl Environment for &Context {
+    fn arity(&self, atom: &str) -> Option<usize> {
+        if self.vars.contains_key(atom) {
+            Some(0)
+        } else if let Some(function) = self.functions.get(atom) {
+            Some(function.arity)
+        } else {
+            DefaultEnvironment.arity(atom)
+        }
+    }
+
+    fn resolve(
+        &mut self,
+        atom: &str,
+        args: &[Value],
+    ) -> Result<Value, CalcError> {
+        if let Some(value) = self.vars.get(atom) {
+            return Ok(value.clone());
+        }
+        match self.functions.get(atom) {
+            Some(function) => function.call(&mut DefaultEnvironment, args),
+            None => DefaultEnvironment.resolve(atom, args),
+        }
+    }
+
+    fn operators(&self) -> Vec<Operator> {
+        self.operators.clone()
+    }
+
+    fn names(&self) -> Vec<String> {
+        let mut names = DefaultEnvironment.names();
+        names.extend(self.vars.keys().cloned());
+        names.extend(self.functions.keys().filter(|f| is_name(f)).cloned());
+        names
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use calculator::Calculator;
+    use eval_with_context;
+    use expression::Expression;
+    use std::thread;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn send_sync() {
+        assert_send_sync::<Context>();
+        assert_send_sync::<Arc<Context>>();
+        assert_send_sync::<Calculator>();
+        assert_send_sync::<Expression>();
+    }
+
+    #[test]
+    fn functions() {
+        let mut context = Context::new();
+        context.register_fn("hyp", 2, |args| {
+            let (a, b) = (args[0].as_f64(), args[1].as_f64());
+            Ok(Value::from_f64((a * a + b * b).sqrt()))
+        });
+        let e = Expression::parse_with("hyp 3 4 * 2", &context).unwrap();
+        assert_eq!(e.value_with(&context), Ok(10.0));
+        context.set_var("hyp", Value::dec(1));
+        assert_eq!(
+            e.value_with(&context),
+            Err(CalcError::WrongArity {
+                atom: "hyp".into(),
+                expected: 0,
+                actual: 2,
+            })
+        );
+    }
+
+    #[test]
+    fn f64_functions() {
+        let mut context = Context::new();
+        context.register_f64_fn("half", 1, |args| Ok(args[0] / 2.0));
+        context.register_f64_fn("check", 1, |args| {
+            if args[0] < 0.0 {
+                Err(CalcError::InvalidArgument("negative".into()))
+            } else {
+                Ok(args[0])
+            }
+        });
+        let minus = |a: f64, b: f64| Ok(a - b);
+        context
+            .register_operator("<>", 3, Assoc::Left, minus)
+            .unwrap();
+        let eval = |input| eval_with_context(input, &context);
+        assert_eq!(eval("half 3 <> 1").map(|v| v.as_f64()), Ok(0.5));
+        assert_eq!(
+            eval("check(-1)"),
+            Err(CalcError::InvalidArgument("negative".into()))
+        );
+    }
+
+    #[test]
+    fn definitions() {
+        let mut context = Context::new();
+        context.define("f(x) = x**2 + 1").unwrap();
+        context
+            .define("hyp(a, b) = (a * a + b * b) ** 0.5")
+            .unwrap();
+        context.define("answer() = 42").unwrap();
+        let eval = |input| ::eval_with_env(input, &mut &context);
+        assert_eq!(eval("f(3)"), Ok(Value::dec(10)));
+        assert_eq!(eval("f(f(2))"), Ok(Value::dec(26)));
+        assert_eq!(eval("hyp 3 4 + answer"), Ok(Value::from_f64(47.0)));
+
+        // Parameters shadow variables, but only within the body.
+        context.set_var("x", Value::dec(100));
+        assert_eq!(
+            ::eval_with_env("f(2) + x", &mut &context),
+            Ok(Value::dec(105))
+        );
+    }
+
+    #[test]
+    fn listed_arguments() {
+        let mut context = Context::new();
+        context.define("f(a, b, c) = a * 100 + b * 10 + c").unwrap();
+        let eval = |input| ::eval_with_env(input, &mut &context);
+        assert_eq!(eval("f(1, 2, 3)"), Ok(Value::dec(123)));
+        assert_eq!(eval("f 1 2 3"), Ok(Value::dec(123)));
+        assert_eq!(eval("f(1 + 1, (2), f(0, 0, 3)) * 2"), Ok(Value::dec(446)));
+        assert_eq!(
+            eval("f(1, 2)"),
+            Err(CalcError::WrongArity {
+                atom: "f".into(),
+                expected: 3,
+                actual: 2,
+            })
+        );
+        assert_eq!(
+            eval("f(1, 2, 3, (4, 5), 6)"),
+            Err(CalcError::WrongArity {
+                atom: "f".into(),
+                expected: 3,
+                actual: 5,
+            })
+        );
+        assert_eq!(
+            eval("sqrt(4, 9) + 1"),
+            Err(CalcError::WrongArity {
+                atom: "sqrt".into(),
+                expected: 1,
+                actual: 2,
+            })
+        );
+        assert_eq!(
+            eval("sqrt(4)(9)"),
+            Err(CalcError::UnexpectedToken("OpenParen".into(), "operator"))
+        );
+        for input in &["f(1)(2)(3)", "f (1) 2 3", "f(1) + 2"] {
+            assert_eq!(
+                eval(input),
+                Err(CalcError::WrongArity {
+                    atom: "f".into(),
+                    expected: 3,
+                    actual: 1,
+                })
+            );
+        }
+        assert!(eval("f(1, 2, 3").is_err());
+        assert!(eval("f(1, 2 3)").is_err());
+    }
+
+    #[test]
+    fn redefinition() {
+        let mut context = Context::new();
+        context.define("f(x) = x + 1").unwrap();
+        context.define("g(x) = f(x) * 2").unwrap();
+        context.define("f(x) = f(x) * 10").unwrap();
+        let eval = |input| ::eval_with_env(input, &mut &context);
+        assert_eq!(eval("f 3"), Ok(Value::dec(40)));
+        // `g` keeps calling the `f` it was defined with.
+        assert_eq!(eval("g 3"), Ok(Value::dec(8)));
+    }
+
+    #[test]
+    fn recursion_is_rejected() {
+        let mut context = Context::new();
+        assert_eq!(
+            context.define("fact(n) = n * fact(n - 1)"),
+            Err(CalcError::UnknownAtom("fact".into(), None))
+        );
+        assert_eq!(
+            ::eval_with_env("fact 3", &mut &context),
+            Err(CalcError::UnknownAtom("fact".into(), None))
+        );
+    }
+
+    #[test]
+    fn bad_definitions() {
+        let mut context = Context::new();
+        for input in
+            &["f(x) + 1", "f x = x", "(x) = x", "f(x y) = x", "2(x) = x"]
+        {
+            match context.define(input) {
+                Err(CalcError::InvalidArgument(_)) => {}
+                other => panic!("{:?} gave {:?}", input, other),
+            }
+        }
+        assert_eq!(
+            context.define("f(x, x) = x"),
+            Err(CalcError::InvalidArgument("parameter x is repeated".into()))
+        );
+        assert_eq!(
+            context.define("f(x) = x + y"),
+            Err(CalcError::UnknownAtom("y".into(), None))
+        );
+    }
+
+    #[test]
+    fn wrong_arity() {
+        let mut context = Context::new();
+        context.define("hyp(a, b) = a + b").unwrap();
+        let expr = Expr::call("hyp", vec![Expr::num(Value::dec(1))]);
+        assert_eq!(
+            ::eval_expr_with(&expr, &mut &context),
+            Err(CalcError::WrongArity {
+                atom: "hyp".into(),
+                expected: 2,
+                actual: 1,
+            })
+        );
+    }
+
+    #[test]
+    fn shared_between_threads() {
+        let mut context = Context::new();
+        context.set_var("offset", Value::dec(100));
+        context
+            .register_fn("square", 1, |args| args[0].clone() * args[0].clone());
+        let context = Arc::new(context);
+        let workers: Vec<_> = (0..8)
+            .map(|i| {
+                let context = Arc::clone(&context);
+                thread::spawn(move || {
+                    let input = format!("square {} + offset", i);
+                    let e = Expression::parse_with(&input, &context).unwrap();
+                    (0..100)
+                        .map(|_| e.value_with(&context))
+                        .collect::<Result<Vec<_>, _>>()
+                })
+            })
+            .collect();
+        for (i, worker) in workers.into_iter().enumerate() {
+            let expected = (i * i + 100) as f64;
+            assert_eq!(worker.join().unwrap(), Ok(vec![expected; 100]));
+        }
+    }
+
+    fn mean(a: f64, b: f64) -> Result<f64, CalcError> {
+        Ok((a * b).sqrt())
+    }
+
+    #[test]
+    fn operators() {
+        let eval = |context: &Context, input: &str| {
+            ::eval_with_env(input, &mut &*context).map(|v| v.as_f64())
+        };
+        let mut context = Context::new();
+        context
+            .register_operator("><", 3, Assoc::Left, mean)
+            .unwrap();
+        assert_eq!(eval(&context, "2 >< 8"), Ok(4.0));
+        // Binds like `*`: tighter than `+`, and left to right with `*`.
+        assert_eq!(eval(&context, "1 + 2 >< 8"), Ok(5.0));
+        assert_eq!(eval(&context, "2 >< 8 + 1"), Ok(5.0));
+        assert_eq!(eval(&context, "2 * 2 >< 16"), Ok(8.0));
+        assert_eq!(eval(&context, "2 >< 2 * 3"), Ok(6.0));
+        assert_eq!(eval(&context, "(1 + 1)><(4 ** 2 * 2)"), Ok(8.0));
+
+        // Binds like `+`: looser than `*`, and left to right with `+`.
+        context
+            .register_operator("><", 2, Assoc::Left, mean)
+            .unwrap();
+        assert_eq!(eval(&context, "2 * 2 >< 16"), Ok(8.0));
+        assert_eq!(eval(&context, "2 >< 2 * 4"), Ok(4.0));
+        assert_eq!(eval(&context, "1 + 3 >< 4"), Ok(4.0));
+
+        context
+            .register_operator("<-", 2, Assoc::Right, |a, b| Ok(a - b))
+            .unwrap();
+        assert_eq!(eval(&context, "10 <- 4 <- 1"), Ok(7.0));
+        assert_eq!(eval(&context, "10 - 4 - 1"), Ok(5.0));
+
+        let e = Expression::parse_with("x >< 4 * 4", &context).unwrap();
+        let mut scope = Context::new();
+        scope.set_var("x", Value::dec(4));
+        scope.register_operator("><", 2, Assoc::Left, mean).unwrap();
+        assert_eq!(e.value_with(&scope), Ok(8.0));
+
+        // Without the registration, the symbol is not an operator.
+        assert!(::eval("2 >< 8").is_err());
+        assert!(eval(&context, ">< 8").is_err());
+    }
+
+    #[test]
+    fn operator_collisions() {
+        let mut context = Context::new();
+        for &symbol in &["+", "**", "<<", ">>", "*-", "", "a+", "<(", "=="] {
+            assert!(
+                context
+                    .register_operator(symbol, 2, Assoc::Left, mean)
+                    .is_err(),
+                "{:?} was accepted",
+                symbol
+            );
+        }
+        assert_eq!(
+            context.register_operator("@", 5, Assoc::Left, mean),
+            Err(CalcError::InvalidArgument(
+                "operator precedence must be between 1 and 4, got 5".into()
+            ))
+        );
+
+        // The longer symbol wins over a builtin `<<`.
+        context
+            .register_operator("<<<", 1, Assoc::Left, |a, b| Ok(a * 8.0 + b))
+            .unwrap();
+        let eval = |input| ::eval_with_env(input, &mut &context);
+        assert_eq!(eval("1 <<< 2").map(|v| v.as_f64()), Ok(10.0));
+        assert_eq!(eval("1 << 2"), Ok(Value::dec(4)));
+    }
+
+    #[test]
+    fn calculator_operators() {
+        let mut calc = Calculator::new().with_cache(4);
+        calc.register_operator("@", 4, Assoc::Right, |a, b| Ok(a.powf(b)))
+            .unwrap();
+        assert_eq!(calc.eval("2 @ 3 @ 2").map(|v| v.as_f64()), Ok(512.0));
+        calc.register_operator("@", 4, Assoc::Left, |a, b| Ok(a.powf(b)))
+            .unwrap();
+        assert_eq!(calc.eval("2 @ 3 @ 2").map(|v| v.as_f64()), Ok(64.0));
+        assert_eq!(calc.eval("@ 2").map(|v| v.as_f64()), Ok(4096.0));
+        calc.define("f(x) = x @ 2").unwrap();
+        assert_eq!(calc.eval("f 3").map(|v| v.as_f64()), Ok(9.0));
+    }
+}
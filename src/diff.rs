@@ -0,0 +1,191 @@
+//! Structural comparison of expression trees, e.g. to show how a stored
+//! formula was edited.
+
+use ast::Expr;
+
+/// A subtree that differs between the two expressions compared by
+/// `Expr::diff`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExprDiff {
+    /// The position of the subtree: the index of the child to descend into
+    /// at each node from the root, where the operand of a unary operator is
+    /// `0`, the operands of a binary operator are `0` and `1`, and the
+    /// arguments of a call are numbered from `0`.
+    pub path: Vec<usize>,
+    /// The subtree in the first expression, rendered like `Expr`'s
+    /// `Display`.
+    pub old: String,
+    /// The subtree in the second expression.
+    pub new: String,
+}
+
+/// Controls how `Expr::diff_with` compares expressions.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DiffOptions {
+    /// Compare the canonical forms of the expressions, as given by
+    /// `Expr::canonicalize`, so that reordering the operands of `+` or `*`
+    /// isn't a change. The paths then refer to the canonical forms.
+    pub canonicalize_first: bool,
+}
+
+impl Expr {
+    /// Returns the subtrees in which the expression and `new` differ, from
+    /// left to right. Identical trees have no differences.
+    ///
+    /// The comparison descends into nodes with the same operator or
+    /// function and the same number of operands, and reports any other
+    /// node that differs as a whole. So does a node whose two operands
+    /// were swapped.
+    ///
+    /// ```
+    /// # extern crate calc;
+    /// # use calc::{CalcError, Expr};
+    /// # fn main() -> Result<(), CalcError> {
+    /// let old = Expr::parse("rate * (x + 2) ** 2")?;
+    /// let new = Expr::parse("rate * (x + 3) ** 2")?;
+    /// let changes = old.diff(&new);
+    /// assert_eq!(changes.len(), 1);
+    /// assert_eq!(changes[0].path, [1, 0, 1]);
+    /// assert_eq!((&*changes[0].old, &*changes[0].new), ("2", "3"));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn diff(&self, new: &Expr) -> Vec<ExprDiff> {
+        self.diff_with(new, &DiffOptions::default())
+    }
+
+    /// Compares two expressions like `diff`, with the given options.
+    pub fn diff_with(
+        &self,
+        new: &Expr,
+        options: &DiffOptions,
+    ) -> Vec<ExprDiff> {
+        let mut diffs = Vec::new();
+        if options.canonicalize_first {
+            let (old, new) = (self.canonicalize(), new.canonicalize());
+            compare(&old, &new, &mut Vec::new(), &mut diffs);
+        } else {
+            compare(self, new, &mut Vec::new(), &mut diffs);
+        }
+        diffs
+    }
+}
+
+fn compare(
+    a: &Expr,
+    b: &Expr,
+    path: &mut Vec<usize>,
+    diffs: &mut Vec<ExprDiff>,
+) {
+    if a == b {
+        return;
+    }
+    let children: Vec<(&Expr, &Expr)> = match (a, b) {
+        (&Expr::Unary(op, ref x), &Expr::Unary(other, ref y))
+            if op == other =>
+        {
+            vec![(x, y)]
+        }
+        (
+            &Expr::Binary(op, ref lhs, ref rhs),
+            &Expr::Binary(other, ref new_lhs, ref new_rhs),
+        ) if op == other && !(lhs == new_rhs && rhs == new_lhs) => {
+            vec![(lhs, new_lhs), (rhs, new_rhs)]
+        }
+        (Expr::Call(name, args), Expr::Call(other, new_args))
+            if name == other && args.len() == new_args.len() =>
+        {
+            args.iter().zip(new_args).collect()
+        }
+        _ => {
+            diffs.push(ExprDiff {
+                path: path.clone(),
+                old: a.to_string(),
+                new: b.to_string(),
+            });
+            return;
+        }
+    };
+    for (i, (a, b)) in children.into_iter().enumerate() {
+        path.push(i);
+        compare(a, b, path, diffs);
+        path.pop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str) -> Expr {
+        Expr::parse(input).unwrap()
+    }
+
+    fn changes(a: &str, b: &str) -> Vec<(Vec<usize>, String, String)> {
+        parse(a)
+            .diff(&parse(b))
+            .into_iter()
+            .map(|d| (d.path, d.old, d.new))
+            .collect()
+    }
+
+    #[test]
+    fn deep_literal() {
+        let a = "cos(x * (2 + y ** 4)) - log 10";
+        let b = "cos(x * (2 + y ** 5)) - log 10";
+        assert_eq!(
+            changes(a, b),
+            [(vec![0, 0, 1, 1, 1], "4".into(), "5".into())]
+        );
+        assert!(parse(a).diff(&parse(a)).is_empty());
+    }
+
+    #[test]
+    fn subtrees() {
+        assert_eq!(
+            changes("x * 2 + y", "x * 3 + z"),
+            [
+                (vec![0, 1], "2".into(), "3".into()),
+                (vec![1], "y".into(), "z".into()),
+            ]
+        );
+        // Nodes with different operators differ as a whole, and are
+        // rendered with the parentheses they need.
+        assert_eq!(
+            changes("2 * (x + 1)", "2 * (x - 1)"),
+            [(vec![1], "x + 1".into(), "x - 1".into())]
+        );
+        assert_eq!(
+            changes("a + b", "(a + b) * c"),
+            [(vec![], "a + b".into(), "(a + b) * c".into())]
+        );
+        assert_eq!(
+            changes("approx(x, 10)", "approx(x, 100)"),
+            [(vec![1], "10".into(), "100".into())]
+        );
+    }
+
+    #[test]
+    fn commutative_operands() {
+        let (a, b) = (parse("y * (x + 1)"), parse("y * (1 + x)"));
+        assert_eq!(
+            a.diff(&b),
+            [ExprDiff {
+                path: vec![1],
+                old: "x + 1".into(),
+                new: "1 + x".into(),
+            }]
+        );
+        let options = DiffOptions {
+            canonicalize_first: true,
+        };
+        assert!(a.diff_with(&b, &options).is_empty());
+        assert!(parse("x - y")
+            .diff_with(&parse("y * -1 + x"), &options)
+            .is_empty());
+        assert_eq!(
+            parse("x + 1").diff_with(&parse("x + 2"), &options).len(),
+            1
+        );
+    }
+}
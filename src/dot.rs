@@ -0,0 +1,132 @@
+//! Export of expression trees to the GraphViz DOT language.
+
+use ast::Expr;
+use std::fmt::Write;
+
+/// Renders the tree of an expression as a DOT digraph, e.g. to inspect
+/// the precedence the parser applied with `dot -Tsvg`.
+///
+/// Every node of the tree becomes a graph node labeled with its operator,
+/// atom or number. Nodes are numbered `n0`, `n1`, ... in pre-order, so the
+/// output only depends on the tree. Edges point from an operation to its
+/// operands, in order; the operands of binary operators are labeled `lhs`
+/// and `rhs`, and the arguments of functions are numbered from 1.
+pub fn to_dot(expr: &Expr) -> String {
+    let mut out = String::from("digraph expr {\n    ordering=out;\n");
+    let mut next_id = 0;
+    write_node(&mut out, expr, &mut next_id);
+    out.push_str("}\n");
+    out
+}
+
+/// Writes the node for `expr` and its subtree, numbering nodes from
+/// `next_id`. Returns the id of the node.
+fn write_node(out: &mut String, expr: &Expr, next_id: &mut usize) -> usize {
+    let id = *next_id;
+    *next_id += 1;
+    let (label, children): (String, Vec<(Option<String>, &Expr)>) = match *expr
+    {
+        Expr::Num(ref value) => (value.to_string(), Vec::new()),
+        Expr::Var(ref name) => (name.clone(), Vec::new()),
+        Expr::Call(ref name, ref args) => {
+            let args = args
+                .iter()
+                .enumerate()
+                .map(|(i, arg)| (Some((i + 1).to_string()), arg))
+                .collect();
+            (name.clone(), args)
+        }
+        Expr::Unary(op, ref arg) => {
+            (op.symbol().to_owned(), vec![(None, &**arg)])
+        }
+        Expr::Binary(op, ref lhs, ref rhs) => (
+            op.symbol().to_owned(),
+            vec![(Some("lhs".into()), &**lhs), (Some("rhs".into()), &**rhs)],
+        ),
+    };
+    writeln!(out, "    n{} [label=\"{}\"];", id, escape(&label))
+        .expect("writing to a String succeeds");
+    for (edge, child) in children {
+        let child_id = write_node(out, child, next_id);
+        let result = match edge {
+            Some(edge) => writeln!(
+                out,
+                "    n{} -> n{} [label=\"{}\"];",
+                id,
+                child_id,
+                escape(&edge)
+            ),
+            None => writeln!(out, "    n{} -> n{};", id, child_id),
+        };
+        result.expect("writing to a String succeeds");
+    }
+    id
+}
+
+/// Escapes text for a double-quoted DOT string.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot() {
+        let expr = Expr::parse("-(2+3)**2").unwrap();
+        let expected = "\
+digraph expr {
+    ordering=out;
+    n0 [label=\"-\"];
+    n1 [label=\"**\"];
+    n2 [label=\"+\"];
+    n3 [label=\"2\"];
+    n2 -> n3 [label=\"lhs\"];
+    n4 [label=\"3\"];
+    n2 -> n4 [label=\"rhs\"];
+    n1 -> n2 [label=\"lhs\"];
+    n5 [label=\"2\"];
+    n1 -> n5 [label=\"rhs\"];
+    n0 -> n1;
+}
+";
+        assert_eq!(to_dot(&expr), expected);
+    }
+
+    #[test]
+    fn structure() {
+        let expr = Expr::parse("log (x * 2) + -y ** 3 - 1").unwrap();
+        let dot = to_dot(&expr);
+        let (edges, nodes): (Vec<&str>, Vec<&str>) = dot
+            .lines()
+            .filter(|line| line.ends_with(';') && line.starts_with("    n"))
+            .partition(|line| line.contains(" -> "));
+        assert_eq!(nodes.len(), expr.iter().count());
+        assert_eq!(edges.len(), nodes.len() - 1);
+        for (id, node) in nodes.iter().enumerate() {
+            assert!(node.starts_with(&format!("    n{} ", id)), "{}", node);
+        }
+        assert_eq!(dot.matches("[label=\"lhs\"]").count(), 4);
+        assert!(dot.contains("n1 -> n2 [label=\"1\"];"));
+        assert_eq!(to_dot(&expr), dot);
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(escape(r#"a "b" \c"#), r#"a \"b\" \\c"#);
+        let expr = Expr::call("say\"hi\\", vec![Expr::var("x")]);
+        assert!(to_dot(&expr).contains(r#"n0 [label="say\"hi\\"];"#));
+    }
+}
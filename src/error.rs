@@ -1,10 +1,14 @@
+use json;
 use num::bigint::ParseBigIntError;
+use parse::Environment;
 use std::error::Error;
 use std::fmt;
+use std::io;
+use std::ops::Range;
 
 /// Represents a partial computation that can be captured as part of an
 /// error message.
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum PartialComp {
     ToFloat(String),
     Unary {
@@ -60,63 +64,781 @@ impl fmt::Display for PartialComp {
     }
 }
 
-#[derive(Debug, PartialEq)]
+/// The reason an equation could not be solved.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Unsolvable {
+    /// The input does not contain exactly one `=`.
+    NotAnEquation,
+    /// The equation is not linear in the variable.
+    Nonlinear,
+    /// The variable does not occur in the equation.
+    VariableAbsent,
+    /// The equation holds for every value of the variable.
+    Identity,
+    /// The equation holds for no value of the variable.
+    Contradiction,
+}
+
+impl fmt::Display for Unsolvable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Unsolvable::NotAnEquation => {
+                write!(f, "expected an equation of the form 'lhs = rhs'")
+            }
+            Unsolvable::Nonlinear => write!(f, "equation is not linear"),
+            Unsolvable::VariableAbsent => {
+                write!(f, "variable does not occur in the equation")
+            }
+            Unsolvable::Identity => write!(f, "equation is always true"),
+            Unsolvable::Contradiction => write!(f, "equation is never true"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum CalcError {
     BadTypes(PartialComp),
+    BudgetExceeded,
+    CannotDifferentiate(String),
+    /// Formulas refer to each other in a cycle, which starts and ends with
+    /// the same name.
+    CircularReference(Vec<String>),
     DivideByZero,
+    InputTooLarge {
+        limit: usize,
+        actual: usize,
+    },
+    /// A function was given an argument outside of its domain.
+    InvalidArgument(String),
+    /// A JSON document does not describe an expression.
+    InvalidJson(String),
     InvalidNumber(String),
     InvalidOperator(char),
+    /// Reading the input failed.
+    Io(String),
+    /// A literal is too large for the numeric type it is evaluated in.
+    LiteralOutOfRange(String),
+    /// A value exceeds the largest magnitude the environment allows.
+    MagnitudeExceeded {
+        value: f64,
+        limit: f64,
+    },
+    /// A numeric method, named by the function using it, did not reach the
+    /// required tolerance.
+    NoConvergence(String),
     UnrecognizedToken(String),
     UnexpectedToken(String, &'static str),
-    UnknownAtom(String),
+    /// An atom that is neither a variable nor a function, with the closest
+    /// name the environment defines, if one is close enough to suggest.
+    UnknownAtom(String, Option<String>),
+    NestingTooDeep,
+    /// An operator, function or literal produced a value that is not an
+    /// integer in `IntegerMode::Strict` or `IntegerMode::FloorDivision`.
+    NotAnInteger {
+        op: String,
+        value: String,
+    },
     UnexpectedEndOfInput,
     UnmatchedParenthesis,
+    Unsolvable(Unsolvable),
     WouldOverflow(PartialComp),
     WouldTruncate(PartialComp),
+    WrongArity {
+        atom: String,
+        expected: usize,
+        actual: usize,
+    },
 }
 
 use CalcError::*;
 
-impl fmt::Display for CalcError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+impl CalcError {
+    /// A hint at what was probably meant, such as the defined name closest
+    /// to a misspelled atom, or `**` for a `^` applied to floats.
+    pub fn suggestion(&self) -> Option<String> {
         match *self {
-            BadTypes(ref comp) => {
-                write!(f, "expression '{}' is not well typed", comp)
+            UnknownAtom(_, Some(ref name)) => {
+                Some(format!("did you mean '{}'?", name))
             }
-            DivideByZero => write!(f, "attempted to divide by zero"),
-            InvalidNumber(ref number) => {
-                write!(f, "invalid number: {}", number)
+            BadTypes(PartialComp::Binary { ref op, .. }) if op == "^" => {
+                Some("did you mean '**' for exponentiation?".into())
             }
-            InvalidOperator(ref c) => write!(f, "invalid operator: {}", c),
-            UnrecognizedToken(ref token) => {
-                write!(f, "unrecognized token: {}", token)
+            _ => None,
+        }
+    }
+
+    /// The name of the variant, such as `"UnexpectedToken"`, which stays the
+    /// same across versions so that tools can match on it.
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            BadTypes(_) => "BadTypes",
+            BudgetExceeded => "BudgetExceeded",
+            CannotDifferentiate(_) => "CannotDifferentiate",
+            CircularReference(_) => "CircularReference",
+            DivideByZero => "DivideByZero",
+            InputTooLarge { .. } => "InputTooLarge",
+            InvalidArgument(_) => "InvalidArgument",
+            InvalidJson(_) => "InvalidJson",
+            InvalidNumber(_) => "InvalidNumber",
+            InvalidOperator(_) => "InvalidOperator",
+            Io(_) => "Io",
+            LiteralOutOfRange(_) => "LiteralOutOfRange",
+            MagnitudeExceeded { .. } => "MagnitudeExceeded",
+            NoConvergence(_) => "NoConvergence",
+            UnrecognizedToken(_) => "UnrecognizedToken",
+            UnexpectedToken(..) => "UnexpectedToken",
+            UnknownAtom(..) => "UnknownAtom",
+            NestingTooDeep => "NestingTooDeep",
+            NotAnInteger { .. } => "NotAnInteger",
+            UnexpectedEndOfInput => "UnexpectedEndOfInput",
+            UnmatchedParenthesis => "UnmatchedParenthesis",
+            Unsolvable(_) => "Unsolvable",
+            WouldOverflow(_) => "WouldOverflow",
+            WouldTruncate(_) => "WouldTruncate",
+            WrongArity { .. } => "WrongArity",
+        }
+    }
+
+    /// What the error found in the input, and what was expected instead,
+    /// for the errors that record them.
+    fn found_expected(&self) -> (Option<String>, Option<String>) {
+        match *self {
+            UnexpectedToken(ref token, kind) => {
+                (Some(token.clone()), Some(kind.into()))
             }
-            UnexpectedToken(ref token, ref kind) => {
-                write!(f, "expected {} token, got {} instead", kind, token)
+            InvalidNumber(ref found)
+            | LiteralOutOfRange(ref found)
+            | UnrecognizedToken(ref found)
+            | UnknownAtom(ref found, _) => (Some(found.clone()), None),
+            InvalidOperator(c) => (Some(c.to_string()), None),
+            InputTooLarge { limit, actual } => {
+                (Some(actual.to_string()), Some(limit.to_string()))
             }
-            UnknownAtom(ref atom) => {
-                write!(f, "unknown variable or function '{}'", atom)
+            MagnitudeExceeded { value, limit } => {
+                (Some(value.to_string()), Some(limit.to_string()))
             }
-            WouldOverflow(ref comp) => {
-                write!(f, "expression '{}' would overflow", comp)
+            NotAnInteger { ref value, .. } => {
+                (Some(value.clone()), Some("integer".into()))
             }
-            WouldTruncate(ref comp) => {
-                write!(f, "expression '{}' would be truncated", comp)
+            WrongArity {
+                expected, actual, ..
+            } => (Some(actual.to_string()), Some(expected.to_string())),
+            _ => (None, None),
+        }
+    }
+
+    /// Describes the error as a JSON object for tools, such as
+    ///
+    /// ```text
+    /// {"kind":"UnexpectedToken","message":"expected number token, got + instead",
+    ///  "span":null,"found":"+","expected":"number","suggestion":null}
+    /// ```
+    ///
+    /// `kind` is that of `kind`, `message` the English message and
+    /// `suggestion` that of `suggestion`. `found` and `expected` are strings
+    /// for errors about unexpected tokens, unknown atoms, bad literals,
+    /// arities and input sizes, and `null` otherwise. An error does not
+    /// record where in the input it occurred, so `span` is `null`;
+    /// `Located::to_json` fills it in with an object of `start` and `end`
+    /// byte offsets. With the `serde` feature, errors serialize to the same
+    /// shape.
+    pub fn to_json(&self) -> String {
+        self.json(None)
+    }
+
+    fn json(&self, span: Option<&Range<usize>>) -> String {
+        let (found, expected) = self.found_expected();
+        let mut out = String::new();
+        out.push_str("{\"kind\":");
+        json::write_string(&mut out, self.kind());
+        out.push_str(",\"message\":");
+        json::write_string(&mut out, &self.to_string());
+        match span {
+            Some(span) => out.push_str(&format!(
+                ",\"span\":{{\"start\":{},\"end\":{}}}",
+                span.start, span.end
+            )),
+            None => out.push_str(",\"span\":null"),
+        }
+        for &(key, ref value) in &[
+            ("found", found),
+            ("expected", expected),
+            ("suggestion", self.suggestion()),
+        ] {
+            out.push_str(&format!(",\"{}\":", key));
+            match *value {
+                Some(ref value) => json::write_string(&mut out, value),
+                None => out.push_str("null"),
+            }
+        }
+        out.push('}');
+        out
+    }
+
+    /// An `UnknownAtom` error for `name`, suggesting the closest of the
+    /// names `env` defines.
+    pub(crate) fn unknown_atom<E: Environment + ?Sized>(
+        name: &str,
+        env: &E,
+    ) -> Self {
+        UnknownAtom(name.to_owned(), closest(name, &env.names()))
+    }
+}
+
+/// The name in `names` with the smallest edit distance to `name`, if that
+/// is at most 2 and less than the length of `name`, so that short names are
+/// not replaced wholesale. Ties go to the alphabetically first name. The
+/// calls that only `eval_to_string` takes as the whole input, such as
+/// `twos`, are known names that get no suggestion.
+pub(crate) fn closest(name: &str, names: &[String]) -> Option<String> {
+    if ::TOP_LEVEL_CALLS.contains(&name) {
+        return None;
+    }
+    let name: Vec<char> = name.chars().collect();
+    names
+        .iter()
+        .filter_map(|candidate| {
+            let distance = edit_distance(&name, candidate);
+            if distance > 0 && distance <= 2 && distance < name.len() {
+                Some((distance, candidate))
+            } else {
+                None
             }
-            UnexpectedEndOfInput => write!(f, "unexpected end of input"),
-            UnmatchedParenthesis => write!(f, "unmatched patenthesis"),
+        })
+        .min()
+        .map(|(_, candidate)| candidate.clone())
+}
+
+/// The Levenshtein distance between two strings.
+fn edit_distance(a: &[char], b: &str) -> usize {
+    let mut row: Vec<usize> = (0..=a.len()).collect();
+    for (i, cb) in b.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &ca) in a.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[a.len()]
+}
+
+/// Composes the human-readable text of errors, e.g. in another language.
+///
+/// A `CalcError` carries everything its message refers to as data: the
+/// offending token, atom or literal, the operands of a `PartialComp`, or the
+/// reason of an `Unsolvable`. Errors carry no source positions. `Display`
+/// uses the English messages of `DefaultCatalog`, which every catalog gets
+/// unless it overrides `message`.
+pub trait MessageCatalog {
+    fn message(&self, err: &CalcError) -> String {
+        english(err)
+    }
+}
+
+/// The English messages.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultCatalog;
+
+impl MessageCatalog for DefaultCatalog {}
+
+fn english(err: &CalcError) -> String {
+    match *err {
+        BadTypes(ref comp) => {
+            format!("expression '{}' is not well typed", comp)
+        }
+        BudgetExceeded => "evaluation budget exceeded".into(),
+        CannotDifferentiate(ref expr) => {
+            format!("cannot differentiate '{}'", expr)
+        }
+        CircularReference(ref cycle) => {
+            format!("circular reference: {}", cycle.join(" -> "))
+        }
+        DivideByZero => "attempted to divide by zero".into(),
+        InputTooLarge { limit, actual } => format!(
+            "input too large: {} exceeds the limit of {}",
+            actual, limit
+        ),
+        InvalidArgument(ref reason) => {
+            format!("invalid argument: {}", reason)
+        }
+        InvalidJson(ref reason) => format!("invalid JSON: {}", reason),
+        InvalidNumber(ref number) => {
+            format!("invalid number: {}", number)
+        }
+        InvalidOperator(ref c) => format!("invalid operator: {}", c),
+        Io(ref message) => format!("I/O error: {}", message),
+        LiteralOutOfRange(ref literal) => {
+            format!("literal out of range: {}", literal)
+        }
+        MagnitudeExceeded { value, limit } => {
+            format!("magnitude of {} exceeds the limit of {}", value, limit)
+        }
+        NoConvergence(ref function) => {
+            format!("{} did not converge", function)
+        }
+        UnrecognizedToken(ref token) => {
+            format!("unrecognized token: {}", token)
+        }
+        UnexpectedToken(ref token, ref kind) => {
+            format!("expected {} token, got {} instead", kind, token)
+        }
+        UnknownAtom(ref atom, _) => {
+            format!("unknown variable or function '{}'", atom)
+        }
+        NestingTooDeep => "expression is nested too deeply".into(),
+        NotAnInteger { ref op, ref value } => {
+            format!("'{}' produced {}, which is not an integer", op, value)
+        }
+        WouldOverflow(ref comp) => {
+            format!("expression '{}' would overflow", comp)
+        }
+        WouldTruncate(ref comp) => {
+            format!("expression '{}' would be truncated", comp)
+        }
+        WrongArity {
+            ref atom,
+            expected,
+            actual,
+        } => format!(
+            "'{}' takes {} argument(s) but was given {}",
+            atom, expected, actual
+        ),
+        UnexpectedEndOfInput => "unexpected end of input".into(),
+        UnmatchedParenthesis => "unmatched patenthesis".into(),
+        Unsolvable(ref reason) => {
+            format!("cannot solve equation: {}", reason)
         }
     }
 }
 
+impl fmt::Display for CalcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&DefaultCatalog.message(self))
+    }
+}
+
+/// An error, with where in the input it occurred, as returned by
+/// `eval_located`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Located {
+    pub error: CalcError,
+    /// Where the error occurred, for errors about a token, such as
+    /// `UnexpectedToken`, `UnrecognizedToken` or `InvalidNumber`, and for
+    /// the input ending too early. `None` for other errors, such as
+    /// `DivideByZero`.
+    pub location: Option<Location>,
+}
+
+/// A place in the input.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Location {
+    /// The byte range of the text an error is about. It is empty at the end
+    /// of the input, and an `UnmatchedParenthesis` is about the parenthesis
+    /// left open, where the parser knows it.
+    pub span: Range<usize>,
+    /// The line of the start of `span`, counting from 1.
+    pub line: usize,
+    /// The column of the start of `span` in its line, counting characters
+    /// from 1, so that `²` takes one column, as it does on screen.
+    pub column: usize,
+}
+
+impl Location {
+    /// Locates `span` in `input`.
+    pub fn new(input: &str, span: Range<usize>) -> Self {
+        let before = &input[..span.start];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        Location {
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            span,
+        }
+    }
+}
+
+impl Located {
+    /// The line of `input` the error is on, with carets under the text the
+    /// error is about, as in
+    ///
+    /// ```text
+    /// 2 * (3 + $)
+    ///          ^
+    /// ```
+    ///
+    /// Returns `None` if the error has no location.
+    pub fn excerpt(&self, input: &str) -> Option<String> {
+        let span = &self.location.as_ref()?.span;
+        let start = input[..span.start].rfind('\n').map_or(0, |i| i + 1);
+        let end = input[start..].find('\n').map_or(input.len(), |i| start + i);
+        let line = input[start..end].trim_end_matches('\r');
+        // Tabs are kept, so that the carets line up however wide they are.
+        let indent: String = input[start..span.start]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let width = input[span.start..span.end.min(end)].chars().count();
+        Some(format!("{}\n{}{}", line, indent, "^".repeat(width.max(1))))
+    }
+
+    /// Describes the error as a JSON object like `CalcError::to_json`,
+    /// with the `span` of its location, if it has one.
+    pub fn to_json(&self) -> String {
+        let span = self.location.as_ref().map(|location| &location.span);
+        self.error.json(span)
+    }
+}
+
+/// Writes the message of the error, followed by its column, and its line
+/// unless that is the first, as in `unknown variable or function 'x' at
+/// column 5`.
+impl fmt::Display for Located {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.error)?;
+        match self.location {
+            Some(Location {
+                line: 1, column, ..
+            }) => {
+                write!(f, " at column {}", column)
+            }
+            Some(Location { line, column, .. }) => {
+                write!(f, " at line {}, column {}", line, column)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl From<Located> for CalcError {
+    fn from(located: Located) -> CalcError {
+        located.error
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for CalcError {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use serde::ser::SerializeStruct;
+
+        let (found, expected) = self.found_expected();
+        let mut state = serializer.serialize_struct("CalcError", 6)?;
+        state.serialize_field("kind", self.kind())?;
+        state.serialize_field("message", &self.to_string())?;
+        state.serialize_field("span", &None::<()>)?;
+        state.serialize_field("found", &found)?;
+        state.serialize_field("expected", &expected)?;
+        state.serialize_field("suggestion", &self.suggestion())?;
+        state.end()
+    }
+}
+
 impl From<ParseBigIntError> for CalcError {
     fn from(data: ParseBigIntError) -> CalcError {
         CalcError::InvalidNumber(data.description().into())
     }
 }
 
+impl From<io::Error> for CalcError {
+    fn from(err: io::Error) -> CalcError {
+        CalcError::Io(err.to_string())
+    }
+}
+
 impl From<CalcError> for String {
     fn from(data: CalcError) -> String {
         format!("{}", data)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use calculator::Calculator;
+    use value::Value;
+
+    /// A catalog matching every variant, so that adding one without a
+    /// message fails to compile.
+    struct German;
+
+    impl MessageCatalog for German {
+        fn message(&self, err: &CalcError) -> String {
+            match *err {
+                BadTypes(ref comp) => {
+                    format!("Ausdruck '{}' ist nicht wohltypisiert", comp)
+                }
+                BudgetExceeded => "Rechenbudget erschöpft".into(),
+                CannotDifferentiate(ref expr) => {
+                    format!("'{}' ist nicht ableitbar", expr)
+                }
+                CircularReference(ref cycle) => {
+                    format!("Zirkelbezug: {}", cycle.join(" -> "))
+                }
+                DivideByZero => "Division durch null".into(),
+                InputTooLarge { limit, actual } => format!(
+                    "Eingabe zu groß: {} überschreitet die Grenze von {}",
+                    actual, limit
+                ),
+                InvalidArgument(ref reason) => {
+                    format!("ungültiges Argument: {}", reason)
+                }
+                InvalidJson(ref reason) => {
+                    format!("ungültiges JSON: {}", reason)
+                }
+                InvalidNumber(ref number) => {
+                    format!("ungültige Zahl: {}", number)
+                }
+                InvalidOperator(ref c) => format!("ungültiger Operator: {}", c),
+                Io(ref message) => format!("E/A-Fehler: {}", message),
+                LiteralOutOfRange(ref literal) => {
+                    format!("Literal außerhalb des Bereichs: {}", literal)
+                }
+                MagnitudeExceeded { value, limit } => format!(
+                    "Betrag von {} überschreitet die Grenze von {}",
+                    value, limit
+                ),
+                NoConvergence(ref function) => {
+                    format!("{} konvergiert nicht", function)
+                }
+                UnrecognizedToken(ref token) => {
+                    format!("unbekanntes Token: {}", token)
+                }
+                UnexpectedToken(ref token, kind) => {
+                    format!("{}-Token erwartet, {} erhalten", kind, token)
+                }
+                UnknownAtom(ref atom, _) => {
+                    format!("unbekannte Variable oder Funktion '{}'", atom)
+                }
+                NestingTooDeep => "Ausdruck ist zu tief verschachtelt".into(),
+                NotAnInteger { ref op, ref value } => {
+                    format!("'{}' ergab {}, keine ganze Zahl", op, value)
+                }
+                WouldOverflow(ref comp) => {
+                    format!("Ausdruck '{}' würde überlaufen", comp)
+                }
+                WouldTruncate(ref comp) => {
+                    format!("Ausdruck '{}' würde abgeschnitten", comp)
+                }
+                WrongArity {
+                    ref atom,
+                    expected,
+                    actual,
+                } => format!(
+                    "'{}' erwartet {} Argument(e), erhielt aber {}",
+                    atom, expected, actual
+                ),
+                UnexpectedEndOfInput => "unerwartetes Ende der Eingabe".into(),
+                UnmatchedParenthesis => "Klammer ohne Gegenstück".into(),
+                Unsolvable(ref reason) => format!(
+                    "Gleichung nicht lösbar: {}",
+                    match *reason {
+                        Unsolvable::NotAnEquation => "keine Gleichung",
+                        Unsolvable::Nonlinear => "nicht linear",
+                        Unsolvable::VariableAbsent => "Variable fehlt",
+                        Unsolvable::Identity => "immer wahr",
+                        Unsolvable::Contradiction => "nie wahr",
+                    }
+                ),
+            }
+        }
+    }
+
+    #[test]
+    fn default_catalog() {
+        for err in &[
+            DivideByZero,
+            UnknownAtom("x".into(), None),
+            BadTypes(PartialComp::binary("+", &1, &2.5)),
+        ] {
+            assert_eq!(DefaultCatalog.message(err), err.to_string());
+        }
+        assert_eq!(DivideByZero.to_string(), "attempted to divide by zero");
+    }
+
+    #[test]
+    fn suggestions() {
+        let err = ::eval("sinn 1").unwrap_err();
+        assert_eq!(err, UnknownAtom("sinn".into(), Some("sin".into())));
+        assert_eq!(err.suggestion(), Some("did you mean 'sin'?".into()));
+
+        let mut calc = Calculator::new();
+        calc.set_var("rate", Value::dec(3));
+        let err = calc.eval("rat * 2").unwrap_err();
+        assert_eq!(err.suggestion(), Some("did you mean 'rate'?".into()));
+
+        let err = ::eval("2.5 ^ 3").unwrap_err();
+        assert_eq!(
+            err.suggestion(),
+            Some("did you mean '**' for exponentiation?".into())
+        );
+
+        for input in &["xyzzy + 1", "q", "1 / 0", "twos(5) + 1"] {
+            assert_eq!(::eval(input).unwrap_err().suggestion(), None);
+        }
+    }
+
+    /// One error of every kind.
+    fn every_kind() -> Vec<CalcError> {
+        let comp = || PartialComp::binary("+", &1, &2);
+        vec![
+            BadTypes(comp()),
+            BudgetExceeded,
+            CannotDifferentiate("x".into()),
+            CircularReference(vec!["a".into(), "a".into()]),
+            DivideByZero,
+            InputTooLarge {
+                limit: 1,
+                actual: 2,
+            },
+            InvalidArgument("x".into()),
+            InvalidJson("x".into()),
+            InvalidNumber("x".into()),
+            InvalidOperator('$'),
+            Io("x".into()),
+            LiteralOutOfRange("x".into()),
+            MagnitudeExceeded {
+                value: 2.0,
+                limit: 1.0,
+            },
+            NoConvergence("x".into()),
+            UnrecognizedToken("x".into()),
+            UnexpectedToken("x".into(), "x"),
+            UnknownAtom("x".into(), None),
+            NestingTooDeep,
+            NotAnInteger {
+                op: "/".into(),
+                value: "2.5".into(),
+            },
+            UnexpectedEndOfInput,
+            UnmatchedParenthesis,
+            Unsolvable(Unsolvable::Identity),
+            WouldOverflow(comp()),
+            WouldTruncate(comp()),
+            WrongArity {
+                atom: "x".into(),
+                expected: 1,
+                actual: 2,
+            },
+        ]
+    }
+
+    #[test]
+    fn kinds() {
+        let errors = every_kind();
+        let mut kinds: Vec<&str> = errors.iter().map(CalcError::kind).collect();
+        for (err, kind) in errors.iter().zip(&kinds) {
+            let debug = format!("{:?}", err);
+            assert_eq!(debug.split(&['(', ' '][..]).next(), Some(*kind));
+        }
+        kinds.sort_unstable();
+        kinds.dedup();
+        assert_eq!(kinds.len(), errors.len());
+    }
+
+    #[test]
+    fn json() {
+        let err = UnexpectedToken("+".into(), "number");
+        assert_eq!(
+            err.to_json(),
+            "{\"kind\":\"UnexpectedToken\",\
+             \"message\":\"expected number token, got + instead\",\
+             \"span\":null,\"found\":\"+\",\"expected\":\"number\",\
+             \"suggestion\":null}"
+        );
+        let err = UnknownAtom("coss".into(), Some("cos".into()));
+        assert_eq!(
+            err.to_json(),
+            "{\"kind\":\"UnknownAtom\",\
+             \"message\":\"unknown variable or function 'coss'\",\
+             \"span\":null,\"found\":\"coss\",\"expected\":null,\
+             \"suggestion\":\"did you mean 'cos'?\"}"
+        );
+        for err in every_kind() {
+            assert!(json::parse(&err.to_json()).is_ok(), "{:?}", err);
+        }
+    }
+
+    #[test]
+    fn locations() {
+        let locate = |input: &str| {
+            let err = ::eval_located(input).unwrap_err();
+            (err.error, err.location.map(|l| (l.span, l.line, l.column)))
+        };
+        assert_eq!(
+            locate("2² + xyzzy"),
+            (UnknownAtom("xyzzy".into(), None), Some((6..11, 1, 6)))
+        );
+        assert_eq!(
+            locate("1 +\n2 * )"),
+            (
+                UnexpectedToken("CloseParen".into(), "number"),
+                Some((8..9, 2, 5))
+            )
+        );
+        assert_eq!(
+            locate("0b12 + 1"),
+            (InvalidNumber("0b12".into()), Some((0..4, 1, 1)))
+        );
+        assert_eq!(
+            locate("2 * (3 + 1"),
+            (UnmatchedParenthesis, Some((4..5, 1, 5)))
+        );
+        assert_eq!(locate("1 + "), (UnexpectedEndOfInput, Some((4..4, 1, 5))));
+        assert_eq!(locate("1 / 0"), (DivideByZero, None));
+        assert_eq!(::eval_located("2 ** 10"), Ok(Value::dec(1024)));
+    }
+
+    #[test]
+    fn excerpts() {
+        let input = "1 +\n\t2 * foo";
+        let err = ::eval_located(input).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unknown variable or function 'foo' at line 2, column 6"
+        );
+        assert_eq!(err.excerpt(input).unwrap(), "\t2 * foo\n\t    ^^^");
+        assert!(err.to_json().contains("\"span\":{\"start\":9,\"end\":12}"));
+        assert_eq!(CalcError::from(err.clone()), err.error);
+
+        let err = ::eval_located("1 / 0").unwrap_err();
+        assert_eq!(err.to_string(), "attempted to divide by zero");
+        assert_eq!(err.excerpt("1 / 0"), None);
+        assert_eq!(err.to_json(), DivideByZero.to_json());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serialize() {
+        use serde_json;
+        for err in every_kind() {
+            let serialized = serde_json::to_value(&err).unwrap();
+            let written: serde_json::Value =
+                serde_json::from_str(&err.to_json()).unwrap();
+            assert_eq!(serialized, written);
+        }
+    }
+
+    #[test]
+    fn german_catalog() {
+        assert_eq!(German.message(&DivideByZero), "Division durch null");
+        assert_eq!(
+            German.message(&WrongArity {
+                atom: "sin".into(),
+                expected: 1,
+                actual: 2,
+            }),
+            "'sin' erwartet 1 Argument(e), erhielt aber 2"
+        );
+        assert_eq!(
+            German.message(&WouldOverflow(PartialComp::binary("**", &2, &99))),
+            "Ausdruck '2 ** 99' würde überlaufen"
+        );
+        assert_eq!(
+            German.message(&Unsolvable(Unsolvable::Nonlinear)),
+            "Gleichung nicht lösbar: nicht linear"
+        );
+    }
+}
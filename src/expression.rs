@@ -0,0 +1,654 @@
+use ast::{self, BinaryOp, Binder, Bound, Expr, Symbolic, UnaryOp};
+use bytecode::{self, CompiledExpr};
+use context::Context;
+use error::CalcError;
+use parse::{self, Builder, DefaultEnvironment, Environment};
+use std::fmt;
+use std::slice;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use token::TokenStream;
+use value::Value;
+
+/// A parsed expression that can be evaluated repeatedly.
+///
+/// Parsing checks the syntax up front; atoms that are not known to the
+/// `DefaultEnvironment` are kept as variables and only need a value once
+/// the expression is evaluated.
+///
+/// Unlike an `Expr`, which allocates every node on its own, the nodes are
+/// stored in a single flat list in post-order. This keeps compiling large
+/// numbers of formulas cheap, and evaluation is a single pass over the
+/// list. The syntax tree returned by `expr` is only built when it is first
+/// asked for.
+///
+/// ```
+/// # extern crate calc;
+/// # use calc::{CalcError, Context, Expression, Value};
+/// # fn main() -> Result<(), CalcError> {
+/// let e: Expression = "1+2*3".parse()?;
+/// assert_eq!(e.value()?, 7.0);
+/// assert_eq!(e.to_string(), "1 + 2 * 3");
+///
+/// let total: Expression = "price * (1 + tax)".parse()?;
+/// let mut context = Context::new();
+/// context.set_var("price", Value::dec(20));
+/// context.set_var("tax", Value::from_f64(0.25));
+/// assert_eq!(total.value_with(&context)?, 25.0);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Expression {
+    nodes: Vec<Node>,
+    /// The syntax tree, once `expr` has built it.
+    tree: OnceLock<Expr>,
+}
+
+impl PartialEq for Expression {
+    fn eq(&self, other: &Self) -> bool {
+        self.nodes == other.nodes
+    }
+}
+
+/// A node of an `Expression`. The operands of a node are the subtrees that
+/// immediately precede it, so they are found by evaluating the nodes in
+/// order with a stack. Only `Let` and `Cond` come before subtrees they
+/// control, so that these are evaluated when needed.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Node {
+    Num(Value),
+    Var(String),
+    /// An atom applied to the given number of arguments.
+    Call(String, u32),
+    Unary(UnaryOp),
+    Binary(BinaryOp),
+    /// A function like `sum` or `integrate` of a variable, applied to the
+    /// given number of preceding arguments. The body is kept as a tree, as
+    /// it is evaluated repeatedly.
+    Binder(Binder, String, Box<Expr>, u32),
+    /// Binds the value before it to the name in the given number of nodes
+    /// after it, which are the body of a `let`. The value is only
+    /// evaluated once, however often the body uses it.
+    Let(String, u32),
+    /// A `cond` of the subtrees of the given lengths after it. Only the
+    /// conditions up to the first that holds, and the value it chooses,
+    /// are evaluated.
+    Cond(Vec<u32>),
+}
+
+impl Expression {
+    /// Parses an expression that may call the functions registered with
+    /// `context`. Parsing with `str::parse` only knows the functions of the
+    /// `DefaultEnvironment`, as it needs to know how many arguments every
+    /// function takes.
+    pub fn parse_with(
+        input: &str,
+        context: &Context,
+    ) -> Result<Self, CalcError> {
+        let mut env = context;
+        parse_with(input, &mut env)
+    }
+
+    /// The syntax tree of the expression, built from the node list the
+    /// first time it is needed.
+    pub fn expr(&self) -> &Expr {
+        self.tree.get_or_init(|| to_expr(&self.nodes))
+    }
+
+    /// Builds a new syntax tree of the expression.
+    pub fn to_expr(&self) -> Expr {
+        to_expr(&self.nodes)
+    }
+
+    /// Evaluates the expression in the `DefaultEnvironment`.
+    pub fn value(&self) -> Result<f64, CalcError> {
+        evaluate(&self.nodes, &mut DefaultEnvironment).map(|v| v.as_f64())
+    }
+
+    /// Evaluates the expression, resolving its variables from `context`.
+    pub fn value_with(&self, context: &Context) -> Result<f64, CalcError> {
+        let mut env = context;
+        evaluate(&self.nodes, &mut env).map(|v| v.as_f64())
+    }
+
+    /// Compiles the expression to bytecode for evaluating it in `f64` with
+    /// different values of `vars`. Every other atom must be known to the
+    /// `DefaultEnvironment`.
+    pub fn compile(&self, vars: &[&str]) -> Result<CompiledExpr, CalcError> {
+        bytecode::compile(&self.nodes, vars)
+    }
+}
+
+impl FromStr for Expression {
+    type Err = CalcError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        parse_with(input, &mut DefaultEnvironment)
+    }
+}
+
+fn parse_with<E: Environment>(
+    input: &str,
+    env: &mut E,
+) -> Result<Expression, CalcError> {
+    let operators = env.operators();
+    let tokens = TokenStream::with_operators(input, &operators)?;
+    let fragment: Fragment = parse::build(&tokens, &mut Symbolic(env))?;
+    Ok(Expression {
+        nodes: fragment.into_nodes(0),
+        tree: OnceLock::new(),
+    })
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.expr().fmt(f)
+    }
+}
+
+impl From<Expr> for Expression {
+    fn from(expr: Expr) -> Self {
+        let mut nodes = Vec::new();
+        flatten(expr, &mut nodes);
+        Expression {
+            nodes,
+            tree: OnceLock::new(),
+        }
+    }
+}
+
+fn flatten(expr: Expr, nodes: &mut Vec<Node>) {
+    let node = match expr {
+        Expr::Num(value) => Node::Num(value),
+        Expr::Var(name) => Node::Var(name),
+        Expr::Call(name, mut args)
+            if name == "let" && ast::let_parts(&args).is_ok() =>
+        {
+            let body = args.pop().expect("checked above");
+            flatten(args.pop().expect("checked above"), nodes);
+            let var = match args.pop() {
+                Some(Expr::Var(var)) => var,
+                _ => unreachable!("checked above"),
+            };
+            let at = nodes.len();
+            nodes.push(Node::Let(var, 0));
+            flatten(body, nodes);
+            let body_len = (nodes.len() - at - 1) as u32;
+            if let Node::Let(_, ref mut len) = nodes[at] {
+                *len = body_len;
+            }
+            return;
+        }
+        Expr::Call(name, args) if name == "cond" => {
+            let at = nodes.len();
+            nodes.push(Node::Cond(Vec::new()));
+            let mut lens = Vec::with_capacity(args.len());
+            for arg in args {
+                let start = nodes.len();
+                flatten(arg, nodes);
+                lens.push((nodes.len() - start) as u32);
+            }
+            nodes[at] = Node::Cond(lens);
+            return;
+        }
+        Expr::Call(name, args) => match binder_parts(&name, &args) {
+            Some((binder, var, body, values)) => {
+                let count = values.len() as u32;
+                for value in values {
+                    flatten(value.clone(), nodes);
+                }
+                Node::Binder(
+                    binder,
+                    var.to_owned(),
+                    Box::new(body.clone()),
+                    count,
+                )
+            }
+            None => {
+                let count = args.len() as u32;
+                for arg in args {
+                    flatten(arg, nodes);
+                }
+                Node::Call(name, count)
+            }
+        },
+        Expr::Unary(op, arg) => {
+            flatten(*arg, nodes);
+            Node::Unary(op)
+        }
+        Expr::Binary(op, lhs, rhs) => {
+            flatten(*lhs, nodes);
+            flatten(*rhs, nodes);
+            Node::Binary(op)
+        }
+    };
+    nodes.push(node);
+}
+
+/// The parts of a call of a function like `sum`, if it is one and its
+/// arguments are valid. Other calls are resolved by the environment.
+fn binder_parts<'a>(
+    name: &str,
+    args: &'a [Expr],
+) -> Option<(Binder, &'a str, &'a Expr, Vec<&'a Expr>)> {
+    let binder = Binder::from_name(name)?;
+    let (var, body, values) = binder.parts(args).ok()?;
+    Some((binder, var, body, values))
+}
+
+fn to_expr(nodes: &[Node]) -> Expr {
+    let mut stack = Vec::new();
+    // The `let`s and `cond`s whose subtrees are not complete yet, with the
+    // index of their last node.
+    let mut open: Vec<(&Node, usize)> = Vec::new();
+    for (i, node) in nodes.iter().enumerate() {
+        let expr = match *node {
+            Node::Num(ref value) => Expr::Num(value.clone()),
+            Node::Var(ref name) => Expr::var(name.as_str()),
+            Node::Call(ref name, count) => {
+                let args = stack.split_off(stack.len() - count as usize);
+                Expr::call(name.as_str(), args)
+            }
+            Node::Unary(op) => {
+                let arg = stack.pop().expect("unary operators have an operand");
+                Expr::Unary(op, Box::new(arg))
+            }
+            Node::Binary(op) => {
+                let rhs = stack.pop().expect("binary operators have operands");
+                let lhs = stack.pop().expect("binary operators have operands");
+                Expr::binary(op, lhs, rhs)
+            }
+            Node::Binder(binder, ref var, ref body, count) => {
+                let args = stack.split_off(stack.len() - count as usize);
+                let args = binder.arguments(var, (**body).clone(), args);
+                Expr::call(binder.name(), args)
+            }
+            Node::Let(_, len) => {
+                // The value stays on the stack until the body is complete.
+                open.push((node, i + len as usize));
+                continue;
+            }
+            Node::Cond(ref lens) if lens.is_empty() => {
+                Expr::call("cond", Vec::new())
+            }
+            Node::Cond(ref lens) => {
+                let len = lens.iter().sum::<u32>() as usize;
+                open.push((node, i + len));
+                continue;
+            }
+        };
+        stack.push(expr);
+        while open.last().is_some_and(|&(_, end)| end == i) {
+            let (node, _) = open.pop().expect("checked by the loop");
+            let expr = match *node {
+                Node::Let(ref name, _) => {
+                    let body = stack.pop().expect("let has a body");
+                    let value = stack.pop().expect("let has a value");
+                    let args = vec![Expr::var(name.as_str()), value, body];
+                    Expr::call("let", args)
+                }
+                Node::Cond(ref lens) => {
+                    let args = stack.split_off(stack.len() - lens.len());
+                    Expr::call("cond", args)
+                }
+                _ => unreachable!("only lets and conds are open"),
+            };
+            stack.push(expr);
+        }
+    }
+    stack.pop().expect("expressions have a root")
+}
+
+fn evaluate<E: Environment>(
+    nodes: &[Node],
+    env: &mut E,
+) -> Result<Value, CalcError> {
+    let mut stack: Vec<Value> = Vec::new();
+    let mut i = 0;
+    while i < nodes.len() {
+        let node = &nodes[i];
+        i += 1;
+        env.step()?;
+        let value = match *node {
+            Node::Num(ref value) => value.clone(),
+            Node::Var(ref name) => resolve(name, &[], env)?,
+            Node::Call(ref name, count) => {
+                let args = stack.split_off(stack.len() - count as usize);
+                resolve(name, &args, env)?
+            }
+            Node::Unary(op) => {
+                let arg = stack.pop().expect("unary operators have an operand");
+                op.apply(arg)?
+            }
+            Node::Binary(op) => {
+                let rhs = stack.pop().expect("binary operators have operands");
+                let lhs = stack.pop().expect("binary operators have operands");
+                parse::binary(&*env, op, lhs, rhs)?
+            }
+            Node::Binder(binder, ref var, ref body, count) => {
+                let args = stack.split_off(stack.len() - count as usize);
+                binder.apply(env, var, body, &args)?
+            }
+            Node::Let(ref name, len) => {
+                let value = stack.pop().expect("let has a value");
+                let body = &nodes[i..i + len as usize];
+                i += len as usize;
+                let mut scope = Bound { name, value, env };
+                evaluate(body, &mut scope)?
+            }
+            Node::Cond(ref lens) => {
+                parse::check_cond_arity(lens.len())?;
+                let mut args = Vec::with_capacity(lens.len());
+                for &len in lens {
+                    args.push(&nodes[i..i + len as usize]);
+                    i += len as usize;
+                }
+                let mut chosen = args[args.len() - 1];
+                for pair in args.chunks(2).filter(|pair| pair.len() == 2) {
+                    if parse::truth(&evaluate(pair[0], env)?) {
+                        chosen = pair[1];
+                        break;
+                    }
+                }
+                evaluate(chosen, env)?
+            }
+        };
+        let op = match *node {
+            Node::Num(_) => "literal",
+            Node::Var(ref name) | Node::Call(ref name, _) => name,
+            Node::Unary(op) => op.symbol(),
+            Node::Binary(op) => op.symbol(),
+            Node::Binder(binder, ..) => binder.name(),
+            Node::Let(..) => "let",
+            Node::Cond(_) => "cond",
+        };
+        parse::check_result(&*env, op, &value)?;
+        stack.push(value);
+    }
+    Ok(stack.pop().expect("expressions have a root"))
+}
+
+fn resolve<E: Environment>(
+    name: &str,
+    args: &[Value],
+    env: &mut E,
+) -> Result<Value, CalcError> {
+    match env.arity(name) {
+        Some(arity) if arity == args.len() => env.resolve(name, args),
+        Some(arity) => Err(CalcError::WrongArity {
+            atom: name.to_owned(),
+            expected: arity,
+            actual: args.len(),
+        }),
+        None => Err(CalcError::unknown_atom(name, &*env)),
+    }
+}
+
+/// A subtree in post-order, as built by the grammar rules while compiling.
+/// Single nodes are kept inline, so that only operators allocate, and
+/// chains of them append to the list of their left operand.
+#[derive(Clone)]
+enum Fragment {
+    Node(Node),
+    Nodes(Vec<Node>),
+}
+
+impl Fragment {
+    fn len(&self) -> usize {
+        match *self {
+            Fragment::Node(_) => 1,
+            Fragment::Nodes(ref nodes) => nodes.len(),
+        }
+    }
+
+    /// Returns the nodes, with room for `additional` more.
+    fn into_nodes(self, additional: usize) -> Vec<Node> {
+        match self {
+            Fragment::Node(node) => {
+                let mut nodes = Vec::with_capacity(1 + additional);
+                nodes.push(node);
+                nodes
+            }
+            Fragment::Nodes(mut nodes) => {
+                nodes.reserve(additional);
+                nodes
+            }
+        }
+    }
+
+    fn append_to(self, nodes: &mut Vec<Node>) {
+        match self {
+            Fragment::Node(node) => nodes.push(node),
+            Fragment::Nodes(more) => nodes.extend(more),
+        }
+    }
+}
+
+impl fmt::Display for Fragment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Fragment::Node(ref node) => to_expr(slice::from_ref(node)).fmt(f),
+            Fragment::Nodes(ref nodes) => to_expr(nodes).fmt(f),
+        }
+    }
+}
+
+impl Builder for Fragment {
+    fn literal(value: &Value) -> Result<Self, CalcError> {
+        Ok(Fragment::Node(Node::Num(value.clone())))
+    }
+
+    fn atom<E: Environment>(
+        _: &mut E,
+        name: &str,
+        args: Vec<Self>,
+    ) -> Result<Self, CalcError> {
+        if args.is_empty() {
+            return Ok(Fragment::Node(Node::Var(name.to_owned())));
+        }
+        let len = args.iter().map(Fragment::len).sum::<usize>();
+        let mut nodes = Vec::with_capacity(len + 1);
+        if name == "cond" {
+            let lens = args.iter().map(|arg| arg.len() as u32).collect();
+            nodes.push(Node::Cond(lens));
+            for arg in args {
+                arg.append_to(&mut nodes);
+            }
+            return Ok(Fragment::Nodes(nodes));
+        }
+        let count = args.len() as u32;
+        for arg in args {
+            arg.append_to(&mut nodes);
+        }
+        nodes.push(Node::Call(name.to_owned(), count));
+        Ok(Fragment::Nodes(nodes))
+    }
+
+    fn unary(op: UnaryOp, arg: Self) -> Result<Self, CalcError> {
+        // Like `Expr::unary`, fold negated literals.
+        match (op, arg) {
+            (UnaryOp::Neg, Fragment::Node(Node::Num(value))) => {
+                Ok(Fragment::Node(Node::Num(-value)))
+            }
+            (op, arg) => {
+                let mut nodes = arg.into_nodes(1);
+                nodes.push(Node::Unary(op));
+                Ok(Fragment::Nodes(nodes))
+            }
+        }
+    }
+
+    fn binary(op: BinaryOp, lhs: Self, rhs: Self) -> Result<Self, CalcError> {
+        let mut nodes = lhs.into_nodes(rhs.len() + 1);
+        rhs.append_to(&mut nodes);
+        nodes.push(Node::Binary(op));
+        Ok(Fragment::Nodes(nodes))
+    }
+
+    fn bind<E: Environment>(
+        _: &mut E,
+        binder: Binder,
+        var: &str,
+        body: Expr,
+        args: Vec<Self>,
+    ) -> Result<Self, CalcError> {
+        let count = args.len() as u32;
+        let mut nodes = Vec::new();
+        for arg in args {
+            arg.append_to(&mut nodes);
+        }
+        nodes.push(Node::Binder(binder, var.to_owned(), Box::new(body), count));
+        Ok(Fragment::Nodes(nodes))
+    }
+
+    fn bound(name: &str, _: &Self) -> Self {
+        Fragment::Node(Node::Var(name.to_owned()))
+    }
+
+    fn bind_let(
+        name: &str,
+        value: Self,
+        body: Self,
+    ) -> Result<Self, CalcError> {
+        let len = body.len();
+        let mut nodes = value.into_nodes(len + 1);
+        nodes.push(Node::Let(name.to_owned(), len as u32));
+        body.append_to(&mut nodes);
+        Ok(Fragment::Nodes(nodes))
+    }
+
+    fn to_expr(&self) -> Expr {
+        match *self {
+            Fragment::Node(ref node) => to_expr(slice::from_ref(node)),
+            Fragment::Nodes(ref nodes) => to_expr(nodes),
+        }
+    }
+}
+
+/// Serializes to the canonical source form, e.g. `"2 * baseline + 5"`.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Expression {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+/// Deserializes from a string, which must parse as an expression. Parse
+/// failures are reported as custom errors carrying the `CalcError` message,
+/// which the deserializer decorates with the position of the field.
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Expression {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        struct ExpressionVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for ExpressionVisitor {
+            type Value = Expression;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a mathematical expression")
+            }
+
+            fn visit_str<E>(self, input: &str) -> Result<Expression, E>
+            where
+                E: serde::de::Error,
+            {
+                input.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_str(ExpressionVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for input in &["1 + 2 * 3", "-(x + 1) ** 2", "sin (2 * x) / y"] {
+            let e: Expression = input.parse().unwrap();
+            assert_eq!(e.to_string().parse::<Expression>(), Ok(e));
+        }
+        assert_eq!(
+            "1 +".parse::<Expression>(),
+            Err(CalcError::UnexpectedEndOfInput)
+        );
+    }
+
+    #[test]
+    fn trees() {
+        let input = "-(x + 1) ** 2 / log (y * 3) - 2 * z";
+        let e: Expression = input.parse().unwrap();
+        let expr = Expr::parse(input).unwrap();
+        assert_eq!(e.to_expr(), expr);
+        assert_eq!(e.expr(), &expr);
+        assert_eq!(Expression::from(expr), e);
+    }
+
+    #[test]
+    fn variables() {
+        let e: Expression = "x * y + 1".parse().unwrap();
+        assert_eq!(e.value(), Err(CalcError::UnknownAtom("x".into(), None)));
+        let mut context = Context::new();
+        context.set_var("x", Value::dec(3));
+        context.set_var("y", Value::from_f64(0.5));
+        assert_eq!(e.value_with(&context), Ok(2.5));
+        context.set_var("x", Value::dec(5));
+        assert_eq!(e.value_with(&context), Ok(3.5));
+    }
+
+    #[cfg(feature = "serde")]
+    mod serde {
+        use super::*;
+        use serde_json;
+        use toml;
+
+        #[derive(Debug, Deserialize, Serialize)]
+        struct Config {
+            name: String,
+            threshold: Expression,
+        }
+
+        #[test]
+        fn json() {
+            let input = r#"{"name":"cpu","threshold":"2*baseline+5"}"#;
+            let config: Config = serde_json::from_str(input).unwrap();
+            let mut context = Context::new();
+            context.set_var("baseline", Value::dec(10));
+            assert_eq!(config.threshold.value_with(&context), Ok(25.0));
+            assert_eq!(
+                serde_json::to_string(&config).unwrap(),
+                r#"{"name":"cpu","threshold":"2 * baseline + 5"}"#
+            );
+
+            let input =
+                "{\"name\": \"cpu\",\n \"threshold\": \"2 * (baseline\"}";
+            let err = serde_json::from_str::<Config>(input).unwrap_err();
+            let message = CalcError::UnmatchedParenthesis.to_string();
+            assert!(err.to_string().contains(&message), "{}", err);
+            assert_eq!(err.line(), 2);
+        }
+
+        #[test]
+        fn toml() {
+            let input = "name = \"cpu\"\nthreshold = \"2 * baseline + 5\"\n";
+            let config: Config = toml::from_str(input).unwrap();
+            assert_eq!(config.threshold.to_string(), "2 * baseline + 5");
+            assert_eq!(toml::to_string(&config).unwrap(), input);
+
+            let input = "name = \"cpu\"\nthreshold = \"2 +\"\n";
+            let err = toml::from_str::<Config>(input).unwrap_err();
+            let message = CalcError::UnexpectedEndOfInput.to_string();
+            assert!(err.to_string().contains(&message), "{}", err);
+            assert_eq!(err.line_col().map(|(line, _)| line), Some(1));
+        }
+    }
+}
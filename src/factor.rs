@@ -0,0 +1,164 @@
+//! The number-theoretic functions of the `DefaultEnvironment`, which are
+//! all computed from the prime factorization of their argument.
+
+use error::CalcError;
+use num::{self, One, ToPrimitive, Zero};
+use value::{Integral, Value};
+
+/// The largest argument accepted, `2 ** 53`. Beyond it, not every integer
+/// survives a trip through an `f64`.
+const MAX_ARGUMENT: u64 = 1 << 53;
+
+/// Checks that the argument of `name` is an integer from 1 to
+/// `MAX_ARGUMENT`.
+fn argument(name: &str, value: &Value) -> Result<u64, CalcError> {
+    let n = value.to_integral().ok_or_else(|| {
+        CalcError::InvalidArgument(format!(
+            "{} needs an integer, got {}",
+            name, value
+        ))
+    })?;
+    if n <= Integral::zero() {
+        return Err(CalcError::InvalidArgument(format!(
+            "{} needs a positive integer, got {}",
+            name, n
+        )));
+    }
+    n.to_u64().filter(|&n| n <= MAX_ARGUMENT).ok_or_else(|| {
+        CalcError::InvalidArgument(format!(
+            "{} needs an integer of at most 2 ** 53, got {}",
+            name, n
+        ))
+    })
+}
+
+/// The prime factors of `n` and their exponents, from the smallest prime
+/// on, found by trial division.
+fn factorize(mut n: u64) -> Vec<(u64, u32)> {
+    let mut factors = Vec::new();
+    let mut divide_out = |n: &mut u64, p: u64| {
+        let mut exponent = 0;
+        while let (quotient, 0) = (*n / p, *n % p) {
+            *n = quotient;
+            exponent += 1;
+        }
+        if exponent > 0 {
+            factors.push((p, exponent));
+        }
+    };
+    divide_out(&mut n, 2);
+    divide_out(&mut n, 3);
+    // Beyond 3, every prime is one off a multiple of 6.
+    let mut p = 5;
+    while p * p <= n {
+        divide_out(&mut n, p);
+        divide_out(&mut n, p + 2);
+        p += 6;
+    }
+    if n > 1 {
+        factors.push((n, 1));
+    }
+    factors
+}
+
+/// Euler's φ: how many of the integers from 1 to `n` are coprime to `n`.
+pub(crate) fn totient(n: &Value) -> Result<Value, CalcError> {
+    let phi = factorize(argument("totient", n)?)
+        .into_iter()
+        .map(|(p, k)| (p - 1) * p.pow(k - 1))
+        .product::<u64>();
+    Ok(Value::dec(phi))
+}
+
+/// The number of divisors of `n`, including 1 and `n`.
+pub(crate) fn numdiv(n: &Value) -> Result<Value, CalcError> {
+    let count = factorize(argument("numdiv", n)?)
+        .into_iter()
+        .map(|(_, k)| u64::from(k) + 1)
+        .product::<u64>();
+    Ok(Value::dec(count))
+}
+
+/// The sum of the divisors of `n`, including 1 and `n`.
+pub(crate) fn sumdiv(n: &Value) -> Result<Value, CalcError> {
+    // The sums of the powers of each prime multiply, and may exceed a
+    // `u64` for the largest arguments.
+    let sum = factorize(argument("sumdiv", n)?).into_iter().fold(
+        Integral::one(),
+        |sum, (p, k)| {
+            let p = Integral::from(p);
+            sum * ((num::pow(p.clone(), k as usize + 1) - 1) / (p - 1))
+        },
+    );
+    Ok(Value::dec(sum))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eval;
+
+    #[test]
+    fn known_values() {
+        let cases = vec![
+            ("totient(1)", 1),
+            ("totient(10)", 4),
+            ("totient(36)", 12),
+            ("totient(97)", 96),
+            ("numdiv(1)", 1),
+            ("numdiv(36)", 9),
+            ("numdiv(97)", 2),
+            ("numdiv(2 ** 10)", 11),
+            ("sumdiv(1)", 1),
+            ("sumdiv(12)", 28),
+            ("sumdiv(97)", 98),
+            ("sumdiv(36.0)", 91),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(eval(input), Ok(Value::dec(expected)), "{}", input);
+        }
+        // 28 is perfect: its divisors other than itself add up to it.
+        assert_eq!(eval("sumdiv(28) - 28"), Ok(Value::dec(28)));
+    }
+
+    #[test]
+    fn semiprime() {
+        let n = 999_983u64 * 1_000_003;
+        assert_eq!(factorize(n), vec![(999_983, 1), (1_000_003, 1)]);
+        let n = Value::dec(n);
+        assert_eq!(totient(&n), Ok(Value::dec(999_983_999_964u64)));
+        assert_eq!(numdiv(&n), Ok(Value::dec(4)));
+        assert_eq!(sumdiv(&n), Ok(Value::dec(999_987_999_936u64)));
+        assert_eq!(
+            factorize(2 * 2 * 3 * 49 * 13),
+            vec![(2, 2), (3, 1), (7, 2), (13, 1)]
+        );
+    }
+
+    #[test]
+    fn domain() {
+        assert_eq!(
+            eval("totient(0)"),
+            Err(CalcError::InvalidArgument(
+                "totient needs a positive integer, got 0".into()
+            ))
+        );
+        assert!(eval("numdiv(-6)").is_err());
+        assert!(eval("sumdiv(-(2 ** 70))").is_err());
+        assert_eq!(
+            eval("numdiv(2.5)"),
+            Err(CalcError::InvalidArgument(
+                "numdiv needs an integer, got 2.5".into()
+            ))
+        );
+        assert_eq!(
+            eval("sumdiv(2 ** 53 + 1)"),
+            Err(CalcError::InvalidArgument(
+                "sumdiv needs an integer of at most 2 ** 53, got \
+                 9007199254740993"
+                    .into()
+            ))
+        );
+        assert_eq!(eval("numdiv(2 ** 53)"), Ok(Value::dec(54)));
+    }
+}
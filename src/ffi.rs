@@ -0,0 +1,201 @@
+//! C bindings for evaluating expressions from a foreign host.
+//!
+//! All entry points are safe to call from C: null pointers and invalid UTF-8
+//! are reported through return codes, and panics are caught before they can
+//! unwind across the FFI boundary. The matching declarations can be found in
+//! `include/calc.h`.
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+use error::CalcError;
+
+/// Returned by `calc_eval` when the expression was evaluated successfully.
+pub const CALC_OK: i32 = 0;
+/// Returned when the input or output pointer is null.
+pub const CALC_ERR_NULL_POINTER: i32 = -1;
+/// Returned when the input is not valid UTF-8.
+pub const CALC_ERR_INVALID_UTF8: i32 = -2;
+/// Returned when evaluation panicked. This always indicates a bug in calc.
+pub const CALC_ERR_PANIC: i32 = -3;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Maps every `CalcError` variant to a stable, positive error code.
+///
+/// These numbers are part of the C API and must never be reused or changed.
+pub fn error_code(err: &CalcError) -> i32 {
+    match *err {
+        CalcError::BadTypes(_) => 1,
+        CalcError::DivideByZero => 2,
+        CalcError::InvalidNumber(_) => 3,
+        CalcError::InvalidOperator(_) => 4,
+        CalcError::UnrecognizedToken(_) => 5,
+        CalcError::UnexpectedToken(..) => 6,
+        CalcError::UnknownAtom(..) => 7,
+        CalcError::UnexpectedEndOfInput => 8,
+        CalcError::UnmatchedParenthesis => 9,
+        CalcError::WouldOverflow(_) => 10,
+        CalcError::WouldTruncate(_) => 11,
+        CalcError::BudgetExceeded => 12,
+        CalcError::InputTooLarge { .. } => 13,
+        CalcError::NestingTooDeep => 14,
+        CalcError::WrongArity { .. } => 15,
+        CalcError::CannotDifferentiate(_) => 16,
+        CalcError::Unsolvable(_) => 17,
+        CalcError::Io(_) => 18,
+        CalcError::LiteralOutOfRange(_) => 19,
+        CalcError::InvalidArgument(_) => 20,
+        CalcError::InvalidJson(_) => 21,
+        CalcError::CircularReference(_) => 22,
+        CalcError::NoConvergence(_) => 23,
+        CalcError::MagnitudeExceeded { .. } => 24,
+        CalcError::NotAnInteger { .. } => 25,
+    }
+}
+
+fn set_last_error<T: Into<Vec<u8>>>(message: T) {
+    // Interior NUL bytes (e.g. echoed from an unrecognized token) cannot be
+    // represented in a C string, so they are dropped.
+    let bytes: Vec<u8> =
+        message.into().into_iter().filter(|&b| b != 0).collect();
+    let message = CString::new(bytes).expect("NUL bytes were filtered out");
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+fn clear_last_error() {
+    LAST_ERROR.with(|last| *last.borrow_mut() = None);
+}
+
+/// Evaluates the NUL-terminated UTF-8 expression `input` and writes the
+/// result to `out`.
+///
+/// Returns `CALC_OK` on success, the positive `error_code` of the
+/// `CalcError` on evaluation failure, or one of the negative `CALC_ERR_*`
+/// codes. On failure `out` is left untouched and a description is available
+/// through `calc_last_error_message`.
+///
+/// # Safety
+///
+/// `input` must be null or point to a NUL-terminated string, and `out` must
+/// be null or point to writable memory for an `f64`.
+#[no_mangle]
+pub unsafe extern "C" fn calc_eval(input: *const c_char, out: *mut f64) -> i32 {
+    if input.is_null() || out.is_null() {
+        set_last_error("null pointer passed to calc_eval");
+        return CALC_ERR_NULL_POINTER;
+    }
+    let input = match CStr::from_ptr(input).to_str() {
+        Ok(input) => input,
+        Err(e) => {
+            set_last_error(format!("input is not valid UTF-8: {}", e));
+            return CALC_ERR_INVALID_UTF8;
+        }
+    };
+    match panic::catch_unwind(AssertUnwindSafe(|| ::eval(input))) {
+        Ok(Ok(value)) => {
+            clear_last_error();
+            *out = value.as_f64();
+            CALC_OK
+        }
+        Ok(Err(e)) => {
+            set_last_error(e.to_string());
+            error_code(&e)
+        }
+        Err(_) => {
+            set_last_error("internal error: evaluation panicked");
+            CALC_ERR_PANIC
+        }
+    }
+}
+
+/// Returns a copy of the message describing the last failure on the calling
+/// thread, or null if the last call succeeded. The returned string must be
+/// released with `calc_string_free`.
+#[no_mangle]
+pub extern "C" fn calc_last_error_message() -> *mut c_char {
+    LAST_ERROR.with(|last| match *last.borrow() {
+        Some(ref message) => message.clone().into_raw(),
+        None => ptr::null_mut(),
+    })
+}
+
+/// Releases a string previously returned by this library. Passing null is a
+/// no-op.
+///
+/// # Safety
+///
+/// `s` must be null or a pointer obtained from this library that has not
+/// been freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn calc_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn last_error() -> Option<String> {
+        let ptr = calc_last_error_message();
+        if ptr.is_null() {
+            return None;
+        }
+        let message = unsafe { CStr::from_ptr(ptr) }
+            .to_string_lossy()
+            .into_owned();
+        unsafe { calc_string_free(ptr) };
+        Some(message)
+    }
+
+    #[test]
+    fn evaluates() {
+        let input = CString::new("3 * (4 + 1)").unwrap();
+        let mut out = 0.0;
+        let code = unsafe { calc_eval(input.as_ptr(), &mut out) };
+        assert_eq!(code, CALC_OK);
+        assert_eq!(out, 15.0);
+        assert_eq!(last_error(), None);
+    }
+
+    #[test]
+    fn reports_calc_errors() {
+        let input = CString::new("1 / 0").unwrap();
+        let mut out = 42.0;
+        let code = unsafe { calc_eval(input.as_ptr(), &mut out) };
+        assert_eq!(code, error_code(&CalcError::DivideByZero));
+        assert_eq!(out, 42.0);
+        assert_eq!(last_error(), Some("attempted to divide by zero".into()));
+    }
+
+    #[test]
+    fn rejects_null_pointers() {
+        let input = CString::new("1").unwrap();
+        let mut out = 0.0;
+        assert_eq!(
+            unsafe { calc_eval(ptr::null(), &mut out) },
+            CALC_ERR_NULL_POINTER
+        );
+        assert_eq!(
+            unsafe { calc_eval(input.as_ptr(), ptr::null_mut()) },
+            CALC_ERR_NULL_POINTER
+        );
+        unsafe { calc_string_free(ptr::null_mut()) };
+    }
+
+    #[test]
+    fn rejects_invalid_utf8() {
+        let input = CString::new(vec![b'1', b'+', 0xff]).unwrap();
+        let mut out = 0.0;
+        let code = unsafe { calc_eval(input.as_ptr(), &mut out) };
+        assert_eq!(code, CALC_ERR_INVALID_UTF8);
+        assert!(last_error().unwrap().contains("UTF-8"));
+    }
+}
@@ -0,0 +1,795 @@
+use decimal::d128;
+use error::{CalcError, PartialComp};
+use num::{BigInt, BigRational, Integer, Signed, ToPrimitive, Zero};
+use std::{fmt, str};
+use value::{ops, IntegralFmt, Value};
+
+/// How a fraction is written out.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FractionStyle {
+    /// `7/2`
+    Improper,
+    /// `3 1/2`
+    Mixed,
+}
+
+/// How a negative result is marked.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum NegativeStyle {
+    /// `-1234.5`
+    #[default]
+    Minus,
+    /// `(1234.5)`, as in accounting.
+    Parentheses,
+}
+
+/// Controls how `eval_to_string` renders a `Value`.
+///
+/// Fractions are found by computing the best rational approximation of the
+/// result whose denominator does not exceed `max_denominator`. The
+/// approximation is only used if it matches the result to within the
+/// precision of a decimal float; irrational results such as `pi` therefore
+/// keep their decimal representation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FormatOptions {
+    /// Round decimal results to this many fractional digits.
+    pub precision: Option<u32>,
+    /// Display non-integral results as fractions.
+    pub fraction: Option<FractionStyle>,
+    /// The largest denominator considered when displaying fractions.
+    pub max_denominator: u64,
+    /// Display a negative zero such as the result of `0 * -1.0` as `0`.
+    /// Only the rendered result is affected: `eval` keeps the sign of
+    /// zeros, and so does every step of the calculation.
+    pub normalize_negative_zero: bool,
+    /// How negative results are marked. Parentheses replace the sign.
+    pub negative_style: NegativeStyle,
+    /// Separate the thousands of the integer part with this character,
+    /// e.g. `','` for `1,234.5`.
+    pub grouping: Option<char>,
+    /// Round decimal results to exactly this many fractional digits,
+    /// padding with zeros, e.g. `2` for `1234.50`. Integers get the zeros
+    /// as well. Takes the place of `precision`.
+    pub fixed_decimals: Option<u8>,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        FormatOptions {
+            precision: None,
+            fraction: None,
+            max_denominator: 1_000_000,
+            normalize_negative_zero: true,
+            negative_style: NegativeStyle::Minus,
+            grouping: None,
+            fixed_decimals: None,
+        }
+    }
+}
+
+impl FormatOptions {
+    /// Renders `value`. Hexadecimal integers are written as they are, since
+    /// grouping and decimals only make sense in base ten.
+    ///
+    /// The options combine, so that accounting's `(1,234.50)` for
+    /// `-1234.5` takes a `grouping` of `','`, two `fixed_decimals` and
+    /// `NegativeStyle::Parentheses`. Such output does not parse back.
+    pub fn format(&self, value: &Value) -> String {
+        let text = match *value {
+            Value::Integral(_, IntegralFmt::Hex) => return value.to_string(),
+            Value::Integral(ref n, IntegralFmt::Dec) => {
+                match self.fixed_decimals {
+                    Some(digits) => pad_decimals(n.to_string(), digits.into()),
+                    None => n.to_string(),
+                }
+            }
+            Value::Float(n) => self.format_float(n),
+        };
+        let (negative, digits) = match text.strip_prefix('-') {
+            Some(digits) => (true, digits),
+            None => (false, &text[..]),
+        };
+        let digits = match self.grouping {
+            Some(separator) => group(digits, separator),
+            None => digits.to_owned(),
+        };
+        match (negative, self.negative_style) {
+            (false, _) => digits,
+            (true, NegativeStyle::Minus) => format!("-{}", digits),
+            (true, NegativeStyle::Parentheses) => format!("({})", digits),
+        }
+    }
+
+    fn format_float(&self, n: d128) -> String {
+        // A zero keeps its exponent through `abs`, which would print `-0.0`
+        // as `0.0`, so it is replaced outright.
+        let n = if self.normalize_negative_zero && n.is_zero() {
+            d128::zero()
+        } else {
+            n
+        };
+        if let Some(style) = self.fraction {
+            if let Some(fraction) = self.to_fraction(n) {
+                return write_fraction(&fraction, style);
+            }
+        }
+        let precision = self.fixed_decimals.map(u32::from).or(self.precision);
+        match precision {
+            Some(digits) => {
+                let quantum = format!("1e-{}", digits)
+                    .parse::<d128>()
+                    .expect("quantum is a valid decimal");
+                let mut rounded = n.quantize(quantum);
+                // What rounds to zero, like `-0.001` to two digits, is
+                // shown as zero.
+                if self.normalize_negative_zero && rounded.is_zero() {
+                    rounded = rounded.abs();
+                }
+                let text = rounded.to_string();
+                match self.fixed_decimals {
+                    Some(digits) => pad_decimals(text, digits.into()),
+                    None => text,
+                }
+            }
+            None => Value::Float(n).to_string(),
+        }
+    }
+
+    fn to_fraction(&self, n: d128) -> Option<BigRational> {
+        let exact = ops::to_rational(n)?;
+        let fraction = best_rational(&exact, &self.max_denominator.into());
+        // A decimal float carries 34 significant digits, so anything closer
+        // than this is the same number.
+        let scale =
+            BigRational::from_integer(ops::int_powu(&10.into(), &30u32.into()));
+        let magnitude = exact.abs().max(BigRational::from_integer(1.into()));
+        if (&fraction - &exact).abs() * scale <= magnitude {
+            Some(fraction)
+        } else {
+            None
+        }
+    }
+}
+
+/// Pads the plain decimal `text` with zeros to `digits` fractional digits.
+/// Exponents and the like are left alone.
+fn pad_decimals(mut text: String, digits: usize) -> String {
+    if !text
+        .chars()
+        .all(|c| c.is_ascii_digit() || c == '-' || c == '.')
+    {
+        return text;
+    }
+    let present = match text.find('.') {
+        Some(point) => text.len() - point - 1,
+        None if digits > 0 => {
+            text.push('.');
+            0
+        }
+        None => 0,
+    };
+    for _ in present..digits {
+        text.push('0');
+    }
+    text
+}
+
+/// Separates the thousands of the leading digits of `text` with
+/// `separator`, leaving the rest as it is.
+fn group(text: &str, separator: char) -> String {
+    let end = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    let (integer, rest) = text.split_at(end);
+    let mut grouped = String::with_capacity(text.len() + integer.len() / 3);
+    for (i, digit) in integer.chars().enumerate() {
+        if i > 0 && (integer.len() - i) % 3 == 0 {
+            grouped.push(separator);
+        }
+        grouped.push(digit);
+    }
+    grouped.push_str(rest);
+    grouped
+}
+
+/// Finds the closest fraction to `x` whose denominator is at most
+/// `max_denominator`, by walking the continued fraction expansion of `x`.
+/// Between the last two convergents that fit lie the semiconvergents, the
+/// largest of which may be closer than either, as `311/99` is to π.
+fn best_rational(x: &BigRational, max_denominator: &BigInt) -> BigRational {
+    let mut convergents = expansion(x, max_denominator);
+    let (p1, q1) = convergents
+        .pop()
+        .unwrap_or_else(|| (BigInt::from(1), BigInt::zero()));
+    let (p0, q0) = convergents
+        .pop()
+        .unwrap_or_else(|| (BigInt::from(1), BigInt::zero()));
+    let best = BigRational::new(p1.clone(), q1.clone());
+    if q0.is_zero() {
+        return best;
+    }
+    let k = (max_denominator - &q0) / &q1;
+    let semi = BigRational::new(p0 + &k * p1, q0 + k * q1);
+    if (&semi - x).abs() < (&best - x).abs() {
+        semi
+    } else {
+        best
+    }
+}
+
+/// The convergents `(p, q)` of the continued fraction expansion of `x`
+/// whose denominators are at most `max_denominator`, from the coarsest to
+/// the closest.
+fn expansion(
+    x: &BigRational,
+    max_denominator: &BigInt,
+) -> Vec<(BigInt, BigInt)> {
+    let (mut p0, mut q0) = (BigInt::zero(), BigInt::from(1));
+    let (mut p1, mut q1) = (BigInt::from(1), BigInt::zero());
+    let mut rest = x.clone();
+    let mut convergents = Vec::new();
+    loop {
+        let a = rest.floor().to_integer();
+        let p2 = &a * &p1 + &p0;
+        let q2 = &a * &q1 + &q0;
+        if &q2 > max_denominator {
+            break;
+        }
+        convergents.push((p2.clone(), q2.clone()));
+        p0 = p1;
+        q0 = q1;
+        p1 = p2;
+        q1 = q2;
+        let fract = rest - BigRational::from_integer(a);
+        if fract.is_zero() {
+            break;
+        }
+        rest = fract.recip();
+    }
+    convergents
+}
+
+/// The convergents `(p, q)` of the continued fraction of `x` whose
+/// denominators are at most `max_den`, from the coarsest to the closest,
+/// e.g. `(3, 1)`, `(22, 7)`, `(333, 106)` and `(355, 113)` for π and
+/// `1000`. The numerators carry the sign.
+///
+/// The expansion is that of the exact binary value of `x`. It is empty if
+/// `x` is not finite, and stops before a numerator that does not fit into
+/// an `i64`.
+pub fn convergents(x: f64, max_den: u64) -> Vec<(i64, u64)> {
+    let x = match BigRational::from_float(x) {
+        Some(x) => x,
+        None => return Vec::new(),
+    };
+    expansion(&x, &max_den.into())
+        .into_iter()
+        .map_while(|(p, q)| Some((p.to_i64()?, q.to_u64()?)))
+        .collect()
+}
+
+/// The fraction closest to `x` whose denominator is at most `max_den`,
+/// which is what the builtin `approx(x, max_den)` returns.
+pub(crate) fn approx(x: &Value, max_den: &Value) -> Result<Value, CalcError> {
+    let fraction = closest_fraction(x, max_den)?;
+    let numer = Value::dec(fraction.numer().clone());
+    if fraction.is_integer() {
+        Ok(numer)
+    } else {
+        numer / Value::dec(fraction.denom().clone())
+    }
+}
+
+/// Renders the fraction `approx` finds as `p/q`, e.g. `5/7`.
+pub(crate) fn approxfrac(
+    x: &Value,
+    max_den: &Value,
+) -> Result<String, CalcError> {
+    let fraction = closest_fraction(x, max_den)?;
+    Ok(write_fraction(&fraction, FractionStyle::Improper))
+}
+
+fn closest_fraction(
+    x: &Value,
+    max_den: &Value,
+) -> Result<BigRational, CalcError> {
+    let bound = match *max_den {
+        Value::Integral(ref n, _) if n.is_positive() => n.clone(),
+        _ => {
+            return Err(CalcError::InvalidArgument(format!(
+                "the largest denominator must be a positive integer, got {}",
+                max_den
+            )))
+        }
+    };
+    let exact = match *x {
+        Value::Integral(ref n, _) => Some(BigRational::from_integer(n.clone())),
+        Value::Float(n) => ops::to_rational(n),
+    };
+    match exact {
+        Some(exact) => Ok(best_rational(&exact, &bound)),
+        None => Err(CalcError::InvalidArgument(format!(
+            "cannot approximate {} by a fraction",
+            x
+        ))),
+    }
+}
+
+fn write_fraction(fraction: &BigRational, style: FractionStyle) -> String {
+    let sign = if fraction.is_negative() { "-" } else { "" };
+    let numer = fraction.numer().abs();
+    let denom = fraction.denom();
+    if fraction.is_integer() {
+        return format!("{}{}", sign, numer);
+    }
+    match style {
+        FractionStyle::Improper => format!("{}{}/{}", sign, numer, denom),
+        FractionStyle::Mixed => {
+            let (whole, rem) = numer.div_rem(denom);
+            if whole.is_zero() {
+                format!("{}{}/{}", sign, rem, denom)
+            } else {
+                format!("{}{} {}/{}", sign, whole, rem, denom)
+            }
+        }
+    }
+}
+
+/// Renders the two's-complement bit pattern of `value` at `width` bits,
+/// with the nibbles separated by underscores.
+pub(crate) fn twos(value: &Value, width: &Value) -> Result<String, CalcError> {
+    let bits = match *width {
+        Value::Integral(ref w, _) => w.to_u32(),
+        Value::Float(_) => None,
+    };
+    let bits = check_width(bits, width)?;
+    let digits =
+        format!("{:01$b}", pattern("twos", value, bits)?, bits as usize);
+    let nibbles: Vec<&str> = digits
+        .as_bytes()
+        .chunks(4)
+        .map(|nibble| str::from_utf8(nibble).expect("digits are ASCII"))
+        .collect();
+    Ok(nibbles.join("_"))
+}
+
+/// Checks that a bit width is one of 8, 16, 32 or 64.
+fn check_width<W: fmt::Display>(
+    bits: Option<u32>,
+    width: W,
+) -> Result<u32, CalcError> {
+    bits.filter(|w| [8, 16, 32, 64].contains(w)).ok_or_else(|| {
+        CalcError::InvalidArgument(format!(
+            "width must be 8, 16, 32 or 64, got {}",
+            width
+        ))
+    })
+}
+
+/// The two's-complement bit pattern of `value` at `bits` bits. It must be
+/// an integer that fits the width as a signed or an unsigned number, from
+/// `-128` to `255` for 8 bits.
+fn pattern(op: &str, value: &Value, bits: u32) -> Result<u64, CalcError> {
+    let n = match *value {
+        Value::Integral(ref n, _) => n,
+        Value::Float(_) => {
+            return Err(CalcError::BadTypes(PartialComp::unary(op, value)))
+        }
+    };
+    let min = i128::from(i64::MIN >> (64 - bits));
+    let max = i128::from(u64::MAX >> (64 - bits));
+    let fits = n
+        .to_i64()
+        .map(i128::from)
+        .or_else(|| n.to_u64().map(i128::from));
+    match fits {
+        Some(m) if m >= min && m <= max => {
+            Ok(m as u64 & (u64::MAX >> (64 - bits)))
+        }
+        _ => Err(CalcError::InvalidArgument(format!(
+            "{} is outside the {}-bit range {}..={}",
+            n, bits, min, max
+        ))),
+    }
+}
+
+/// An integer result written out in every base at once, as a register
+/// would hold it.
+///
+/// `hex`, `bin` and `oct` show the two's-complement bit pattern at the
+/// chosen width, with `hex` and `bin` padded to the full width. `signed`
+/// and `unsigned` read that same pattern as an `i64` or a `u64`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BaseView {
+    /// Width of the pattern, in bits.
+    pub width: u32,
+    /// The value in decimal, e.g. `-3`.
+    pub dec: String,
+    /// The pattern in hexadecimal, e.g. `0xfd`.
+    pub hex: String,
+    /// The pattern in binary, e.g. `0b11111101`.
+    pub bin: String,
+    /// The pattern in octal, e.g. `0o375`.
+    pub oct: String,
+    /// The pattern read as a signed number.
+    pub signed: i64,
+    /// The pattern read as an unsigned number.
+    pub unsigned: u64,
+}
+
+impl BaseView {
+    /// Lays `value` out at a width of 8, 16, 32 or 64 bits.
+    ///
+    /// Fails with `CalcError::BadTypes` if `value` is not an integer, and
+    /// with `CalcError::InvalidArgument` if the width is not supported or
+    /// `value` does not fit in it.
+    pub fn new(value: &Value, width: u32) -> Result<BaseView, CalcError> {
+        let bits = check_width(Some(width), width)?;
+        let unsigned = pattern("bases", value, bits)?;
+        let shift = 64 - bits;
+        let signed = ((unsigned << shift) as i64) >> shift;
+        // Numbers above the signed range are shown as they were given.
+        let dec = if value.as_f64() < 0.0 {
+            signed.to_string()
+        } else {
+            unsigned.to_string()
+        };
+        Ok(BaseView {
+            width: bits,
+            dec,
+            hex: format!("0x{:01$x}", unsigned, bits as usize / 4),
+            bin: format!("0b{:01$b}", unsigned, bits as usize),
+            oct: format!("0o{:o}", unsigned),
+            signed,
+            unsigned,
+        })
+    }
+}
+
+impl fmt::Display for BaseView {
+    /// One aligned line per base.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rows = [
+            ("dec", &self.dec),
+            ("hex", &self.hex),
+            ("oct", &self.oct),
+            ("bin", &self.bin),
+        ];
+        for &(name, text) in &rows {
+            writeln!(f, "{:<8}  {}", name, text)?;
+        }
+        writeln!(f, "{:<8}  {}", "signed", self.signed)?;
+        write!(f, "{:<8}  {}", "unsigned", self.unsigned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {eval, eval_bases, eval_to_string};
+
+    fn fractions(style: FractionStyle) -> FormatOptions {
+        FormatOptions {
+            fraction: Some(style),
+            ..FormatOptions::default()
+        }
+    }
+
+    #[test]
+    fn proper_fractions() {
+        let options = fractions(FractionStyle::Improper);
+        let cases = vec![
+            ("1/3 + 1/6", "1/2"),
+            ("0.75", "3/4"),
+            ("1 / 7 * 7", "1"),
+            ("4 / 2", "2"),
+            ("0x10 / 2", "0x8"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(eval_to_string(input, &options), Ok(expected.into()));
+        }
+    }
+
+    #[test]
+    fn improper_and_mixed() {
+        let improper = fractions(FractionStyle::Improper);
+        let mixed = fractions(FractionStyle::Mixed);
+        assert_eq!(eval_to_string("7 / 2", &improper), Ok("7/2".into()));
+        assert_eq!(eval_to_string("7 / 2", &mixed), Ok("3 1/2".into()));
+        assert_eq!(eval_to_string("1 / 4", &mixed), Ok("1/4".into()));
+        assert_eq!(eval_to_string("0.3 + 1", &mixed), Ok("1 3/10".into()));
+    }
+
+    #[test]
+    fn negative_fractions() {
+        let improper = fractions(FractionStyle::Improper);
+        let mixed = fractions(FractionStyle::Mixed);
+        assert_eq!(eval_to_string("-7 / 2", &improper), Ok("-7/2".into()));
+        assert_eq!(eval_to_string("-7 / 2", &mixed), Ok("-3 1/2".into()));
+        assert_eq!(eval_to_string("1/8 - 1/2", &mixed), Ok("-3/8".into()));
+    }
+
+    #[test]
+    fn negative_zero() {
+        let options = FormatOptions::default();
+        for input in &["-0.0", "0 * -1.0", "-0.0 - 0.0", "-(1.5 - 1.5)"] {
+            assert_eq!(eval_to_string(input, &options), Ok("0".into()));
+            assert!(eval(input).unwrap().is_zero());
+        }
+        // Only the final result is normalized; the value itself keeps the
+        // sign that multiplication gives it.
+        assert_eq!(eval("0 * -1.0").map(|v| v.to_string()), Ok("-0.0".into()));
+        assert_eq!(
+            eval_to_string("1 / -0.0", &options),
+            Err(CalcError::DivideByZero)
+        );
+
+        let options = FormatOptions {
+            normalize_negative_zero: false,
+            ..options
+        };
+        assert_eq!(eval_to_string("0 * -1.0", &options), Ok("-0.0".into()));
+        let options = FormatOptions {
+            precision: Some(2),
+            ..FormatOptions::default()
+        };
+        let rounded = eval_to_string("0 * -1.0", &options).unwrap();
+        assert!(!rounded.starts_with('-'), "{}", rounded);
+    }
+
+    #[test]
+    fn accounting() {
+        let options = FormatOptions {
+            negative_style: NegativeStyle::Parentheses,
+            grouping: Some(','),
+            fixed_decimals: Some(2),
+            ..FormatOptions::default()
+        };
+        let cases = vec![
+            ("-1234.5", "(1,234.50)"),
+            ("1234.5", "1,234.50"),
+            ("0", "0.00"),
+            ("0.0 * -1", "0.00"),
+            ("100", "100.00"),
+            ("-1234567", "(1,234,567.00)"),
+            ("1234567.891", "1,234,567.89"),
+            ("999.999", "1,000.00"),
+            ("0.004", "0.00"),
+            ("-0.004", "0.00"),
+            ("-0.006", "(0.01)"),
+            ("0x10", "0x10"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(eval_to_string(input, &options), Ok(expected.into()));
+        }
+    }
+
+    #[test]
+    fn display_options_combine() {
+        let grouped = FormatOptions {
+            grouping: Some(' '),
+            ..FormatOptions::default()
+        };
+        assert_eq!(
+            eval_to_string("-1234567", &grouped),
+            Ok("-1 234 567".into())
+        );
+        assert_eq!(
+            eval_to_string("1234.5678", &grouped),
+            Ok("1 234.5678".into())
+        );
+        assert_eq!(eval_to_string("123", &grouped), Ok("123".into()));
+
+        let rounded = FormatOptions {
+            precision: Some(1),
+            negative_style: NegativeStyle::Parentheses,
+            ..grouped.clone()
+        };
+        assert_eq!(
+            eval_to_string("-1234.56", &rounded),
+            Ok("(1 234.6)".into())
+        );
+        assert_eq!(eval_to_string("-2", &rounded), Ok("(2)".into()));
+        // Fixed decimals take the place of the precision.
+        let fixed = FormatOptions {
+            fixed_decimals: Some(3),
+            ..rounded
+        };
+        assert_eq!(eval_to_string("-1234.5", &fixed), Ok("(1 234.500)".into()));
+        let none = FormatOptions {
+            fixed_decimals: Some(0),
+            ..fixed
+        };
+        assert_eq!(eval_to_string("-1234.4", &none), Ok("(1 234)".into()));
+
+        let mixed = FormatOptions {
+            negative_style: NegativeStyle::Parentheses,
+            grouping: Some(','),
+            ..fractions(FractionStyle::Mixed)
+        };
+        assert_eq!(
+            eval_to_string("-2469 / 2", &mixed),
+            Ok("(1,234 1/2)".into())
+        );
+    }
+
+    #[test]
+    fn twos_complement() {
+        let options = FormatOptions::default();
+        let ones = vec!["1111"; 16].join("_");
+        let min = format!("1000{}", "_0000".repeat(15));
+        let cases = vec![
+            ("twos(-1, 8)", "1111_1111"),
+            ("twos(-3, 16)", "1111_1111_1111_1101"),
+            ("twos(-128, 8)", "1000_0000"),
+            ("twos(127, 8)", "0111_1111"),
+            ("twos(0, 8)", "0000_0000"),
+            ("twos(-32768, 16)", "1000_0000_0000_0000"),
+            (
+                " twos (5 - 6, 2 * 16) ",
+                "1111_1111_1111_1111_1111_1111_1111_1111",
+            ),
+            ("twos(-1, 64)", &ones),
+            ("twos(-(2 ** 63), 64)", &min),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(eval_to_string(input, &options), Ok(expected.into()));
+        }
+
+        // Unsigned numbers fit as well.
+        let cases = vec![
+            ("twos(128, 8)", "1000_0000"),
+            ("twos(255, 8)", "1111_1111"),
+            ("twos(0xffff, 16)", "1111_1111_1111_1111"),
+            ("twos(2 ** 64 - 1, 64)", &ones),
+            ("twos(2 ** 63, 64)", &min),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(eval_to_string(input, &options), Ok(expected.into()));
+        }
+
+        assert_eq!(
+            eval_to_string("twos(256, 8)", &options),
+            Err(CalcError::InvalidArgument(
+                "256 is outside the 8-bit range -128..=255".into()
+            ))
+        );
+        assert!(eval_to_string("twos(-129, 8)", &options).is_err());
+        assert!(eval_to_string("twos(2 ** 64, 64)", &options).is_err());
+        assert_eq!(
+            eval_to_string("twos(1, 12)", &options),
+            Err(CalcError::InvalidArgument(
+                "width must be 8, 16, 32 or 64, got 12".into()
+            ))
+        );
+        assert!(eval_to_string("twos(1.5, 8)", &options).is_err());
+        // The result is a string, so it cannot be computed with.
+        assert!(eval_to_string("twos(1, 8) + 1", &options).is_err());
+        assert!(eval_to_string("twos(1, 8) + (2)", &options).is_err());
+        assert!(eval("twos(1, 8)").is_err());
+    }
+
+    #[test]
+    fn all_bases() {
+        let view = eval_bases("0x10 + 10", 16).unwrap();
+        assert_eq!(view.dec, "26");
+        assert_eq!(view.hex, "0x001a");
+        assert_eq!(view.oct, "0o32");
+        assert_eq!(view.bin, "0b0000000000011010");
+        assert_eq!((view.signed, view.unsigned), (26, 26));
+
+        let view = eval_bases("0 - 3", 8).unwrap();
+        assert_eq!(view.dec, "-3");
+        assert_eq!(view.hex, "0xfd");
+        assert_eq!(view.oct, "0o375");
+        assert_eq!(view.bin, "0b11111101");
+        assert_eq!((view.signed, view.unsigned), (-3, 253));
+        assert_eq!(u64::from_str_radix(&view.hex[2..], 16), Ok(253));
+        assert_eq!(u64::from_str_radix(&view.oct[2..], 8), Ok(253));
+        assert_eq!(u64::from_str_radix(&view.bin[2..], 2), Ok(253));
+
+        let view = eval_bases("0", 32).unwrap();
+        assert_eq!(view.hex, "0x00000000");
+        assert_eq!(view.oct, "0o0");
+        assert_eq!((view.signed, view.unsigned), (0, 0));
+
+        let wide = eval_bases("0 - 1", 64).unwrap();
+        assert_eq!(wide.hex, format!("0x{}", "f".repeat(16)));
+        assert_eq!(wide.bin.len(), 66);
+        assert_eq!((wide.signed, wide.unsigned), (-1, u64::MAX));
+        let narrow = eval_bases("0 - 1", 8).unwrap();
+        assert_eq!(narrow.hex, "0xff");
+        assert_eq!((narrow.signed, narrow.unsigned), (-1, 255));
+
+        assert_eq!(
+            eval_bases("1.5", 8),
+            Err(CalcError::BadTypes(PartialComp::unary(
+                "bases",
+                eval("1.5").unwrap()
+            )))
+        );
+        for input in &["255", "0xff"] {
+            let view = eval_bases(input, 8).unwrap();
+            assert_eq!(view.dec, "255");
+            assert_eq!(view.hex, "0xff");
+            assert_eq!(view.bin, "0b11111111");
+            assert_eq!((view.signed, view.unsigned), (-1, 255));
+        }
+        let view = eval_bases("2 ** 64 - 1", 64).unwrap();
+        assert_eq!(view.dec, u64::MAX.to_string());
+        assert_eq!((view.signed, view.unsigned), (-1, u64::MAX));
+        assert_eq!(eval_bases("128", 8).unwrap().signed, -128);
+        assert!(eval_bases("256", 8).is_err());
+        assert!(eval_bases("-129", 8).is_err());
+        assert!(eval_bases("1", 12).is_err());
+        assert_eq!(
+            eval_bases("0 - 3", 8).unwrap().to_string(),
+            "dec       -3\nhex       0xfd\noct       0o375\n\
+             bin       0b11111101\nsigned    -3\nunsigned  253"
+        );
+    }
+
+    #[test]
+    fn approximations() {
+        let options = FormatOptions::default();
+        let cases = vec![
+            ("approxfrac(0.7142857, 100)", "5/7"),
+            ("approxfrac(pi, 1000)", "355/113"),
+            ("approxfrac(pi, 100)", "311/99"),
+            ("approxfrac(-0.7142857, 100)", "-5/7"),
+            ("approxfrac(0.75, 1000)", "3/4"),
+            ("approxfrac(3, 10)", "3"),
+            ("approxfrac(0.333, 10)", "1/3"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(eval_to_string(input, &options), Ok(expected.into()));
+        }
+
+        assert_eq!(eval("approx(0.7142857, 100)"), eval("5 / 7"));
+        assert_eq!(eval("approx(0.25, 100)"), eval("0.25"));
+        assert_eq!(eval("approx(3, 10)"), Ok(Value::dec(3)));
+        assert_eq!(eval("approx(-2.5, 1)"), Ok(Value::dec(-3)));
+        let improper = fractions(FractionStyle::Improper);
+        assert_eq!(
+            eval_to_string("approx(0.76, 4) + 1", &improper),
+            Ok("7/4".into())
+        );
+
+        assert_eq!(
+            eval("approx(0.5, 0)"),
+            Err(CalcError::InvalidArgument(
+                "the largest denominator must be a positive integer, got 0"
+                    .into()
+            ))
+        );
+        assert!(eval("approx(0.5, 2.5)").is_err());
+        assert!(eval_to_string("approxfrac(0.5, -1)", &options).is_err());
+    }
+
+    #[test]
+    fn continued_fractions() {
+        assert_eq!(
+            convergents(::std::f64::consts::PI, 1000),
+            vec![(3, 1), (22, 7), (333, 106), (355, 113)]
+        );
+        assert_eq!(convergents(-0.75, 100), vec![(-1, 1), (-3, 4)]);
+        assert_eq!(convergents(0.5, 10), vec![(0, 1), (1, 2)]);
+        assert_eq!(convergents(5.0, 1), vec![(5, 1)]);
+        assert_eq!(convergents(1e300, 10), vec![]);
+        assert_eq!(convergents(f64::NAN, 10), vec![]);
+    }
+
+    #[test]
+    fn irrational_fallback() {
+        let options = FormatOptions {
+            precision: Some(4),
+            ..fractions(FractionStyle::Improper)
+        };
+        assert_eq!(eval_to_string("pi", &options), Ok("3.1416".into()));
+        let small = FormatOptions {
+            max_denominator: 10,
+            ..options
+        };
+        assert_eq!(eval_to_string("1 / 30", &small), Ok("0.0333".into()));
+    }
+}
@@ -0,0 +1,154 @@
+//! Conversions between binary and the reflected binary Gray code, in which
+//! consecutive values differ in a single bit.
+
+use error::CalcError;
+use num::{BigUint, Signed, Zero};
+use value::{Integral, Value};
+
+/// The width of the unsigned values taken by `togray` and `fromgray`,
+/// unless a `Calculator` shifts logically at another one.
+pub(crate) const DEFAULT_WIDTH: u32 = 64;
+
+/// Whether `atom` names one of the conversions.
+pub(crate) fn is_gray(atom: &str) -> bool {
+    atom == "togray" || atom == "fromgray"
+}
+
+/// Applies the conversion `name` to a value of at most `width` bits.
+pub(crate) fn gray(
+    name: &str,
+    value: &Value,
+    width: u32,
+) -> Result<Value, CalcError> {
+    let n = argument(name, value, width)?;
+    let result = if name == "togray" {
+        to_gray(&n)
+    } else {
+        from_gray(n, width)
+    };
+    Ok(Value::dec(Integral::from(result)))
+}
+
+/// Checks that the argument of `name` is an integer from 0 to
+/// `2 ** width - 1`.
+fn argument(
+    name: &str,
+    value: &Value,
+    width: u32,
+) -> Result<BigUint, CalcError> {
+    let n = value.to_integral().ok_or_else(|| {
+        CalcError::InvalidArgument(format!(
+            "{} needs an integer, got {}",
+            name, value
+        ))
+    })?;
+    if n.is_negative() {
+        return Err(CalcError::InvalidArgument(format!(
+            "{} needs a value that is not negative, as Gray code is \
+             defined on unsigned values here, got {}",
+            name, n
+        )));
+    }
+    if n.bits() > width as usize {
+        return Err(CalcError::InvalidArgument(format!(
+            "{} needs a value that fits into {} bits, got {}",
+            name, width, n
+        )));
+    }
+    Ok(n.to_biguint().expect("checked not to be negative"))
+}
+
+/// `n ^ (n >> 1)`.
+fn to_gray(n: &BigUint) -> BigUint {
+    n ^ (n >> 1)
+}
+
+/// Undoes `to_gray` by XOR-ing together all the right shifts of `g`, which
+/// takes doubling shifts up to the width.
+fn from_gray(mut g: BigUint, width: u32) -> BigUint {
+    let mut shift = 1;
+    while shift < width as usize && !g.is_zero() {
+        g = &g ^ (&g >> shift);
+        shift *= 2;
+    }
+    g
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use calculator::Calculator;
+    use eval;
+    use parse::RightShift;
+
+    fn round_trip(n: Integral, width: u32) {
+        let value = Value::dec(n);
+        let code = gray("togray", &value, width).unwrap();
+        assert_eq!(gray("fromgray", &code, width), Ok(value));
+    }
+
+    #[test]
+    fn conversions() {
+        let codes = [0, 1, 3, 2, 6, 7, 5, 4, 12];
+        for (n, &code) in codes.iter().enumerate() {
+            let input = format!("togray({})", n);
+            assert_eq!(eval(&input), Ok(Value::dec(code)), "{}", input);
+            let input = format!("fromgray({})", code);
+            assert_eq!(eval(&input), Ok(Value::dec(n as u64)), "{}", input);
+        }
+        assert_eq!(eval("togray(255)"), Ok(Value::dec(128)));
+        assert_eq!(eval("fromgray(2 ** 63)"), Ok(eval("2 ** 64 - 1").unwrap()));
+    }
+
+    #[test]
+    fn inverse() {
+        for width in &[8, 64] {
+            for n in 0..256 {
+                round_trip(Integral::from(n), *width);
+            }
+        }
+        let max = |width: usize| (Integral::from(1) << width) - 1;
+        for n in &[max(8), max(7), Integral::from(128)] {
+            round_trip(n.clone(), 8);
+        }
+        for n in &[max(64), max(63), Integral::from(1) << 63] {
+            round_trip(n.clone(), 64);
+        }
+    }
+
+    #[test]
+    fn width() {
+        let mut calc = Calculator::new();
+        calc.set_right_shift(RightShift::Logical(8));
+        assert_eq!(calc.eval("togray(255)"), Ok(Value::dec(128)));
+        assert_eq!(calc.eval("fromgray(128)"), Ok(Value::dec(255)));
+        assert_eq!(
+            calc.eval("togray(256)"),
+            Err(CalcError::InvalidArgument(
+                "togray needs a value that fits into 8 bits, got 256".into()
+            ))
+        );
+        calc.set_right_shift(RightShift::Arithmetic);
+        assert_eq!(calc.eval("togray(256)"), Ok(Value::dec(384)));
+        assert!(calc.eval("togray(2 ** 64)").is_err());
+    }
+
+    #[test]
+    fn domain() {
+        assert_eq!(
+            eval("togray(-1)"),
+            Err(CalcError::InvalidArgument(
+                "togray needs a value that is not negative, as Gray code is \
+                 defined on unsigned values here, got -1"
+                    .into()
+            ))
+        );
+        assert_eq!(
+            eval("fromgray(1.5)"),
+            Err(CalcError::InvalidArgument(
+                "fromgray needs an integer, got 1.5".into()
+            ))
+        );
+        assert_eq!(eval("togray(6.0)"), Ok(Value::dec(5)));
+    }
+}
@@ -0,0 +1,660 @@
+//! Export and import of expression trees as JSON.
+//!
+//! A document is an object holding the version of the schema, currently 1,
+//! and the root node of the tree:
+//!
+//! ```text
+//! {"version": 1, "expr": {"op": "add", "args": [1, {"var": "x"}]}}
+//! ```
+//!
+//! Nodes take one of four forms:
+//!
+//! - Numbers are JSON numbers. Integers are written without a fractional
+//!   part or exponent and decimal floats with one, e.g. `2` and `2.0`.
+//!   Integers lose their hexadecimal formatting, and floats that are not
+//!   finite are written as `null`, which is rejected on import.
+//! - Variables and constants are `{"var": name}`.
+//! - Function calls are `{"call": name, "args": [...]}`.
+//! - Operators are `{"op": name, "args": [...]}`. The unary operators are
+//!   `neg` and `not`; the binary ones are `add`, `sub`, `mul`, `div`,
+//!   `rem`, `pow`, `and`, `or`, `xor`, `shl` and `shr`.
+
+use ast::{BinaryOp, Expr, UnaryOp};
+use decimal::d128;
+use error::CalcError;
+use std::fmt::Write;
+use value::{Integral, Value};
+
+/// The version of the schema written by `to_json`.
+pub const VERSION: u64 = 1;
+
+/// How deeply arrays and objects may nest in a document read by
+/// `from_json`. Every node of an expression takes two levels.
+const MAX_NESTING: usize = 512;
+
+/// Serializes an expression tree to a JSON document. See the `json` module
+/// for the schema.
+pub fn to_json(expr: &Expr) -> String {
+    let mut out = format!("{{\"version\":{},\"expr\":", VERSION);
+    write_node(&mut out, expr);
+    out.push('}');
+    out
+}
+
+/// Reads an expression tree from a JSON document written by `to_json`.
+///
+/// Malformed documents, unknown operators and nodes with the wrong number
+/// of arguments are rejected with `CalcError::InvalidJson` or
+/// `CalcError::WrongArity`.
+pub fn from_json(input: &str) -> Result<Expr, CalcError> {
+    let mut fields = into_object(parse(input)?, "document")?;
+    match take(&mut fields, "version") {
+        Some(Json::Number(ref version)) if *version == VERSION.to_string() => {}
+        Some(Json::Number(version)) => {
+            return Err(invalid(format!("unsupported version {}", version)))
+        }
+        _ => return Err(invalid("missing version")),
+    }
+    let expr =
+        take(&mut fields, "expr").ok_or_else(|| invalid("missing expr"))?;
+    no_more_fields(&fields, "document")?;
+    read_node(expr)
+}
+
+/// Parses a JSON document.
+pub(crate) fn parse(input: &str) -> Result<Json, CalcError> {
+    let mut reader = Reader {
+        input: input.as_bytes(),
+        pos: 0,
+        depth: 0,
+    };
+    let document = reader.value()?;
+    reader.skip_whitespace();
+    if reader.pos < reader.input.len() {
+        return Err(reader.error("trailing characters"));
+    }
+    Ok(document)
+}
+
+/// Writes a number the way `read_value` reads it back.
+pub(crate) fn write_value(out: &mut String, value: &Value) {
+    match *value {
+        Value::Integral(ref n, _) => {
+            write!(out, "{}", n).expect("writing to a String succeeds")
+        }
+        Value::Float(n) if n.is_finite() => {
+            let text = n.to_string();
+            out.push_str(&text);
+            if !text.contains(&['.', 'E', 'e'][..]) {
+                out.push_str(".0");
+            }
+        }
+        Value::Float(_) => out.push_str("null"),
+    }
+}
+
+fn write_node(out: &mut String, expr: &Expr) {
+    match *expr {
+        Expr::Num(ref value) => write_value(out, value),
+        Expr::Var(ref name) => {
+            out.push_str("{\"var\":");
+            write_string(out, name);
+            out.push('}');
+        }
+        Expr::Call(ref name, ref args) => {
+            out.push_str("{\"call\":");
+            write_string(out, name);
+            write_args(out, args.iter());
+        }
+        Expr::Unary(op, ref arg) => {
+            write!(out, "{{\"op\":\"{}\"", unary_name(op))
+                .expect("writing to a String succeeds");
+            write_args(out, Some(&**arg).into_iter());
+        }
+        Expr::Binary(op, ref lhs, ref rhs) => {
+            write!(out, "{{\"op\":\"{}\"", binary_name(op))
+                .expect("writing to a String succeeds");
+            write_args(out, vec![&**lhs, &**rhs].into_iter());
+        }
+    }
+}
+
+/// Writes the `args` field and closes the object of a node.
+fn write_args<'a, I: Iterator<Item = &'a Expr>>(out: &mut String, args: I) {
+    out.push_str(",\"args\":[");
+    for (i, arg) in args.enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write_node(out, arg);
+    }
+    out.push_str("]}");
+}
+
+pub(crate) fn write_string(out: &mut String, text: &str) {
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => write!(out, "\\u{:04x}", c as u32)
+                .expect("writing to a String succeeds"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn unary_name(op: UnaryOp) -> &'static str {
+    match op {
+        UnaryOp::Neg => "neg",
+        UnaryOp::Not => "not",
+    }
+}
+
+pub(crate) fn binary_name(op: BinaryOp) -> &'static str {
+    match op {
+        BinaryOp::Add => "add",
+        BinaryOp::Sub => "sub",
+        BinaryOp::Mul => "mul",
+        BinaryOp::Div => "div",
+        BinaryOp::Rem => "rem",
+        BinaryOp::Pow => "pow",
+        BinaryOp::BitAnd => "and",
+        BinaryOp::BitOr => "or",
+        BinaryOp::BitXor => "xor",
+        BinaryOp::Shl => "shl",
+        BinaryOp::Shr => "shr",
+    }
+}
+
+const UNARY_OPS: [UnaryOp; 2] = [UnaryOp::Neg, UnaryOp::Not];
+
+const BINARY_OPS: [BinaryOp; 11] = [
+    BinaryOp::Add,
+    BinaryOp::Sub,
+    BinaryOp::Mul,
+    BinaryOp::Div,
+    BinaryOp::Rem,
+    BinaryOp::Pow,
+    BinaryOp::BitAnd,
+    BinaryOp::BitOr,
+    BinaryOp::BitXor,
+    BinaryOp::Shl,
+    BinaryOp::Shr,
+];
+
+/// The operator that `binary_name` names `name`.
+pub(crate) fn binary_op(name: &str) -> Option<BinaryOp> {
+    BINARY_OPS
+        .iter()
+        .cloned()
+        .find(|&op| binary_name(op) == name)
+}
+
+fn read_node(node: Json) -> Result<Expr, CalcError> {
+    let mut fields = match node {
+        Json::Number(text) => return read_value(&text).map(Expr::Num),
+        Json::Null => return Err(invalid("numbers must be finite")),
+        Json::Object(fields) => fields,
+        _ => return Err(invalid("expected a number or an object as a node")),
+    };
+    if let Some(name) = take(&mut fields, "var") {
+        let name = into_string(name, "var")?;
+        no_more_fields(&fields, "var node")?;
+        return Ok(Expr::Var(name));
+    }
+    let (name, is_call) =
+        match (take(&mut fields, "op"), take(&mut fields, "call")) {
+            (Some(name), None) => (into_string(name, "op")?, false),
+            (None, Some(name)) => (into_string(name, "call")?, true),
+            (Some(_), Some(_)) => {
+                return Err(invalid("a node cannot have both op and call"))
+            }
+            (None, None) => {
+                return Err(invalid("expected a var, call or op node"))
+            }
+        };
+    let args = match take(&mut fields, "args") {
+        Some(Json::Array(args)) => args,
+        Some(_) => {
+            return Err(invalid(format!("args of '{}' must be an array", name)))
+        }
+        None => return Err(invalid(format!("'{}' has no args", name))),
+    };
+    no_more_fields(&fields, "node")?;
+    let mut args = args
+        .into_iter()
+        .map(read_node)
+        .collect::<Result<Vec<_>, _>>()?;
+    if is_call {
+        if args.is_empty() {
+            return Err(invalid(format!("call of '{}' has no args", name)));
+        }
+        return Ok(Expr::Call(name, args));
+    }
+    let expected = if let Some(&op) =
+        UNARY_OPS.iter().find(|&&op| unary_name(op) == name)
+    {
+        if args.len() == 1 {
+            return Ok(Expr::Unary(op, Box::new(args.remove(0))));
+        }
+        1
+    } else if let Some(op) = binary_op(&name) {
+        if args.len() == 2 {
+            let rhs = args.pop().expect("there are two args");
+            let lhs = args.pop().expect("there are two args");
+            return Ok(Expr::binary(op, lhs, rhs));
+        }
+        2
+    } else {
+        return Err(invalid(format!("unknown op '{}'", name)));
+    };
+    Err(CalcError::WrongArity {
+        atom: name,
+        expected,
+        actual: args.len(),
+    })
+}
+
+/// Reads the text of a JSON number as an integer, or as a decimal float if
+/// it has a fractional part or an exponent.
+pub(crate) fn read_value(text: &str) -> Result<Value, CalcError> {
+    let value = if text.contains(&['.', 'e', 'E'][..]) {
+        let n = text
+            .parse::<d128>()
+            .map_err(|_| invalid(format!("invalid number {}", text)))?;
+        Value::Float(n)
+    } else {
+        let n: Integral = text
+            .parse()
+            .map_err(|_| invalid(format!("invalid number {}", text)))?;
+        Value::dec(n)
+    };
+    Ok(value)
+}
+
+pub(crate) fn invalid<S: Into<String>>(reason: S) -> CalcError {
+    CalcError::InvalidJson(reason.into())
+}
+
+pub(crate) fn take(
+    fields: &mut Vec<(String, Json)>,
+    key: &str,
+) -> Option<Json> {
+    let pos = fields.iter().position(|field| field.0 == key)?;
+    Some(fields.remove(pos).1)
+}
+
+pub(crate) fn no_more_fields(
+    fields: &[(String, Json)],
+    what: &str,
+) -> Result<(), CalcError> {
+    match fields.first() {
+        Some(field) => Err(invalid(format!(
+            "unexpected field '{}' in {}",
+            field.0, what
+        ))),
+        None => Ok(()),
+    }
+}
+
+pub(crate) fn into_object(
+    json: Json,
+    what: &str,
+) -> Result<Vec<(String, Json)>, CalcError> {
+    match json {
+        Json::Object(fields) => Ok(fields),
+        _ => Err(invalid(format!("{} must be an object", what))),
+    }
+}
+
+pub(crate) fn into_string(json: Json, what: &str) -> Result<String, CalcError> {
+    match json {
+        Json::String(text) => Ok(text),
+        _ => Err(invalid(format!("{} must be a string", what))),
+    }
+}
+
+/// A parsed JSON value. Numbers keep their text, so that integers of any
+/// size survive.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Json {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+/// A recursive descent parser for JSON documents.
+struct Reader<'a> {
+    input: &'a [u8],
+    pos: usize,
+    depth: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn error(&self, reason: &str) -> CalcError {
+        invalid(format!("{} at byte {}", reason, self.pos))
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') =
+            self.input.get(self.pos)
+        {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, byte: u8) -> bool {
+        self.skip_whitespace();
+        if self.input.get(self.pos) == Some(&byte) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), CalcError> {
+        if self.eat(byte) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected '{}'", byte as char)))
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, CalcError> {
+        self.skip_whitespace();
+        match self.input.get(self.pos) {
+            Some(b'{') | Some(b'[') => {
+                self.depth += 1;
+                if self.depth > MAX_NESTING {
+                    return Err(CalcError::NestingTooDeep);
+                }
+                let value = if self.eat(b'{') {
+                    self.object()
+                } else {
+                    self.expect(b'[')?;
+                    self.array()
+                };
+                self.depth -= 1;
+                value
+            }
+            Some(b'"') => self.string().map(Json::String),
+            Some(b'-') | Some(b'0'..=b'9') => self.number(),
+            Some(_) => {
+                let rest = &self.input[self.pos..];
+                let (value, len) = if rest.starts_with(b"null") {
+                    (Json::Null, 4)
+                } else if rest.starts_with(b"true") {
+                    (Json::Bool(true), 4)
+                } else if rest.starts_with(b"false") {
+                    (Json::Bool(false), 5)
+                } else {
+                    return Err(self.error("unexpected character"));
+                };
+                self.pos += len;
+                Ok(value)
+            }
+            None => Err(self.error("unexpected end of document")),
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, CalcError> {
+        let mut fields: Vec<(String, Json)> = Vec::new();
+        if self.eat(b'}') {
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.input.get(self.pos) != Some(&b'"') {
+                return Err(self.error("expected a key"));
+            }
+            let key = self.string()?;
+            if fields.iter().any(|field| field.0 == key) {
+                return Err(self.error(&format!("duplicate key '{}'", key)));
+            }
+            self.expect(b':')?;
+            let value = self.value()?;
+            fields.push((key, value));
+            if !self.eat(b',') {
+                self.expect(b'}')?;
+                return Ok(Json::Object(fields));
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, CalcError> {
+        let mut items = Vec::new();
+        if self.eat(b']') {
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            if !self.eat(b',') {
+                self.expect(b']')?;
+                return Ok(Json::Array(items));
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, CalcError> {
+        let start = self.pos;
+        let digits = |reader: &mut Reader| {
+            let from = reader.pos;
+            while let Some(b'0'..=b'9') = reader.input.get(reader.pos) {
+                reader.pos += 1;
+            }
+            reader.pos > from
+        };
+        if self.input[self.pos] == b'-' {
+            self.pos += 1;
+        }
+        if !digits(self) {
+            return Err(self.error("expected digits"));
+        }
+        if self.input.get(self.pos) == Some(&b'.') {
+            self.pos += 1;
+            if !digits(self) {
+                return Err(self.error("expected digits"));
+            }
+        }
+        if let Some(b'e') | Some(b'E') = self.input.get(self.pos) {
+            self.pos += 1;
+            if let Some(b'+') | Some(b'-') = self.input.get(self.pos) {
+                self.pos += 1;
+            }
+            if !digits(self) {
+                return Err(self.error("expected digits"));
+            }
+        }
+        let text = String::from_utf8_lossy(&self.input[start..self.pos]);
+        Ok(Json::Number(text.into_owned()))
+    }
+
+    fn string(&mut self) -> Result<String, CalcError> {
+        // The opening quote was checked by the caller.
+        self.pos += 1;
+        let mut bytes = Vec::new();
+        loop {
+            let byte = match self.input.get(self.pos) {
+                Some(&byte) => byte,
+                None => return Err(self.error("unterminated string")),
+            };
+            self.pos += 1;
+            match byte {
+                b'"' => break,
+                b'\\' => {
+                    let escape = self.input.get(self.pos).cloned();
+                    self.pos += 1;
+                    let c = match escape {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => self.unicode_escape()?,
+                        _ => return Err(self.error("invalid escape")),
+                    };
+                    let mut buf = [0; 4];
+                    bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                }
+                0..=0x1f => {
+                    return Err(self.error("control character in string"))
+                }
+                byte => bytes.push(byte),
+            }
+        }
+        // The input is a `str`, and escapes produce whole characters.
+        Ok(String::from_utf8(bytes).expect("strings are valid UTF-8"))
+    }
+
+    /// Reads the digits of a `\u` escape, combining surrogate pairs.
+    fn unicode_escape(&mut self) -> Result<char, CalcError> {
+        let high = self.hex4()?;
+        let code = if (0xd800..0xdc00).contains(&high) {
+            if !self.input[self.pos..].starts_with(b"\\u") {
+                return Err(self.error("unpaired surrogate"));
+            }
+            self.pos += 2;
+            let low = self.hex4()?;
+            if !(0xdc00..0xe000).contains(&low) {
+                return Err(self.error("unpaired surrogate"));
+            }
+            0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+        } else {
+            high
+        };
+        ::std::char::from_u32(code).ok_or_else(|| self.error("invalid escape"))
+    }
+
+    fn hex4(&mut self) -> Result<u32, CalcError> {
+        let digits = self
+            .input
+            .get(self.pos..self.pos + 4)
+            .filter(|digits| digits.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|digits| ::std::str::from_utf8(digits).ok())
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .ok_or_else(|| self.error("invalid escape"))?;
+        self.pos += 4;
+        Ok(digits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eval_expr;
+
+    #[test]
+    fn schema() {
+        let expr = Expr::parse("(x + 2) * -log 0.5").unwrap();
+        assert_eq!(
+            to_json(&expr),
+            "{\"version\":1,\"expr\":{\"op\":\"mul\",\"args\":[\
+             {\"op\":\"add\",\"args\":[{\"var\":\"x\"},2]},\
+             {\"op\":\"neg\",\"args\":[{\"call\":\"log\",\"args\":[0.5]}]}]}}"
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let inputs = [
+            "1 + 2 * 3",
+            "-(3 - 5) ** 2 / 4",
+            "2 ** 3 ** 2 % 7",
+            "1.5 * 4",
+            "16 << 2 | 1 & 3 ^ 2",
+            "~12 >> 1",
+            "log (100 * 10) + cos pi",
+            "123456789012345678901234567890 * 3",
+        ];
+        for input in &inputs {
+            let expr = Expr::parse(input).unwrap();
+            let json = to_json(&expr);
+            let read = from_json(&json).unwrap();
+            assert_eq!(eval_expr(&read), eval_expr(&expr), "{}", json);
+        }
+        let expr = Expr::call("f", vec![Expr::var("a \"quoted\"\n\\ name")]);
+        assert_eq!(from_json(&to_json(&expr)), Ok(expr));
+    }
+
+    #[test]
+    fn whitespace_and_escapes() {
+        let json = r#" { "expr" : { "op" : "sub" , "args" : [ 1.25e1 ,
+            { "var" : "été😀" } ] } , "version" : 1 } "#;
+        let expr = Expr::Num(Value::Float(d128!(12.5))) - Expr::var("été😀");
+        assert_eq!(from_json(json), Ok(expr));
+    }
+
+    #[test]
+    fn unknown_op() {
+        let json = r#"{"version":1,"expr":{"op":"frobnicate","args":[1,2]}}"#;
+        assert_eq!(
+            from_json(json),
+            Err(CalcError::InvalidJson("unknown op 'frobnicate'".into()))
+        );
+    }
+
+    #[test]
+    fn wrong_arity() {
+        let json = r#"{"version":1,"expr":{"op":"add","args":[1]}}"#;
+        assert_eq!(
+            from_json(json),
+            Err(CalcError::WrongArity {
+                atom: "add".into(),
+                expected: 2,
+                actual: 1,
+            })
+        );
+        let json = r#"{"version":1,"expr":{"op":"neg","args":[1,2,3]}}"#;
+        assert!(matches!(
+            from_json(json),
+            Err(CalcError::WrongArity { expected: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn malformed() {
+        let documents = [
+            "",
+            "1",
+            "{",
+            "[1, 2]",
+            r#"{"version":1}"#,
+            r#"{"expr":1}"#,
+            r#"{"version":2,"expr":1}"#,
+            r#"{"version":1,"expr":1,"extra":0}"#,
+            r#"{"version":1,"expr":1} 2"#,
+            r#"{"version":1,"version":1,"expr":1}"#,
+            r#"{"version":1,"expr":null}"#,
+            r#"{"version":1,"expr":true}"#,
+            r#"{"version":1,"expr":"x"}"#,
+            r#"{"version":1,"expr":{"var":3}}"#,
+            r#"{"version":1,"expr":{"op":"add","args":1}}"#,
+            r#"{"version":1,"expr":{"op":"add","call":"f","args":[]}}"#,
+            r#"{"version":1,"expr":{"call":"f","args":[]}}"#,
+            r#"{"version":1,"expr":{"var":"x\"}}"#,
+            r#"{"version":1,"expr":{"var":"\ud800"}}"#,
+            r#"{"version":1,"expr":{"var":"\u+123"}}"#,
+            r#"{"version":1,"expr":-}"#,
+            r#"{"version":1,"expr":1.}"#,
+            r#"{"version":1,"expr":[1,]}"#,
+        ];
+        for document in &documents {
+            let result = from_json(document);
+            assert!(result.is_err(), "{} gave {:?}", document, result);
+        }
+        let deep = format!("{}1{}", "[".repeat(10_000), "]".repeat(10_000));
+        assert_eq!(from_json(&deep), Err(CalcError::NestingTooDeep));
+    }
+}
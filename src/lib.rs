@@ -5,24 +5,362 @@ extern crate decimal;
 #[macro_use]
 extern crate failure;
 extern crate num;
+#[cfg(feature = "trace")]
+#[macro_use]
+extern crate log;
+#[cfg(feature = "python")]
+extern crate pyo3;
+#[cfg(feature = "parallel")]
+extern crate rayon;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+#[macro_use]
+extern crate serde_derive;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
 #[cfg(test)]
 extern crate test;
+#[cfg(all(test, feature = "serde"))]
+extern crate toml;
+
+// Without the `trace` feature, logging compiles to nothing, but the
+// arguments are still type checked.
+#[cfg(not(feature = "trace"))]
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        if false {
+            let _ = format_args!($($arg)*);
+        }
+    };
+}
+#[cfg(not(feature = "trace"))]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if false {
+            let _ = format_args!($($arg)*);
+        }
+    };
+}
 
 #[cfg(test)]
 mod bench;
 
+pub mod ast;
+mod bc;
+#[cfg(feature = "bignum")]
+mod bignum;
+mod bytecode;
+mod calculator;
+mod complex;
+mod context;
+mod diff;
+mod dot;
 mod error;
+mod expression;
+mod factor;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+mod format;
+mod gray;
+pub mod json;
+mod lint;
+mod number;
 pub mod parse;
-mod token;
+mod prefix;
+mod pretty;
+#[cfg(feature = "python")]
+pub mod python;
+mod sample;
+mod sheet;
+mod style;
+mod symbolic;
+pub mod token;
+mod uncertain;
 pub mod value;
 
-pub use error::CalcError;
+pub use ast::Expr;
+pub use bc::Bc;
+#[cfg(feature = "bignum")]
+pub use bignum::BigNum;
+pub use bytecode::CompiledExpr;
+pub use calculator::{
+    CacheStats, Calculator, NumberBackend, TranscriptEntry, TranscriptFormat,
+};
+pub use complex::Complex;
+pub use context::Context;
+pub use diff::{DiffOptions, ExprDiff};
+pub use dot::to_dot;
+pub use error::{
+    CalcError, DefaultCatalog, Located, Location, MessageCatalog, Unsolvable,
+};
+pub use expression::Expression;
+pub use format::{
+    convergents, BaseView, FormatOptions, FractionStyle, NegativeStyle,
+};
+pub use json::{from_json, to_json};
+pub use lint::{lint, lint_with, Lint, LintOptions, LintRule};
+pub use number::{CalcNum, OverflowPolicy};
+pub use parse::{Assoc, IntegerMode, NumericOptions, Operator, RightShift};
+pub use prefix::PrefixOptions;
+pub use pretty::{to_pretty_string, PrettyOptions};
+pub use sample::{sample, sample_with, SampleOptions};
+pub use sheet::eval_sheet;
+pub use style::{format_expr, FormatStyle, PowerStyle};
+pub use symbolic::Equivalence;
+pub use token::TokenizerOptions;
+pub use uncertain::Uncertain;
 pub use value::Value;
 
+use parse::Environment;
+use std::io::{self, BufRead};
+use std::iter;
+use std::num::{Saturating, Wrapping};
+use token::TokenStream;
+
 /// Evalulates a regular mathematical expression.
+///
+/// `let NAME = VALUE in BODY` binds a name for the rest of the expression,
+/// as in `let r = 3 in pi * r ** 2`. Within the body, the name shadows the
+/// atoms of the environment and the names of enclosing `let`s.
+///
+/// `cond(c1, v1, c2, v2, ..., default)` gives the value following the first
+/// condition that is not zero, or else the default. Only the conditions up
+/// to that one and its value are evaluated, so `cond(1, 2, 1/0)` is `2`.
+///
+/// `sum(i, lo, hi, body)` and `prod(i, lo, hi, body)` add or multiply the
+/// values of `body` for every integer `i` from `lo` to `hi`, e.g.
+/// `sum(i, 1, 100, i**2)` is `338350`. Ranges of more than
+/// `ast::MAX_RANGE` values are rejected.
+///
+/// `integrate(body, x, a, b)` approximates the integral of `body` over `x`
+/// from `a` to `b`, to the tolerance of the `NumericOptions` of the
+/// environment, e.g. `integrate(x**2, x, 0, 1)` is about `0.3333`. It
+/// fails with `CalcError::NoConvergence` at a singularity.
+/// `solve(body, x, guess)` finds a root of `body` near `guess`, such as
+/// `solve(x**2 - 2, x, 1)`, while `solve(body, x, lo, hi)` bisects an
+/// interval at whose ends `body` has opposite signs. Both fail with
+/// `CalcError::NoConvergence` if no root is found. `ddx(body, x, at)`
+/// approximates the derivative of `body` with respect to `x` at `at`, and
+/// fails with `CalcError::NoConvergence` if it does not settle, as at a
+/// pole.
 pub fn eval(input: &str) -> Result<Value, CalcError> {
     let mut env = parse::DefaultEnvironment;
-    token::tokenize(input).and_then(|x| parse::parse(&x, &mut env))
+    TokenStream::new(input).and_then(|x| parse::build(&x, &mut env))
+}
+
+/// The functions that `eval_to_string` only accepts as the whole input.
+pub(crate) const TOP_LEVEL_CALLS: [&str; 2] = ["twos", "approxfrac"];
+
+/// Evaluates a regular mathematical expression and renders the result
+/// according to `options`, e.g. as a fraction.
+///
+/// The whole input may also be a call to `twos(value, width)`, which
+/// renders the two's-complement bit pattern of `value` at a width of 8, 16,
+/// 32 or 64 bits, e.g. `twos(-3, 8)` gives `1111_1101`. Since it produces
+/// a string, it cannot be part of a larger expression. The same goes for
+/// `approxfrac(x, max_den)`, which renders the fraction that
+/// `approx(x, max_den)` finds, e.g. `approxfrac(0.7142857, 100)` gives
+/// `5/7`.
+pub fn eval_to_string(
+    input: &str,
+    options: &FormatOptions,
+) -> Result<String, CalcError> {
+    if let Some((value, width)) = call_args(input, "twos") {
+        return format::twos(&eval(value)?, &eval(width)?);
+    }
+    if let Some((x, max_den)) = call_args(input, "approxfrac") {
+        return format::approxfrac(&eval(x)?, &eval(max_den)?);
+    }
+    eval(input).map(|value| options.format(&value))
+}
+
+/// Evaluates a regular mathematical expression and lays its integer result
+/// out in every base at `width` bits, e.g. `eval_bases("-3", 8)` gives
+/// `0xfd`, `0o375` and `0b11111101`, read back as `-3` or `253`.
+///
+/// Fails with `CalcError::BadTypes` if the result is not an integer.
+pub fn eval_bases(input: &str, width: u32) -> Result<BaseView, CalcError> {
+    eval(input).and_then(|value| BaseView::new(&value, width))
+}
+
+/// Splits an input of the form `name(a, b)`, such as `twos(value, width)`,
+/// into its arguments.
+fn call_args<'a>(input: &'a str, name: &str) -> Option<(&'a str, &'a str)> {
+    let args = input.trim().strip_prefix(name)?.trim_start();
+    let args = args.strip_prefix('(')?.strip_suffix(')')?;
+    let mut depth = 0;
+    let mut comma = None;
+    for (pos, c) in args.char_indices() {
+        match c {
+            '(' => depth += 1,
+            // The closing parenthesis belongs to an earlier group, as in
+            // `twos(1, 8) + (2)`.
+            ')' if depth == 0 => return None,
+            ')' => depth -= 1,
+            ',' if depth == 0 && comma.is_none() => comma = Some(pos),
+            ',' if depth == 0 => return None,
+            _ => {}
+        }
+    }
+    comma.map(|pos| (&args[..pos], &args[pos + 1..]))
+}
+
+/// Evaluates an expression tree, e.g. one built with the `Expr`
+/// constructors.
+pub fn eval_expr(expr: &Expr) -> Result<Value, CalcError> {
+    ast::evaluate(expr, &mut parse::DefaultEnvironment)
+}
+
+/// Evaluates an expression tree, with extra environment variables.
+pub fn eval_expr_with<E>(expr: &Expr, env: &mut E) -> Result<Value, CalcError>
+where
+    E: parse::Environment,
+{
+    ast::evaluate(expr, env)
+}
+
+/// Differentiates an expression with respect to the variable `var`, e.g.
+/// `diff("3 * x ** 2 + sin x", "x")` gives `6 * x + cos x`.
+pub fn diff(input: &str, var: &str) -> Result<Expr, CalcError> {
+    Expr::parse(input)?.derivative(var)
+}
+
+/// Solves an equation such as `2 * x + 6 = 20` for the variable `var`.
+///
+/// The equation must be linear in `var`, and every other atom must be
+/// known to the default environment.
+pub fn solve(input: &str, var: &str) -> Result<f64, CalcError> {
+    let sides: Vec<&str> = input.split('=').collect();
+    if sides.len() != 2 {
+        return Err(CalcError::Unsolvable(Unsolvable::NotAnEquation));
+    }
+    let lhs = Expr::parse(sides[0].trim())?;
+    let rhs = Expr::parse(sides[1].trim())?;
+    symbolic::solve_linear(&lhs, &rhs, var)
+}
+
+/// Checks whether two expressions compute the same function of their
+/// variables, e.g. `x * (y + 1)` and `x * y + x`.
+pub fn equivalent(a: &str, b: &str) -> Result<Equivalence, CalcError> {
+    Ok(symbolic::compare(&Expr::parse(a)?, &Expr::parse(b)?))
+}
+
+/// Computes a hash of an expression that ignores whitespace, for caching or
+/// deduplicating expressions across processes.
+///
+/// The hash is the 64-bit FNV-1a of the tokens, so `1+2` and ` 1 + 2 `
+/// collide but `1 + 2` and `2 + 1` do not. Unlike `DefaultHasher`, it is
+/// stable across runs.
+pub fn fingerprint(input: &str) -> Result<u64, CalcError> {
+    TokenStream::new(input).map(|tokens| tokens.fingerprint())
+}
+
+/// Renders an expression in prefix notation with the default
+/// `PrefixOptions`, e.g. `1 + 2 * 3` as `(+ 1 (* 2 3))`.
+pub fn to_prefix(input: &str) -> Result<String, CalcError> {
+    Ok(Expr::parse(input)?.to_prefix(&PrefixOptions::default()))
+}
+
+/// Evaluates a regular mathematical expression in the given numeric type,
+/// e.g. `f64` or `f32`. `eval` is the instantiation for `Value`.
+pub fn eval_generic<N: CalcNum>(input: &str) -> Result<N, CalcError> {
+    let mut env = parse::DefaultEnvironment;
+    TokenStream::new(input).and_then(|x| parse::build(&x, &mut env))
+}
+
+/// Evaluates a regular mathematical expression using exact `i128`
+/// arithmetic.
+///
+/// Unlike `eval`, fractional literals are rejected, division must be exact,
+/// and results that do not fit into an `i128` produce
+/// `CalcError::WouldOverflow` instead of being promoted or wrapped.
+pub fn eval_int(input: &str) -> Result<i128, CalcError> {
+    eval_generic(input)
+}
+
+/// Evaluates a regular mathematical expression using `i64` arithmetic,
+/// handling results that do not fit into an `i64` according to `policy`.
+///
+/// As with `eval_int`, fractional literals are rejected and division must
+/// be exact. Literals that do not fit into an `i64` are rejected under
+/// every policy. A `-` in front of a literal belongs to it, so
+/// `-9223372036854775808` is `i64::MIN`.
+///
+/// ```
+/// # extern crate calc;
+/// # use calc::{eval_i64, OverflowPolicy};
+/// let max = "9223372036854775807";
+/// assert!(eval_i64(&format!("{} + 1", max), OverflowPolicy::Error).is_err());
+/// assert_eq!(
+///     eval_i64(&format!("{} + 1", max), OverflowPolicy::Wrap),
+///     Ok(i64::MIN)
+/// );
+/// assert_eq!(eval_i64("2 ** 70", OverflowPolicy::Saturate), Ok(i64::MAX));
+/// assert_eq!(
+///     eval_i64("-9223372036854775808", OverflowPolicy::Error),
+///     Ok(i64::MIN)
+/// );
+/// ```
+pub fn eval_i64(input: &str, policy: OverflowPolicy) -> Result<i64, CalcError> {
+    match policy {
+        OverflowPolicy::Error => eval_generic(input),
+        OverflowPolicy::Wrap => eval_generic(input).map(|n: Wrapping<i64>| n.0),
+        OverflowPolicy::Saturate => {
+            eval_generic(input).map(|n: Saturating<i64>| n.0)
+        }
+    }
+}
+
+/// Evaluates a regular mathematical expression using exact rational
+/// arithmetic on numbers of unbounded size.
+#[cfg(feature = "bignum")]
+pub fn eval_big(input: &str) -> Result<BigNum, CalcError> {
+    eval_generic(input)
+}
+
+/// Evaluates an expression of measurements with uncertainties, such as
+/// `(5.0 ± 0.1) * (2.0 ± 0.05)`, which is `10.00 ± 0.32`. `+-` is
+/// accepted for `±`, which binds like `+`, so `2 * 3 ± 0.1` is `6 ± 0.1`.
+/// See `Uncertain` for how the uncertainties propagate.
+pub fn eval_uncertain(input: &str) -> Result<Uncertain, CalcError> {
+    let mut env = uncertain::UncertainEnvironment;
+    let operators = env.operators();
+    TokenStream::with_operators(input, &operators)
+        .and_then(|x| parse::build(&x, &mut env))
+}
+
+/// Evaluates an expression of complex numbers, such as `(2 + 3i) * (1 - i)`,
+/// which is `5 + i`. `i` and `j` stand for the imaginary unit, and a number
+/// directly followed by either is an imaginary literal. `sqrt`, `exp`, `ln`,
+/// `log`, the trigonometric functions and `abs` take complex arguments,
+/// `re`, `im`, `arg` and `conj` take them apart, and the other functions
+/// only take real ones.
+pub fn eval_complex(input: &str) -> Result<Complex, CalcError> {
+    let tokens = complex::imaginary_literals(token::tokenize(input)?)?;
+    parse::build(
+        &TokenStream::from_tokens(&tokens),
+        &mut complex::ComplexEnvironment,
+    )
+}
+
+/// Evaluates an expression in `f64` with `args` bound to its placeholders.
+/// Each bare `?` takes the next argument, while `?N` takes the `N`th one,
+/// counting from 1, and may be repeated. There must be exactly as many
+/// arguments as the highest index, or `CalcError::WrongArity` is returned.
+///
+/// To evaluate the same expression with many sets of arguments, compile it
+/// once and use `CompiledExpr::eval_with_args`.
+///
+/// ```
+/// # extern crate calc;
+/// # use calc::eval_with_args;
+/// assert_eq!(eval_with_args("? * ? + ?", &[2.0, 3.0, 4.0]), Ok(10.0));
+/// assert_eq!(eval_with_args("?1 * ?1 - ?2", &[3.0, 1.0]), Ok(8.0));
+/// ```
+pub fn eval_with_args(input: &str, args: &[f64]) -> Result<f64, CalcError> {
+    let expression: Expression = input.parse()?;
+    expression.compile(&[])?.eval_with_args(args)
 }
 
 /// Evalulates a regular mathematical expression, with extra environment
@@ -31,7 +369,113 @@ pub fn eval_with_env<E>(input: &str, env: &mut E) -> Result<Value, CalcError>
 where
     E: parse::Environment,
 {
-    token::tokenize(input).and_then(|x| parse::parse(&x, env))
+    let operators = env.operators();
+    TokenStream::with_operators(input, &operators)
+        .and_then(|x| parse::build(&x, env))
+}
+
+/// Evaluates a regular mathematical expression against the variables,
+/// functions and operators of a `Context`. Names the context doesn't
+/// define are looked up in the `DefaultEnvironment`.
+///
+/// ```
+/// # extern crate calc;
+/// # use calc::{eval_with_context, CalcError, Context, Value};
+/// # fn main() -> Result<(), CalcError> {
+/// let mut context = Context::new();
+/// context.set_var("r", Value::dec(2));
+/// context.register_f64_fn("clamp", 3, |args| {
+///     Ok(args[0].max(args[1]).min(args[2]))
+/// });
+/// let value = eval_with_context("clamp(pi * r, 0, 5)", &context)?;
+/// assert_eq!(value, Value::from_f64(5.0));
+/// # Ok(())
+/// # }
+/// ```
+pub fn eval_with_context(
+    input: &str,
+    context: &Context,
+) -> Result<Value, CalcError> {
+    eval_with_env(input, &mut { context })
+}
+
+/// Evaluates a regular mathematical expression like `eval`, telling where
+/// in the input an error occurred, if it is about a token or about the
+/// input ending too early.
+///
+/// ```
+/// # extern crate calc;
+/// # use calc::{eval_located, CalcError};
+/// let err = eval_located("2 * (3 + $)").unwrap_err();
+/// assert_eq!(err.error, CalcError::UnrecognizedToken("$".into()));
+/// assert_eq!(err.to_string(), "unrecognized token: $ at column 10");
+/// println!("{}", err.excerpt("2 * (3 + $)").unwrap());
+/// ```
+pub fn eval_located(input: &str) -> Result<Value, Located> {
+    eval_located_with_env(input, &mut parse::DefaultEnvironment)
+}
+
+/// Evaluates a regular mathematical expression like `eval_located`, with
+/// extra environment variables.
+pub fn eval_located_with_env<E>(
+    input: &str,
+    env: &mut E,
+) -> Result<Value, Located>
+where
+    E: parse::Environment,
+{
+    parse::build_located(input, env)
+}
+
+/// Evaluates a batch of independent expressions, preserving their order.
+///
+/// Each input produces its own result, so a failing expression never aborts
+/// the rest of the batch. With the `parallel` feature the inputs are spread
+/// over the rayon thread pool; otherwise they are evaluated one by one.
+#[cfg(feature = "parallel")]
+pub fn eval_many(inputs: &[&str]) -> Vec<Result<Value, CalcError>> {
+    use rayon::prelude::*;
+    inputs.par_iter().map(|input| eval(input)).collect()
+}
+
+/// Evaluates a batch of independent expressions, preserving their order.
+///
+/// Each input produces its own result, so a failing expression never aborts
+/// the rest of the batch. With the `parallel` feature the inputs are spread
+/// over the rayon thread pool; otherwise they are evaluated one by one.
+#[cfg(not(feature = "parallel"))]
+pub fn eval_many(inputs: &[&str]) -> Vec<Result<Value, CalcError>> {
+    inputs.iter().map(|input| eval(input)).collect()
+}
+
+/// Evaluates a stream of expressions, one per line, lazily.
+///
+/// Blank lines and comments starting with `#` are skipped. Like `eval_many`,
+/// every line produces its own result, so an invalid line does not stop the
+/// following ones. Read failures are reported as `CalcError::Io`; the
+/// iterator ends after one, unless it was caused by a line that is not
+/// valid UTF-8.
+pub fn eval_lines<R: BufRead>(
+    reader: R,
+) -> impl Iterator<Item = Result<f64, CalcError>> {
+    let mut lines = reader.lines();
+    let mut done = false;
+    iter::from_fn(move || loop {
+        if done {
+            return None;
+        }
+        let line = match lines.next()? {
+            Ok(line) => line,
+            Err(err) => {
+                done = err.kind() != io::ErrorKind::InvalidData;
+                return Some(Err(err.into()));
+            }
+        };
+        let line = line.trim();
+        if !line.is_empty() && !line.starts_with('#') {
+            return Some(eval(line).map(|value| value.as_f64()));
+        }
+    })
 }
 
 /// Evalulates mathematical expressions that are written in Polish Notation.
@@ -99,12 +543,391 @@ mod tests {
             ("4 ^ 3 ^ 2 ^ 3 ^ 4 ^ 2", Value::dec(0)),
             ("3 << (4 >> 2)", Value::dec(6)),
             ("~0", Value::dec(-1)),
-            // ("cos pi + sin (tau * (3 / 4))", Value::Float(d128!(-2.0))),
+            ("cos pi + sin (tau * (3 / 4))", Value::Float(d128!(-2.0))),
             ("~~5", Value::dec(5)),
+            ("0xff & 0x0f", Value::hex(15)),
+            ("0b1 << 4", Value::dec(16)),
+            ("0o755 - 0b1010", Value::dec(483)),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(eval(input), Ok(expected));
+        }
+    }
+
+    #[test]
+    fn exact_integers() {
+        let big = 9_007_199_254_740_993i64;
+        let cases = vec![
+            (
+                "0xfffffffffffffff | 1",
+                Value::hex(0x0fff_ffff_ffff_ffffi64),
+            ),
+            ("9007199254740993 & -1", Value::dec(big)),
+            ("~9007199254740992", Value::dec(-big)),
+            ("9007199254740993 ^ 1", Value::dec(big - 1)),
+            ("1 << 63 >> 63", Value::dec(1)),
+            ("6 / 2", Value::dec(3)),
+            ("7 / 2", Value::Float(d128!(3.5))),
+            ("2 * 1.5", Value::Float(d128!(3.0))),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(eval(input), Ok(expected));
+        }
+        assert!(matches!(eval("1.5 & 1"), Err(CalcError::BadTypes(_))));
+    }
+
+    #[test]
+    fn unary_operators() {
+        let cases = vec![
+            ("-(2+3)*4", Value::dec(-20)),
+            ("--5", Value::dec(5)),
+            ("3 - -(1+1)", Value::dec(5)),
+            ("-(2*3)", Value::dec(-6)),
+            ("+5", Value::dec(5)),
+            ("2 * +(1 + 2) ** 2", Value::dec(18)),
+            ("1 + +2 - +-3", Value::dec(6)),
+            // A power binds tighter than the `-` in front of it.
+            ("-2 ** 2", Value::dec(-4)),
+            ("-2²", Value::dec(-4)),
+            ("-2³ * 2", Value::dec(-16)),
+            ("-2 ** 2 ** 2", Value::dec(-16)),
+            ("(-2)²", Value::dec(4)),
+            ("2 ** -2 ** 2", Value::dec(2).pow(Value::dec(-4)).unwrap()),
+            ("-2 * 3", Value::dec(-6)),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(eval(input), Ok(expected));
+        }
+        assert_eq!(eval("5 * -"), Err(CalcError::UnexpectedEndOfInput));
+        assert_eq!(eval("5 * +"), Err(CalcError::UnexpectedEndOfInput));
+    }
+
+    #[test]
+    fn let_bindings() {
+        let cases = vec![
+            ("let r = 3 in r ** 2 * 2", Value::dec(18)),
+            ("let a = 1 in let b = a + 1 in a + b", Value::dec(3)),
+            ("let a = 1 in let a = a + 10 in a", Value::dec(11)),
+            ("let pi = 3 in pi * 2", Value::dec(6)),
+            ("2 * let x = 4 in x + 1", Value::dec(10)),
+            ("let x = (let y = 2 in y * y) in x + 1", Value::dec(5)),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(eval(input), Ok(expected));
+        }
+
+        let mut context = Context::new();
+        context.set_var("x", Value::dec(10));
+        let input = "(let x = 1 in x) + x";
+        assert_eq!(eval_with_env(input, &mut &context), Ok(Value::dec(11)));
+        assert_eq!(context.var("x"), Some(&Value::dec(10)));
+    }
+
+    #[test]
+    fn let_scope() {
+        assert_eq!(
+            eval("(let a = 1 in a) + a"),
+            Err(CalcError::UnknownAtom("a".into(), None))
+        );
+        assert_eq!(eval("let x = 1"), Err(CalcError::UnexpectedEndOfInput));
+        assert_eq!(
+            eval("let x 1 in x"),
+            Err(CalcError::UnexpectedToken("'1'".into(), "="))
+        );
+        assert_eq!(
+            eval("let in = 1 in 2"),
+            Err(CalcError::UnexpectedToken("'in'".into(), "name"))
+        );
+    }
+
+    #[test]
+    fn trailing_tokens() {
+        for input in &["1,2", "1 , 2", "2π", "3 x", "1 = 2", "1 in 2"] {
+            match eval(input) {
+                Err(CalcError::UnexpectedToken(_, "operator")) => {}
+                other => panic!("{:?} gave {:?}", input, other),
+            }
+        }
+        for input in &["approx(2.5) + 10", "approx(2.5)", "approx(0.5) * 3"] {
+            assert_eq!(
+                eval(input),
+                Err(CalcError::WrongArity {
+                    atom: "approx".into(),
+                    expected: 2,
+                    actual: 1,
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn let_shared() {
+        // Every binding doubles the one before, which the trees must not
+        // do by copying it.
+        let mut input = "let a0 = 1 in ".to_owned();
+        for i in 1..40 {
+            input += &format!("let a{} = a{} + a{} in ", i, i - 1, i - 1);
+        }
+        input += "a39 - 1";
+        let expected = Value::dec((1u64 << 39) - 1);
+        assert_eq!(eval(&input), Ok(expected.clone()));
+
+        let expr = Expr::parse(&input).unwrap();
+        assert!(expr.iter().count() < 1000, "{}", expr);
+        assert_eq!(eval_expr(&expr), Ok(expected));
+        assert_eq!(Expr::parse(&expr.to_string()), Ok(expr));
+
+        let e: Expression = input.parse().unwrap();
+        assert_eq!(e.value(), Ok(549_755_813_887.0));
+        let compiled = e.compile(&[]).unwrap();
+        assert_eq!(compiled.eval(&[]), Ok(549_755_813_887.0));
+
+        let e: Expression =
+            "let x = y * 2 in let y = x + 1 in x * y".parse().unwrap();
+        assert_eq!(e.to_string(), "let x = y * 2 in let y = x + 1 in x * y");
+        assert_eq!(e.compile(&["y"]).unwrap().eval(&[3.0]), Ok(42.0));
+    }
+
+    #[test]
+    fn functions() {
+        let cases = vec![
+            ("sqrt(abs(-16))", Value::dec(4)),
+            ("sqrt 0x90", Value::hex(12)),
+            ("log(1000)", Value::Float(d128!(3))),
+            ("abs -2.5", Value::Float(d128!(2.5))),
+            ("floor(2.5) + ceil 2.5", Value::Float(d128!(5))),
+            ("floor(-2.5) + ceil(-2.5)", Value::Float(d128!(-5))),
+            ("ceil(7) * floor(-7)", Value::dec(-49)),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(eval(input), Ok(expected));
+        }
+
+        let approximations = vec![
+            ("2 * pi * 3²", 18.0 * std::f64::consts::PI),
+            ("sin(pi / 4) * sqrt 2", 1.0),
+            ("sqrt 2", 2f64.sqrt()),
+            ("ln(e ** 2)", 2.0),
+            ("sqrt(16) + log(100) + ln(1) + exp(0) + abs(-3)", 10.0),
+        ];
+        for (input, expected) in approximations {
+            let value = eval(input).unwrap().as_f64();
+            assert!((value - expected).abs() < 1e-9, "{}: {}", input, value);
+        }
+        match eval("sqrt(-4)") {
+            Err(CalcError::InvalidArgument(_)) => {}
+            other => panic!("square root of -4 gave {:?}", other),
+        }
+    }
+
+    #[test]
+    fn cond() {
+        let cases = vec![
+            ("cond(1, 10, 1, 20, 30)", Value::dec(10)),
+            ("cond(0, 10, 2 - 2, 20, 30)", Value::dec(30)),
+            ("cond(0, 10, 5 % 3, 20, 30)", Value::dec(20)),
+            ("cond(1, 2, 1/0)", Value::dec(2)),
+            ("cond(0, 1/0, 1, 2, 1/0)", Value::dec(2)),
+            ("1 + cond(0, 10, 20) * 2", Value::dec(41)),
+            ("cond(cond(0, 1, 0), 1, cond(1, 2, 3)) ** 2", Value::dec(4)),
+            ("let x = 4 in cond(x % 2, 1, x / 2)", Value::dec(2)),
         ];
         for (input, expected) in cases {
             assert_eq!(eval(input), Ok(expected));
         }
+
+        let arity = |expected, actual| {
+            Err(CalcError::WrongArity {
+                atom: "cond".into(),
+                expected,
+                actual,
+            })
+        };
+        assert_eq!(eval("cond(1, 2)"), arity(3, 2));
+        assert_eq!(eval("cond(1, 2, 3, 4)"), arity(5, 4));
+        assert_eq!(
+            eval("cond 1"),
+            Err(CalcError::UnexpectedToken("'1'".into(), "("))
+        );
+    }
+
+    #[test]
+    fn cond_trees() {
+        let expr = Expr::parse("cond(x, 1 / 0, x + 1, 2, 3)").unwrap();
+        assert_eq!(expr.to_string(), "cond(x, 1 / 0, x + 1, 2, 3)");
+        assert_eq!(Expr::parse(&expr.to_string()), Ok(expr.clone()));
+        let mut context = Context::new();
+        context.set_var("x", Value::dec(0));
+        assert_eq!(eval_expr_with(&expr, &mut &context), Ok(Value::dec(2)));
+    }
+
+    #[test]
+    fn sums() {
+        let cases = vec![
+            ("sum(i, 1, 100, i**2)", Value::dec(338_350)),
+            ("prod(i, 1, 10, i)", Value::dec(3_628_800)),
+            ("sum(i, 5, 1, i)", Value::dec(0)),
+            ("prod(i, 5, 1, 1/0)", Value::dec(1)),
+            ("sum(i, 1, 3, sum(j, 1, i, j))", Value::dec(10)),
+            ("let n = 4 in sum(i, 1, n, i * n) + 1", Value::dec(41)),
+            ("sum(i, -2, 2, cond(i, 1, 10))", Value::dec(14)),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(eval(input), Ok(expected));
+        }
+
+        let mut context = Context::new();
+        context.set_var("k", Value::dec(2));
+        assert_eq!(
+            eval_with_env("sum(i, 1, 3, i * k)", &mut &context),
+            Ok(Value::dec(12))
+        );
+        assert_eq!(
+            eval("sum(i, 1, 3, i) + i"),
+            Err(CalcError::UnknownAtom("i".into(), None))
+        );
+        match eval("sum(i, 1, 10**12, i)") {
+            Err(CalcError::InvalidArgument(_)) => {}
+            other => panic!("huge range gave {:?}", other),
+        }
+        match eval("sum(i, 1, 2.5, i)") {
+            Err(CalcError::InvalidArgument(_)) => {}
+            other => panic!("fractional bound gave {:?}", other),
+        }
+    }
+
+    #[test]
+    fn sum_trees() {
+        let input = "sum(i, 1, n, i ** 2) * 2";
+        let expr = Expr::parse(input).unwrap();
+        assert_eq!(expr.to_string(), input);
+        let mut context = Context::new();
+        context.set_var("n", Value::dec(3));
+        assert_eq!(eval_expr_with(&expr, &mut &context), Ok(Value::dec(28)));
+        let e = Expression::parse_with(input, &context).unwrap();
+        assert_eq!(e.to_expr(), expr);
+        assert_eq!(e.value_with(&context), Ok(28.0));
+    }
+
+    #[test]
+    fn integrals() {
+        let cases = vec![
+            ("integrate(x**2, x, 0, 1)", 1.0 / 3.0),
+            ("integrate(sin x, x, 0, pi)", 2.0),
+            ("integrate(exp x, x, 0, 1)", std::f64::consts::E - 1.0),
+            ("integrate(x**2, x, 1, 0)", -1.0 / 3.0),
+            ("integrate(x, x, 2, 2)", 0.0),
+            ("integrate(x**0.5, x, 0, 1)", 2.0 / 3.0),
+            ("let a = 2 in integrate(a * x, x, 0, a)", 4.0),
+            ("integrate(integrate(x * y, y, 0, 1), x, 0, 2)", 1.0),
+        ];
+        for (input, expected) in cases {
+            let value = eval(input).unwrap().as_f64();
+            assert!((value - expected).abs() < 1e-9, "{}: {}", input, value);
+        }
+        // However the body fails at a singularity, the integral does not
+        // converge.
+        for input in &[
+            "integrate(x**-0.5, x, 0, 1)",
+            "integrate(1 / sqrt(x), x, 0, 1)",
+            "integrate(1 / x, x, -1, 1)",
+        ] {
+            assert_eq!(
+                eval(input),
+                Err(CalcError::NoConvergence("integrate".into())),
+                "{}",
+                input
+            );
+        }
+        assert_eq!(
+            eval("integrate(x, x, 0, 1, 2)"),
+            Err(CalcError::WrongArity {
+                atom: "integrate".into(),
+                expected: 4,
+                actual: 5,
+            })
+        );
+    }
+
+    #[test]
+    fn roots() {
+        let cases = vec![
+            ("solve(x**2 - 2, x, 1)", 2f64.sqrt()),
+            ("solve(x**2 - 2, x, -1000)", -(2f64.sqrt())),
+            ("solve(cos x - x, x, 1)", 0.739_085_133_215_160_6),
+            ("solve(sin x, x, 3)", std::f64::consts::PI),
+            ("solve(x**3 - x - 2, x, 1, 2)", 1.521_379_706_804_567_6),
+            ("solve(exp x - 3, x, 0, 5)", 3f64.ln()),
+            ("solve(x - 1, x, 1, 1)", 1.0),
+        ];
+        for (input, expected) in cases {
+            let value = eval(input).unwrap().as_f64();
+            assert!((value - expected).abs() < 1e-9, "{}: {}", input, value);
+        }
+        let no_convergence = Err(CalcError::NoConvergence("solve".into()));
+        // No real roots, a derivative of zero, and a cycle of Newton steps.
+        assert_eq!(eval("solve(x**2 + 1, x, 1)"), no_convergence);
+        assert_eq!(eval("solve(x**2 - 2, x, 0)"), no_convergence);
+        assert_eq!(eval("solve(x**3 - 2*x + 2, x, 0)"), no_convergence);
+        match eval("solve(x**2 + 1, x, -1, 1)") {
+            Err(CalcError::InvalidArgument(_)) => {}
+            other => panic!("interval without a sign change gave {:?}", other),
+        }
+        assert_eq!(
+            eval("solve(x, x)"),
+            Err(CalcError::WrongArity {
+                atom: "solve".into(),
+                expected: 3,
+                actual: 2,
+            })
+        );
+    }
+
+    #[test]
+    fn derivatives() {
+        let cases = vec![
+            ("ddx(sin(x) * x, x, 2)", 2f64.sin() + 2.0 * 2f64.cos()),
+            ("ddx(x * cos x, x, 0)", 1.0),
+            ("ddx(exp x, x, 1)", std::f64::consts::E),
+            ("ddx(x**3, x, -2)", 12.0),
+            ("ddx(ln x, x, 10)", 0.1),
+            ("ddx(x**2, x, 1000000)", 2e6),
+            ("ddx(7, x, 3)", 0.0),
+            ("let a = 3 in ddx(a * x**2, x, a)", 18.0),
+        ];
+        for (input, expected) in cases {
+            let value = eval(input).unwrap().as_f64();
+            let error = (value - expected).abs() / expected.abs().max(1.0);
+            assert!(error < 1e-8, "{}: {}", input, value);
+        }
+        // The samples are taken a tenth to either side of 0.
+        assert_eq!(
+            eval("ddx(1 / (x - 0.1), x, 0)"),
+            Err(CalcError::DivideByZero)
+        );
+        // Near a pole, the estimates grow with every step.
+        for input in &["ddx(1 / x, x, 0)", "ddx(tan x, x, pi / 2)"] {
+            assert_eq!(
+                eval(input),
+                Err(CalcError::NoConvergence("ddx".into())),
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn integral_trees() {
+        let input = "integrate(x ** n, x, 0, 1)";
+        let expr = Expr::parse(input).unwrap();
+        assert_eq!(expr.to_string(), input);
+        let mut context = Context::new();
+        context.set_var("n", Value::dec(3));
+        let value = eval_expr_with(&expr, &mut &context).unwrap().as_f64();
+        assert!((value - 0.25).abs() < 1e-9);
+        let e = Expression::parse_with(input, &context).unwrap();
+        assert_eq!(e.to_expr(), expr);
+        assert!((e.value_with(&context).unwrap() - 0.25).abs() < 1e-9);
     }
 
     #[test]
@@ -124,6 +947,128 @@ mod tests {
         }
     }
 
+    #[test]
+    fn many() {
+        let inputs: Vec<String> = (1..1000)
+            .map(|i| {
+                if i % 3 == 0 {
+                    format!("{} / 0", i)
+                } else {
+                    format!("{} * 2", i)
+                }
+            })
+            .collect();
+        let inputs: Vec<&str> = inputs.iter().map(|s| s.as_str()).collect();
+        let results = eval_many(&inputs);
+        assert_eq!(results.len(), inputs.len());
+        for (i, result) in (1..).zip(results) {
+            if i % 3 == 0 {
+                assert_eq!(result, Err(CalcError::DivideByZero));
+            } else {
+                assert_eq!(result, Ok(Value::dec(i * 2)));
+            }
+        }
+    }
+
+    #[test]
+    fn fingerprints() {
+        let sum = fingerprint("1 + 2").unwrap();
+        assert_eq!(fingerprint(" 1+2 "), Ok(sum));
+        assert_eq!(fingerprint("1\t+\n2"), Ok(sum));
+        assert_ne!(fingerprint("2 + 1"), Ok(sum));
+        assert_ne!(fingerprint("12"), fingerprint("1 2"));
+        assert_ne!(fingerprint("x y"), fingerprint("xy"));
+        assert_ne!(fingerprint("0x10"), fingerprint("16"));
+        assert!(fingerprint("1 $ 2").is_err());
+
+        // The hash is pinned, so it may be stored and compared by other
+        // processes, whatever the seed of their `HashMap`s.
+        assert_eq!(sum, 0x30be_847d_d225_2310);
+    }
+
+    #[test]
+    fn lines() {
+        let input = b"1 + 2\n\n  # a comment\n2 *\n   \n10 / 4\n\xff\n3\n";
+        let results: Vec<_> = eval_lines(io::Cursor::new(&input[..])).collect();
+        assert_eq!(results.len(), 5);
+        assert_eq!(results[0], Ok(3.0));
+        assert_eq!(results[1], Err(CalcError::UnexpectedEndOfInput));
+        assert_eq!(results[2], Ok(2.5));
+        assert!(matches!(results[3], Err(CalcError::Io(_))));
+        assert_eq!(results[4], Ok(3.0));
+
+        struct Broken;
+
+        impl io::Read for Broken {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("disconnected"))
+            }
+        }
+
+        let results: Vec<_> = eval_lines(io::BufReader::new(Broken)).collect();
+        assert_eq!(results, vec![Err(CalcError::Io("disconnected".into()))]);
+    }
+
+    #[test]
+    fn no_panics() {
+        let deep_parens = format!("{}1{}", "(".repeat(1000), ")".repeat(1000));
+        let deep_unary = format!("{}1", "-~".repeat(1000));
+        let deep_pow = vec!["2"; 1000].join("**");
+        let cases = vec![
+            ("2 ** -1", Ok(Value::Float(d128!(0.5)))),
+            ("2 ** -(2 ** 2)", Ok(Value::Float(d128!(0.0625)))),
+            ("1 << -9223372036854775808", Err(())),
+            ("1 >> 9223372036854775808", Ok(Value::dec(0))),
+            ("1 >> -99999999999999999999", Err(())),
+            ("1 << 99999999999", Err(())),
+            ("(1 - 1) << 99999999999", Ok(Value::dec(0))),
+            ("9 ** 9 ** 9", Err(())),
+            ("1 ** 99999999999999999999", Ok(Value::dec(1))),
+            (&deep_parens, Ok(Value::dec(1))),
+            (&deep_unary, Ok(Value::dec(1001))),
+            (&deep_pow, Err(())),
+        ];
+        for (input, expected) in cases {
+            match expected {
+                Ok(value) => assert_eq!(eval(input), Ok(value)),
+                Err(()) => assert!(eval(input).is_err()),
+            }
+        }
+    }
+
+    #[test]
+    fn deep_nesting() {
+        let input = format!("{}1{}", "(".repeat(50_000), ")".repeat(50_000));
+        let result = ::std::thread::spawn(move || eval(&input)).join().unwrap();
+        assert_eq!(result, Ok(Value::dec(1)));
+
+        let input = format!("{}1{}", "(".repeat(100), ")".repeat(100));
+        assert_eq!(Expr::parse(&input), Err(CalcError::NestingTooDeep));
+    }
+
+    #[test]
+    fn long_chains() {
+        let input = vec!["1"; 100_000].join(" + ");
+        assert_eq!(eval(&input), Ok(Value::dec(100_000)));
+        // Every operator nests the chain before it one level deeper in a
+        // tree.
+        assert_eq!(Expr::parse(&input), Err(CalcError::NestingTooDeep));
+        let input = vec!["1"; 64].join(" + ");
+        assert_eq!(Expr::parse(&input).map(|e| e.depth()), Ok(64));
+    }
+
+    #[test]
+    fn long_chains_in_trees() {
+        let input = vec!["x"; 100_000].join(" + ");
+        let too_deep = CalcError::NestingTooDeep;
+        assert_eq!(lint(&input), Err(too_deep.clone()));
+        assert_eq!(diff(&input, "x"), Err(too_deep.clone()));
+        assert_eq!(equivalent(&input, "x"), Err(too_deep.clone()));
+        let mut cells = std::collections::HashMap::new();
+        cells.insert("a".to_owned(), input.replace('x', "1"));
+        assert_eq!(eval_sheet(&cells), Err(too_deep));
+    }
+
     #[test]
     fn random() {
         let cases = vec![
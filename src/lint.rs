@@ -0,0 +1,365 @@
+//! Hints about expressions that are valid, but likely not what their author
+//! meant.
+
+use ast::Expr;
+use error::CalcError;
+use std::collections::HashSet;
+use std::ops::Range;
+use token::{RawToken, Tokenizer};
+
+/// A check that `lint` runs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum LintRule {
+    /// Parentheses around a lone literal, as in `(2) * x`.
+    RedundantParens,
+    /// The same prefix operator twice in a row, as in `--x` or `~~x`,
+    /// where the two cancel out.
+    DoubleUnary,
+    /// Bitwise and arithmetic operators in one group without parentheses,
+    /// as in `x & 1 + 2`, where `+` binds tighter than `&`.
+    MixedBitwise,
+    /// Parentheses nested deeper than `LintOptions::max_nesting`.
+    DeepNesting,
+}
+
+impl LintRule {
+    /// Every rule, in the order above.
+    pub const ALL: [LintRule; 4] = [
+        LintRule::RedundantParens,
+        LintRule::DoubleUnary,
+        LintRule::MixedBitwise,
+        LintRule::DeepNesting,
+    ];
+}
+
+/// A hint about part of an expression.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lint {
+    pub rule: LintRule,
+    pub message: String,
+    /// The bytes of the input the hint is about.
+    pub span: Option<Range<usize>>,
+}
+
+/// Controls which rules `lint_with` checks.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LintOptions {
+    /// The rules to check, all of them by default.
+    pub rules: HashSet<LintRule>,
+    /// How deeply parentheses may nest before `LintRule::DeepNesting`
+    /// reports them. Defaults to 8.
+    pub max_nesting: usize,
+}
+
+impl Default for LintOptions {
+    fn default() -> Self {
+        LintOptions {
+            rules: LintRule::ALL.iter().cloned().collect(),
+            max_nesting: 8,
+        }
+    }
+}
+
+/// Checks an expression against every `LintRule`, and returns the hints
+/// ordered by their position in `input`.
+///
+/// Input that does not parse fails with the error `eval` would give,
+/// rather than producing hints.
+///
+/// ```
+/// # extern crate calc;
+/// # use calc::{lint, CalcError, LintRule};
+/// # fn main() -> Result<(), CalcError> {
+/// let lints = lint("flags & 1 + 2")?;
+/// assert_eq!(lints[0].rule, LintRule::MixedBitwise);
+/// assert_eq!(lints[0].span, Some(6..11));
+/// assert!(lint("flags & (1 + 2)")?.is_empty());
+/// # Ok(())
+/// # }
+/// ```
+pub fn lint(input: &str) -> Result<Vec<Lint>, CalcError> {
+    lint_with(input, &LintOptions::default())
+}
+
+/// Checks an expression like `lint`, against the rules in `options` only.
+pub fn lint_with(
+    input: &str,
+    options: &LintOptions,
+) -> Result<Vec<Lint>, CalcError> {
+    Expr::parse(input)?;
+    let mut tokens = Vec::new();
+    let mut tokenizer = Tokenizer::new(input);
+    while let Some(token) = tokenizer.next_spanned() {
+        tokens.push(token?);
+    }
+    let mut lints = Vec::new();
+    let enabled = |rule| options.rules.contains(&rule);
+    if enabled(LintRule::RedundantParens) {
+        redundant_parens(&tokens, &mut lints);
+    }
+    if enabled(LintRule::DoubleUnary) {
+        double_unary(input, &tokens, &mut lints);
+    }
+    if enabled(LintRule::MixedBitwise) {
+        mixed_bitwise(input, &tokens, &mut lints);
+    }
+    if enabled(LintRule::DeepNesting) {
+        deep_nesting(&tokens, options.max_nesting, &mut lints);
+    }
+    lints.sort_by_key(|lint| lint.span.as_ref().map(|span| span.start));
+    Ok(lints)
+}
+
+type Spanned<'a> = (RawToken<'a>, Range<usize>);
+
+/// Whether the operator at `pos` is a prefix one, judging by the token
+/// before it: `-` negates unless it follows an operand.
+fn is_prefix(tokens: &[Spanned], pos: usize) -> bool {
+    match tokens[pos].0 {
+        RawToken::BitWiseNot => true,
+        RawToken::Minus => !matches!(
+            pos.checked_sub(1).map(|prev| tokens[prev].0),
+            Some(RawToken::Number(_))
+                | Some(RawToken::Atom(_))
+                | Some(RawToken::Placeholder(_))
+                | Some(RawToken::CloseParen)
+                | Some(RawToken::Square)
+                | Some(RawToken::Cube)
+        ),
+        _ => false,
+    }
+}
+
+fn redundant_parens(tokens: &[Spanned], lints: &mut Vec<Lint>) {
+    for (pos, window) in tokens.windows(3).enumerate() {
+        let text = match (window[0].0, window[1].0, window[2].0) {
+            (
+                RawToken::OpenParen,
+                RawToken::Number(text),
+                RawToken::CloseParen,
+            ) => text,
+            _ => continue,
+        };
+        // The parentheses of `log(100)` belong to the call.
+        if pos > 0 && matches!(tokens[pos - 1].0, RawToken::Atom(_)) {
+            continue;
+        }
+        lints.push(Lint {
+            rule: LintRule::RedundantParens,
+            message: format!("the parentheses around {} are redundant", text),
+            span: Some(window[0].1.start..window[2].1.end),
+        });
+    }
+}
+
+fn double_unary(input: &str, tokens: &[Spanned], lints: &mut Vec<Lint>) {
+    let mut pos = 0;
+    while pos + 1 < tokens.len() {
+        if is_prefix(tokens, pos) && tokens[pos].0 == tokens[pos + 1].0 {
+            let span = tokens[pos].1.start..tokens[pos + 1].1.end;
+            lints.push(Lint {
+                rule: LintRule::DoubleUnary,
+                message: format!(
+                    "'{}' cancels out; remove both operators",
+                    &input[span.clone()]
+                ),
+                span: Some(span),
+            });
+            // A third operator starts a new pair, as in `---x`.
+            pos += 2;
+        } else {
+            pos += 1;
+        }
+    }
+}
+
+fn mixed_bitwise(input: &str, tokens: &[Spanned], lints: &mut Vec<Lint>) {
+    /// The first operator of each kind in a parenthesized group or call
+    /// argument.
+    #[derive(Default)]
+    struct Group {
+        bitwise: Option<usize>,
+        arithmetic: Option<usize>,
+        reported: bool,
+    }
+
+    let mut groups = vec![Group::default()];
+    for pos in 0..tokens.len() {
+        let bitwise = match tokens[pos].0 {
+            RawToken::OpenParen => {
+                groups.push(Group::default());
+                continue;
+            }
+            RawToken::CloseParen => {
+                if groups.len() > 1 {
+                    groups.pop();
+                }
+                continue;
+            }
+            RawToken::Comma | RawToken::Assign => {
+                groups.pop();
+                groups.push(Group::default());
+                continue;
+            }
+            RawToken::BitWiseAnd
+            | RawToken::BitWiseOr
+            | RawToken::BitWiseXor
+            | RawToken::BitWiseLShift
+            | RawToken::BitWiseRShift => true,
+            RawToken::Plus
+            | RawToken::Multiply
+            | RawToken::Divide
+            | RawToken::Modulo
+            | RawToken::Exponent => false,
+            RawToken::Minus if !is_prefix(tokens, pos) => false,
+            _ => continue,
+        };
+        let group = groups.last_mut().expect("the outermost group stays");
+        if bitwise {
+            group.bitwise = group.bitwise.or(Some(pos));
+        } else {
+            group.arithmetic = group.arithmetic.or(Some(pos));
+        }
+        let (bitwise, arithmetic) = match (group.bitwise, group.arithmetic) {
+            (Some(b), Some(a)) if !group.reported => (b, a),
+            _ => continue,
+        };
+        group.reported = true;
+        let (first, last) = (bitwise.min(arithmetic), bitwise.max(arithmetic));
+        lints.push(Lint {
+            rule: LintRule::MixedBitwise,
+            message: format!(
+                "'{}' binds tighter than '{}'; add parentheses to make the \
+                 grouping explicit",
+                &input[tokens[arithmetic].1.clone()],
+                &input[tokens[bitwise].1.clone()]
+            ),
+            span: Some(tokens[first].1.start..tokens[last].1.end),
+        });
+    }
+}
+
+fn deep_nesting(tokens: &[Spanned], max: usize, lints: &mut Vec<Lint>) {
+    let mut depth = 0;
+    for &(token, ref span) in tokens {
+        match token {
+            RawToken::OpenParen => {
+                depth += 1;
+                if depth == max + 1 {
+                    lints.push(Lint {
+                        rule: LintRule::DeepNesting,
+                        message: format!(
+                            "parentheses are nested more than {} deep; \
+                             consider splitting the expression",
+                            max
+                        ),
+                        span: Some(span.clone()),
+                    });
+                }
+            }
+            RawToken::CloseParen => depth -= 1,
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(input: &str) -> Vec<LintRule> {
+        lint(input).unwrap().iter().map(|lint| lint.rule).collect()
+    }
+
+    #[test]
+    fn redundant_parens() {
+        let lints = lint("(2) * x").unwrap();
+        assert_eq!(
+            lints,
+            vec![Lint {
+                rule: LintRule::RedundantParens,
+                message: "the parentheses around 2 are redundant".into(),
+                span: Some(0..3),
+            }]
+        );
+        assert_eq!(rules("x * ( 0.5 )"), vec![LintRule::RedundantParens]);
+        assert!(rules("log(100) + (x)").is_empty());
+        assert!(rules("(2 + 3) * x").is_empty());
+    }
+
+    #[test]
+    fn double_unary() {
+        let lints = lint("2 * --x").unwrap();
+        assert_eq!(lints[0].rule, LintRule::DoubleUnary);
+        assert_eq!(lints[0].message, "'--' cancels out; remove both operators");
+        assert_eq!(lints[0].span, Some(4..6));
+        assert_eq!(rules("~ ~x"), vec![LintRule::DoubleUnary]);
+        assert_eq!(rules("---x"), vec![LintRule::DoubleUnary]);
+        assert!(rules("2 - -x").is_empty());
+        assert!(rules("-~x").is_empty());
+    }
+
+    #[test]
+    fn mixed_bitwise() {
+        let lints = lint("x & 1 + 2").unwrap();
+        assert_eq!(lints[0].rule, LintRule::MixedBitwise);
+        assert_eq!(
+            lints[0].message,
+            "'+' binds tighter than '&'; add parentheses to make the \
+             grouping explicit"
+        );
+        assert_eq!(lints[0].span, Some(2..7));
+        assert_eq!(rules("1 << x * 2 | 4"), vec![LintRule::MixedBitwise]);
+        assert!(rules("x & (1 + 2)").is_empty());
+        assert!(rules("(x & 1) + 2").is_empty());
+        assert!(rules("x & -1").is_empty());
+        assert!(rules("x & 1 | 2 ^ 3").is_empty());
+    }
+
+    #[test]
+    fn deep_nesting() {
+        let deep = format!("{}x{}", "(".repeat(9), ")".repeat(9));
+        let lints = lint(&deep).unwrap();
+        assert_eq!(lints.len(), 1);
+        assert_eq!(lints[0].rule, LintRule::DeepNesting);
+        assert_eq!(lints[0].span, Some(8..9));
+        let shallow = format!("{}x{}", "(".repeat(8), ")".repeat(8));
+        assert!(lint(&shallow).unwrap().is_empty());
+
+        let options = LintOptions {
+            max_nesting: 1,
+            ..LintOptions::default()
+        };
+        let lints = lint_with("((x + 1) * 2) + ((y))", &options).unwrap();
+        let spans: Vec<_> = lints.into_iter().map(|lint| lint.span).collect();
+        assert_eq!(spans, vec![Some(1..2), Some(17..18)]);
+    }
+
+    #[test]
+    fn options() {
+        let input = "(1) & --x + 2";
+        assert_eq!(
+            rules(input),
+            vec![
+                LintRule::RedundantParens,
+                LintRule::MixedBitwise,
+                LintRule::DoubleUnary,
+            ]
+        );
+        let mut options = LintOptions::default();
+        options.rules.remove(&LintRule::RedundantParens);
+        options.rules.remove(&LintRule::DoubleUnary);
+        let lints = lint_with(input, &options).unwrap();
+        assert_eq!(lints.len(), 1);
+        assert_eq!(lints[0].rule, LintRule::MixedBitwise);
+
+        options.rules.clear();
+        assert!(lint_with(input, &options).unwrap().is_empty());
+    }
+
+    #[test]
+    fn invalid_input() {
+        assert_eq!(lint("(1 +"), Err(CalcError::UnexpectedEndOfInput));
+        assert!(lint("2 $ 3").is_err());
+    }
+}
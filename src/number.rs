@@ -0,0 +1,1022 @@
+use decimal::d128;
+use error::{CalcError, PartialComp};
+use num::ToPrimitive;
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::fmt;
+use std::num::{Saturating, Wrapping};
+use std::ops::*;
+use value::{ops, Integral, Value};
+
+/// A numeric type that expressions can be evaluated in.
+///
+/// `Value` is the canonical implementation used by `eval`, but the parser
+/// is generic over this trait so that the same expression can also be
+/// evaluated in `f64` or `f32` with `eval_generic`.
+///
+/// The bitwise operators are provided in terms of `to_bits_integer` and
+/// `from_bits_integer`, which round-trip the number through an `i64`.
+/// Types with a native integer representation may override them.
+pub trait CalcNum: Clone + fmt::Debug + fmt::Display + Sized {
+    /// Converts a value, e.g. the result of an `Environment`.
+    fn from_value(value: &Value) -> Result<Self, CalcError>;
+
+    /// Converts a literal produced by the tokenizer. Unlike `from_value`,
+    /// this rejects literals that the type cannot represent.
+    fn from_literal(value: &Value) -> Result<Self, CalcError> {
+        Self::from_value(value)
+    }
+
+    /// A note for `Environment::warn` if `from_literal` rounds `value` to
+    /// fewer significant digits than it has. The default has none.
+    fn literal_warning(_value: &Value) -> Option<String> {
+        None
+    }
+
+    /// Converts the number back into a `Value`, which is used to pass
+    /// arguments to, and receive results from, an `Environment`.
+    fn to_value(&self) -> Value;
+
+    /// Applies the function `name` of an `Environment` to `args`, where
+    /// `resolve` computes it on `Value`s. The default converts the
+    /// arguments with `to_value` and the result with `from_value`. Types
+    /// that hold more than a `Value` can override it, e.g. to call
+    /// `resolve` more than once.
+    fn call<F>(
+        _name: &str,
+        args: Vec<Self>,
+        mut resolve: F,
+    ) -> Result<Self, CalcError>
+    where
+        F: FnMut(&[Value]) -> Result<Value, CalcError>,
+    {
+        let args: Vec<Value> = args.iter().map(CalcNum::to_value).collect();
+        Self::from_value(&resolve(&args)?)
+    }
+
+    fn add(self, that: Self) -> Result<Self, CalcError>;
+    fn sub(self, that: Self) -> Result<Self, CalcError>;
+    fn mul(self, that: Self) -> Result<Self, CalcError>;
+    fn div(self, that: Self) -> Result<Self, CalcError>;
+    fn rem(self, that: Self) -> Result<Self, CalcError>;
+    fn neg(self) -> Result<Self, CalcError>;
+    fn powf(self, that: Self) -> Result<Self, CalcError>;
+    fn floor(self) -> Result<Self, CalcError>;
+    fn compare(&self, that: &Self) -> Option<Ordering>;
+
+    /// Returns the rounding error of `sum = self + that`, for compensated
+    /// summation, or `None` if the sum was exact or the error can't be
+    /// told. The default follows Neumaier, subtracting the sum from the
+    /// operand of larger magnitude and adding back the other one.
+    fn sum_error(&self, that: &Self, sum: &Self) -> Option<Self> {
+        let zero = Self::from_bits_integer(0);
+        let magnitude = |n: &Self| match n.compare(&zero)? {
+            Ordering::Less => n.clone().neg().ok(),
+            _ => Some(n.clone()),
+        };
+        let (big, small) = match magnitude(self)?.compare(&magnitude(that)?)? {
+            Ordering::Less => (that, self),
+            _ => (self, that),
+        };
+        let error =
+            big.clone().sub(sum.clone()).ok()?.add(small.clone()).ok()?;
+        match error.compare(&zero)? {
+            Ordering::Equal => None,
+            _ => Some(error),
+        }
+    }
+
+    /// Returns the number as an integer if it is integral and fits into an
+    /// `i64`.
+    fn to_bits_integer(&self) -> Option<i64>;
+
+    /// Creates a number from the integer result of a bitwise operation.
+    fn from_bits_integer(n: i64) -> Self;
+
+    fn bitand(self, that: Self) -> Result<Self, CalcError> {
+        bits_binary(self, that, "&", |n, m| Some(n & m))
+    }
+
+    fn bitor(self, that: Self) -> Result<Self, CalcError> {
+        bits_binary(self, that, "|", |n, m| Some(n | m))
+    }
+
+    fn bitxor(self, that: Self) -> Result<Self, CalcError> {
+        bits_binary(self, that, "^", |n, m| Some(n ^ m))
+    }
+
+    fn shl(self, that: Self) -> Result<Self, CalcError> {
+        check_shift("<<", &that)?;
+        bits_binary(self, that, "<<", bits_shl)
+    }
+
+    fn shr(self, that: Self) -> Result<Self, CalcError> {
+        check_shift(">>", &that)?;
+        bits_binary(self, that, ">>", |n, m| Some(n >> m.min(63)))
+    }
+
+    /// Shifts the `width`-bit two's-complement pattern of the number right,
+    /// filling with zeros instead of copies of the sign bit.
+    fn logical_shr(self, that: Self, width: u32) -> Result<Self, CalcError> {
+        check_shift(">>", &that)?;
+        bits_binary(self, that, ">>", |n, m| bits_logical_shr(n, m, width))
+    }
+
+    fn not(self) -> Result<Self, CalcError> {
+        match self.to_bits_integer() {
+            Some(n) => Ok(Self::from_bits_integer(!n)),
+            None => Err(CalcError::BadTypes(PartialComp::unary("~", self))),
+        }
+    }
+}
+
+fn bits_binary<N, F>(n: N, m: N, op: &str, f: F) -> Result<N, CalcError>
+where
+    N: CalcNum,
+    F: Fn(i64, i64) -> Option<i64>,
+{
+    match (n.to_bits_integer(), m.to_bits_integer()) {
+        (Some(a), Some(b)) => {
+            f(a, b).map(N::from_bits_integer).ok_or_else(|| {
+                CalcError::WouldOverflow(PartialComp::binary(op, &n, &m))
+            })
+        }
+        _ => Err(CalcError::BadTypes(PartialComp::binary(op, &n, &m))),
+    }
+}
+
+/// Fails with `CalcError::InvalidArgument` if the amount `m` of the shift
+/// `op` is negative, rather than shifting the other way.
+pub(crate) fn check_shift<N: CalcNum>(
+    op: &str,
+    m: &N,
+) -> Result<(), CalcError> {
+    match m.compare(&N::from_bits_integer(0)) {
+        Some(Ordering::Less) => Err(CalcError::InvalidArgument(format!(
+            "the amount of {} must not be negative, got {}",
+            op, m
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Shifts `n` left by `m` bits, for `m` that is not negative. Returns
+/// `None` if any set bits would be shifted out on the left.
+fn bits_shl(n: i64, m: i64) -> Option<i64> {
+    if n == 0 {
+        Some(0)
+    } else if m >= 64 {
+        None
+    } else {
+        let res = n << m;
+        if res >> m == n {
+            Some(res)
+        } else {
+            None
+        }
+    }
+}
+
+/// Shifts the `width`-bit pattern of `n` right by `m` bits, filling with
+/// zeros, for `m` that is not negative. Returns `None` if `n` does not fit
+/// into `width` bits or the result does not fit into an `i64`.
+fn bits_logical_shr(n: i64, m: i64, width: u32) -> Option<i64> {
+    if width > 64 {
+        return None;
+    }
+    let modulus = 1i128 << width;
+    let n = i128::from(n);
+    if n >= modulus || n < -(modulus >> 1) {
+        return None;
+    }
+    let pattern = n.rem_euclid(modulus);
+    let res = if m >= 64 { 0 } else { pattern >> m };
+    i64::try_from(res).ok()
+}
+
+impl CalcNum for Value {
+    fn from_value(value: &Value) -> Result<Self, CalcError> {
+        Ok(value.clone())
+    }
+
+    fn to_value(&self) -> Value {
+        self.clone()
+    }
+
+    fn add(self, that: Self) -> Result<Self, CalcError> {
+        self + that
+    }
+
+    fn sub(self, that: Self) -> Result<Self, CalcError> {
+        self - that
+    }
+
+    fn mul(self, that: Self) -> Result<Self, CalcError> {
+        self * that
+    }
+
+    fn div(self, that: Self) -> Result<Self, CalcError> {
+        self / that
+    }
+
+    fn rem(self, that: Self) -> Result<Self, CalcError> {
+        self % that
+    }
+
+    fn neg(self) -> Result<Self, CalcError> {
+        Ok(-self)
+    }
+
+    fn powf(self, that: Self) -> Result<Self, CalcError> {
+        self.pow(that)
+    }
+
+    fn floor(self) -> Result<Self, CalcError> {
+        match self {
+            Value::Integral(..) => Ok(self),
+            Value::Float(n) if !n.is_finite() => Ok(self),
+            Value::Float(n) => {
+                let rounded = n.quantize(d128!(1));
+                // Numbers too large to quantize are integers already.
+                if rounded.is_nan() {
+                    Ok(self)
+                } else if rounded > n {
+                    Ok(Value::Float(rounded - d128!(1)))
+                } else {
+                    Ok(Value::Float(rounded))
+                }
+            }
+        }
+    }
+
+    fn compare(&self, that: &Self) -> Option<Ordering> {
+        match (self, that) {
+            (Value::Integral(n, _), Value::Integral(m, _)) => Some(n.cmp(m)),
+            _ => {
+                let n = self.as_float().ok()?;
+                let m = that.as_float().ok()?;
+                n.partial_cmp(&m)
+            }
+        }
+    }
+
+    fn to_bits_integer(&self) -> Option<i64> {
+        match *self {
+            Value::Integral(ref n, _) => n.to_i64(),
+            Value::Float(_) => None,
+        }
+    }
+
+    fn from_bits_integer(n: i64) -> Self {
+        Value::dec(n)
+    }
+
+    fn bitand(self, that: Self) -> Result<Self, CalcError> {
+        self & that
+    }
+
+    fn bitor(self, that: Self) -> Result<Self, CalcError> {
+        self | that
+    }
+
+    fn bitxor(self, that: Self) -> Result<Self, CalcError> {
+        self ^ that
+    }
+
+    fn shl(self, that: Self) -> Result<Self, CalcError> {
+        self << that
+    }
+
+    fn shr(self, that: Self) -> Result<Self, CalcError> {
+        self >> that
+    }
+
+    fn logical_shr(self, that: Self, width: u32) -> Result<Self, CalcError> {
+        check_shift(">>", &that)?;
+        self.intmap(&that, ">>", |n, m| {
+            ops::logical_shr(n, m, width).ok_or_else(|| {
+                CalcError::WouldOverflow(PartialComp::binary(
+                    ">>", &self, &that,
+                ))
+            })
+        })
+    }
+
+    fn not(self) -> Result<Self, CalcError> {
+        !self
+    }
+}
+
+/// The sign, significant digits and exponent of the decimal number
+/// `text`, so that `-1.50e2` and `-150` are both `(true, "15", 1)`.
+fn decimal_parts(text: &str) -> (bool, String, i64) {
+    let (negative, text) = match text.strip_prefix('-') {
+        Some(text) => (true, text),
+        None => (false, text),
+    };
+    let (mantissa, exponent) = match text.find(['e', 'E']) {
+        Some(e) => (&text[..e], text[e + 1..].parse().unwrap_or(0)),
+        None => (text, 0),
+    };
+    let fraction = mantissa.find('.').map_or(0, |dot| mantissa.len() - dot - 1);
+    let digits: String = mantissa.chars().filter(|&c| c != '.').collect();
+    let digits = digits.trim_start_matches('0');
+    let significant = digits.trim_end_matches('0');
+    if significant.is_empty() {
+        return (false, String::new(), 0);
+    }
+    let zeros = digits.len() - significant.len();
+    let exponent = exponent - fraction as i64 + zeros as i64;
+    (negative, significant.to_owned(), exponent)
+}
+
+macro_rules! float_calc_num {
+    ($t:ident) => {
+        impl CalcNum for $t {
+            fn from_value(value: &Value) -> Result<Self, CalcError> {
+                Ok(value.as_f64() as $t)
+            }
+
+            fn from_literal(value: &Value) -> Result<Self, CalcError> {
+                let n = Self::from_value(value)?;
+                if n.is_infinite() {
+                    return Err(CalcError::LiteralOutOfRange(
+                        value.to_string(),
+                    ));
+                }
+                Ok(n)
+            }
+
+            fn literal_warning(value: &Value) -> Option<String> {
+                let n = Self::from_value(value).ok()?;
+                let text = match *value {
+                    Value::Integral(ref n, _) => n.to_string(),
+                    Value::Float(ref n) => n.to_string(),
+                };
+                if n.is_infinite()
+                    || decimal_parts(&text) == decimal_parts(&n.to_string())
+                {
+                    return None;
+                }
+                Some(format!(
+                    "{} is rounded to {} as {}",
+                    value,
+                    n,
+                    stringify!($t)
+                ))
+            }
+
+            fn to_value(&self) -> Value {
+                match self.to_bits_integer() {
+                    Some(n) if n > -(1 << 53) && n < (1 << 53) => Value::dec(n),
+                    _ => Value::from_f64(f64::from(*self)),
+                }
+            }
+
+            fn add(self, that: Self) -> Result<Self, CalcError> {
+                Ok(self + that)
+            }
+
+            fn sub(self, that: Self) -> Result<Self, CalcError> {
+                Ok(self - that)
+            }
+
+            fn mul(self, that: Self) -> Result<Self, CalcError> {
+                Ok(self * that)
+            }
+
+            fn div(self, that: Self) -> Result<Self, CalcError> {
+                if that == 0.0 {
+                    return Err(CalcError::DivideByZero);
+                }
+                Ok(self / that)
+            }
+
+            fn rem(self, that: Self) -> Result<Self, CalcError> {
+                if that == 0.0 {
+                    return Err(CalcError::DivideByZero);
+                }
+                Ok(self % that)
+            }
+
+            fn neg(self) -> Result<Self, CalcError> {
+                Ok(-self)
+            }
+
+            fn powf(self, that: Self) -> Result<Self, CalcError> {
+                Ok(self.powf(that))
+            }
+
+            fn floor(self) -> Result<Self, CalcError> {
+                Ok(self.floor())
+            }
+
+            fn compare(&self, that: &Self) -> Option<Ordering> {
+                self.partial_cmp(that)
+            }
+
+            fn to_bits_integer(&self) -> Option<i64> {
+                // The upper bound is exclusive, as 2^63 itself is
+                // representable as a float but not as an `i64`.
+                let bound = -(i64::MIN as $t);
+                if self.fract() == 0.0 && *self >= -bound && *self < bound {
+                    Some(*self as i64)
+                } else {
+                    None
+                }
+            }
+
+            fn from_bits_integer(n: i64) -> Self {
+                n as $t
+            }
+        }
+    };
+}
+
+float_calc_num!(f64);
+float_calc_num!(f32);
+
+/// Exact integer arithmetic. Fractional literals are rejected, division
+/// fails unless it is exact, and every operation that would overflow an
+/// `i128` is reported as `CalcError::WouldOverflow` instead of wrapping.
+impl CalcNum for i128 {
+    fn from_value(value: &Value) -> Result<Self, CalcError> {
+        match *value {
+            Value::Integral(ref n, _) => n
+                .to_string()
+                .parse()
+                .map_err(|_| CalcError::InvalidNumber(value.to_string())),
+            Value::Float(_) => Err(CalcError::InvalidNumber(value.to_string())),
+        }
+    }
+
+    fn to_value(&self) -> Value {
+        Value::dec(
+            self.to_string()
+                .parse::<Integral>()
+                .expect("formatted i128 is a valid integer"),
+        )
+    }
+
+    fn add(self, that: Self) -> Result<Self, CalcError> {
+        int_binary(self, that, "+", i128::checked_add)
+    }
+
+    fn sub(self, that: Self) -> Result<Self, CalcError> {
+        int_binary(self, that, "-", i128::checked_sub)
+    }
+
+    fn mul(self, that: Self) -> Result<Self, CalcError> {
+        int_binary(self, that, "*", i128::checked_mul)
+    }
+
+    fn div(self, that: Self) -> Result<Self, CalcError> {
+        if that == 0 {
+            return Err(CalcError::DivideByZero);
+        }
+        if self.checked_rem(that).is_some_and(|r| r != 0) {
+            return Err(CalcError::WouldTruncate(PartialComp::binary(
+                "/", &self, &that,
+            )));
+        }
+        int_binary(self, that, "/", i128::checked_div)
+    }
+
+    fn rem(self, that: Self) -> Result<Self, CalcError> {
+        if that == 0 {
+            return Err(CalcError::DivideByZero);
+        }
+        // `i128::MIN % -1` is zero, but `checked_rem` reports an overflow.
+        Ok(self.checked_rem(that).unwrap_or(0))
+    }
+
+    fn neg(self) -> Result<Self, CalcError> {
+        self.checked_neg().ok_or_else(|| {
+            CalcError::WouldOverflow(PartialComp::unary("-", self))
+        })
+    }
+
+    fn powf(self, that: Self) -> Result<Self, CalcError> {
+        if that < 0 {
+            return Err(CalcError::WouldTruncate(PartialComp::binary(
+                "**", &self, &that,
+            )));
+        }
+        let res = match self {
+            0 | 1 => Some(if that == 0 { 1 } else { self }),
+            -1 => Some(if that % 2 == 0 { 1 } else { -1 }),
+            _ if that > i128::from(u32::MAX) => None,
+            _ => self.checked_pow(that as u32),
+        };
+        res.ok_or_else(|| {
+            CalcError::WouldOverflow(PartialComp::binary("**", &self, &that))
+        })
+    }
+
+    fn floor(self) -> Result<Self, CalcError> {
+        Ok(self)
+    }
+
+    fn compare(&self, that: &Self) -> Option<Ordering> {
+        Some(self.cmp(that))
+    }
+
+    fn to_bits_integer(&self) -> Option<i64> {
+        if *self >= i128::from(i64::MIN) && *self <= i128::from(i64::MAX) {
+            Some(*self as i64)
+        } else {
+            None
+        }
+    }
+
+    fn from_bits_integer(n: i64) -> Self {
+        i128::from(n)
+    }
+
+    fn bitand(self, that: Self) -> Result<Self, CalcError> {
+        Ok(self & that)
+    }
+
+    fn bitor(self, that: Self) -> Result<Self, CalcError> {
+        Ok(self | that)
+    }
+
+    fn bitxor(self, that: Self) -> Result<Self, CalcError> {
+        Ok(self ^ that)
+    }
+
+    fn shl(self, that: Self) -> Result<Self, CalcError> {
+        check_shift("<<", &that)?;
+        int_binary(self, that, "<<", int_shl)
+    }
+
+    fn shr(self, that: Self) -> Result<Self, CalcError> {
+        check_shift(">>", &that)?;
+        int_binary(self, that, ">>", |n, m| Some(n >> m.min(127)))
+    }
+
+    fn not(self) -> Result<Self, CalcError> {
+        Ok(!self)
+    }
+}
+
+fn int_binary<F>(n: i128, m: i128, op: &str, f: F) -> Result<i128, CalcError>
+where
+    F: Fn(i128, i128) -> Option<i128>,
+{
+    f(n, m).ok_or_else(|| {
+        CalcError::WouldOverflow(PartialComp::binary(op, &n, &m))
+    })
+}
+
+/// Shifts `n` left by `m` bits, for `m` that is not negative. Returns
+/// `None` if any set bits would be shifted out on the left.
+fn int_shl(n: i128, m: i128) -> Option<i128> {
+    if n == 0 {
+        Some(0)
+    } else if m >= 128 {
+        None
+    } else {
+        let res = n << m;
+        if res >> m == n {
+            Some(res)
+        } else {
+            None
+        }
+    }
+}
+
+/// What `eval_i64` does with a result that does not fit into an `i64`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum OverflowPolicy {
+    /// Fail with `CalcError::WouldOverflow`, as `i64` does with
+    /// `eval_generic`.
+    #[default]
+    Error,
+    /// Keep the low 64 bits, so that `9223372036854775807 + 1` is
+    /// `-9223372036854775808`, as `Wrapping<i64>` does.
+    Wrap,
+    /// Clamp to the nearest `i64`, as `Saturating<i64>` does.
+    Saturate,
+}
+
+/// An `i64` that handles overflows according to a policy.
+trait Int64: Copy + fmt::Display {
+    fn new(n: i64) -> Self;
+    fn get(self) -> i64;
+    /// Returns the number that stands for `exact`, which does not fit into
+    /// an `i64`, given its low 64 bits, or `None` to report an overflow.
+    fn overflow(exact: i128, wrapped: i64) -> Option<i64>;
+}
+
+impl Int64 for i64 {
+    fn new(n: i64) -> Self {
+        n
+    }
+
+    fn get(self) -> i64 {
+        self
+    }
+
+    fn overflow(_: i128, _: i64) -> Option<i64> {
+        None
+    }
+}
+
+impl Int64 for Wrapping<i64> {
+    fn new(n: i64) -> Self {
+        Wrapping(n)
+    }
+
+    fn get(self) -> i64 {
+        self.0
+    }
+
+    fn overflow(_: i128, wrapped: i64) -> Option<i64> {
+        Some(wrapped)
+    }
+}
+
+impl Int64 for Saturating<i64> {
+    fn new(n: i64) -> Self {
+        Saturating(n)
+    }
+
+    fn get(self) -> i64 {
+        self.0
+    }
+
+    fn overflow(exact: i128, _: i64) -> Option<i64> {
+        Some(if exact < 0 { i64::MIN } else { i64::MAX })
+    }
+}
+
+/// Returns the result of an operation, given its `exact` value, which may
+/// be clamped to the range of an `i128`, and its low 64 bits.
+fn fit<N, F>(exact: i128, wrapped: i64, comp: F) -> Result<N, CalcError>
+where
+    N: Int64,
+    F: FnOnce() -> PartialComp,
+{
+    match i64::try_from(exact)
+        .ok()
+        .or_else(|| N::overflow(exact, wrapped))
+    {
+        Some(n) => Ok(N::new(n)),
+        None => Err(CalcError::WouldOverflow(comp())),
+    }
+}
+
+fn fit_binary<N, F>(n: N, m: N, op: &str, f: F) -> Result<N, CalcError>
+where
+    N: Int64,
+    F: Fn(i64, i64) -> (i128, i64),
+{
+    let (exact, wrapped) = f(n.get(), m.get());
+    fit(exact, wrapped, || PartialComp::binary(op, &n, &m))
+}
+
+/// The exact value and the low 64 bits of `n ** m`, for `m` that is not
+/// negative.
+fn pow64(n: i64, m: i64) -> (i128, i64) {
+    let exact = match n {
+        0 | 1 => i128::from(if m == 0 { 1 } else { n }),
+        -1 => i128::from(if m % 2 == 0 { 1 } else { -1 }),
+        _ if m > i64::from(u32::MAX) => {
+            if n < 0 && m % 2 == 1 {
+                i128::MIN
+            } else {
+                i128::MAX
+            }
+        }
+        _ => i128::from(n).saturating_pow(m as u32),
+    };
+    let mut wrapped: i64 = 1;
+    let (mut base, mut exp) = (n, m as u64);
+    while exp > 0 {
+        if exp & 1 == 1 {
+            wrapped = wrapped.wrapping_mul(base);
+        }
+        base = base.wrapping_mul(base);
+        exp >>= 1;
+    }
+    (exact, wrapped)
+}
+
+/// The exact value and the low 64 bits of `n` shifted left by `m` bits, for
+/// `m` that is not negative.
+fn shl64(n: i64, m: i64) -> (i128, i64) {
+    if n == 0 {
+        (0, 0)
+    } else if m >= 64 {
+        (if n < 0 { i128::MIN } else { i128::MAX }, 0)
+    } else {
+        (i128::from(n) << m, n << m)
+    }
+}
+
+/// Implements `CalcNum` for an `Int64`. Like `i128`, the numbers reject
+/// fractional literals and division that isn't exact.
+macro_rules! int64_calc_num {
+    ($t:ty) => {
+        impl CalcNum for $t {
+            fn from_value(value: &Value) -> Result<Self, CalcError> {
+                match *value {
+                    Value::Integral(ref n, _) => n.to_i64().map(Self::new),
+                    Value::Float(_) => None,
+                }
+                .ok_or_else(|| CalcError::InvalidNumber(value.to_string()))
+            }
+
+            fn to_value(&self) -> Value {
+                Value::dec(self.get())
+            }
+
+            fn add(self, that: Self) -> Result<Self, CalcError> {
+                fit_binary(self, that, "+", |n, m| {
+                    (i128::from(n) + i128::from(m), n.wrapping_add(m))
+                })
+            }
+
+            fn sub(self, that: Self) -> Result<Self, CalcError> {
+                fit_binary(self, that, "-", |n, m| {
+                    (i128::from(n) - i128::from(m), n.wrapping_sub(m))
+                })
+            }
+
+            fn mul(self, that: Self) -> Result<Self, CalcError> {
+                fit_binary(self, that, "*", |n, m| {
+                    (i128::from(n) * i128::from(m), n.wrapping_mul(m))
+                })
+            }
+
+            fn div(self, that: Self) -> Result<Self, CalcError> {
+                let (n, m) = (self.get(), that.get());
+                if m == 0 {
+                    return Err(CalcError::DivideByZero);
+                }
+                if n.checked_rem(m).is_some_and(|r| r != 0) {
+                    return Err(CalcError::WouldTruncate(PartialComp::binary(
+                        "/", &self, &that,
+                    )));
+                }
+                fit_binary(self, that, "/", |n, m| {
+                    (i128::from(n) / i128::from(m), n.wrapping_div(m))
+                })
+            }
+
+            fn rem(self, that: Self) -> Result<Self, CalcError> {
+                if that.get() == 0 {
+                    return Err(CalcError::DivideByZero);
+                }
+                Ok(Self::new(self.get().wrapping_rem(that.get())))
+            }
+
+            fn neg(self) -> Result<Self, CalcError> {
+                let n = self.get();
+                fit(-i128::from(n), n.wrapping_neg(), || {
+                    PartialComp::unary("-", self)
+                })
+            }
+
+            fn powf(self, that: Self) -> Result<Self, CalcError> {
+                if that.get() < 0 {
+                    return Err(CalcError::WouldTruncate(PartialComp::binary(
+                        "**", &self, &that,
+                    )));
+                }
+                fit_binary(self, that, "**", pow64)
+            }
+
+            fn floor(self) -> Result<Self, CalcError> {
+                Ok(self)
+            }
+
+            fn compare(&self, that: &Self) -> Option<Ordering> {
+                Some(self.get().cmp(&that.get()))
+            }
+
+            fn to_bits_integer(&self) -> Option<i64> {
+                Some(self.get())
+            }
+
+            fn from_bits_integer(n: i64) -> Self {
+                Self::new(n)
+            }
+
+            fn shl(self, that: Self) -> Result<Self, CalcError> {
+                check_shift("<<", &that)?;
+                fit_binary(self, that, "<<", shl64)
+            }
+
+            fn shr(self, that: Self) -> Result<Self, CalcError> {
+                check_shift(">>", &that)?;
+                fit_binary(self, that, ">>", |n, m| {
+                    let shifted = n >> m.min(63);
+                    (i128::from(shifted), shifted)
+                })
+            }
+        }
+    };
+}
+
+int64_calc_num!(i64);
+int64_calc_num!(Wrapping<i64>);
+int64_calc_num!(Saturating<i64>);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {eval_generic, eval_i64, eval_int};
+
+    #[test]
+    fn floats() {
+        let cases = vec![
+            ("3 + 4 * 2", 11.0),
+            ("2 ** 10", 1024.0),
+            ("7 % 4 - 0.5", 2.5),
+            ("(1 + 2) / 4", 0.75),
+            ("-(2 ** 3)", -8.0),
+            ("0xFF & 0x0F", 15.0),
+            ("1 << 10 | 3", 1027.0),
+            ("~5", -6.0),
+            ("16 >> 2 ^ 1", 5.0),
+            ("3²", 9.0),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(eval_generic::<f64>(input), Ok(expected));
+            assert_eq!(eval_generic::<f32>(input), Ok(expected as f32));
+            assert_eq!(
+                eval_generic::<Value>(input).map(|v| v.as_f64()),
+                Ok(expected)
+            );
+        }
+    }
+
+    #[test]
+    fn precision() {
+        assert_eq!(eval_generic::<f64>("16777217"), Ok(16_777_217.0));
+        assert_eq!(eval_generic::<f32>("16777217"), Ok(16_777_216.0));
+        assert_eq!(eval_generic::<f64>("log 100"), Ok(2.0));
+        assert_eq!(eval_generic::<f32>("log 100"), Ok(2.0));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(eval_generic::<f64>("1 / 0"), Err(CalcError::DivideByZero));
+        assert_eq!(eval_generic::<f32>("1 % 0"), Err(CalcError::DivideByZero));
+        assert_eq!(
+            eval_generic::<f64>("1.5 & 1"),
+            Err(CalcError::BadTypes(PartialComp::binary("&", &1.5, &1)))
+        );
+        assert_eq!(
+            eval_generic::<f64>("1 << 64"),
+            Err(CalcError::WouldOverflow(PartialComp::binary("<<", &1, &64)))
+        );
+        assert_eq!(eval_generic::<f64>("-1 >> 100"), Ok(-1.0));
+        assert_eq!(
+            eval_generic::<f64>("1 << -1"),
+            Err(CalcError::InvalidArgument(
+                "the amount of << must not be negative, got -1".into()
+            ))
+        );
+    }
+
+    #[test]
+    fn literal_range() {
+        // f64::MAX is 1.7976931348623157e308; anything from halfway to the
+        // next power of two on rounds to infinity.
+        let max = format!("17976931348623157{}", "0".repeat(292));
+        let over = format!("17976931348623159{}", "0".repeat(292));
+        assert_eq!(eval_generic::<f64>(&max), Ok(f64::MAX));
+        assert_eq!(eval_generic::<f64>(&format!("-{}", max)), Ok(f64::MIN));
+        assert_eq!(
+            eval_generic::<f64>(&format!("1 + {}", over)),
+            Err(CalcError::LiteralOutOfRange(over.clone()))
+        );
+        assert_eq!(
+            eval_generic::<f32>(&max),
+            Err(CalcError::LiteralOutOfRange(max.clone()))
+        );
+        assert!(eval_generic::<Value>(&over).is_ok());
+        // Only literals are checked; results may still overflow.
+        assert_eq!(eval_generic::<f64>("2 ** 2000"), Ok(f64::INFINITY));
+    }
+
+    #[test]
+    fn floor_and_compare() {
+        assert_eq!(CalcNum::floor(-2.5f64), Ok(-3.0));
+        assert_eq!(
+            CalcNum::floor(Value::Float(d128!(-2.5))),
+            Ok(Value::Float(d128!(-3)))
+        );
+        assert_eq!(
+            CalcNum::floor(Value::Float(d128!(2.5))),
+            Ok(Value::Float(d128!(2)))
+        );
+        assert_eq!(
+            Value::dec(2).compare(&Value::Float(d128!(2.5))),
+            Some(Ordering::Less)
+        );
+        assert_eq!(1.0f32.compare(&0.5), Some(Ordering::Greater));
+    }
+
+    #[test]
+    fn integers() {
+        let cases = vec![
+            ("(1 << 100) - 1", (1i128 << 100) - 1),
+            ("9007199254740993 + 2", 9_007_199_254_740_995),
+            ("3 ** 80", 3i128.pow(80)),
+            ("~1 >> 200", -1),
+            (
+                "0xFFFFFFFFFFFFFFFFFFFF & (~0xFF)",
+                0xFFFF_FFFF_FFFF_FFFF_FF00,
+            ),
+            ("(1 << 126) ^ (1 << 125)", (1 << 126) | (1 << 125)),
+            ("12 / 4 % 2", 1),
+            ("-7 % 3", -1),
+            ("1 ** 99999999999999", 1),
+            ("-1 ** 99999999999999", -1),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(eval_int(input), Ok(expected));
+        }
+    }
+
+    #[test]
+    fn integer_errors() {
+        assert_eq!(
+            eval_int("1.5 + 1"),
+            Err(CalcError::InvalidNumber("1.5".into()))
+        );
+        assert_eq!(
+            eval_int("7 / 2"),
+            Err(CalcError::WouldTruncate(PartialComp::binary("/", &7, &2)))
+        );
+        assert_eq!(
+            eval_int("2 ** 127"),
+            Err(CalcError::WouldOverflow(PartialComp::binary(
+                "**", &2, &127,
+            )))
+        );
+        assert_eq!(
+            eval_int("1 << 127"),
+            Err(CalcError::WouldOverflow(PartialComp::binary(
+                "<<", &1, &127,
+            )))
+        );
+        assert!(eval_int("(1 << 126) * 4").is_err());
+        assert!(eval_int("8 >> -1").is_err());
+        assert!(eval_int("0x8000000000000000000000000000000000").is_err());
+        assert_eq!(eval_int("1 / 0"), Err(CalcError::DivideByZero));
+    }
+
+    #[test]
+    fn overflow_policies() {
+        use OverflowPolicy::*;
+        let max = "9223372036854775807";
+        let cases = vec![
+            (format!("{} + 1", max), None, i64::MIN, i64::MAX),
+            (format!("-{} - 2", max), None, i64::MAX, i64::MIN),
+            (format!("(-{} - 1) / -1", max), None, i64::MIN, i64::MAX),
+            (format!("-(-{} - 1)", max), None, i64::MIN, i64::MAX),
+            (
+                "-9223372036854775808".into(),
+                Some(i64::MIN),
+                i64::MIN,
+                i64::MIN,
+            ),
+            ("-(-9223372036854775808)".into(), None, i64::MIN, i64::MAX),
+            ("3 ** 40".into(), None, 3i64.wrapping_pow(40), i64::MAX),
+            ("(-2) ** 63".into(), Some(i64::MIN), i64::MIN, i64::MIN),
+            (
+                "(-3) ** 99999999999".into(),
+                None,
+                2_062_592_170_169_791_829,
+                i64::MIN,
+            ),
+            ("3 << 62".into(), None, i64::MIN | 1 << 62, i64::MAX),
+            ("-1 << 64".into(), None, 0, i64::MIN),
+            ("-8 >> 99".into(), Some(-1), -1, -1),
+            ("0x7f & 6 | 1 ^ 3 % 2".into(), Some(6), 6, 6),
+        ];
+        for (input, checked, wrapped, saturated) in cases {
+            let overflow = checked.ok_or(());
+            assert_eq!(
+                eval_i64(&input, Error).map_err(|_| ()),
+                overflow,
+                "{}",
+                input
+            );
+            assert_eq!(eval_i64(&input, Wrap), Ok(wrapped), "{}", input);
+            assert_eq!(eval_i64(&input, Saturate), Ok(saturated), "{}", input);
+        }
+
+        for &policy in &[Error, Wrap, Saturate] {
+            assert!(eval_i64("9223372036854775808", policy).is_err());
+            assert!(eval_i64("7 / 2", policy).is_err());
+            assert!(eval_i64("0.5", policy).is_err());
+            assert_eq!(eval_i64("1 % 0", policy), Err(CalcError::DivideByZero));
+        }
+    }
+}
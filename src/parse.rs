@@ -1,6 +1,23 @@
-use error::CalcError;
+use ast::{BinaryOp, Binder, Expr, Symbolic, UnaryOp};
+use error::{CalcError, Located, Location};
+use factor;
+use format;
+use gray;
+use num::{Integer, Signed, ToPrimitive, Zero};
+use number::CalcNum;
+use std::cmp::Ordering;
+use std::fmt;
+use std::mem;
 use token::*;
-use value::{Value, IR};
+use value::{Integral, Value, IR};
+
+/// The maximum nesting depth of parentheses, unary operators, exponents and
+/// function arguments when parsing into an `Expr`, and the maximum depth of
+/// the tree. Every operator of a chain like `1 + 2 + 3` nests the operators
+/// before it one level deeper. Deeper expressions are rejected with
+/// `CalcError::NestingTooDeep`, since syntax trees are processed
+/// recursively.
+pub const MAX_DEPTH: usize = 64;
 
 /// Represents an environment for evaluating a mathematical expression
 pub trait Environment {
@@ -18,240 +35,1552 @@ pub trait Environment {
         atom: &str,
         args: &[Value],
     ) -> Result<Value, CalcError>;
+
+    /// Called before every grammar rule and every operation performed while
+    /// evaluating an expression. Returning an error aborts the evaluation,
+    /// which allows environments to bound the work done on untrusted input.
+    fn step(&mut self) -> Result<(), CalcError> {
+        Ok(())
+    }
+
+    /// The maximum nesting depth of parentheses, unary operators, exponents
+    /// and function arguments. Deeper expressions are rejected with
+    /// `CalcError::NestingTooDeep`. `None`, the default, allows any depth.
+    fn max_depth(&self) -> Option<usize> {
+        None
+    }
+
+    /// How `>>` treats negative numbers. Only affects numbers computed while
+    /// parsing; syntax trees always record a plain `BinaryOp::Shr`.
+    fn right_shift(&self) -> RightShift {
+        RightShift::Arithmetic
+    }
+
+    /// The tolerance and limits of numeric methods such as `integrate`
+    /// and `solve`.
+    fn numeric_options(&self) -> NumericOptions {
+        NumericOptions::default()
+    }
+
+    /// The largest absolute value any literal, intermediate or final result
+    /// may have. Larger values fail with `CalcError::MagnitudeExceeded`.
+    /// `None`, the default, allows any magnitude.
+    fn max_magnitude(&self) -> Option<f64> {
+        None
+    }
+
+    /// Whether values must be integers. See `IntegerMode`.
+    fn integer_mode(&self) -> IntegerMode {
+        IntegerMode::Any
+    }
+
+    /// Whether the end of the input closes the parentheses that are still
+    /// open, as in `((5 + 3) * 2`. A `)` without a matching `(` is an error
+    /// either way. Off by default.
+    fn lenient_parens(&self) -> bool {
+        false
+    }
+
+    /// Whether chains of `+` and `-` carry the rounding error of each step
+    /// along, as in Neumaier's variant of Kahan summation, and add it back
+    /// at the end of the chain. Off by default. `sum` always does this.
+    fn compensated_sum(&self) -> bool {
+        false
+    }
+
+    /// Receives a note about input that was accepted, but not as written,
+    /// such as parentheses closed by `lenient_parens`. The default discards
+    /// it.
+    fn warn(&mut self, _message: String) {}
+
+    /// The binary operators registered on top of the builtin ones. Each is
+    /// applied by resolving its symbol as an atom with two arguments.
+    fn operators(&self) -> Vec<Operator> {
+        Vec::new()
+    }
+
+    /// The precedence `op` parses with, from 1 to 4 as in
+    /// `BinaryOp::precedence`, which is the default.
+    fn precedence(&self, op: BinaryOp) -> u8 {
+        op.precedence()
+    }
+
+    /// How chains of `op` group. Defaults to `BinaryOp::assoc`.
+    fn assoc(&self, op: BinaryOp) -> Assoc {
+        op.assoc()
+    }
+
+    /// The atoms the environment defines, among which the closest one is
+    /// suggested for an unknown atom. The default lists none.
+    fn names(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// How a chain of operators of the same precedence groups.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Assoc {
+    /// `a >< b >< c` is `(a >< b) >< c`, like `-`.
+    Left,
+    /// `a >< b >< c` is `a >< (b >< c)`, like `**`.
+    Right,
+}
+
+/// A binary operator registered with `Context::register_operator`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Operator {
+    pub symbol: String,
+    /// The precedence of the builtin operators it binds like, from 1 for
+    /// the bitwise operators to 4 for `**`. See `BinaryOp::precedence`.
+    pub precedence: u8,
+    /// How it groups with itself and the operators of its precedence. The
+    /// right operand of a right-associative operator extends over all of
+    /// them.
+    pub assoc: Assoc,
+}
+
+/// Settings of the numeric methods, such as `integrate` and `solve`, that
+/// approximate their result.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NumericOptions {
+    /// The absolute error at which an approximation is accepted.
+    pub tolerance: f64,
+    /// How often `integrate` may split an interval before it fails with
+    /// `CalcError::NoConvergence`.
+    pub max_subdivisions: u32,
+    /// How many steps `solve` may take before it fails with
+    /// `CalcError::NoConvergence`.
+    pub max_iterations: u32,
+}
+
+impl Default for NumericOptions {
+    fn default() -> Self {
+        NumericOptions {
+            tolerance: 1e-10,
+            max_subdivisions: 10_000,
+            max_iterations: 100,
+        }
+    }
 }
 
-fn d_expr<E>(token_list: &[Token], env: &mut E) -> Result<IR, CalcError>
+/// The behaviour of the `>>` operator.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum RightShift {
+    /// Copies the sign bit, so that `-8 >> 1` is `-4`.
+    #[default]
+    Arithmetic,
+    /// Shifts the two's-complement pattern of the given width in bits,
+    /// filling with zeros, so that `-8 >> 1` is `124` at a width of 8.
+    /// Operands that do not fit into the width are rejected.
+    Logical(u32),
+}
+
+/// Whether values must be integers, and what `/` does then.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum IntegerMode {
+    /// Values may have fractions.
+    #[default]
+    Any,
+    /// Every literal and every result must be an integer, so `/` must
+    /// divide evenly, e.g. `10 / 4` fails with `CalcError::NotAnInteger`
+    /// while `10 / 2` is `5`. `**` only takes exponents that are not
+    /// negative.
+    Strict,
+    /// Like `Strict`, except that `/` rounds down, so that `7 / 2` is `3`
+    /// and `-7 / 2` is `-4`.
+    FloorDivision,
+}
+
+/// Combines the results of grammar rules. Numbers compute the result
+/// right away, while `Expr` records the syntax tree.
+pub(crate) trait Builder: Clone + fmt::Display + Sized {
+    fn literal(value: &Value) -> Result<Self, CalcError>;
+
+    /// A note for `Environment::warn` if `literal` rounds `value`.
+    fn literal_warning(_value: &Value) -> Option<String> {
+        None
+    }
+
+    fn atom<E: Environment>(
+        env: &mut E,
+        name: &str,
+        args: Vec<Self>,
+    ) -> Result<Self, CalcError>;
+    fn unary(op: UnaryOp, arg: Self) -> Result<Self, CalcError>;
+    fn binary(op: BinaryOp, lhs: Self, rhs: Self) -> Result<Self, CalcError>;
+
+    /// Applies `>>` as configured by the environment.
+    fn shr(lhs: Self, rhs: Self, _: RightShift) -> Result<Self, CalcError> {
+        Self::binary(BinaryOp::Shr, lhs, rhs)
+    }
+
+    /// Whether the result holds as a condition of `cond`, if it is known
+    /// while parsing. Otherwise `cond` is recorded as a call of all its
+    /// arguments.
+    fn truth(&self) -> Option<bool> {
+        None
+    }
+
+    /// The rounding error of `sum = lhs + rhs`, for `compensated_sum`, if
+    /// it is known while parsing and not zero.
+    fn sum_error(_lhs: &Self, _rhs: &Self, _sum: &Self) -> Option<Self> {
+        None
+    }
+
+    /// Applies a function like `sum` or `integrate` to a body, which is
+    /// always parsed into a tree, as it is evaluated for many values of
+    /// `var`. `args` are the other arguments in order.
+    fn bind<E: Environment>(
+        env: &mut E,
+        binder: Binder,
+        var: &str,
+        body: Expr,
+        args: Vec<Self>,
+    ) -> Result<Self, CalcError>;
+
+    /// What the name of a `let` binding stands for where it is used. Trees
+    /// refer to the binding by name, so that the value is not copied.
+    fn bound(name: &str, value: &Self) -> Self;
+
+    /// Completes `let NAME = VALUE in BODY`, given the result of the body.
+    fn bind_let(name: &str, value: Self, body: Self)
+        -> Result<Self, CalcError>;
+
+    /// Converts the result into a tree, so that the body of a `sum` or
+    /// `integrate` can use the names bound by enclosing `let`s.
+    fn to_expr(&self) -> Expr;
+
+    /// The result, if it is known while parsing, to check it against the
+    /// `max_magnitude` and `IntegerMode` of the environment.
+    fn value(&self) -> Option<Value> {
+        None
+    }
+}
+
+impl<N: CalcNum> Builder for N {
+    fn literal(value: &Value) -> Result<Self, CalcError> {
+        N::from_literal(value)
+    }
+
+    fn literal_warning(value: &Value) -> Option<String> {
+        <N as CalcNum>::literal_warning(value)
+    }
+
+    fn atom<E: Environment>(
+        env: &mut E,
+        name: &str,
+        args: Vec<Self>,
+    ) -> Result<Self, CalcError> {
+        N::call(name, args, |args| env.resolve(name, args))
+    }
+
+    fn unary(op: UnaryOp, arg: Self) -> Result<Self, CalcError> {
+        op.apply(arg)
+    }
+
+    fn binary(op: BinaryOp, lhs: Self, rhs: Self) -> Result<Self, CalcError> {
+        op.apply(lhs, rhs)
+    }
+
+    fn shr(lhs: Self, rhs: Self, shift: RightShift) -> Result<Self, CalcError> {
+        match shift {
+            RightShift::Arithmetic => CalcNum::shr(lhs, rhs),
+            RightShift::Logical(width) => lhs.logical_shr(rhs, width),
+        }
+    }
+
+    fn sum_error(lhs: &Self, rhs: &Self, sum: &Self) -> Option<Self> {
+        lhs.sum_error(rhs, sum)
+    }
+
+    fn truth(&self) -> Option<bool> {
+        Some(truth(self))
+    }
+
+    fn bind<E: Environment>(
+        env: &mut E,
+        binder: Binder,
+        var: &str,
+        body: Expr,
+        args: Vec<Self>,
+    ) -> Result<Self, CalcError> {
+        binder.apply(env, var, &body, &args)
+    }
+
+    fn bound(_: &str, value: &Self) -> Self {
+        value.clone()
+    }
+
+    fn bind_let(_: &str, _: Self, body: Self) -> Result<Self, CalcError> {
+        Ok(body)
+    }
+
+    fn to_expr(&self) -> Expr {
+        Expr::Num(self.to_value())
+    }
+
+    fn value(&self) -> Option<Value> {
+        Some(self.to_value())
+    }
+}
+
+/// Checks a result that is known while parsing against the
+/// `max_magnitude` and the `IntegerMode` of `env`. `op` names the operator,
+/// function or literal that produced it.
+pub(crate) fn check_result<N, E>(
+    env: &E,
+    op: &str,
+    n: &N,
+) -> Result<(), CalcError>
 where
-    E: Environment,
+    N: Builder,
+    E: Environment + ?Sized,
 {
-    if !token_list.is_empty() && token_list[0] == Token::BitWiseNot {
-        let mut e = d_expr(&token_list[1..], env)?;
-        e.value = (!e.value)?;
-        e.tokens += 1;
-        return Ok(e);
-    }
-
-    let mut e1 = e_expr(token_list, env)?;
-    let mut index = e1.tokens;
-
-    while index < token_list.len() {
-        match token_list[index] {
-            Token::BitWiseAnd => {
-                let e2 = e_expr(&token_list[index + 1..], env)?;
-                e1.value = (e1.value & e2.value)?;
-                e1.tokens += e2.tokens + 1;
-            }
-            Token::BitWiseOr => {
-                let e2 = e_expr(&token_list[index + 1..], env)?;
-                e1.value = (e1.value | e2.value)?;
-                e1.tokens += e2.tokens + 1;
-            }
-            Token::BitWiseXor => {
-                let e2 = e_expr(&token_list[index + 1..], env)?;
-                e1.value = (e1.value ^ e2.value)?;
-                e1.tokens += e2.tokens + 1;
-            }
-            Token::BitWiseLShift => {
-                let e2 = e_expr(&token_list[index + 1..], env)?;
-                e1.value = (e1.value << e2.value)?;
-                e1.tokens += e2.tokens + 1;
-            }
-            Token::BitWiseRShift => {
-                let e2 = e_expr(&token_list[index + 1..], env)?;
-                e1.value = (e1.value >> e2.value)?;
-                e1.tokens += e2.tokens + 1;
-            }
-            Token::Number(ref n) => {
-                return Err(CalcError::UnexpectedToken(
-                    n.to_string(),
-                    "operator",
-                ));
-            }
-            _ => break,
-        };
-        index = e1.tokens;
+    let limit = env.max_magnitude();
+    let integers = env.integer_mode() != IntegerMode::Any;
+    if limit.is_none() && !integers {
+        return Ok(());
     }
-    Ok(e1)
+    let value = match n.value() {
+        Some(value) => value,
+        None => return Ok(()),
+    };
+    if let Some(limit) = limit {
+        let n = value.as_f64();
+        if n.abs() > limit {
+            return Err(CalcError::MagnitudeExceeded { value: n, limit });
+        }
+    }
+    if integers && !value.is_whole() {
+        return Err(CalcError::NotAnInteger {
+            op: op.to_owned(),
+            value: value.to_string(),
+        });
+    }
+    Ok(())
 }
-// Addition and subtraction
-fn e_expr<E>(token_list: &[Token], env: &mut E) -> Result<IR, CalcError>
+
+/// Converts the literal `value`, passing the warning on to `env` if it is
+/// rounded.
+fn literal<N, E>(env: &mut E, value: &Value) -> Result<N, CalcError>
 where
-    E: Environment,
+    N: Builder,
+    E: Environment + ?Sized,
 {
-    let mut t1 = t_expr(token_list, env)?;
-    let mut index = t1.tokens;
-
-    while index < token_list.len() {
-        match token_list[index] {
-            Token::Plus => {
-                let t2 = t_expr(&token_list[index + 1..], env)?;
-                t1.value = (t1.value + t2.value)?;
-                t1.tokens += t2.tokens + 1;
-            }
-            Token::Minus => {
-                let t2 = t_expr(&token_list[index + 1..], env)?;
-                t1.value = (t1.value - t2.value)?;
-                t1.tokens += t2.tokens + 1;
-            }
-            Token::Number(ref n) => {
-                return Err(CalcError::UnexpectedToken(
-                    n.to_string(),
-                    "operator",
-                ))
-            }
-            _ => break,
-        };
-        index = t1.tokens;
+    if let Some(warning) = N::literal_warning(value) {
+        env.warn(warning);
     }
-    Ok(t1)
+    N::literal(value)
 }
 
-// Multiplication and division
-fn t_expr<E>(token_list: &[Token], env: &mut E) -> Result<IR, CalcError>
+/// Applies a binary operator other than `>>`, with `/` and `**` as
+/// configured by the `IntegerMode` of `env`.
+pub(crate) fn binary<N, E>(
+    env: &E,
+    op: BinaryOp,
+    lhs: N,
+    rhs: N,
+) -> Result<N, CalcError>
 where
-    E: Environment,
+    N: Builder,
+    E: Environment + ?Sized,
 {
-    let mut f1 = f_expr(token_list, env)?;
-    let mut index = f1.tokens;
-
-    while index < token_list.len() {
-        match token_list[index] {
-            Token::Multiply => {
-                let f2 = f_expr(&token_list[index + 1..], env)?;
-                f1.value = (f1.value * f2.value)?;
-                f1.tokens += f2.tokens + 1;
-            }
-            Token::Divide => {
-                let f2 = f_expr(&token_list[index + 1..], env)?;
-                f1.value = (f1.value / f2.value)?;
-                f1.tokens += f2.tokens + 1;
-            }
-            Token::Modulo => {
-                let f2 = f_expr(&token_list[index + 1..], env)?;
-                f1.value = (f1.value % f2.value)?;
-                f1.tokens += f2.tokens + 1;
-            }
-            Token::Number(ref n) => {
-                return Err(CalcError::UnexpectedToken(
-                    n.to_string(),
-                    "operator",
-                ));
-            }
-            _ => break,
+    let mode = env.integer_mode();
+    if mode != IntegerMode::Any {
+        match (op, lhs.value(), rhs.value()) {
+            (BinaryOp::Pow, _, Some(ref exponent))
+                if exponent.as_f64() < 0.0 =>
+            {
+                return Err(CalcError::InvalidArgument(format!(
+                    "negative exponent {} of an integer power",
+                    exponent
+                )))
+            }
+            (BinaryOp::Div, Some(ref lhs), Some(ref rhs))
+                if mode == IntegerMode::FloorDivision =>
+            {
+                return N::literal(&floor_div(lhs, rhs)?);
+            }
+            _ => {}
         }
-        index = f1.tokens;
     }
-    Ok(f1)
+    N::binary(op, lhs, rhs)
 }
 
-// Exponentiation
-fn f_expr<E>(token_list: &[Token], env: &mut E) -> Result<IR, CalcError>
-where
-    E: Environment,
-{
-    let mut g1 = g_expr(token_list, env)?; // was g1
-    let mut index = g1.tokens;
-    let token_len = token_list.len();
-    while index < token_len {
-        match token_list[index] {
-            Token::Exponent => {
-                let f = f_expr(&token_list[index + 1..], env)?;
-                g1.value = g1.value.pow(f.value)?;
-                g1.tokens += f.tokens + 1;
-            }
-            Token::Square => {
-                g1.value = (g1.value.clone() * g1.value)?;
-                g1.tokens += 1;
-            }
-            Token::Cube => {
-                g1.value = ((g1.value.clone() * g1.value.clone())? * g1.value)?;
-                g1.tokens += 1;
-            }
-            Token::Number(ref n) => {
-                return Err(CalcError::UnexpectedToken(
-                    n.to_string(),
-                    "operator",
-                ));
-            }
-            _ => break,
+/// Divides integers, rounding down.
+fn floor_div(lhs: &Value, rhs: &Value) -> Result<Value, CalcError> {
+    let whole = |value: &Value| {
+        value.to_integral().ok_or_else(|| CalcError::NotAnInteger {
+            op: "/".into(),
+            value: value.to_string(),
+        })
+    };
+    let (lhs, rhs) = (whole(lhs)?, whole(rhs)?);
+    if rhs.is_zero() {
+        return Err(CalcError::DivideByZero);
+    }
+    Ok(Value::dec(lhs.div_floor(&rhs)))
+}
+
+/// Whether a number holds as a condition of `cond`, which is when it is
+/// not zero.
+pub(crate) fn truth<N: CalcNum>(n: &N) -> bool {
+    n.compare(&N::from_bits_integer(0)) != Some(Ordering::Equal)
+}
+
+/// Checks the number of arguments of `cond`, which takes pairs of a
+/// condition and a value, followed by a default.
+pub(crate) fn check_cond_arity(count: usize) -> Result<(), CalcError> {
+    if count >= 3 && count % 2 == 1 {
+        Ok(())
+    } else {
+        Err(CalcError::WrongArity {
+            atom: "cond".into(),
+            expected: if count < 3 { 3 } else { count + 1 },
+            actual: count,
+        })
+    }
+}
+
+impl Builder for Expr {
+    fn literal(value: &Value) -> Result<Self, CalcError> {
+        Ok(Expr::Num(value.clone()))
+    }
+
+    fn atom<E: Environment>(
+        _: &mut E,
+        name: &str,
+        args: Vec<Self>,
+    ) -> Result<Self, CalcError> {
+        if args.is_empty() {
+            Ok(Expr::var(name))
+        } else {
+            shallow(Expr::call(name, args))
         }
-        index = g1.tokens;
     }
-    Ok(g1)
+
+    fn unary(op: UnaryOp, arg: Self) -> Result<Self, CalcError> {
+        shallow(Expr::unary(op, arg))
+    }
+
+    fn binary(op: BinaryOp, lhs: Self, rhs: Self) -> Result<Self, CalcError> {
+        shallow(Expr::binary(op, lhs, rhs))
+    }
+
+    fn bind<E: Environment>(
+        _: &mut E,
+        binder: Binder,
+        var: &str,
+        body: Expr,
+        args: Vec<Self>,
+    ) -> Result<Self, CalcError> {
+        shallow(Expr::call(binder.name(), binder.arguments(var, body, args)))
+    }
+
+    fn bound(name: &str, _: &Self) -> Self {
+        Expr::var(name)
+    }
+
+    fn bind_let(
+        name: &str,
+        value: Self,
+        body: Self,
+    ) -> Result<Self, CalcError> {
+        shallow(Expr::call("let", vec![Expr::var(name), value, body]))
+    }
+
+    fn to_expr(&self) -> Expr {
+        self.clone()
+    }
 }
 
-// Numbers, parenthesized expressions, and atoms
-fn g_expr<E>(token_list: &[Token], env: &mut E) -> Result<IR, CalcError>
+/// Rejects `expr` if it is deeper than `MAX_DEPTH`. Its children were
+/// checked when they were built, so measuring it recurses no deeper.
+fn shallow(expr: Expr) -> Result<Expr, CalcError> {
+    if expr.depth() > MAX_DEPTH {
+        Err(CalcError::NestingTooDeep)
+    } else {
+        Ok(expr)
+    }
+}
+
+macro_rules! trace_return {
+    ($rule:expr, $ir:expr) => {
+        trace!(
+            "{} returned {} consuming {} tokens",
+            $rule,
+            $ir.value,
+            $ir.tokens
+        )
+    };
+}
+
+/// The rules of the grammar, from the loosest to the tightest binding.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Rule {
+    /// Bitwise operators
+    D,
+    /// Addition and subtraction
+    E,
+    /// Multiplication and division
+    T,
+    /// Exponentiation
+    F,
+    /// Numbers, parenthesized expressions, and atoms
+    G,
+}
+
+impl Rule {
+    fn name(self) -> &'static str {
+        match self {
+            Rule::D => "d_expr",
+            Rule::E => "e_expr",
+            Rule::T => "t_expr",
+            Rule::F => "f_expr",
+            Rule::G => "g_expr",
+        }
+    }
+
+    /// The rule of the chains of operators of `precedence`, as given by
+    /// `BinaryOp::precedence`.
+    fn from_precedence(precedence: u8) -> Option<Rule> {
+        match precedence {
+            1 => Some(Rule::D),
+            2 => Some(Rule::E),
+            3 => Some(Rule::T),
+            4 => Some(Rule::F),
+            _ => None,
+        }
+    }
+
+    /// The rule that binds next tighter than this one.
+    fn tighter(self) -> Rule {
+        match self {
+            Rule::D => Rule::E,
+            Rule::E => Rule::T,
+            Rule::T => Rule::F,
+            Rule::F | Rule::G => Rule::G,
+        }
+    }
+}
+
+/// The builtin binary operator that `token` denotes, whichever chain it
+/// ends up in.
+fn binary_op(token: PackedToken) -> Option<BinaryOp> {
+    match token {
+        PackedToken::BitWiseAnd => Some(BinaryOp::BitAnd),
+        PackedToken::BitWiseOr => Some(BinaryOp::BitOr),
+        PackedToken::BitWiseXor => Some(BinaryOp::BitXor),
+        PackedToken::BitWiseLShift => Some(BinaryOp::Shl),
+        PackedToken::BitWiseRShift => Some(BinaryOp::Shr),
+        PackedToken::Plus => Some(BinaryOp::Add),
+        PackedToken::Minus => Some(BinaryOp::Sub),
+        PackedToken::Multiply => Some(BinaryOp::Mul),
+        PackedToken::Divide => Some(BinaryOp::Div),
+        PackedToken::Modulo => Some(BinaryOp::Rem),
+        PackedToken::Exponent => Some(BinaryOp::Pow),
+        _ => None,
+    }
+}
+
+/// The part of a rule that remains to be done once the rule it invoked
+/// returns.
+enum Frame<'t, N> {
+    /// A chain of operators of `rule` beginning at token `start`. `comp`
+    /// is the rounding error its sums have accumulated so far, under
+    /// `compensated_sum`.
+    Chain {
+        rule: Rule,
+        start: usize,
+        depth: usize,
+        lhs: Option<(IR<N>, ChainOp<'t>)>,
+        comp: Option<N>,
+    },
+    /// A `~` negating the rest of the input.
+    Not,
+    /// A `-` negating the rest of the input, or the power of a number
+    /// after it.
+    Neg,
+    /// A `+` in front of an operand, which leaves it as it is.
+    Plus,
+    /// An atom whose arguments are being parsed; `tokens` have been
+    /// consumed so far. `listed` arguments are separated by commas inside
+    /// the parentheses of the call, as in `f(a, b)`, rather than passed side
+    /// by side, as in `f a b`.
+    Args {
+        name: &'t str,
+        arity: usize,
+        start: usize,
+        depth: usize,
+        args: Vec<N>,
+        tokens: usize,
+        listed: bool,
+    },
+    /// A parenthesized expression opened at token `start`.
+    Group { start: usize },
+    /// The value bound by a `let` at token `start` to `name`.
+    LetValue {
+        name: &'t str,
+        start: usize,
+        depth: usize,
+    },
+    /// The body of a `let` that binds `value` to `name`, of which `tokens`
+    /// up to `in` have been consumed.
+    LetBody {
+        name: &'t str,
+        value: N,
+        tokens: usize,
+    },
+    /// A numeric argument of a `sum` or `integrate`.
+    Binder(BinderCall<'t, N>),
+    /// The arguments of a `cond` at token `start`, of which `count` and
+    /// the `tokens` before the next one have been parsed. `args` holds the
+    /// arguments that were not skipped, and `truth` whether the last
+    /// condition holds.
+    Cond {
+        start: usize,
+        depth: usize,
+        count: usize,
+        tokens: usize,
+        args: Vec<N>,
+        truth: Option<bool>,
+        chosen: Option<N>,
+        skipped: bool,
+    },
+}
+
+/// An operator in a chain: a builtin one, or a registered one given by its
+/// symbol.
+#[derive(Clone, Copy)]
+enum ChainOp<'t> {
+    Builtin(BinaryOp),
+    Registered(&'t str),
+}
+
+/// A call of a function like `sum` or `integrate` at token `start`, of
+/// which `count` arguments and `tokens` tokens have been parsed.
+struct BinderCall<'t, N> {
+    binder: Binder,
+    var: &'t str,
+    start: usize,
+    depth: usize,
+    count: usize,
+    tokens: usize,
+    body: Option<Expr>,
+    args: Vec<N>,
+}
+
+enum Step<N> {
+    Enter(Rule, usize, usize),
+    Return(IR<N>),
+}
+
+/// A recursive descent parser that keeps its rules on a stack of `Frame`s
+/// rather than on the machine stack, so the nesting of the input is only
+/// bounded by memory and by the `Environment::max_depth` of `env`.
+struct Parser<'t, 'e, N, E: 'e> {
+    tokens: &'t TokenStream<'t>,
+    env: &'e mut E,
+    stack: Vec<Frame<'t, N>>,
+    /// The names bound by the enclosing `let`s, innermost last, with what
+    /// they stand for.
+    bindings: Vec<(&'t str, N)>,
+    /// The number of enclosing `cond` arguments that are skipped. They are
+    /// parsed, but not evaluated.
+    skip: usize,
+    /// The operators registered with `env`.
+    operators: Vec<Operator>,
+    /// The index of the token the error returned is about, if it is about
+    /// one.
+    failed_at: Option<usize>,
+}
+
+impl<'t, 'e, N, E> Parser<'t, 'e, N, E>
 where
+    N: Builder,
     E: Environment,
 {
-    if !token_list.is_empty() {
-        match token_list[0] {
-            Token::Number(ref n) => Ok(IR::new(n.clone(), 1)),
-            Token::Atom(ref s) => {
-                if let Some(nargs) = env.arity(s) {
-                    let mut args: Vec<Value> = Vec::new();
-                    let mut start = 1;
-                    for _ in 0..nargs {
-                        let ir = g_expr(&token_list[start..], env)?;
-                        start += ir.tokens;
-                        args.push(ir.value);
+    /// Parses a `d_expr` beginning at token `pos`.
+    fn run(&mut self, pos: usize, depth: usize) -> Result<IR<N>, CalcError> {
+        let mut step = Step::Enter(Rule::D, pos, depth);
+        loop {
+            step = match step {
+                Step::Enter(rule, pos, depth) => {
+                    self.enter(rule, pos, depth)?
+                }
+                Step::Return(ir) => match self.stack.pop() {
+                    Some(frame) => self.resume(frame, ir)?,
+                    None => return Ok(ir),
+                },
+            };
+        }
+    }
+
+    /// Records that `error`, which is about to be returned, is about the
+    /// token at `pos`.
+    fn at(&mut self, pos: usize, error: CalcError) -> CalcError {
+        self.failed_at = Some(pos);
+        error
+    }
+
+    /// Combines results with `build`, unless the current `cond` argument is
+    /// skipped, in which case the result is a placeholder. `op` names what
+    /// `build` applies, for the errors of `check_result`.
+    fn make<F>(&mut self, op: &str, build: F) -> Result<N, CalcError>
+    where
+        F: FnOnce(&mut E) -> Result<N, CalcError>,
+    {
+        if self.skip > 0 {
+            return N::literal(&Value::dec(0));
+        }
+        let n = build(self.env)?;
+        check_result(&*self.env, op, &n)?;
+        Ok(n)
+    }
+
+    /// Starts parsing `rule` at token `pos`.
+    fn enter(
+        &mut self,
+        rule: Rule,
+        pos: usize,
+        depth: usize,
+    ) -> Result<Step<N>, CalcError> {
+        trace!("{} entered at index {}", rule.name(), pos);
+        self.env.step()?;
+        match rule {
+            Rule::D | Rule::F | Rule::G => {
+                if let Some(max_depth) = self.env.max_depth() {
+                    if depth > max_depth {
+                        return Err(CalcError::NestingTooDeep);
                     }
-                    let res = env.resolve(s, &args);
-                    Ok(IR::new(res?, start))
-                } else {
-                    Err(CalcError::UnknownAtom(s.clone()))
                 }
             }
-            Token::Minus => {
-                if token_list.len() >= 2 {
-                    if let Token::Number(ref n) = token_list[1] {
-                        Ok(IR::new(-n.clone(), 2))
-                    } else {
-                        let mut ir = d_expr(&token_list[1..], env)?;
-                        ir.value = -ir.value;
-                        ir.tokens += 1;
-                        Ok(ir)
+            Rule::E | Rule::T => {}
+        }
+        if rule != Rule::G {
+            if rule == Rule::D
+                && self.tokens.get(pos) == Some(PackedToken::BitWiseNot)
+            {
+                self.stack.push(Frame::Not);
+                return Ok(Step::Enter(Rule::D, pos + 1, depth + 1));
+            }
+            self.stack.push(Frame::Chain {
+                rule,
+                start: pos,
+                depth,
+                lhs: None,
+                comp: None,
+            });
+            return Ok(Step::Enter(rule.tighter(), pos, depth));
+        }
+
+        let tokens = self.tokens;
+        let token = match tokens.get(pos) {
+            Some(token) => token,
+            None => return Err(CalcError::UnexpectedEndOfInput),
+        };
+        let ir = match token {
+            PackedToken::Number(n) => IR::new(
+                self.make("literal", |env| literal(env, tokens.number(n)))?,
+                1,
+            ),
+            PackedToken::Atom(name) => {
+                let name = tokens.atom(name);
+                if name == "let" {
+                    return self.binding(pos, depth);
+                }
+                if name == "cond" {
+                    return self.cond(pos, depth);
+                }
+                if let Some(binder) = Binder::from_name(name) {
+                    return self.binder(binder, pos, depth);
+                }
+                let bound = self.bindings.iter().rev().find(|b| b.0 == name);
+                if let Some((_, value)) = bound {
+                    let ir = IR::new(value.clone(), 1);
+                    trace_return!("g_expr", ir);
+                    return Ok(Step::Return(ir));
+                }
+                match self.env.arity(name) {
+                    Some(0) => {
+                        let value = self
+                            .make(name, |env| N::atom(env, name, Vec::new()))?;
+                        IR::new(value, 1)
+                    }
+                    Some(arity) => {
+                        let listed =
+                            tokens.get(pos + 1) == Some(PackedToken::OpenParen);
+                        let skip = if listed { 2 } else { 1 };
+                        self.stack.push(Frame::Args {
+                            name,
+                            arity,
+                            start: pos,
+                            depth,
+                            args: Vec::with_capacity(arity),
+                            tokens: skip,
+                            listed,
+                        });
+                        let rule = if listed { Rule::D } else { Rule::G };
+                        return Ok(Step::Enter(rule, pos + skip, depth + 1));
+                    }
+                    None => {
+                        let error = CalcError::unknown_atom(name, &*self.env);
+                        return Err(self.at(pos, error));
+                    }
+                }
+            }
+            PackedToken::Placeholder(index) => {
+                // Placeholders are looked up like variables named `?N`,
+                // which only an environment bound to arguments knows.
+                let name = format!("?{}", index);
+                if self.env.arity(&name) != Some(0) {
+                    let error = CalcError::unknown_atom(&name, &*self.env);
+                    return Err(self.at(pos, error));
+                }
+                let value =
+                    self.make(&name, |env| N::atom(env, &name, Vec::new()))?;
+                IR::new(value, 1)
+            }
+            // A `-` before a number is part of the literal, unless the
+            // number is raised to a power, as in `-2²`, which is `-(2²)`.
+            PackedToken::Minus => match tokens.get(pos + 1) {
+                Some(PackedToken::Number(_))
+                    if matches!(
+                        tokens.get(pos + 2),
+                        Some(PackedToken::Exponent)
+                            | Some(PackedToken::Square)
+                            | Some(PackedToken::Cube)
+                    ) =>
+                {
+                    self.stack.push(Frame::Neg);
+                    return Ok(Step::Enter(Rule::F, pos + 1, depth + 1));
+                }
+                Some(PackedToken::Number(n)) => {
+                    let n = tokens.number(n);
+                    // Negated as a `Value`, so that a literal like
+                    // `-9223372036854775808` may fit where its magnitude
+                    // does not.
+                    let value =
+                        self.make("literal", |env| literal(env, &-n.clone()))?;
+                    IR::new(value, 2)
+                }
+                Some(_) => {
+                    self.stack.push(Frame::Neg);
+                    return Ok(Step::Enter(Rule::D, pos + 1, depth + 1));
+                }
+                None => return Err(CalcError::UnexpectedEndOfInput),
+            },
+            PackedToken::Plus => {
+                self.stack.push(Frame::Plus);
+                return Ok(Step::Enter(Rule::G, pos + 1, depth + 1));
+            }
+            PackedToken::OpenParen => {
+                self.stack.push(Frame::Group { start: pos });
+                return Ok(Step::Enter(Rule::D, pos + 1, depth + 1));
+            }
+            _ => {
+                return Err(self.at(
+                    pos,
+                    CalcError::UnexpectedToken(
+                        tokens.token(pos).to_string(),
+                        "number",
+                    ),
+                ))
+            }
+        };
+        trace_return!("g_expr", ir);
+        Ok(Step::Return(ir))
+    }
+
+    /// Starts parsing `let NAME = VALUE in BODY` at token `pos`. Like a
+    /// unary operator, the body extends as far as possible.
+    fn binding(
+        &mut self,
+        pos: usize,
+        depth: usize,
+    ) -> Result<Step<N>, CalcError> {
+        let tokens = self.tokens;
+        let name = match tokens.get(pos + 1) {
+            Some(PackedToken::Atom(name)) if !is_keyword(tokens.atom(name)) => {
+                tokens.atom(name)
+            }
+            Some(_) => {
+                return Err(self.at(
+                    pos + 1,
+                    CalcError::UnexpectedToken(
+                        tokens.token(pos + 1).to_string(),
+                        "name",
+                    ),
+                ))
+            }
+            None => return Err(CalcError::UnexpectedEndOfInput),
+        };
+        match tokens.get(pos + 2) {
+            Some(PackedToken::Assign) => {}
+            Some(_) => {
+                return Err(self.at(
+                    pos + 2,
+                    CalcError::UnexpectedToken(
+                        tokens.token(pos + 2).to_string(),
+                        "=",
+                    ),
+                ))
+            }
+            None => return Err(CalcError::UnexpectedEndOfInput),
+        }
+        self.stack.push(Frame::LetValue {
+            name,
+            start: pos,
+            depth,
+        });
+        Ok(Step::Enter(Rule::D, pos + 3, depth + 1))
+    }
+
+    /// Checks that the token at `pos` is `expected`.
+    fn expect(
+        &mut self,
+        pos: usize,
+        expected: PackedToken,
+        name: &'static str,
+    ) -> Result<(), CalcError> {
+        match self.tokens.get(pos) {
+            Some(token) if token == expected => Ok(()),
+            Some(_) => Err(self.at(
+                pos,
+                CalcError::UnexpectedToken(
+                    self.tokens.token(pos).to_string(),
+                    name,
+                ),
+            )),
+            None if expected == PackedToken::CloseParen => {
+                Err(CalcError::UnmatchedParenthesis)
+            }
+            None => Err(CalcError::UnexpectedEndOfInput),
+        }
+    }
+
+    /// Starts parsing a call of `binder`, such as `sum(VAR, LO, HI, BODY)`
+    /// or `integrate(BODY, VAR, A, B)`, at token `pos`. The variable is
+    /// looked up first, since the body may come before it.
+    fn binder(
+        &mut self,
+        binder: Binder,
+        pos: usize,
+        depth: usize,
+    ) -> Result<Step<N>, CalcError> {
+        self.expect(pos + 1, PackedToken::OpenParen, "(")?;
+        let (var_at, _) = binder.layout();
+        let at = self.skip_arguments(pos + 2, var_at, binder)?;
+        let var = match self.tokens.get(at) {
+            Some(PackedToken::Atom(var))
+                if !is_keyword(self.tokens.atom(var)) =>
+            {
+                self.tokens.atom(var)
+            }
+            Some(_) => {
+                return Err(self.at(
+                    at,
+                    CalcError::UnexpectedToken(
+                        self.tokens.token(at).to_string(),
+                        "name",
+                    ),
+                ))
+            }
+            None => return Err(CalcError::UnexpectedEndOfInput),
+        };
+        let call = BinderCall {
+            binder,
+            var,
+            start: pos,
+            depth,
+            count: 0,
+            tokens: 2,
+            body: None,
+            args: Vec::new(),
+        };
+        self.binder_args(call)
+    }
+
+    /// Finds the token after the first `count` arguments of a call whose
+    /// arguments start at token `pos`.
+    fn skip_arguments(
+        &self,
+        mut pos: usize,
+        count: usize,
+        binder: Binder,
+    ) -> Result<usize, CalcError> {
+        let (mut seen, mut nesting) = (0, 0);
+        while seen < count {
+            match self.tokens.get(pos) {
+                Some(PackedToken::OpenParen) => nesting += 1,
+                Some(PackedToken::CloseParen) if nesting == 0 => {
+                    return Err(binder.wrong_arity(seen + 1));
+                }
+                Some(PackedToken::CloseParen) => nesting -= 1,
+                Some(PackedToken::Comma) if nesting == 0 => seen += 1,
+                Some(_) => {}
+                None => return Err(CalcError::UnmatchedParenthesis),
+            }
+            pos += 1;
+        }
+        Ok(pos)
+    }
+
+    /// Counts the arguments of a call from the one at token `pos` to its
+    /// closing parenthesis, or to the end of the input if it has none.
+    fn count_arguments(&self, mut pos: usize) -> usize {
+        let (mut count, mut nesting) = (1, 0);
+        loop {
+            match self.tokens.get(pos) {
+                Some(PackedToken::OpenParen) => nesting += 1,
+                Some(PackedToken::CloseParen) if nesting == 0 => return count,
+                Some(PackedToken::CloseParen) => nesting -= 1,
+                Some(PackedToken::Comma) if nesting == 0 => count += 1,
+                Some(_) => {}
+                None => return count,
+            }
+            pos += 1;
+        }
+    }
+
+    /// Parses the arguments of `call` from the one at `call.count` on,
+    /// until one of them is a number, which is parsed by the caller.
+    fn binder_args(
+        &mut self,
+        mut call: BinderCall<'t, N>,
+    ) -> Result<Step<N>, CalcError> {
+        let (var_at, body_at) = call.binder.layout();
+        loop {
+            let pos = call.start + call.tokens;
+            if call.count == var_at {
+                call.tokens += 1;
+            } else if call.count == body_at {
+                let body = self.binder_body(call.var, pos, call.depth + 1)?;
+                call.tokens += body.tokens;
+                call.body = Some(body.value);
+            } else {
+                let depth = call.depth + 1;
+                self.stack.push(Frame::Binder(call));
+                return Ok(Step::Enter(Rule::D, pos, depth));
+            }
+            if let Some(step) = self.binder_next(&mut call)? {
+                return Ok(step);
+            }
+        }
+    }
+
+    /// Moves past the separator after an argument of `call`, and finishes
+    /// the call at its closing parenthesis.
+    fn binder_next(
+        &mut self,
+        call: &mut BinderCall<'t, N>,
+    ) -> Result<Option<Step<N>>, CalcError> {
+        call.count += 1;
+        let next = call.start + call.tokens;
+        if self.tokens.get(next) == Some(PackedToken::Comma) {
+            call.tokens += 1;
+            return Ok(None);
+        }
+        self.expect(next, PackedToken::CloseParen, ")")?;
+        let binder = call.binder;
+        binder.check_arity(call.count)?;
+        let (var, args) = (call.var, mem::take(&mut call.args));
+        let body = call.body.take().expect("checked by the arity");
+        let value = self
+            .make(binder.name(), |env| N::bind(env, binder, var, body, args))?;
+        let ir = IR::new(value, call.tokens + 1);
+        trace_return!("g_expr", ir);
+        Ok(Some(Step::Return(ir)))
+    }
+
+    /// Parses the body of a `sum` or `integrate` at token `pos` into a
+    /// tree, in which `var` and the names bound by enclosing `let`s are
+    /// known.
+    fn binder_body(
+        &mut self,
+        var: &'t str,
+        pos: usize,
+        depth: usize,
+    ) -> Result<IR<Expr>, CalcError> {
+        let mut bindings: Vec<(&str, Expr)> = self
+            .bindings
+            .iter()
+            .map(|&(name, ref value)| (name, value.to_expr()))
+            .collect();
+        bindings.push((var, Expr::var(var)));
+        // Nested bodies parse with the same type of environment, which
+        // keeps the instantiations of `Parser` finite.
+        let env: &mut dyn Environment = self.env;
+        let mut parser = Parser {
+            tokens: self.tokens,
+            env: &mut Symbolic(env),
+            stack: Vec::new(),
+            bindings,
+            skip: 0,
+            operators: self.operators.clone(),
+            failed_at: None,
+        };
+        let body = parser.run(pos, depth);
+        self.failed_at = parser.failed_at;
+        body
+    }
+
+    /// Starts parsing `cond(c1, v1, c2, v2, ..., default)` at token `pos`.
+    fn cond(&mut self, pos: usize, depth: usize) -> Result<Step<N>, CalcError> {
+        self.expect(pos + 1, PackedToken::OpenParen, "(")?;
+        self.stack.push(Frame::Cond {
+            start: pos,
+            depth,
+            count: 0,
+            tokens: 2,
+            args: Vec::new(),
+            truth: None,
+            chosen: None,
+            skipped: false,
+        });
+        Ok(Step::Enter(Rule::D, pos + 2, depth + 1))
+    }
+
+    /// Continues the rule of `frame` with the result of the rule it
+    /// invoked.
+    fn resume(
+        &mut self,
+        frame: Frame<'t, N>,
+        mut ir: IR<N>,
+    ) -> Result<Step<N>, CalcError> {
+        match frame {
+            Frame::Chain {
+                rule,
+                start,
+                depth,
+                lhs,
+                mut comp,
+            } => {
+                if let Some((lhs, op)) = lhs {
+                    let (lhs_value, rhs) = (lhs.value, ir.value);
+                    // Subtraction is compensated as the addition of the
+                    // negated operand.
+                    let addend = match op {
+                        ChainOp::Builtin(BinaryOp::Add)
+                            if self.env.compensated_sum() =>
+                        {
+                            Some(rhs.clone())
+                        }
+                        ChainOp::Builtin(BinaryOp::Sub)
+                            if self.env.compensated_sum() =>
+                        {
+                            N::unary(UnaryOp::Neg, rhs.clone()).ok()
+                        }
+                        _ => None,
+                    };
+                    let augend = addend.as_ref().map(|_| lhs_value.clone());
+                    let value = match op {
+                        ChainOp::Builtin(op) => {
+                            self.make(op.symbol(), |env| {
+                                if op == BinaryOp::Shr {
+                                    N::shr(lhs_value, rhs, env.right_shift())
+                                } else {
+                                    binary(&*env, op, lhs_value, rhs)
+                                }
+                            })?
+                        }
+                        ChainOp::Registered(symbol) => self
+                            .make(symbol, |env| {
+                                N::atom(env, symbol, vec![lhs_value, rhs])
+                            })?,
+                    };
+                    if let (Some(augend), Some(addend)) = (augend, addend) {
+                        if let Some(error) =
+                            N::sum_error(&augend, &addend, &value)
+                        {
+                            comp = Some(match comp {
+                                Some(comp) => {
+                                    N::binary(BinaryOp::Add, comp, error)?
+                                }
+                                None => error,
+                            });
+                        }
                     }
-                } else {
-                    Err(CalcError::UnexpectedEndOfInput)
+                    ir = IR::new(value, lhs.tokens + ir.tokens + 1);
                 }
+                self.chain(rule, start, depth, ir, comp)
+            }
+            Frame::Not => {
+                let arg = ir.value;
+                ir.value = self.make(UnaryOp::Not.symbol(), |_| {
+                    N::unary(UnaryOp::Not, arg)
+                })?;
+                ir.tokens += 1;
+                trace_return!("d_expr", ir);
+                Ok(Step::Return(ir))
+            }
+            Frame::Neg => {
+                let arg = ir.value;
+                ir.value = self.make(UnaryOp::Neg.symbol(), |_| {
+                    N::unary(UnaryOp::Neg, arg)
+                })?;
+                ir.tokens += 1;
+                trace_return!("g_expr", ir);
+                Ok(Step::Return(ir))
+            }
+            Frame::Plus => {
+                ir.tokens += 1;
+                trace_return!("g_expr", ir);
+                Ok(Step::Return(ir))
             }
-            Token::OpenParen => {
-                let mut ir = d_expr(&token_list[1..], env)?;
-                let close_paren = ir.tokens + 1;
-                if close_paren < token_list.len() {
-                    match token_list[close_paren] {
-                        Token::CloseParen => {
-                            ir.tokens = close_paren + 1;
-                            Ok(ir)
+            Frame::Args {
+                name,
+                arity,
+                start,
+                depth,
+                mut args,
+                mut tokens,
+                listed,
+            } => {
+                tokens += ir.tokens;
+                args.push(ir.value);
+                if listed {
+                    let next = start + tokens;
+                    match self.tokens.get(next) {
+                        Some(PackedToken::Comma) if args.len() < arity => {
+                            self.stack.push(Frame::Args {
+                                name,
+                                arity,
+                                start,
+                                depth,
+                                args,
+                                tokens: tokens + 1,
+                                listed,
+                            });
+                            return Ok(Step::Enter(
+                                Rule::D,
+                                next + 1,
+                                depth + 1,
+                            ));
                         }
-                        _ => Err(CalcError::UnexpectedToken(
-                            token_list[close_paren].to_string(),
+                        Some(PackedToken::CloseParen) => {
+                            tokens += 1;
+                            if args.len() < arity {
+                                return Err(CalcError::WrongArity {
+                                    atom: name.to_owned(),
+                                    expected: arity,
+                                    actual: args.len(),
+                                });
+                            }
+                        }
+                        Some(PackedToken::Comma) => {
+                            let extra = self.count_arguments(next + 1);
+                            return Err(self.at(
+                                next,
+                                CalcError::WrongArity {
+                                    atom: name.to_owned(),
+                                    expected: arity,
+                                    actual: args.len() + extra,
+                                },
+                            ));
+                        }
+                        Some(_) => {
+                            return Err(self.at(
+                                next,
+                                CalcError::UnexpectedToken(
+                                    self.tokens.token(next).to_string(),
+                                    ")",
+                                ),
+                            ))
+                        }
+                        // The parenthesis left open follows the name.
+                        None => {
+                            let error = CalcError::UnmatchedParenthesis;
+                            return Err(self.at(start + 1, error));
+                        }
+                    }
+                }
+                if args.len() < arity {
+                    self.stack.push(Frame::Args {
+                        name,
+                        arity,
+                        start,
+                        depth,
+                        args,
+                        tokens,
+                        listed: false,
+                    });
+                    return Ok(Step::Enter(Rule::G, start + tokens, depth + 1));
+                }
+                let value = self.make(name, |env| N::atom(env, name, args))?;
+                let ir = IR::new(value, tokens);
+                trace_return!("g_expr", ir);
+                Ok(Step::Return(ir))
+            }
+            Frame::Group { start } => {
+                let close_paren = start + ir.tokens + 1;
+                match self.tokens.get(close_paren) {
+                    Some(PackedToken::CloseParen) => {
+                        ir.tokens = close_paren + 1 - start;
+                        trace_return!("g_expr", ir);
+                        Ok(Step::Return(ir))
+                    }
+                    Some(_) => Err(self.at(
+                        close_paren,
+                        CalcError::UnexpectedToken(
+                            self.tokens.token(close_paren).to_string(),
                             ")",
-                        )),
+                        ),
+                    )),
+                    None => {
+                        Err(self.at(start, CalcError::UnmatchedParenthesis))
+                    }
+                }
+            }
+            Frame::LetValue { name, start, depth } => {
+                let tokens = 3 + ir.tokens;
+                match self.tokens.get(start + tokens) {
+                    Some(PackedToken::Atom(word))
+                        if self.tokens.atom(word) == "in" => {}
+                    Some(_) => {
+                        return Err(self.at(
+                            start + tokens,
+                            CalcError::UnexpectedToken(
+                                self.tokens.token(start + tokens).to_string(),
+                                "in",
+                            ),
+                        ))
                     }
+                    None => return Err(CalcError::UnexpectedEndOfInput),
+                }
+                self.bindings.push((name, N::bound(name, &ir.value)));
+                self.stack.push(Frame::LetBody {
+                    name,
+                    value: ir.value,
+                    tokens: tokens + 1,
+                });
+                Ok(Step::Enter(Rule::D, start + tokens + 1, depth + 1))
+            }
+            Frame::LetBody {
+                name,
+                value,
+                tokens,
+            } => {
+                self.bindings.pop();
+                let body = ir.value;
+                ir.value =
+                    self.make("let", |_| N::bind_let(name, value, body))?;
+                ir.tokens += tokens;
+                trace_return!("g_expr", ir);
+                Ok(Step::Return(ir))
+            }
+            Frame::Binder(mut call) => {
+                call.tokens += ir.tokens;
+                call.args.push(ir.value);
+                match self.binder_next(&mut call)? {
+                    Some(step) => Ok(step),
+                    None => self.binder_args(call),
+                }
+            }
+            Frame::Cond {
+                start,
+                depth,
+                mut count,
+                mut tokens,
+                mut args,
+                mut truth,
+                mut chosen,
+                skipped,
+            } => {
+                tokens += ir.tokens;
+                if skipped {
+                    self.skip -= 1;
+                } else if count % 2 == 0 {
+                    truth = ir.value.truth();
+                    args.push(ir.value);
+                } else if truth == Some(true) {
+                    chosen = Some(ir.value);
                 } else {
-                    Err(CalcError::UnmatchedParenthesis)
+                    args.push(ir.value);
+                }
+                count += 1;
+                let next = start + tokens;
+                match self.tokens.get(next) {
+                    Some(PackedToken::Comma) => {
+                        // Conditions and values after the first condition
+                        // that holds are skipped, as are the values of
+                        // conditions that do not hold.
+                        let skipped = chosen.is_some()
+                            || count % 2 == 1 && truth == Some(false);
+                        if skipped {
+                            self.skip += 1;
+                        }
+                        self.stack.push(Frame::Cond {
+                            start,
+                            depth,
+                            count,
+                            tokens: tokens + 1,
+                            args,
+                            truth,
+                            chosen,
+                            skipped,
+                        });
+                        Ok(Step::Enter(Rule::D, next + 1, depth + 1))
+                    }
+                    Some(PackedToken::CloseParen) => {
+                        check_cond_arity(count)?;
+                        // Unless a condition held, the default was the last
+                        // argument to be evaluated.
+                        let value = match (chosen, truth) {
+                            (Some(value), _) => value,
+                            (None, Some(_)) => args.pop().expect("default"),
+                            (None, None) => self.make("cond", |env| {
+                                N::atom(env, "cond", args)
+                            })?,
+                        };
+                        let ir = IR::new(value, tokens + 1);
+                        trace_return!("g_expr", ir);
+                        Ok(Step::Return(ir))
+                    }
+                    Some(_) => Err(self.at(
+                        next,
+                        CalcError::UnexpectedToken(
+                            self.tokens.token(next).to_string(),
+                            ")",
+                        ),
+                    )),
+                    None => {
+                        Err(self.at(start + 1, CalcError::UnmatchedParenthesis))
+                    }
                 }
             }
-            _ => Err(CalcError::UnexpectedToken(
-                token_list[0].to_string(),
-                "number",
-            )),
         }
-    } else {
-        Err(CalcError::UnexpectedEndOfInput)
+    }
+
+    /// Applies the postfix operators of a chain and looks for the next
+    /// binary operator, given everything parsed so far in `acc`, and the
+    /// rounding error of its sums in `comp`.
+    fn chain(
+        &mut self,
+        rule: Rule,
+        start: usize,
+        depth: usize,
+        mut acc: IR<N>,
+        mut comp: Option<N>,
+    ) -> Result<Step<N>, CalcError> {
+        while let Some(token) = self.tokens.get(start + acc.tokens) {
+            self.env.step()?;
+            let next = start + acc.tokens + 1;
+            if let Some(op) = binary_op(token) {
+                // Operators of looser rules are left to the enclosing
+                // chains.
+                if Rule::from_precedence(self.env.precedence(op)) != Some(rule)
+                {
+                    break;
+                }
+                let (operand, operand_depth) = match self.env.assoc(op) {
+                    Assoc::Left => (rule.tighter(), depth),
+                    Assoc::Right => (rule, depth + 1),
+                };
+                if op != BinaryOp::Add && op != BinaryOp::Sub {
+                    acc.value = self.compensate(acc.value, comp.take())?;
+                }
+                self.stack.push(Frame::Chain {
+                    rule,
+                    start,
+                    depth,
+                    lhs: Some((acc, ChainOp::Builtin(op))),
+                    comp,
+                });
+                return Ok(Step::Enter(operand, next, operand_depth));
+            }
+            if let PackedToken::Operator(index) = token {
+                let symbol = self.tokens.atom(index);
+                let op = match self
+                    .operators
+                    .iter()
+                    .find(|op| op.symbol == symbol)
+                {
+                    Some(op) => op,
+                    None => {
+                        return Err(self.at(
+                            next - 1,
+                            CalcError::UnexpectedToken(
+                                symbol.into(),
+                                "operator",
+                            ),
+                        ))
+                    }
+                };
+                if Rule::from_precedence(op.precedence) != Some(rule) {
+                    break;
+                }
+                let (operand, operand_depth) = match op.assoc {
+                    Assoc::Left => (rule.tighter(), depth),
+                    Assoc::Right => (rule, depth + 1),
+                };
+                acc.value = self.compensate(acc.value, comp.take())?;
+                self.stack.push(Frame::Chain {
+                    rule,
+                    start,
+                    depth,
+                    lhs: Some((acc, ChainOp::Registered(symbol))),
+                    comp: None,
+                });
+                return Ok(Step::Enter(operand, next, operand_depth));
+            }
+            let power = match token {
+                PackedToken::Square if rule == Rule::F => 2,
+                PackedToken::Cube if rule == Rule::F => 3,
+                PackedToken::Number(n) => {
+                    return Err(self.at(
+                        next - 1,
+                        CalcError::UnexpectedToken(
+                            self.tokens.number(n).to_string(),
+                            "operator",
+                        ),
+                    ))
+                }
+                _ => break,
+            };
+            let base = self.compensate(acc.value, comp.take())?;
+            acc.value = self.make(BinaryOp::Pow.symbol(), |_| {
+                N::binary(BinaryOp::Pow, base, N::literal(&Value::dec(power))?)
+            })?;
+            acc.tokens += 1;
+        }
+        acc.value = self.compensate(acc.value, comp)?;
+        trace_return!(rule.name(), acc);
+        Ok(Step::Return(acc))
+    }
+
+    /// Adds the rounding error a chain has accumulated back into its
+    /// `value`.
+    fn compensate(
+        &mut self,
+        value: N,
+        comp: Option<N>,
+    ) -> Result<N, CalcError> {
+        match comp {
+            Some(comp) => self.make(BinaryOp::Add.symbol(), |_| {
+                N::binary(BinaryOp::Add, value, comp)
+            }),
+            None => Ok(value),
+        }
     }
 }
 
+/// Whether `name` is reserved by the `let NAME = VALUE in BODY` syntax.
+fn is_keyword(name: &str) -> bool {
+    name == "let" || name == "in"
+}
+
 pub struct DefaultEnvironment;
 
 impl Environment for DefaultEnvironment {
     fn arity(&self, atom: &str) -> Option<usize> {
         match atom {
-            "pi" | "tau" => Some(0),
-            "log" => Some(1),
+            "pi" | "tau" | "e" => Some(0),
+            "log" | "ln" | "exp" | "sin" | "cos" | "tan" => Some(1),
+            "sqrt" | "abs" | "floor" | "ceil" => Some(1),
+            "totient" | "numdiv" | "sumdiv" => Some(1),
+            "togray" | "fromgray" => Some(1),
+            "approx" => Some(2),
             _ => None,
         }
     }
@@ -268,20 +1597,199 @@ impl Environment for DefaultEnvironment {
             "tau" => Ok(Value::Float(
                 d128!(3.1415926535897932384626433832795028) * d128!(2.0),
             )),
+            "e" => Ok(Value::Float(d128!(2.718281828459045235360287471352662))),
             "log" => Ok(Value::Float(args[0].as_float()?.log10())),
-            // "sin" => Ok(Value::Float(args[0].as_float().sin())),
-            // "cos" => Ok(Value::Float(args[0].as_float().cos())),
-            // "tan" => Ok(Value::Float(args[0].as_float().tan())),
-            _ => Err(CalcError::UnknownAtom(atom.to_owned())),
+            "ln" => Ok(Value::Float(args[0].as_float()?.ln())),
+            // The decimal crate's exp is unusable, so go through f64.
+            "exp" => Ok(Value::from_f64(args[0].as_f64().exp())),
+            // Decimal floats have no trigonometry, so go through f64.
+            "sin" => Ok(Value::from_f64(args[0].as_f64().sin())),
+            "cos" => Ok(Value::from_f64(args[0].as_f64().cos())),
+            "tan" => Ok(Value::from_f64(args[0].as_f64().tan())),
+            "sqrt" => sqrt(&args[0]),
+            "abs" => Ok(match args[0] {
+                Value::Integral(ref n, fmt) => Value::Integral(n.abs(), fmt),
+                Value::Float(n) => Value::Float(n.abs()),
+            }),
+            "floor" => args[0].clone().floor(),
+            "ceil" => {
+                let floor = args[0].clone().floor()?;
+                if floor == args[0] {
+                    Ok(floor)
+                } else {
+                    floor + Value::dec(1)
+                }
+            }
+            "totient" => factor::totient(&args[0]),
+            "numdiv" => factor::numdiv(&args[0]),
+            "sumdiv" => factor::sumdiv(&args[0]),
+            "togray" | "fromgray" => {
+                gray::gray(atom, &args[0], gray::DEFAULT_WIDTH)
+            }
+            "approx" => format::approx(&args[0], &args[1]),
+            _ => Err(CalcError::unknown_atom(atom, self)),
         }
     }
+
+    fn names(&self) -> Vec<String> {
+        [
+            "pi", "tau", "e", "log", "ln", "exp", "sin", "cos", "tan", "sqrt",
+            "abs", "floor", "ceil", "totient", "numdiv", "sumdiv", "togray",
+            "fromgray", "approx",
+        ]
+        .iter()
+        .map(|&name| name.to_owned())
+        .collect()
+    }
+}
+
+/// The square root of `n`, which stays an integer if `n` is the square of
+/// one.
+fn sqrt(n: &Value) -> Result<Value, CalcError> {
+    if n.compare(&Value::dec(0)) == Some(Ordering::Less) {
+        return Err(CalcError::InvalidArgument(format!(
+            "sqrt needs a non-negative number, got {}",
+            n
+        )));
+    }
+    if let Value::Integral(ref n, fmt) = *n {
+        // Exact for every square that fits into an `f64` unrounded.
+        let root = Integral::from(n.to_f64().unwrap_or(0.0).sqrt() as u64);
+        if &root * &root == *n {
+            return Ok(Value::Integral(root, fmt));
+        }
+    }
+    Ok(Value::Float(n.as_float()?.pow(d128!(0.5))))
 }
 
 pub fn parse<E>(tokens: &[Token], env: &mut E) -> Result<Value, CalcError>
 where
     E: Environment,
 {
-    d_expr(tokens, env).map(|answer| answer.value)
+    parse_generic(tokens, env)
+}
+
+/// Evaluates the tokens in any numeric type. Arguments to, and results of,
+/// the environment's atoms are converted through `Value`.
+pub fn parse_generic<N, E>(
+    tokens: &[Token],
+    env: &mut E,
+) -> Result<N, CalcError>
+where
+    N: CalcNum,
+    E: Environment,
+{
+    build(&TokenStream::from_tokens(tokens), env)
+}
+
+/// Parses the tokens with any `Builder`, e.g. into an `Expr`.
+pub(crate) fn build<N, E>(
+    tokens: &TokenStream,
+    env: &mut E,
+) -> Result<N, CalcError>
+where
+    N: Builder,
+    E: Environment,
+{
+    build_at(tokens, env).map_err(|(error, _)| error)
+}
+
+/// Tokenizes and parses `input` like `build`, telling where in the input an
+/// error is, if it is about a token or about the input ending too early.
+pub(crate) fn build_located<N, E>(
+    input: &str,
+    env: &mut E,
+) -> Result<N, Located>
+where
+    N: Builder,
+    E: Environment,
+{
+    let operators = env.operators();
+    let mut spans = Vec::new();
+    let tokens = match TokenStream::spanned(input, &operators, &mut spans) {
+        Ok(tokens) => tokens,
+        Err(error) => {
+            let location = spans.pop().map(|span| Location::new(input, span));
+            return Err(Located { error, location });
+        }
+    };
+    build_at(&tokens, env).map_err(|(error, index)| {
+        // Parentheses closed by `Environment::lenient_parens` follow the
+        // last token.
+        let end = input.len()..input.len();
+        let span = match index {
+            Some(index) => Some(spans.get(index).cloned().unwrap_or(end)),
+            None => match error {
+                CalcError::UnexpectedEndOfInput
+                | CalcError::UnmatchedParenthesis => Some(end),
+                _ => None,
+            },
+        };
+        Located {
+            error,
+            location: span.map(|span| Location::new(input, span)),
+        }
+    })
+}
+
+/// Parses the tokens like `build`, failing with the index of the token the
+/// error is about, if it is about one.
+fn build_at<N, E>(
+    tokens: &TokenStream,
+    env: &mut E,
+) -> Result<N, (CalcError, Option<usize>)>
+where
+    N: Builder,
+    E: Environment,
+{
+    let closed;
+    let mut tokens = tokens;
+    let unclosed = if env.lenient_parens() {
+        tokens.unclosed_parens()
+    } else {
+        0
+    };
+    if unclosed > 0 {
+        let mut copy = tokens.clone();
+        copy.close_parens(unclosed);
+        closed = copy;
+        tokens = &closed;
+    }
+    let operators = env.operators();
+    let mut parser = Parser {
+        tokens,
+        env: &mut *env,
+        stack: Vec::new(),
+        bindings: Vec::new(),
+        skip: 0,
+        operators,
+        failed_at: None,
+    };
+    let ir = match parser.run(0, 0) {
+        Ok(ir) => ir,
+        Err(error) => return Err((error, parser.failed_at)),
+    };
+    // Whatever follows a complete expression has nothing to attach to.
+    match tokens.get(ir.tokens) {
+        Some(PackedToken::CloseParen) => {
+            let error = CalcError::UnmatchedParenthesis;
+            return Err((error, Some(ir.tokens)));
+        }
+        Some(_) => {
+            let error = CalcError::UnexpectedToken(
+                tokens.token(ir.tokens).to_string(),
+                "operator",
+            );
+            return Err((error, Some(ir.tokens)));
+        }
+        None => {}
+    }
+    match unclosed {
+        0 => {}
+        1 => env.warn("auto-closed 1 parenthesis".into()),
+        n => env.warn(format!("auto-closed {} parentheses", n)),
+    }
+    Ok(ir.value)
 }
 
 #[cfg(test)]
@@ -316,4 +1824,61 @@ mod tests {
         assert_eq!(super::parse(&expr, &mut env), Ok(expected));
     }
 
+    #[cfg(feature = "trace")]
+    #[test]
+    fn trace_rules() {
+        use log::{self, LevelFilter, Log, Metadata, Record};
+        use std::cell::RefCell;
+        use std::sync::Once;
+
+        thread_local! {
+            static LINES: RefCell<Vec<String>> = RefCell::new(Vec::new());
+        }
+
+        struct Capture;
+
+        impl Log for Capture {
+            fn enabled(&self, _: &Metadata) -> bool {
+                true
+            }
+
+            fn log(&self, record: &Record) {
+                let line = record.args().to_string();
+                LINES.with(|lines| lines.borrow_mut().push(line));
+            }
+
+            fn flush(&self) {}
+        }
+
+        static CAPTURE: Capture = Capture;
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            log::set_logger(&CAPTURE).unwrap();
+            log::set_max_level(LevelFilter::Trace);
+        });
+
+        assert_eq!(::eval("1 + 2"), Ok(Value::dec(3)));
+        let lines = LINES.with(|lines| lines.borrow_mut().split_off(0));
+        assert!(lines[0].starts_with("tokenized \"1 + 2\""));
+        let expected = vec![
+            "d_expr entered at index 0",
+            "e_expr entered at index 0",
+            "t_expr entered at index 0",
+            "f_expr entered at index 0",
+            "g_expr entered at index 0",
+            "g_expr returned 1 consuming 1 tokens",
+            "f_expr returned 1 consuming 1 tokens",
+            "t_expr returned 1 consuming 1 tokens",
+            "t_expr entered at index 2",
+            "f_expr entered at index 2",
+            "g_expr entered at index 2",
+            "g_expr returned 2 consuming 1 tokens",
+            "f_expr returned 2 consuming 1 tokens",
+            "t_expr returned 2 consuming 1 tokens",
+            "e_expr returned 3 consuming 3 tokens",
+            "d_expr returned 3 consuming 3 tokens",
+        ];
+        assert_eq!(&lines[1..], &expected[..]);
+    }
+
 }
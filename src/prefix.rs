@@ -0,0 +1,202 @@
+//! Rendering of expression trees in prefix (Polish) notation.
+
+use ast::{BinaryOp, Expr, UnaryOp};
+use std::collections::HashMap;
+use std::fmt::Write;
+use value::{IntegralFmt, Value};
+
+/// Controls how `Expr::to_prefix` renders an expression.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PrefixOptions {
+    /// Wraps every operation and call in parentheses, like an
+    /// s-expression: `(+ 1 (* 2 3))`. Without them the output is bare
+    /// Polish notation, `+ 1 * 2 3`, which relies on every operator having
+    /// a fixed arity.
+    pub parens: bool,
+    /// Replaces the names of operators, keyed by their default names, e.g.
+    /// `"**"` with `"expt"`.
+    ///
+    /// Binary operators default to their infix symbols. Negation is named
+    /// `neg` and the bitwise complement `~`. Powers with a literal exponent
+    /// of 2 or 3, as written with `²` and `³`, are named `sq` and `cube`
+    /// and take a single operand.
+    pub names: HashMap<String, String>,
+}
+
+impl Default for PrefixOptions {
+    fn default() -> Self {
+        PrefixOptions {
+            parens: true,
+            names: HashMap::new(),
+        }
+    }
+}
+
+impl Expr {
+    /// Renders the expression in prefix notation, e.g. `1 + 2 * 3` as
+    /// `(+ 1 (* 2 3))`.
+    pub fn to_prefix(&self, options: &PrefixOptions) -> String {
+        let mut out = String::new();
+        write_prefix(&mut out, self, options);
+        out
+    }
+}
+
+fn write_prefix(out: &mut String, expr: &Expr, options: &PrefixOptions) {
+    let (name, args): (&str, Vec<&Expr>) = match *expr {
+        Expr::Num(ref value) => {
+            write!(out, "{}", value).expect("writing to a String succeeds");
+            return;
+        }
+        Expr::Var(ref name) => {
+            out.push_str(name);
+            return;
+        }
+        Expr::Call(ref name, ref args) => (name, args.iter().collect()),
+        Expr::Unary(UnaryOp::Neg, ref arg) => ("neg", vec![&**arg]),
+        Expr::Unary(UnaryOp::Not, ref arg) => ("~", vec![&**arg]),
+        Expr::Binary(BinaryOp::Pow, ref lhs, ref rhs) => {
+            match power_name(rhs) {
+                Some(name) => (name, vec![&**lhs]),
+                None => ("**", vec![&**lhs, &**rhs]),
+            }
+        }
+        Expr::Binary(op, ref lhs, ref rhs) => {
+            (op.symbol(), vec![&**lhs, &**rhs])
+        }
+    };
+    let name = options.names.get(name).map_or(name, |name| &name[..]);
+    if options.parens {
+        out.push('(');
+    }
+    out.push_str(name);
+    for arg in args {
+        out.push(' ');
+        write_prefix(out, arg, options);
+    }
+    if options.parens {
+        out.push(')');
+    }
+}
+
+/// Returns the name of a power with a literal exponent of 2 or 3.
+fn power_name(exponent: &Expr) -> Option<&'static str> {
+    match *exponent {
+        Expr::Num(Value::Integral(ref n, IntegralFmt::Dec)) => {
+            if *n == 2.into() {
+                Some("sq")
+            } else if *n == 3.into() {
+                Some("cube")
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use number::CalcNum;
+    use {eval, to_prefix};
+
+    /// Evaluates an s-expression in `f64`, consuming its tokens.
+    fn eval_sexpr(tokens: &mut Vec<String>) -> f64 {
+        let token = tokens.pop().expect("unexpected end of s-expression");
+        if token != "(" {
+            return match &token[..] {
+                "pi" => ::std::f64::consts::PI,
+                number => number.parse().expect("not a number"),
+            };
+        }
+        let op = tokens.pop().expect("missing operator");
+        let mut args = Vec::new();
+        while tokens.last().map(|t| &t[..]) != Some(")") {
+            args.push(eval_sexpr(tokens));
+        }
+        tokens.pop();
+        match (&op[..], &args[..]) {
+            ("+", &[a, b]) => a + b,
+            ("-", &[a, b]) => a - b,
+            ("*", &[a, b]) => a * b,
+            ("/", &[a, b]) => a / b,
+            ("%", &[a, b]) => a % b,
+            ("**", &[a, b]) => a.powf(b),
+            ("<<", &[a, b]) => CalcNum::shl(a, b).unwrap(),
+            ("&", &[a, b]) => a.bitand(b).unwrap(),
+            ("neg", &[a]) => -a,
+            ("sq", &[a]) => a * a,
+            ("cube", &[a]) => a * a * a,
+            ("log", &[a]) => a.log10(),
+            ("cos", &[a]) => a.cos(),
+            _ => panic!("unknown operation {} {:?}", op, args),
+        }
+    }
+
+    fn eval_prefix(prefix: &str) -> f64 {
+        let spaced = prefix.replace('(', " ( ").replace(')', " ) ");
+        let mut tokens: Vec<String> =
+            spaced.split_whitespace().rev().map(String::from).collect();
+        let result = eval_sexpr(&mut tokens);
+        assert!(tokens.is_empty(), "trailing tokens in {}", prefix);
+        result
+    }
+
+    #[test]
+    fn renderings() {
+        assert_eq!(to_prefix("1 + 2 * 3"), Ok("(+ 1 (* 2 3))".into()));
+        assert_eq!(to_prefix("-(x - 1)"), Ok("(neg (- x 1))".into()));
+        assert_eq!(to_prefix("-2 * x"), Ok("(* -2 x)".into()));
+        assert_eq!(to_prefix("x² + y³"), Ok("(+ (sq x) (cube y))".into()));
+        assert_eq!(to_prefix("x ** 2 ** 4"), Ok("(** x (** 2 4))".into()));
+        assert_eq!(to_prefix("~x & 1"), Ok("(~ (& x 1))".into()));
+        assert_eq!(to_prefix("log (x / 2)"), Ok("(log (/ x 2))".into()));
+
+        let bare = PrefixOptions {
+            parens: false,
+            ..PrefixOptions::default()
+        };
+        let expr = Expr::parse("1 + 2 * 3 ** 2").unwrap();
+        assert_eq!(expr.to_prefix(&bare), "+ 1 * 2 sq 3");
+
+        let mut lisp = PrefixOptions::default();
+        lisp.names.insert("**".into(), "expt".into());
+        lisp.names.insert("neg".into(), "-".into());
+        let expr = Expr::parse("-(2 ** x)").unwrap();
+        assert_eq!(expr.to_prefix(&lisp), "(- (expt 2 x))");
+    }
+
+    #[test]
+    fn evaluates_like_infix() {
+        let corpus = [
+            "1 + 2 * 3",
+            "(1 + 2) * 3",
+            "10 - 4 - 3",
+            "100 / 10 / 5",
+            "2 ** 3 ** 2",
+            "-(3 - 5) * 2",
+            "-2 ** 2",
+            "7 % 4 + 1",
+            "3² - 2³",
+            "(1 + 2)² * 0.5",
+            "1 << 3 & 12",
+            "log (10 ** 3) + cos 0",
+            "pi * 2",
+            "-(1 + 2 * (3 - 4) ** 2) / 8",
+        ];
+        for input in &corpus {
+            let prefix = to_prefix(input).unwrap();
+            let expected = eval(input).unwrap().as_f64();
+            let actual = eval_prefix(&prefix);
+            assert!(
+                (actual - expected).abs() < 1e-9,
+                "{} as {}: {} != {}",
+                input,
+                prefix,
+                actual,
+                expected
+            );
+        }
+    }
+}
@@ -0,0 +1,232 @@
+//! Rendering of expression trees with mathematical typography.
+
+use ast::{self, BinaryOp, Binder, Expr, UnaryOp};
+use std::fmt;
+use value::{IntegralFmt, Value};
+
+/// Controls how `to_pretty_string` renders an expression.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PrettyOptions {
+    /// Write divisions with `÷` instead of `/`.
+    pub division_sign: bool,
+}
+
+/// Renders an expression like its `Display` implementation, but with
+/// typographic symbols: `×` for multiplication, `−` for minus, `²` and `³`
+/// for literal squares and cubes, and `√` for `sqrt`.
+///
+/// The tokenizer accepts all of these symbols, so the output parses back
+/// into the same tree.
+///
+/// ```
+/// # extern crate calc;
+/// # use calc::{to_pretty_string, CalcError, Expr, PrettyOptions};
+/// # fn main() -> Result<(), CalcError> {
+/// let expr = Expr::parse("x ** 2 * (y - 3) / -2")?;
+/// let options = PrettyOptions { division_sign: true };
+/// assert_eq!(to_pretty_string(&expr, &options), "x² × (y − 3) ÷ −2");
+/// # Ok(())
+/// # }
+/// ```
+pub fn to_pretty_string(expr: &Expr, options: &PrettyOptions) -> String {
+    Pretty { expr, options }.to_string()
+}
+
+struct Pretty<'a> {
+    expr: &'a Expr,
+    options: &'a PrettyOptions,
+}
+
+impl<'a> Pretty<'a> {
+    fn operand(&self, expr: &'a Expr, parens: bool) -> String {
+        let operand = Pretty {
+            expr,
+            options: self.options,
+        };
+        if parens {
+            format!("({})", operand)
+        } else {
+            operand.to_string()
+        }
+    }
+
+    fn symbol(&self, op: BinaryOp) -> &'static str {
+        match op {
+            BinaryOp::Sub => "−",
+            BinaryOp::Mul => "×",
+            BinaryOp::Div if self.options.division_sign => "÷",
+            op => op.symbol(),
+        }
+    }
+}
+
+/// Returns the superscript for a literal exponent of 2 or 3.
+fn superscript(exponent: &Expr) -> Option<&'static str> {
+    match *exponent {
+        Expr::Num(Value::Integral(ref n, IntegralFmt::Dec)) => {
+            if *n == 2.into() {
+                Some("²")
+            } else if *n == 3.into() {
+                Some("³")
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+impl<'a> fmt::Display for Pretty<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self.expr {
+            Expr::Num(ref value) => {
+                let text = ast::literal(value);
+                match text.strip_prefix('-') {
+                    Some(abs) => write!(f, "−{}", abs),
+                    None => write!(f, "{}", text),
+                }
+            }
+            Expr::Var(ref name) => write!(f, "{}", name),
+            Expr::Call(ref name, ref args)
+                if name == "sqrt" && args.len() == 1 =>
+            {
+                let arg = &args[0];
+                write!(f, "√{}", self.operand(arg, arg.precedence() < 5))
+            }
+            Expr::Call(ref name, ref args)
+                if name == "cond" || Binder::from_name(name).is_some() =>
+            {
+                write!(f, "{}(", name)?;
+                for (i, arg) in args.iter().enumerate() {
+                    let sep = if i == 0 { "" } else { ", " };
+                    write!(f, "{}{}", sep, self.operand(arg, false))?;
+                }
+                write!(f, ")")
+            }
+            Expr::Call(ref name, ref args) => {
+                write!(f, "{}", name)?;
+                for arg in args {
+                    let arg = self.operand(arg, arg.precedence() < 5);
+                    write!(f, " {}", arg)?;
+                }
+                Ok(())
+            }
+            Expr::Unary(op, ref arg) => {
+                let symbol = match op {
+                    UnaryOp::Neg => "−",
+                    UnaryOp::Not => op.symbol(),
+                };
+                let arg = self.operand(arg, arg.precedence() < 5);
+                write!(f, "{}{}", symbol, arg)
+            }
+            Expr::Binary(op, ref lhs, ref rhs) => {
+                let prec = op.precedence();
+                let right_assoc = op == BinaryOp::Pow;
+                let (lhs_prec, rhs_prec) = (lhs.precedence(), rhs.precedence());
+                let lhs = self.operand(
+                    lhs,
+                    lhs_prec < prec
+                        || (right_assoc && lhs_prec == prec)
+                        || (right_assoc && ast::negative_base(lhs)),
+                );
+                if op == BinaryOp::Pow {
+                    if let Some(superscript) = superscript(rhs) {
+                        return write!(f, "{}{}", lhs, superscript);
+                    }
+                }
+                let rhs = self.operand(
+                    rhs,
+                    rhs_prec < prec || (!right_assoc && rhs_prec == prec),
+                );
+                write!(f, "{} {} {}", lhs, self.symbol(op), rhs)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use context::Context;
+    use token::{tokenize, Token};
+    use {eval, eval_with_env, CalcError};
+
+    #[test]
+    fn snapshots() {
+        let options = PrettyOptions::default();
+        let cases = vec![
+            ("1 + 2 * 3", "1 + 2 × 3"),
+            ("(1 - 2) * 3", "(1 − 2) × 3"),
+            ("8 / 2 / 2", "8 / 2 / 2"),
+            ("x ** 2 + y ** 3", "x² + y³"),
+            ("x ** 4", "x ** 4"),
+            ("x ** 0.5", "x ** 0.5"),
+            ("x ** 0x2", "x ** 0x2"),
+            ("x ** (1 + 1)", "x ** (1 + 1)"),
+            ("(x + 1) ** 2", "(x + 1)²"),
+            ("2 ** 3 ** 2", "2 ** 3²"),
+            ("-x", "−x"),
+            ("-3 ** 2", "−(3²)"),
+            ("(-3) ** 2", "(−3)²"),
+            ("-(x - -1)", "−(x − −1)"),
+            ("~x & 7 % 4", "~(x & 7 % 4)"),
+            ("log (x * 100)", "log (x × 100)"),
+            ("-0x1f * -2", "−0x1F × −2"),
+            ("x - -0x10", "x − −0x10"),
+            ("1. * 0x1", "1. × 0x1"),
+        ];
+        for (input, expected) in cases {
+            let expr = Expr::parse(input).unwrap();
+            let pretty = to_pretty_string(&expr, &options);
+            assert_eq!(pretty, expected);
+            assert_eq!(Expr::parse(&pretty), Ok(expr), "{}", pretty);
+        }
+    }
+
+    #[test]
+    fn division_sign() {
+        let options = PrettyOptions {
+            division_sign: true,
+        };
+        let expr = Expr::parse("(7 - 1) / 2 / 3").unwrap();
+        assert_eq!(to_pretty_string(&expr, &options), "(7 − 1) ÷ 2 ÷ 3");
+    }
+
+    #[test]
+    fn round_trip_values() {
+        let options = PrettyOptions {
+            division_sign: true,
+        };
+        for input in &[
+            "2 * 3 - 10 / 4",
+            "(1 - 2) ** 3",
+            "-2 ** 2 * 5",
+            "7 % 3²",
+            "-0x1f * -2",
+        ] {
+            let pretty =
+                to_pretty_string(&Expr::parse(input).unwrap(), &options);
+            assert_eq!(eval(&pretty), eval(input), "{}", pretty);
+        }
+    }
+
+    #[test]
+    fn square_root() -> Result<(), CalcError> {
+        let options = PrettyOptions::default();
+        let expr = Expr::call("sqrt", vec![Expr::var("x") + Expr::num(1)]);
+        let pretty = to_pretty_string(&expr, &options);
+        assert_eq!(pretty, "√(x + 1)");
+        assert_eq!(tokenize(&pretty)?[0], Token::Atom("sqrt".into()));
+
+        let mut context = Context::new();
+        context.register_fn("sqrt", 1, |args| {
+            Ok(Value::from_f64(args[0].as_f64().sqrt()))
+        });
+        let expr = Expr::call("sqrt", vec![Expr::num(16)]);
+        let pretty = to_pretty_string(&expr, &options);
+        assert_eq!(pretty, "√16");
+        let result = eval_with_env(&pretty, &mut &context);
+        assert_eq!(result, Ok(Value::from_f64(4.0)));
+        Ok(())
+    }
+}
@@ -0,0 +1,215 @@
+//! Python bindings, available with the `python` cargo feature.
+//!
+//! The module exposes `eval`, `eval_to_string` and a `Calculator` class.
+//! Evaluation failures are raised as subclasses of `calc.CalcError`, one per
+//! family of `CalcError` variants.
+
+// Newer compilers flag the impl blocks generated by `#[pymethods]`.
+#![allow(unknown_lints, non_local_definitions)]
+
+use pyo3::prelude::*;
+use pyo3::types::PyModule;
+
+use calculator::Calculator;
+use error::CalcError;
+use format::FormatOptions;
+use value::Value;
+
+mod exceptions {
+    use pyo3::create_exception;
+    use pyo3::exceptions::PyException;
+
+    create_exception!(calc, CalcError, PyException);
+    create_exception!(calc, ParseError, CalcError);
+    create_exception!(calc, MathError, CalcError);
+    create_exception!(calc, TypeMismatchError, CalcError);
+    create_exception!(calc, UnknownAtomError, CalcError);
+    create_exception!(calc, LimitExceededError, CalcError);
+    create_exception!(calc, ReadError, CalcError);
+}
+
+fn to_py_err(err: CalcError) -> PyErr {
+    let message = err.to_string();
+    match err {
+        CalcError::InvalidJson(_)
+        | CalcError::InvalidNumber(_)
+        | CalcError::InvalidOperator(_)
+        | CalcError::LiteralOutOfRange(_)
+        | CalcError::UnrecognizedToken(_)
+        | CalcError::UnexpectedToken(..)
+        | CalcError::UnexpectedEndOfInput
+        | CalcError::UnmatchedParenthesis => {
+            exceptions::ParseError::new_err(message)
+        }
+        CalcError::CannotDifferentiate(_)
+        | CalcError::CircularReference(_)
+        | CalcError::DivideByZero
+        | CalcError::NoConvergence(_)
+        | CalcError::NotAnInteger { .. }
+        | CalcError::Unsolvable(_)
+        | CalcError::WouldOverflow(_)
+        | CalcError::WouldTruncate(_) => {
+            exceptions::MathError::new_err(message)
+        }
+        CalcError::BadTypes(_)
+        | CalcError::InvalidArgument(_)
+        | CalcError::WrongArity { .. } => {
+            exceptions::TypeMismatchError::new_err(message)
+        }
+        CalcError::UnknownAtom(..) => {
+            exceptions::UnknownAtomError::new_err(message)
+        }
+        CalcError::BudgetExceeded
+        | CalcError::InputTooLarge { .. }
+        | CalcError::MagnitudeExceeded { .. }
+        | CalcError::NestingTooDeep => {
+            exceptions::LimitExceededError::new_err(message)
+        }
+        CalcError::Io(_) => exceptions::ReadError::new_err(message),
+    }
+}
+
+/// Evaluates an expression and returns the result as a float.
+#[pyfunction]
+fn eval(input: &str) -> PyResult<f64> {
+    ::eval(input).map(|v| v.as_f64()).map_err(to_py_err)
+}
+
+/// Evaluates an expression and returns its exact textual representation,
+/// optionally rounded to `precision` fractional digits.
+#[pyfunction]
+#[pyo3(signature = (input, precision = None))]
+fn eval_to_string(input: &str, precision: Option<u32>) -> PyResult<String> {
+    let options = FormatOptions {
+        precision,
+        ..FormatOptions::default()
+    };
+    ::eval_to_string(input, &options).map_err(to_py_err)
+}
+
+/// A stateful evaluator that remembers variables between evaluations.
+#[pyclass(name = "Calculator")]
+struct PyCalculator {
+    inner: Calculator,
+}
+
+#[pymethods]
+impl PyCalculator {
+    #[new]
+    fn new() -> Self {
+        PyCalculator {
+            inner: Calculator::new(),
+        }
+    }
+
+    /// Defines a variable. Python integers are stored exactly.
+    fn set_var(&mut self, name: &str, value: &PyAny) -> PyResult<()> {
+        let value = match value.extract::<i64>() {
+            Ok(n) => Value::dec(n),
+            Err(_) => Value::from_f64(value.extract()?),
+        };
+        self.inner.set_var(name, value);
+        Ok(())
+    }
+
+    /// Evaluates an expression that may refer to the defined variables.
+    fn eval(&mut self, input: &str) -> PyResult<f64> {
+        self.inner
+            .eval(input)
+            .map(|v| v.as_f64())
+            .map_err(to_py_err)
+    }
+}
+
+/// Initializes the `calc` Python module.
+#[pymodule]
+pub fn calc(py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(self::eval, m)?)?;
+    m.add_function(wrap_pyfunction!(self::eval_to_string, m)?)?;
+    m.add_class::<PyCalculator>()?;
+    m.add("CalcError", py.get_type::<exceptions::CalcError>())?;
+    m.add("ParseError", py.get_type::<exceptions::ParseError>())?;
+    m.add("MathError", py.get_type::<exceptions::MathError>())?;
+    m.add(
+        "TypeMismatchError",
+        py.get_type::<exceptions::TypeMismatchError>(),
+    )?;
+    m.add(
+        "UnknownAtomError",
+        py.get_type::<exceptions::UnknownAtomError>(),
+    )?;
+    m.add(
+        "LimitExceededError",
+        py.get_type::<exceptions::LimitExceededError>(),
+    )?;
+    m.add("ReadError", py.get_type::<exceptions::ReadError>())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pyo3::types::PyDict;
+
+    fn run(script: &str) {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let module = PyModule::new(py, "calc").unwrap();
+            calc(py, module).unwrap();
+            let locals = PyDict::new(py);
+            locals.set_item("calc", module).unwrap();
+            if let Err(e) = py.run(script, None, Some(locals)) {
+                e.print(py);
+                panic!("python script failed");
+            }
+        });
+    }
+
+    #[test]
+    fn values() {
+        run(r#"
+assert calc.eval("1 + 2 * 3") == 7.0
+assert calc.eval_to_string("0x10 + 1") == "0x11"
+assert calc.eval_to_string("2 / 3", precision=3) == "0.667"
+"#);
+    }
+
+    #[test]
+    fn calculator() {
+        run(r#"
+c = calc.Calculator()
+c.set_var("x", 3)
+c.set_var("y", 0.5)
+assert c.eval("x * 2 + y") == 6.5
+"#);
+    }
+
+    #[test]
+    fn exceptions() {
+        run(r#"
+cases = [
+    ("1 / 0", calc.MathError),
+    ("1 +", calc.ParseError),
+    ("1.5 & 1", calc.TypeMismatchError),
+    ("nope", calc.UnknownAtomError),
+]
+for expr, exc in cases:
+    try:
+        calc.eval(expr)
+    except exc as e:
+        assert isinstance(e, calc.CalcError)
+    else:
+        raise AssertionError(expr)
+"#);
+    }
+
+    #[test]
+    fn read_errors() {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let err = to_py_err(CalcError::Io("disconnected".into()));
+            assert!(err.is_instance_of::<exceptions::ReadError>(py));
+            assert!(err.is_instance_of::<exceptions::CalcError>(py));
+        });
+    }
+}
@@ -0,0 +1,137 @@
+//! Sampling of compiled expressions, e.g. for plotting.
+
+use bytecode::CompiledExpr;
+use error::CalcError;
+
+/// Controls how `sample_with` treats points at which the expression fails.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SampleOptions {
+    /// Abort the whole sweep with the first error, instead of recording a
+    /// NaN as the value of the point.
+    pub fail_on_error: bool,
+}
+
+/// Evaluates `expr` at `n` evenly spaced values of `var` from `from` to
+/// `to`, both included, and returns the `(x, y)` pairs. Points at which the
+/// evaluation fails, such as `1 / x` at zero, get a NaN as their value.
+///
+/// The points run from `from` towards `to`, so they descend if `to` is the
+/// smaller one, and are all the same if both are equal. A single point is
+/// taken at `from`, and none if `n` is zero.
+///
+/// `expr` must be compiled for `var` alone; otherwise this fails with
+/// `CalcError::InvalidArgument`, as would bounds that are not finite.
+///
+/// ```
+/// # extern crate calc;
+/// # use calc::{sample, CalcError, Expression};
+/// # fn main() -> Result<(), CalcError> {
+/// let e: Expression = "x ** 2".parse()?;
+/// let points = sample(&e.compile(&["x"])?, "x", 0.0, 2.0, 3)?;
+/// assert_eq!(points, vec![(0.0, 0.0), (1.0, 1.0), (2.0, 4.0)]);
+/// # Ok(())
+/// # }
+/// ```
+pub fn sample(
+    expr: &CompiledExpr,
+    var: &str,
+    from: f64,
+    to: f64,
+    n: usize,
+) -> Result<Vec<(f64, f64)>, CalcError> {
+    sample_with(expr, var, from, to, n, &SampleOptions::default())
+}
+
+/// Like `sample`, with the given options.
+pub fn sample_with(
+    expr: &CompiledExpr,
+    var: &str,
+    from: f64,
+    to: f64,
+    n: usize,
+    options: &SampleOptions,
+) -> Result<Vec<(f64, f64)>, CalcError> {
+    if expr.vars() != [var] {
+        return Err(CalcError::InvalidArgument(format!(
+            "expected an expression compiled for {}, got one for {:?}",
+            var,
+            expr.vars()
+        )));
+    }
+    if !from.is_finite() || !to.is_finite() {
+        return Err(CalcError::InvalidArgument(format!(
+            "cannot sample from {} to {}",
+            from, to
+        )));
+    }
+    let mut points = Vec::with_capacity(n);
+    for i in 0..n {
+        // The last point is exactly `to`, regardless of rounding.
+        let x = if i + 1 == n && n > 1 {
+            to
+        } else {
+            from + (to - from) * i as f64 / (n - 1).max(1) as f64
+        };
+        let y = match expr.eval(&[x]) {
+            Ok(y) => y,
+            Err(e) if options.fail_on_error => return Err(e),
+            Err(_) => f64::NAN,
+        };
+        points.push((x, y));
+    }
+    Ok(points)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use expression::Expression;
+
+    fn compile(input: &str) -> CompiledExpr {
+        let e: Expression = input.parse().unwrap();
+        e.compile(&["x"]).unwrap()
+    }
+
+    #[test]
+    fn squares() {
+        let points = sample(&compile("x**2"), "x", 0.0, 10.0, 11).unwrap();
+        let expected: Vec<(f64, f64)> =
+            (0..11).map(|i| (i as f64, (i * i) as f64)).collect();
+        assert_eq!(points, expected);
+    }
+
+    #[test]
+    fn ranges() {
+        let e = compile("x + 1");
+        assert_eq!(sample(&e, "x", 0.0, 1.0, 0), Ok(Vec::new()));
+        assert_eq!(sample(&e, "x", 3.0, 5.0, 1), Ok(vec![(3.0, 4.0)]));
+        assert_eq!(
+            sample(&e, "x", 2.0, 2.0, 3),
+            Ok(vec![(2.0, 3.0), (2.0, 3.0), (2.0, 3.0)])
+        );
+        assert_eq!(
+            sample(&e, "x", 1.0, -1.0, 3),
+            Ok(vec![(1.0, 2.0), (0.0, 1.0), (-1.0, 0.0)])
+        );
+        assert!(sample(&e, "x", 0.0, f64::INFINITY, 3).is_err());
+        assert!(sample(&e, "y", 0.0, 1.0, 3).is_err());
+    }
+
+    #[test]
+    fn errors() {
+        let e = compile("1 / x");
+        let points = sample(&e, "x", -1.0, 1.0, 3).unwrap();
+        assert_eq!(points[0], (-1.0, -1.0));
+        assert_eq!(points[1].0, 0.0);
+        assert!(points[1].1.is_nan());
+        assert_eq!(points[2], (1.0, 1.0));
+
+        let options = SampleOptions {
+            fail_on_error: true,
+        };
+        assert_eq!(
+            sample_with(&e, "x", -1.0, 1.0, 3, &options),
+            Err(CalcError::DivideByZero)
+        );
+    }
+}
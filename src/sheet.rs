@@ -0,0 +1,203 @@
+//! Evaluation of named formulas that refer to each other, like the cells of
+//! a spreadsheet.
+
+use ast::Expr;
+use context::Context;
+use error::{closest, CalcError};
+use eval_expr_with;
+use parse::{DefaultEnvironment, Environment};
+use std::collections::HashMap;
+
+/// A parsed cell and the names of the cells it refers to.
+struct Cell {
+    expr: Expr,
+    deps: Vec<String>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mark {
+    Visiting,
+    Done,
+}
+
+/// Evaluates a set of named formulas, such as `{"a": "2 + 3", "b": "a * 2"}`,
+/// that may refer to each other by name.
+///
+/// Cells are evaluated after the cells they refer to, and shadow the
+/// constants of the `DefaultEnvironment`. Referring to a name that is
+/// neither a cell nor a constant fails with `CalcError::UnknownAtom`, and
+/// cells that depend on themselves with `CalcError::CircularReference`.
+pub fn eval_sheet(
+    cells: &HashMap<String, String>,
+) -> Result<HashMap<String, f64>, CalcError> {
+    // Visiting the cells in a fixed order keeps the reported errors stable.
+    let mut names: Vec<&str> = cells.keys().map(|name| &name[..]).collect();
+    names.sort_unstable();
+    let mut parsed = HashMap::with_capacity(cells.len());
+    for &name in &names {
+        let expr = Expr::parse(&cells[name])?;
+        let mut deps: Vec<String> = Vec::new();
+        for node in expr.iter() {
+            if let Expr::Var(ref var) = *node {
+                if cells.contains_key(var) {
+                    if !deps.contains(var) {
+                        deps.push(var.clone());
+                    }
+                } else if DefaultEnvironment.arity(var).is_none() {
+                    let mut names = DefaultEnvironment.names();
+                    names.extend(cells.keys().cloned());
+                    let suggestion = closest(var, &names);
+                    return Err(CalcError::UnknownAtom(
+                        var.clone(),
+                        suggestion,
+                    ));
+                }
+            }
+        }
+        parsed.insert(name, Cell { expr, deps });
+    }
+
+    let mut context = Context::new();
+    let mut results = HashMap::with_capacity(cells.len());
+    for name in dependency_order(&names, &parsed)? {
+        let value = eval_expr_with(&parsed[name].expr, &mut &context)?;
+        results.insert(name.to_owned(), value.as_f64());
+        context.set_var(name, value);
+    }
+    Ok(results)
+}
+
+/// Sorts the cells so that every cell comes after the cells it refers to,
+/// with an iterative depth-first search.
+fn dependency_order<'a>(
+    names: &[&'a str],
+    cells: &'a HashMap<&'a str, Cell>,
+) -> Result<Vec<&'a str>, CalcError> {
+    let mut marks: HashMap<&str, Mark> = HashMap::with_capacity(names.len());
+    let mut order = Vec::with_capacity(names.len());
+    for &root in names {
+        if marks.contains_key(root) {
+            continue;
+        }
+        marks.insert(root, Mark::Visiting);
+        // The cells on the current path, with the index of the next
+        // dependency to visit.
+        let mut path: Vec<(&str, usize)> = vec![(root, 0)];
+        while let Some(&mut (name, ref mut next)) = path.last_mut() {
+            let deps = &cells[name].deps;
+            if *next == deps.len() {
+                marks.insert(name, Mark::Done);
+                order.push(name);
+                path.pop();
+                continue;
+            }
+            let dep = &deps[*next][..];
+            *next += 1;
+            match marks.get(dep) {
+                Some(&Mark::Done) => {}
+                Some(&Mark::Visiting) => {
+                    let start = path
+                        .iter()
+                        .position(|&(name, _)| name == dep)
+                        .expect("visiting cells are on the path");
+                    let mut cycle: Vec<String> = path[start..]
+                        .iter()
+                        .map(|&(name, _)| name.to_owned())
+                        .collect();
+                    cycle.push(dep.to_owned());
+                    return Err(CalcError::CircularReference(cycle));
+                }
+                None => {
+                    marks.insert(dep, Mark::Visiting);
+                    path.push((dep, 0));
+                }
+            }
+        }
+    }
+    Ok(order)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sheet(cells: &[(&str, &str)]) -> HashMap<String, String> {
+        cells
+            .iter()
+            .map(|&(name, formula)| (name.to_owned(), formula.to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn chain() {
+        let cells = sheet(&[("a", "2+3"), ("b", "a*2"), ("c", "a+b")]);
+        let results = eval_sheet(&cells).unwrap();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results["a"], 5.0);
+        assert_eq!(results["b"], 10.0);
+        assert_eq!(results["c"], 15.0);
+    }
+
+    #[test]
+    fn diamond() {
+        let cells = sheet(&[
+            ("top", "left * right"),
+            ("left", "base + 1"),
+            ("right", "base - 1"),
+            ("base", "pi * 0 + 4"),
+        ]);
+        let results = eval_sheet(&cells).unwrap();
+        assert_eq!(results["top"], 15.0);
+        assert_eq!(results["base"], 4.0);
+    }
+
+    #[test]
+    fn shadows_constants() {
+        let cells = sheet(&[("pi", "3"), ("area", "pi * 2 ** 2")]);
+        assert_eq!(eval_sheet(&cells).unwrap()["area"], 12.0);
+    }
+
+    #[test]
+    fn cycle() {
+        let cells = sheet(&[("a", "c + 1"), ("b", "a * 2"), ("c", "b - 1")]);
+        assert_eq!(
+            eval_sheet(&cells),
+            Err(CalcError::CircularReference(vec![
+                "a".into(),
+                "c".into(),
+                "b".into(),
+                "a".into(),
+            ]))
+        );
+    }
+
+    #[test]
+    fn self_reference() {
+        let cells = sheet(&[("x", "1"), ("total", "total + x")]);
+        assert_eq!(
+            eval_sheet(&cells),
+            Err(CalcError::CircularReference(vec![
+                "total".into(),
+                "total".into(),
+            ]))
+        );
+    }
+
+    #[test]
+    fn unknown_reference() {
+        let cells = sheet(&[("a", "1"), ("b", "a + missing")]);
+        assert_eq!(
+            eval_sheet(&cells),
+            Err(CalcError::UnknownAtom("missing".into(), None))
+        );
+    }
+
+    #[test]
+    fn long_chain() {
+        let mut cells = sheet(&[("c0", "1")]);
+        for i in 1..10_000 {
+            cells.insert(format!("c{}", i), format!("c{} + 1", i - 1));
+        }
+        assert_eq!(eval_sheet(&cells).unwrap()["c9999"], 10_000.0);
+    }
+}
@@ -0,0 +1,559 @@
+//! Rewriting of expressions in a consistent style, working on the tokens
+//! of the input so that literals and names stay as they were written.
+
+use ast::BinaryOp;
+use error::CalcError;
+use parse::{Assoc, DefaultEnvironment, Environment};
+use token::{tokenize_lossless, RawToken};
+
+/// How `format_expr` writes squares and cubes.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PowerStyle {
+    /// As in the input.
+    #[default]
+    Preserve,
+    /// With `²` and `³` wherever the exponent is a literal `2` or `3`, as
+    /// in `x²`.
+    Superscript,
+    /// With `**`, as in `x ** 2`.
+    Operator,
+}
+
+/// Controls how `format_expr` rewrites an expression.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FormatStyle {
+    /// Whether to remove the parentheses that don't change how the
+    /// expression groups. On by default.
+    pub remove_parens: bool,
+    pub powers: PowerStyle,
+}
+
+impl Default for FormatStyle {
+    fn default() -> Self {
+        FormatStyle {
+            remove_parens: true,
+            powers: PowerStyle::Preserve,
+        }
+    }
+}
+
+/// A token and its text, which is the input's unless the token was
+/// rewritten.
+type Piece<'a> = (RawToken<'a>, &'a str);
+
+/// Rewrites an expression with a space on either side of binary operators,
+/// `=` and after commas, and nowhere else, and with parentheses and powers
+/// as `style` asks for. Formatting the result again doesn't change it.
+///
+/// Parentheses are only removed where the expression groups the same way,
+/// and evaluates to the same value, without them. This follows the default
+/// precedence of the operators, and the functions of the default
+/// environment. As a `-` that isn't part of a literal negates everything
+/// after it, `-(x + 1) * 2` keeps its parentheses.
+///
+/// The expression grammar has no comments, so only whitespace is lost.
+///
+/// ```
+/// # extern crate calc;
+/// # use calc::{format_expr, CalcError, FormatStyle, PowerStyle};
+/// # fn main() -> Result<(), CalcError> {
+/// let style = FormatStyle::default();
+/// assert_eq!(format_expr("((1+2))*(3 ** x)", &style)?, "(1 + 2) * 3 ** x");
+/// let style = FormatStyle {
+///     powers: PowerStyle::Superscript,
+///     ..FormatStyle::default()
+/// };
+/// assert_eq!(format_expr("max(x**2,-(y))", &style)?, "max(x², -y)");
+/// # Ok(())
+/// # }
+/// ```
+pub fn format_expr(
+    input: &str,
+    style: &FormatStyle,
+) -> Result<String, CalcError> {
+    let mut tokens: Vec<Piece> = tokenize_lossless(input)?
+        .into_iter()
+        .filter_map(|rich| rich.token.map(|token| (token, rich.text)))
+        .collect();
+    // Each rewrite may enable another one, as when `(x ** 2)²` becomes
+    // `(x²)²`, so they are repeated until none applies.
+    loop {
+        let mut changed = style.remove_parens && remove_parens(&mut tokens);
+        changed |= match style.powers {
+            PowerStyle::Preserve => false,
+            PowerStyle::Superscript => to_superscripts(&mut tokens),
+            PowerStyle::Operator => to_operators(&mut tokens),
+        };
+        if !changed {
+            return Ok(join(&tokens));
+        }
+    }
+}
+
+/// Whether an atom takes arguments side by side, so that a `-` after it
+/// negates the first one.
+fn is_function(name: &str) -> bool {
+    name == "in" || DefaultEnvironment.arity(name).is_some_and(|n| n > 0)
+}
+
+/// Whether the operator at `pos` is a prefix one: `-` and `+` are unless
+/// they follow an operand.
+fn is_prefix(tokens: &[Piece], pos: usize) -> bool {
+    match tokens[pos].0 {
+        RawToken::BitWiseNot => true,
+        RawToken::Minus | RawToken::Plus => {
+            match pos.checked_sub(1).map(|prev| tokens[prev].0) {
+                Some(RawToken::Atom(name)) => is_function(name),
+                Some(RawToken::Number(_))
+                | Some(RawToken::Placeholder(_))
+                | Some(RawToken::CloseParen)
+                | Some(RawToken::Square)
+                | Some(RawToken::Cube) => false,
+                _ => true,
+            }
+        }
+        _ => false,
+    }
+}
+
+/// Whether the token at `pos` is a `-` or `+` after an atom that isn't
+/// known, which is a binary operator after a variable, but a prefix one on
+/// the argument of a function.
+fn is_ambiguous(tokens: &[Piece], pos: usize) -> bool {
+    match (tokens[pos].0, pos.checked_sub(1).map(|prev| tokens[prev].0)) {
+        (RawToken::Minus, Some(RawToken::Atom(name)))
+        | (RawToken::Plus, Some(RawToken::Atom(name))) => {
+            DefaultEnvironment.arity(name).is_none() && name != "in"
+        }
+        _ => false,
+    }
+}
+
+/// The builtin binary operator at `pos`, if the token there is one.
+fn binary(tokens: &[Piece], pos: usize) -> Option<BinaryOp> {
+    let op = match tokens[pos].0 {
+        RawToken::Plus if !is_prefix(tokens, pos) => BinaryOp::Add,
+        RawToken::Minus if !is_prefix(tokens, pos) => BinaryOp::Sub,
+        RawToken::Multiply => BinaryOp::Mul,
+        RawToken::Divide => BinaryOp::Div,
+        RawToken::Modulo => BinaryOp::Rem,
+        RawToken::Exponent => BinaryOp::Pow,
+        RawToken::BitWiseAnd => BinaryOp::BitAnd,
+        RawToken::BitWiseOr => BinaryOp::BitOr,
+        RawToken::BitWiseXor => BinaryOp::BitXor,
+        RawToken::BitWiseLShift => BinaryOp::Shl,
+        RawToken::BitWiseRShift => BinaryOp::Shr,
+        _ => return None,
+    };
+    Some(op)
+}
+
+/// The `)` matching the `(` at `open`.
+fn closing(tokens: &[Piece], open: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (pos, token) in tokens.iter().enumerate().skip(open) {
+        match token.0 {
+            RawToken::OpenParen => depth += 1,
+            RawToken::CloseParen if depth == 1 => return Some(pos),
+            RawToken::CloseParen => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// What the operators outside a group of parentheses need to know about
+/// its contents.
+struct Shape {
+    /// The lowest precedence of the binary operators, outside of nested
+    /// parentheses.
+    loosest: Option<u8>,
+    /// Whether a prefix operator reaches up to the closing parenthesis,
+    /// which it would reach past without it.
+    open_ended: bool,
+}
+
+/// The shape of the tokens from `start` to `end`, or `None` if they are
+/// more than an expression, such as the arguments of a call.
+fn shape(tokens: &[Piece], start: usize, end: usize) -> Option<Shape> {
+    let mut shape = Shape {
+        loosest: None,
+        open_ended: false,
+    };
+    let mut depth = 0;
+    for pos in start..end {
+        match tokens[pos].0 {
+            RawToken::OpenParen => depth += 1,
+            RawToken::CloseParen => depth -= 1,
+            _ if depth > 0 => {}
+            RawToken::Comma | RawToken::Assign | RawToken::Operator(_) => {
+                return None
+            }
+            RawToken::Atom("let") | RawToken::Atom("in") => return None,
+            // `-2` is a literal, but `-x` negates everything after it,
+            // whereas a prefix `+` only applies to its operand.
+            RawToken::Minus if is_prefix(tokens, pos) => {
+                if !matches!(tokens[pos + 1].0, RawToken::Number(_)) {
+                    shape.open_ended = true;
+                }
+            }
+            RawToken::BitWiseNot => shape.open_ended = true,
+            _ => {
+                if let Some(op) = binary(tokens, pos) {
+                    let precedence = op.precedence();
+                    shape.loosest = Some(
+                        shape.loosest.map_or(precedence, |p| p.min(precedence)),
+                    );
+                    shape.open_ended |=
+                        op == BinaryOp::Sub && is_ambiguous(tokens, pos);
+                }
+            }
+        }
+    }
+    Some(shape)
+}
+
+/// Whether the parentheses at `open` and `close` can be removed without
+/// changing how the expression groups.
+fn is_redundant(tokens: &[Piece], open: usize, close: usize) -> bool {
+    let shape = match shape(tokens, open + 1, close) {
+        Some(shape) => shape,
+        None => return false,
+    };
+    if open + 1 == close {
+        return false;
+    }
+    // Chains of operators of the same precedence group like their outer
+    // operator, as in `(a - b) + c`, or `a ** (b ** c)`.
+    let binds_tighter = |op: BinaryOp, assoc: Assoc| match shape.loosest {
+        None => true,
+        Some(p) => {
+            p > op.precedence() || p == op.precedence() && op.assoc() == assoc
+        }
+    };
+    let first = tokens[open + 1].0;
+    match open.checked_sub(1) {
+        // Only the loosest rule may start with `~`.
+        None => {}
+        Some(prev) => match tokens[prev].0 {
+            RawToken::OpenParen | RawToken::Comma => {}
+            _ if first == RawToken::BitWiseNot
+                || is_ambiguous(tokens, prev) =>
+            {
+                return false
+            }
+            // `-(2) * 3` negates the product, while `-2 * 3` multiplies
+            // the literal `-2`. A `+` only takes the operand after it, which
+            // may then be followed by a power, as in `+(2²)` and `+2²`.
+            _ if is_prefix(tokens, prev) => {
+                if shape.loosest.is_some()
+                    || shape.open_ended
+                    || matches!(first, RawToken::Number(_))
+                    || tokens[prev].0 == RawToken::Plus && close > open + 2
+                {
+                    return false;
+                }
+            }
+            _ => match binary(tokens, prev) {
+                Some(op) if binds_tighter(op, Assoc::Right) => {}
+                _ => return false,
+            },
+        },
+    }
+    match tokens.get(close + 1).map(|token| token.0) {
+        None | Some(RawToken::CloseParen) | Some(RawToken::Comma) => true,
+        // A power of `(-2)` would negate the power of `2` instead.
+        Some(RawToken::Square) | Some(RawToken::Cube) => {
+            shape.loosest.is_none()
+                && !shape.open_ended
+                && first != RawToken::Minus
+        }
+        Some(_) => match binary(tokens, close + 1) {
+            Some(BinaryOp::Pow) if first == RawToken::Minus => false,
+            Some(op) => !shape.open_ended && binds_tighter(op, Assoc::Left),
+            None => false,
+        },
+    }
+}
+
+fn remove_parens(tokens: &mut Vec<Piece>) -> bool {
+    let mut removed = false;
+    let mut open = 0;
+    while open < tokens.len() {
+        if tokens[open].0 == RawToken::OpenParen {
+            // The parentheses of `log(100)` belong to the call.
+            let call = open > 0
+                && matches!(
+                    tokens[open - 1].0,
+                    RawToken::Atom(_) | RawToken::CloseParen
+                );
+            if let Some(close) = closing(tokens, open) {
+                if !call && is_redundant(tokens, open, close) {
+                    tokens.remove(close);
+                    tokens.remove(open);
+                    removed = true;
+                    continue;
+                }
+            }
+        }
+        open += 1;
+    }
+    removed
+}
+
+/// Whether the token at `pos` continues the power before it, so that the
+/// power can't be written the other way.
+fn continues_power(tokens: &[Piece], pos: usize) -> bool {
+    matches!(
+        tokens.get(pos).map(|token| token.0),
+        Some(RawToken::Exponent)
+            | Some(RawToken::Square)
+            | Some(RawToken::Cube)
+    )
+}
+
+/// Writes `** 2` and `** 3` as `²` and `³`.
+fn to_superscripts(tokens: &mut Vec<Piece>) -> bool {
+    let mut changed = false;
+    for pos in (1..tokens.len()).rev() {
+        let power = match (tokens[pos - 1].0, tokens[pos].0) {
+            (RawToken::Exponent, RawToken::Number("2")) => {
+                (RawToken::Square, "²")
+            }
+            (RawToken::Exponent, RawToken::Number("3")) => {
+                (RawToken::Cube, "³")
+            }
+            _ => continue,
+        };
+        if !continues_power(tokens, pos + 1) {
+            tokens.splice(pos - 1..=pos, Some(power));
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Writes `²` and `³` as `** 2` and `** 3`.
+fn to_operators(tokens: &mut Vec<Piece>) -> bool {
+    let mut changed = false;
+    for pos in (0..tokens.len()).rev() {
+        let exponent = match tokens[pos].0 {
+            RawToken::Square => (RawToken::Number("2"), "2"),
+            RawToken::Cube => (RawToken::Number("3"), "3"),
+            _ => continue,
+        };
+        if !continues_power(tokens, pos + 1) {
+            let power = [(RawToken::Exponent, "**"), exponent];
+            tokens.splice(pos..=pos, power);
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Whether a space goes between the tokens at `pos - 1` and `pos`.
+fn space_before(tokens: &[Piece], pos: usize) -> bool {
+    let spaced = |pos: usize| {
+        tokens[pos].0 == RawToken::Assign || binary(tokens, pos).is_some()
+    };
+    if spaced(pos - 1) || spaced(pos) {
+        return true;
+    }
+    match (tokens[pos - 1], tokens[pos].0) {
+        (_, RawToken::CloseParen)
+        | (_, RawToken::Comma)
+        | (_, RawToken::Square)
+        | (_, RawToken::Cube) => false,
+        ((RawToken::Comma, _), _) => true,
+        ((RawToken::OpenParen, _), _) | ((_, "√"), _) => false,
+        _ if is_prefix(tokens, pos - 1) => false,
+        ((RawToken::Atom(name), _), RawToken::OpenParen) => name == "in",
+        ((RawToken::CloseParen, _), RawToken::OpenParen) => false,
+        _ => true,
+    }
+}
+
+fn join(tokens: &[Piece]) -> String {
+    let mut out = String::new();
+    for (pos, &(_, text)) in tokens.iter().enumerate() {
+        if pos > 0 && space_before(tokens, pos) {
+            out.push(' ');
+        }
+        out.push_str(text);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eval;
+
+    fn format(input: &str) -> String {
+        format_expr(input, &FormatStyle::default()).unwrap()
+    }
+
+    fn format_powers(input: &str, powers: PowerStyle) -> String {
+        let style = FormatStyle {
+            powers,
+            ..FormatStyle::default()
+        };
+        format_expr(input, &style).unwrap()
+    }
+
+    #[test]
+    fn spacing() {
+        let style = FormatStyle {
+            remove_parens: false,
+            ..FormatStyle::default()
+        };
+        let cases = [
+            ("1+2*3", "1 + 2 * 3"),
+            ("  2 *-x ", "2 * -x"),
+            ("( 1 - - 2 )", "(1 - -2)"),
+            ("log  4/log 2", "log 4 / log 2"),
+            ("max( 1 ,2 )", "max(1, 2)"),
+            ("f(x)=x**2", "f(x) = x ** 2"),
+            ("let x=2 in ( x ×x )", "let x = 2 in (x × x)"),
+            ("√ 2 + 3 ²", "√2 + 3²"),
+            ("~ 5&3", "~5 & 3"),
+            ("log -2", "log -2"),
+            ("+ 2*+ x", "+2 * +x"),
+            ("\t1<<2 >>1\n", "1 << 2 >> 1"),
+        ];
+        for &(input, expected) in &cases {
+            assert_eq!(format_expr(input, &style), Ok(expected.into()));
+        }
+        assert!(format_expr("1 + $", &style).is_err());
+    }
+
+    #[test]
+    fn parentheses() {
+        let cases = [
+            ("((1 + 2))", "1 + 2"),
+            ("(1 + 2) * 3", "(1 + 2) * 3"),
+            ("(1 * 2) + 3", "1 * 2 + 3"),
+            ("(8 - 4) - 2", "8 - 4 - 2"),
+            ("8 - (4 - 2)", "8 - (4 - 2)"),
+            ("2 ** (3 ** 2)", "2 ** 3 ** 2"),
+            ("(2 ** 3) ** 2", "(2 ** 3) ** 2"),
+            ("(2)³", "2³"),
+            ("(1 + 2)²", "(1 + 2)²"),
+            ("(-2) ** 2", "(-2) ** 2"),
+            ("(-2)²", "(-2)²"),
+            ("-(2) ** 2", "-(2) ** 2"),
+            ("-(x)", "-x"),
+            ("2 * (-x)", "2 * -x"),
+            ("(-x) + 1", "(-x) + 1"),
+            ("-(x + 1) * 2", "-(x + 1) * 2"),
+            ("(+x) + 1", "+x + 1"),
+            ("+(x + 1) * 2", "+(x + 1) * 2"),
+            ("cos +((x)²)", "cos +(x²)"),
+            ("f + (2 * 3)", "f + (2 * 3)"),
+            ("(~5) & 3", "(~5) & 3"),
+            ("1 + (~5)", "1 + (~5)"),
+            ("log(100) + (log 4)", "log(100) + log 4"),
+            ("max((1), (2 + 3))", "max(1, 2 + 3)"),
+            ("f -(2)", "f - (2)"),
+            ("1 << (2 + 1)", "1 << 2 + 1"),
+            ("let x = (2 + 3) in (x)", "let x = (2 + 3) in (x)"),
+            ("()", "()"),
+        ];
+        for &(input, expected) in &cases {
+            assert_eq!(format(input), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn powers() {
+        let cases = [
+            ("x ** 2 + y ** 3", "x² + y³", "x ** 2 + y ** 3"),
+            ("x² + y³", "x² + y³", "x ** 2 + y ** 3"),
+            ("x ** 2 ** 3", "x ** 2³", "x ** 2 ** 3"),
+            ("x ** 3 ** 2", "x ** 3²", "x ** 3 ** 2"),
+            ("x²²", "x²²", "x² ** 2"),
+            (
+                "x ** 2.0 + x ** 4",
+                "x ** 2.0 + x ** 4",
+                "x ** 2.0 + x ** 4",
+            ),
+            ("(x ** 2)²", "x²²", "(x ** 2) ** 2"),
+        ];
+        for &(input, superscript, operator) in &cases {
+            assert_eq!(
+                format_powers(input, PowerStyle::Superscript),
+                superscript,
+                "{}",
+                input
+            );
+            assert_eq!(
+                format_powers(input, PowerStyle::Operator),
+                operator,
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn same_value() {
+        let corpus = [
+            "1+2*3",
+            "((1 + 2)) * 3",
+            "(1 * 2) + 3",
+            "(8 - 4) - 2",
+            "8 - (4 - 2)",
+            "(0.1 + 0.2) + 0.3",
+            "0.1 + (0.2 + 0.3)",
+            "2 ** (3 ** 2)",
+            "(2 ** 3) ** 2",
+            "(3²) ** 2",
+            "2 ** 3²",
+            "(2)³ - (3)²",
+            "(-2) ** 2",
+            "-(2) ** 2",
+            "-(2)²",
+            "-(-2) ** 2",
+            "2 * (-3) + 1",
+            "2 * -(3) + 1",
+            "-(1 + 2) * 3",
+            "(-(1 + 2)) * 3",
+            "+(1 + 2) * 3",
+            "(+2)² - +(3) * 2",
+            "(7 % 4) * 2",
+            "7 % (4 * 2)",
+            "1 << (2 + 1)",
+            "(1 << 2) + 1",
+            "(~5) & 3",
+            "~(5 & 3)",
+            "log (10 ** 3) + cos 0",
+            "(log 4) / (log 2)",
+            "(log 4) ** 2",
+            "cos -(2) ** 2 + 100",
+            "approx((0.75), (2 + 2)) ** (2)",
+            "sum(i, 1, 4, (i ** 2))",
+            "let x = (2 + 3) in (x * (x))",
+            "(2 ** 0.5) ** (2)",
+        ];
+        let styles = [
+            FormatStyle::default(),
+            FormatStyle {
+                powers: PowerStyle::Superscript,
+                ..FormatStyle::default()
+            },
+            FormatStyle {
+                powers: PowerStyle::Operator,
+                ..FormatStyle::default()
+            },
+        ];
+        for input in &corpus {
+            let expected = eval(input);
+            assert!(expected.is_ok(), "{}", input);
+            for style in &styles {
+                let once = format_expr(input, style).unwrap();
+                assert_eq!(eval(&once), expected, "{} as {}", input, once);
+                assert_eq!(format_expr(&once, style), Ok(once.clone()));
+            }
+        }
+    }
+}
@@ -0,0 +1,575 @@
+//! Symbolic manipulation of expression trees: simplification and
+//! differentiation.
+
+use ast::{BinaryOp, Expr, UnaryOp};
+use calculator::Calculator;
+use decimal::d128;
+use error::{CalcError, Unsolvable};
+use parse::{DefaultEnvironment, Environment};
+use value::Value;
+use {eval_expr, eval_expr_with};
+
+impl Expr {
+    /// Returns whether the variable `name` occurs in the expression.
+    pub fn contains_var(&self, name: &str) -> bool {
+        self.iter()
+            .any(|node| matches!(*node, Expr::Var(ref var) if var == name))
+    }
+
+    /// Rewrites the expression with algebraic identities such as `x + 0 =
+    /// x`, `x * 1 = x` and `--x = x`, and folds operations on literals as
+    /// long as the result is exact.
+    pub fn simplify(&self) -> Expr {
+        self.clone().map(simplify_node)
+    }
+
+    /// Rewrites the expression into a canonical form, in which expressions
+    /// that only differ by the order of commutative operands, by constant
+    /// folding or by distributing products over sums are identical.
+    ///
+    /// Subtraction becomes addition of the operand multiplied by `-1`, and
+    /// the operands of `+`, `*`, `&`, `|` and `^` are sorted, with literal
+    /// coefficients in front of products and constant terms at the end of
+    /// sums.
+    pub fn canonicalize(&self) -> Expr {
+        self.clone().map(canonical_node)
+    }
+
+    /// Differentiates the expression with respect to `var` and simplifies
+    /// the result.
+    ///
+    /// Supports arithmetic, powers and the functions `sin`, `cos`, `tan`,
+    /// `exp`, `ln` and `log`. Anything else that depends on `var`, such as
+    /// the bitwise operators, fails with `CalcError::CannotDifferentiate`.
+    pub fn derivative(&self, var: &str) -> Result<Expr, CalcError> {
+        derive(self, var).map(|d| d.simplify())
+    }
+}
+
+fn is_value(expr: &Expr, n: i32) -> bool {
+    match *expr {
+        Expr::Num(Value::Integral(ref m, _)) => *m == n.into(),
+        Expr::Num(Value::Float(m)) => m == d128::from(n),
+        _ => false,
+    }
+}
+
+fn simplify_node(node: Expr) -> Expr {
+    match node {
+        Expr::Unary(UnaryOp::Neg, arg) => match *arg {
+            Expr::Unary(UnaryOp::Neg, inner) => *inner,
+            arg => -arg,
+        },
+        Expr::Binary(op, lhs, rhs) => simplify_binary(op, *lhs, *rhs),
+        node => node,
+    }
+}
+
+fn simplify_binary(op: BinaryOp, lhs: Expr, rhs: Expr) -> Expr {
+    if let (Expr::Num(n), Expr::Num(m)) = (&lhs, &rhs) {
+        // Integers are only folded into integers, so that `1 / 3` is not
+        // turned into a rounded decimal.
+        let floats =
+            !matches!((n, m), (&Value::Integral(..), &Value::Integral(..)));
+        match op.apply(n.clone(), m.clone()) {
+            Ok(value @ Value::Integral(..)) => return Expr::Num(value),
+            Ok(value) if floats => return Expr::Num(value),
+            _ => {}
+        }
+    }
+    match op {
+        BinaryOp::Add if is_value(&lhs, 0) => rhs,
+        BinaryOp::Add | BinaryOp::Sub if is_value(&rhs, 0) => lhs,
+        BinaryOp::Sub if is_value(&lhs, 0) => -rhs,
+        BinaryOp::Mul if is_value(&lhs, 0) || is_value(&rhs, 0) => Expr::num(0),
+        BinaryOp::Mul if is_value(&lhs, 1) => rhs,
+        BinaryOp::Mul | BinaryOp::Div if is_value(&rhs, 1) => lhs,
+        BinaryOp::Div if is_value(&lhs, 0) => Expr::num(0),
+        BinaryOp::Pow if is_value(&rhs, 0) || is_value(&lhs, 1) => Expr::num(1),
+        BinaryOp::Pow if is_value(&rhs, 1) => lhs,
+        BinaryOp::Add => match rhs {
+            Expr::Unary(UnaryOp::Neg, rhs) => lhs - *rhs,
+            rhs => lhs + rhs,
+        },
+        BinaryOp::Sub => match rhs {
+            Expr::Unary(UnaryOp::Neg, rhs) => lhs + *rhs,
+            rhs => lhs - rhs,
+        },
+        BinaryOp::Mul => simplify_product(lhs, rhs),
+        op => Expr::binary(op, lhs, rhs),
+    }
+}
+
+/// Moves literal factors to the front of a product and merges them, so that
+/// `x * 2 * 3` becomes `6 * x`.
+fn simplify_product(lhs: Expr, rhs: Expr) -> Expr {
+    let (lhs, rhs) = match (lhs, rhs) {
+        (lhs, rhs @ Expr::Num(_)) => (rhs, lhs),
+        pair => pair,
+    };
+    match (lhs, rhs) {
+        (Expr::Num(n), Expr::Binary(BinaryOp::Mul, inner, rest)) => {
+            match *inner {
+                Expr::Num(m) => {
+                    let factor = simplify_binary(
+                        BinaryOp::Mul,
+                        Expr::Num(n),
+                        Expr::Num(m),
+                    );
+                    simplify_binary(BinaryOp::Mul, factor, *rest)
+                }
+                inner => Expr::Num(n) * (inner * *rest),
+            }
+        }
+        (lhs, rhs) => lhs * rhs,
+    }
+}
+
+fn is_commutative(op: BinaryOp) -> bool {
+    matches!(
+        op,
+        BinaryOp::Add
+            | BinaryOp::Mul
+            | BinaryOp::BitAnd
+            | BinaryOp::BitOr
+            | BinaryOp::BitXor
+    )
+}
+
+/// Collects the operands of a chain of `op`, e.g. `a`, `b` and `c` of
+/// `a + b + c`.
+fn operands(op: BinaryOp, expr: Expr, out: &mut Vec<Expr>) {
+    match expr {
+        Expr::Binary(inner, lhs, rhs) if inner == op => {
+            operands(op, *lhs, out);
+            operands(op, *rhs, out);
+        }
+        expr => out.push(expr),
+    }
+}
+
+fn canonical_node(node: Expr) -> Expr {
+    match simplify_node(node) {
+        Expr::Unary(UnaryOp::Neg, arg) => canonical_node(Expr::num(-1) * *arg),
+        Expr::Binary(BinaryOp::Sub, lhs, rhs) => {
+            let rhs = canonical_node(-*rhs);
+            canonical_node(*lhs + rhs)
+        }
+        Expr::Binary(BinaryOp::Mul, lhs, rhs) => {
+            let (mut terms, mut factors) = (Vec::new(), Vec::new());
+            operands(BinaryOp::Add, *lhs, &mut terms);
+            operands(BinaryOp::Add, *rhs, &mut factors);
+            if terms.len() == 1 && factors.len() == 1 {
+                return sort_operands(
+                    BinaryOp::Mul,
+                    terms.remove(0),
+                    factors.remove(0),
+                );
+            }
+            let mut products = Vec::new();
+            for term in &terms {
+                for factor in &factors {
+                    products
+                        .push(canonical_node(term.clone() * factor.clone()));
+                }
+            }
+            let mut products = products.into_iter();
+            let first = products.next().expect("sums have operands");
+            canonical_node(products.fold(first, |sum, p| sum + p))
+        }
+        Expr::Binary(op, lhs, rhs) if is_commutative(op) => {
+            sort_operands(op, *lhs, *rhs)
+        }
+        node => node,
+    }
+}
+
+/// Flattens a chain of a commutative operator, merges its literals and
+/// sorts the remaining operands.
+fn sort_operands(op: BinaryOp, lhs: Expr, rhs: Expr) -> Expr {
+    let mut all = Vec::new();
+    operands(op, lhs, &mut all);
+    operands(op, rhs, &mut all);
+    let (literals, mut rest): (Vec<Expr>, Vec<Expr>) =
+        all.into_iter().partition(Expr::is_num);
+    rest.sort_by_key(|e| e.to_string());
+    let mut literals = literals.into_iter();
+    if let Some(first) = literals.next() {
+        let literal =
+            literals.fold(first, |acc, n| simplify_binary(op, acc, n));
+        match op {
+            BinaryOp::Mul if is_value(&literal, 0) => return literal,
+            BinaryOp::Mul if is_value(&literal, 1) && !rest.is_empty() => {}
+            BinaryOp::Add if is_value(&literal, 0) && !rest.is_empty() => {}
+            BinaryOp::Mul => rest.insert(0, literal),
+            _ => rest.push(literal),
+        }
+    }
+    let mut rest = rest.into_iter();
+    let first = rest.next().expect("chains have operands");
+    rest.fold(first, |acc, e| Expr::binary(op, acc, e))
+}
+
+fn cannot_differentiate(expr: &Expr) -> CalcError {
+    CalcError::CannotDifferentiate(expr.to_string())
+}
+
+fn call(name: &str, arg: &Expr) -> Expr {
+    Expr::call(name, vec![arg.clone()])
+}
+
+fn derive(expr: &Expr, var: &str) -> Result<Expr, CalcError> {
+    if !expr.contains_var(var) {
+        return Ok(Expr::num(0));
+    }
+    let derivative = match *expr {
+        // Only `var` itself reaches this point.
+        Expr::Num(_) | Expr::Var(_) => Expr::num(1),
+        Expr::Unary(UnaryOp::Neg, ref arg) => -derive(arg, var)?,
+        Expr::Binary(op, ref f, ref g) => {
+            let (f, g) = (&**f, &**g);
+            match op {
+                BinaryOp::Add => derive(f, var)? + derive(g, var)?,
+                BinaryOp::Sub => derive(f, var)? - derive(g, var)?,
+                BinaryOp::Mul => {
+                    derive(f, var)? * g.clone() + f.clone() * derive(g, var)?
+                }
+                BinaryOp::Div => {
+                    let numer = derive(f, var)? * g.clone()
+                        - f.clone() * derive(g, var)?;
+                    numer / g.clone().pow(Expr::num(2))
+                }
+                BinaryOp::Pow if !g.contains_var(var) => {
+                    let power = f.clone().pow(g.clone() - Expr::num(1));
+                    g.clone() * power * derive(f, var)?
+                }
+                // (f ** g)' = f ** g * (g' * ln f + g * f' / f)
+                BinaryOp::Pow => {
+                    let inner = derive(g, var)? * call("ln", f)
+                        + g.clone() * derive(f, var)? / f.clone();
+                    expr.clone() * inner
+                }
+                _ => return Err(cannot_differentiate(expr)),
+            }
+        }
+        Expr::Call(ref name, ref args) if args.len() == 1 => {
+            let u = &args[0];
+            let outer = match name.as_str() {
+                "sin" => call("cos", u),
+                "cos" => -call("sin", u),
+                "tan" => Expr::num(1) / call("cos", u).pow(Expr::num(2)),
+                "exp" => call("exp", u),
+                "ln" => Expr::num(1) / u.clone(),
+                "log" => {
+                    Expr::num(1) / (u.clone() * call("ln", &Expr::num(10)))
+                }
+                _ => return Err(cannot_differentiate(expr)),
+            };
+            outer * derive(u, var)?
+        }
+        _ => return Err(cannot_differentiate(expr)),
+    };
+    Ok(derivative)
+}
+
+/// Solves `lhs = rhs` for `var`, which must occur linearly.
+///
+/// The slope is the derivative of `lhs - rhs`, which must evaluate to the
+/// same value at several points; this also accepts derivatives that the
+/// simplifier leaves in a form like `x + 1 - x`.
+pub fn solve_linear(
+    lhs: &Expr,
+    rhs: &Expr,
+    var: &str,
+) -> Result<f64, CalcError> {
+    let f = lhs.clone() - rhs.clone();
+    let at = |e: &Expr, x: Value| eval_expr(&e.substitute(var, &Expr::Num(x)));
+    if !f.contains_var(var) {
+        return match eval_expr(&f) {
+            Ok(ref diff) if diff.is_zero() => {
+                Err(CalcError::Unsolvable(Unsolvable::Identity))
+            }
+            Ok(_) => Err(CalcError::Unsolvable(Unsolvable::Contradiction)),
+            // Other unknowns keep the equation from being decided.
+            Err(CalcError::UnknownAtom(..)) => {
+                Err(CalcError::Unsolvable(Unsolvable::VariableAbsent))
+            }
+            Err(err) => Err(err),
+        };
+    }
+    let nonlinear = || CalcError::Unsolvable(Unsolvable::Nonlinear);
+    let d = f.derivative(var).map_err(|_| nonlinear())?;
+    let slope = if d.contains_var(var) {
+        let points = vec![
+            Value::dec(-1),
+            Value::dec(1),
+            Value::from(2.5),
+            Value::dec(7),
+        ];
+        let mut slopes = Vec::new();
+        for x in points {
+            slopes.push(at(&d, x).map_err(|_| nonlinear())?.as_f64());
+        }
+        let first = slopes[0];
+        let tolerance = 1e-9 * first.abs().max(1.0);
+        if slopes.iter().any(|s| (s - first).abs() > tolerance) {
+            return Err(nonlinear());
+        }
+        first
+    } else {
+        eval_expr(&d)?.as_f64()
+    };
+    let intercept = at(&f, Value::dec(0))?.as_f64();
+    if slope == 0.0 {
+        return if intercept == 0.0 {
+            Err(CalcError::Unsolvable(Unsolvable::Identity))
+        } else {
+            Err(CalcError::Unsolvable(Unsolvable::Contradiction))
+        };
+    }
+    Ok(-intercept / slope)
+}
+
+/// How closely two expressions agree, as determined by `equivalent`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Equivalence {
+    /// Both expressions have the same canonical form.
+    ProvablyEqual,
+    /// The canonical forms differ, but both expressions evaluate to the
+    /// same values at a number of random points.
+    ProbablyEqual,
+    NotEqual,
+}
+
+/// Compares two expressions, first by their canonical form and then by
+/// evaluating them at random points. Variables are matched by name, and
+/// atoms of the default environment such as `pi` keep their meaning.
+pub fn compare(a: &Expr, b: &Expr) -> Equivalence {
+    if a.canonicalize() == b.canonicalize() {
+        return Equivalence::ProvablyEqual;
+    }
+    let mut vars: Vec<&str> = a
+        .iter()
+        .chain(b.iter())
+        .filter_map(|node| match *node {
+            Expr::Var(ref name) if DefaultEnvironment.arity(name).is_none() => {
+                Some(name.as_str())
+            }
+            _ => None,
+        })
+        .collect();
+    vars.sort();
+    vars.dedup();
+
+    // A fixed xorshift generator keeps the outcome reproducible.
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let mut random = || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state >> 11) as f64 / (1u64 << 53) as f64
+    };
+    let mut agreed = 0;
+    for _ in 0..16 {
+        let mut env = Calculator::new();
+        for var in &vars {
+            env.set_var(*var, Value::from(random() * 6.0 - 3.0));
+        }
+        match (eval_expr_with(a, &mut env), eval_expr_with(b, &mut env)) {
+            (Ok(x), Ok(y)) => {
+                let (x, y) = (x.as_f64(), y.as_f64());
+                let tolerance = 1e-9 * x.abs().max(y.abs()).max(1.0);
+                if (x - y).abs() <= tolerance || (x.is_nan() && y.is_nan()) {
+                    agreed += 1;
+                } else {
+                    return Equivalence::NotEqual;
+                }
+            }
+            (Err(_), Err(_)) => {}
+            _ => return Equivalence::NotEqual,
+        }
+    }
+    if agreed > 0 {
+        Equivalence::ProbablyEqual
+    } else {
+        Equivalence::NotEqual
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ast::evaluate;
+    use calculator::Calculator;
+    use {diff, equivalent, solve};
+
+    #[test]
+    fn simplification() {
+        let cases = vec![
+            ("(1 - 1) + x * 1", "x"),
+            ("x - (1 - 1) + (2 - 2) * y", "x"),
+            ("x * 2 * 3", "6 * x"),
+            ("2 * (3 * x) ** 1", "6 * x"),
+            ("x ** (1 - 1) + 1 ** y", "2"),
+            ("x - (-(y))", "x + y"),
+            ("(4 - 1) * x / (3 - 2)", "3 * x"),
+            ("1 / 3 * x", "1 / 3 * x"),
+            ("0.5 * 4 * x", "2.0 * x"),
+        ];
+        for (input, expected) in cases {
+            let e = Expr::parse(input).unwrap().simplify();
+            assert_eq!(e, Expr::parse(expected).unwrap(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn readable_derivatives() {
+        let cases = vec![
+            ("3*x**2 + sin(x)", "6 * x + cos x"),
+            ("5 * x - y", "5"),
+            ("x ** 3", "3 * x ** 2"),
+            ("y ** 2", "0"),
+            ("exp (2 * x)", "2 * exp (2 * x)"),
+        ];
+        for (input, expected) in cases {
+            let d = diff(input, "x").unwrap();
+            assert_eq!(d.to_string(), expected, "{}", input);
+        }
+    }
+
+    fn at(expr: &Expr, x: f64) -> f64 {
+        let mut env = Calculator::new();
+        env.set_var("x", Value::from(x));
+        evaluate(expr, &mut env).unwrap()
+    }
+
+    #[test]
+    fn matches_central_difference() {
+        let inputs = vec![
+            "3 * x ** 2 + sin x",
+            "x ** 3 / (x + 1)",
+            "exp (2 * x) * cos x",
+            "ln (x ** 2 + 1) - tan x",
+            "2 ** x + x ** x",
+            "log (x * 7) - x",
+            "-(x * x) + 4 * x",
+        ];
+        let h = 1e-5;
+        for input in inputs {
+            let f = Expr::parse(input).unwrap();
+            let d = f.derivative("x").unwrap();
+            for &x in &[0.5, 1.25, 2.0] {
+                let expected = (at(&f, x + h) - at(&f, x - h)) / (2.0 * h);
+                let actual = at(&d, x);
+                let tolerance = 1e-5 * actual.abs().max(1.0);
+                assert!(
+                    (expected - actual).abs() < tolerance,
+                    "{} at {}: {} != {}",
+                    input,
+                    x,
+                    expected,
+                    actual
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn unsupported() {
+        for input in &["x & 1", "x % 2", "~x", "pi ** (x << 1)"] {
+            match diff(input, "x") {
+                Err(CalcError::CannotDifferentiate(_)) => {}
+                other => panic!("{}: {:?}", input, other),
+            }
+        }
+        assert_eq!(diff("y & 1", "x"), Ok(Expr::num(0)));
+    }
+
+    #[test]
+    fn linear_equations() {
+        let cases = vec![
+            ("2*x + 6 = 20", 7.0),
+            ("3 * x - 4 = x + 8", 6.0),
+            ("-2 * x = 10", -5.0),
+            ("5 - x = 2 * x - 4", 3.0),
+            ("x / 4 + 1 = 3", 8.0),
+            ("7 = 2 * (x - 1) + 1", 4.0),
+            ("(x - 1) * 3 = (x + 1) * 2", 5.0),
+            ("x * x - x * x + x = 3", 3.0),
+            ("log 100 * x = pi - pi", 0.0),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(solve(input, "x"), Ok(expected), "{}", input);
+        }
+    }
+
+    #[test]
+    fn unsolvable_equations() {
+        let cases = vec![
+            ("x * x = 4", Unsolvable::Nonlinear),
+            ("2 ** x = 8", Unsolvable::Nonlinear),
+            ("1 / x = 2", Unsolvable::Nonlinear),
+            ("x & 3 = 1", Unsolvable::Nonlinear),
+            ("y + 1 = 3", Unsolvable::VariableAbsent),
+            ("x = x", Unsolvable::Identity),
+            ("2 * (x + 1) = 2 * x + 2", Unsolvable::Identity),
+            ("0 = 1", Unsolvable::Contradiction),
+            ("2 * x + 1 = 2 * x + 3", Unsolvable::Contradiction),
+            ("x + 1", Unsolvable::NotAnEquation),
+            ("x = 1 = 2", Unsolvable::NotAnEquation),
+        ];
+        for (input, reason) in cases {
+            assert_eq!(
+                solve(input, "x"),
+                Err(CalcError::Unsolvable(reason)),
+                "{}",
+                input
+            );
+        }
+        assert_eq!(
+            solve("x + y = 1", "x"),
+            Err(CalcError::UnknownAtom("y".into(), None))
+        );
+        // Without the variable, errors of evaluation are passed on.
+        assert_eq!(solve("1 / 0 = 1", "x"), Err(CalcError::DivideByZero));
+    }
+
+    #[test]
+    fn canonical_forms() {
+        let cases = vec![
+            ("x * (y + 1)", "x * y + x"),
+            ("a + b + c", "c + (b + a)"),
+            ("x - y", "-(y - x)"),
+            ("2 * x * 3 + 1 - 1", "6 * x"),
+            ("(x & y) ^ z", "z ^ (y & x)"),
+            ("(a + 1) * (b - 2)", "a * b - 2 * a + b - 2"),
+        ];
+        for (a, b) in cases {
+            let (a, b) = (Expr::parse(a).unwrap(), Expr::parse(b).unwrap());
+            assert_eq!(a.canonicalize(), b.canonicalize());
+        }
+    }
+
+    #[test]
+    fn equivalence() {
+        let cases = vec![
+            ("x*(y+1)", "x*y + x", Equivalence::ProvablyEqual),
+            ("x + 2 * (1 - 1) * y", "x", Equivalence::ProvablyEqual),
+            (
+                "(x + 1) ** 2",
+                "x ** 2 + 2 * x + 1",
+                Equivalence::ProbablyEqual,
+            ),
+            ("sin x ** 2 + cos x ** 2", "1", Equivalence::ProbablyEqual),
+            ("x + x", "2 * x", Equivalence::ProbablyEqual),
+            ("x * x", "2 * x", Equivalence::NotEqual),
+            ("x * y", "x + y", Equivalence::NotEqual),
+            ("x", "y", Equivalence::NotEqual),
+            ("pi * x", "3 * x", Equivalence::NotEqual),
+        ];
+        for (a, b, expected) in cases {
+            assert_eq!(equivalent(a, b), Ok(expected), "{} vs {}", a, b);
+        }
+        assert!(equivalent("x +", "x").is_err());
+    }
+}
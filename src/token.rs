@@ -2,8 +2,11 @@ use decimal::d128;
 use error::CalcError;
 use error::CalcError::*;
 use num::Num;
+use parse::Operator;
+use std::borrow::Cow;
 use std::fmt;
 use std::iter::Peekable;
+use std::ops::Range;
 use value::{Integral, Value};
 
 /// Tokens used for parsing an arithmetic expression
@@ -25,8 +28,15 @@ pub enum Token {
     Modulo,
     OpenParen,
     CloseParen,
+    Comma,
+    Assign,
     Number(Value),
     Atom(String),
+    /// A binary operator registered with `Context::register_operator`.
+    Operator(String),
+    /// A positional placeholder, `?` or `?N`, for the argument of the given
+    /// index, counting from 1.
+    Placeholder(usize),
 }
 
 impl fmt::Display for Token {
@@ -48,8 +58,12 @@ impl fmt::Display for Token {
             Token::Modulo => write!(f, "Modulo"),
             Token::OpenParen => write!(f, "OpenParen"),
             Token::CloseParen => write!(f, "CloseParen"),
+            Token::Comma => write!(f, "Comma"),
+            Token::Assign => write!(f, "Assign"),
             Token::Number(ref n) => write!(f, "'{}'", n),
             Token::Atom(ref s) => write!(f, "'{}'", s),
+            Token::Operator(ref s) => write!(f, "'{}'", s),
+            Token::Placeholder(index) => write!(f, "'?{}'", index),
         }
     }
 }
@@ -68,7 +82,7 @@ impl IsOperator for char {
     fn is_operator(self) -> bool {
         match self {
             '+' | '-' | '/' | '^' | '²' | '³' | '&' | '|' | '~' | '>'
-            | '%' | '(' | ')' | '*' | '<' => true,
+            | '%' | '(' | ')' | '*' | '<' | '×' | '÷' | '−' | ',' | '=' => true,
             _ => false,
         }
     }
@@ -82,7 +96,7 @@ impl CheckOperator for char {
     fn check_operator(self) -> OperatorState {
         match self {
             '+' | '-' | '/' | '^' | '²' | '³' | '&' | '|' | '~' | '%'
-            | '(' | ')' => OperatorState::Complete,
+            | '(' | ')' | '×' | '÷' | '−' | ',' | '=' => OperatorState::Complete,
             '*' | '<' | '>' => OperatorState::PotentiallyIncomplete,
             _ => OperatorState::NotAnOperator,
         }
@@ -111,9 +125,9 @@ impl OperatorMatch for char {
     fn operator_type(self) -> Option<Token> {
         match self {
             '+' => Some(Token::Plus),
-            '-' => Some(Token::Minus),
-            '/' => Some(Token::Divide),
-            '*' => Some(Token::Multiply),
+            '-' | '−' => Some(Token::Minus),
+            '/' | '÷' => Some(Token::Divide),
+            '*' | '×' => Some(Token::Multiply),
             '^' => Some(Token::BitWiseXor),
             '²' => Some(Token::Square),
             '³' => Some(Token::Cube),
@@ -123,11 +137,35 @@ impl OperatorMatch for char {
             '%' => Some(Token::Modulo),
             '(' => Some(Token::OpenParen),
             ')' => Some(Token::CloseParen),
+            ',' => Some(Token::Comma),
+            '=' => Some(Token::Assign),
             _ => None,
         }
     }
 }
 
+/// Limits applied while tokenizing untrusted input, and the separators
+/// numbers are written with. All limits are disabled by default, and
+/// numbers have a decimal point.
+#[derive(Clone, Debug, Default, Hash, PartialEq)]
+pub struct TokenizerOptions {
+    /// The maximum length of the input in bytes.
+    pub max_input_len: Option<usize>,
+    /// The maximum number of tokens the input may produce.
+    pub max_tokens: Option<usize>,
+    /// Read `,` as the decimal separator, as in `3,14 * 2`. A comma between
+    /// two digits is then part of a number, so the arguments of a call
+    /// should be separated by a comma and a space, as in `max(1,5, 2)`:
+    /// `cond(1,2,3)` is `cond(1.2, 3)`, which lacks an argument. A comma
+    /// that is not followed by a digit, as in `1,`, separates as usual. A `.`
+    /// is still read as a decimal point, unless `thousands_point` is set.
+    pub decimal_comma: bool,
+    /// Together with `decimal_comma`, read `.` as a thousands separator, as
+    /// in `1.000.000,5`. The first group has one to three digits and the
+    /// others exactly three, so `1.5` is rejected rather than read as `15`.
+    pub thousands_point: bool,
+}
+
 /// Tokenizes a mathematical expression written written with the standard infix
 /// notation.
 ///
@@ -135,53 +173,788 @@ impl OperatorMatch for char {
 /// expression is valid. This
 /// vector can then be pased into the `parse` function to be evaluated.
 pub fn tokenize(input: &str) -> Result<Vec<Token>, CalcError> {
-    let mut tokens = Vec::with_capacity(input.len());
-    let mut chars = input.chars().peekable();
+    tokenize_with_options(input, &TokenizerOptions::default())
+}
 
-    while let Some(&c) = chars.peek() {
-        if c.is_alphabetic() {
-            tokens.push(Token::Atom(consume_atom(&mut chars)));
-        } else if c.is_digit(16) || c == '.' {
-            tokens.push(Token::Number(consume_number(&mut chars)?));
+/// Tokenizes an infix expression like `tokenize`, rejecting input that
+/// exceeds the given limits with `CalcError::InputTooLarge`.
+pub fn tokenize_with_options(
+    input: &str,
+    options: &TokenizerOptions,
+) -> Result<Vec<Token>, CalcError> {
+    check_input_len(input, options)?;
+    // Reserving space for the worst case up front is only done when the
+    // caller doesn't care about bounding allocations.
+    let mut tokens = if *options == TokenizerOptions::default() {
+        Vec::with_capacity(input.len())
+    } else {
+        Vec::new()
+    };
+    for token in Tokenizer::new(input).with_options(options) {
+        tokens.push(match token? {
+            RawToken::Number(text) => {
+                Token::Number(parse_with_options(text, options)?)
+            }
+            token => token.to_token()?,
+        });
+        check_token_count(tokens.len(), options)?;
+    }
+    debug!("tokenized {:?} into {:?}", input, tokens);
+    Ok(tokens)
+}
+
+fn check_input_len(
+    input: &str,
+    options: &TokenizerOptions,
+) -> Result<(), CalcError> {
+    match options.max_input_len {
+        Some(limit) if input.len() > limit => Err(CalcError::InputTooLarge {
+            limit,
+            actual: input.len(),
+        }),
+        _ => Ok(()),
+    }
+}
+
+fn check_token_count(
+    count: usize,
+    options: &TokenizerOptions,
+) -> Result<(), CalcError> {
+    match options.max_tokens {
+        Some(limit) if count > limit => Err(CalcError::InputTooLarge {
+            limit,
+            actual: count,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// A token of `tokenize_lossless`, with the text it was read from and the
+/// whitespace before it.
+///
+/// The expression grammar has no comments, so whitespace is the only
+/// trivia.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RichToken<'a> {
+    /// The token, or `None` for the end of the input, which only holds the
+    /// trivia after the last token.
+    pub token: Option<RawToken<'a>>,
+    /// The byte range of the input `text` was read from.
+    pub span: Range<usize>,
+    /// The text of the token as written, e.g. `−` rather than `-`.
+    pub text: &'a str,
+    /// The whitespace between the previous token and this one.
+    pub leading: &'a str,
+}
+
+/// Tokenizes an infix expression like `tokenize`, keeping everything
+/// needed to restore the input, e.g. for a formatter. The last token is
+/// always the end of the input, so that `reconstruct` returns the input
+/// as it was.
+///
+/// ```
+/// # extern crate calc;
+/// use calc::token::{reconstruct, tokenize_lossless, RawToken};
+///
+/// let tokens = tokenize_lossless(" 2 *\tx ").unwrap();
+/// assert_eq!(tokens[1].token, Some(RawToken::Multiply));
+/// assert_eq!(tokens[2].leading, "\t");
+/// assert_eq!(reconstruct(&tokens), " 2 *\tx ");
+/// ```
+pub fn tokenize_lossless(input: &str) -> Result<Vec<RichToken<'_>>, CalcError> {
+    let mut tokens = Vec::new();
+    let mut tokenizer = Tokenizer::new(input);
+    let mut end = 0;
+    while let Some(token) = tokenizer.next_spanned() {
+        let (token, span) = token?;
+        tokens.push(RichToken {
+            token: Some(token),
+            text: &input[span.clone()],
+            leading: &input[end..span.start],
+            span: span.clone(),
+        });
+        end = span.end;
+    }
+    tokens.push(RichToken {
+        token: None,
+        span: input.len()..input.len(),
+        text: "",
+        leading: &input[end..],
+    });
+    Ok(tokens)
+}
+
+/// Joins the trivia and text of `tokens` back together. For the tokens of
+/// `tokenize_lossless`, this is its input.
+pub fn reconstruct(tokens: &[RichToken]) -> String {
+    let mut text = String::new();
+    for token in tokens {
+        text.push_str(token.leading);
+        text.push_str(token.text);
+    }
+    text
+}
+
+/// A list of tokens in the compact form the parser works on.
+///
+/// A `Token` is as large as a `Value`, even though most tokens are
+/// operators. Here every token takes eight bytes instead: numbers and atoms
+/// are kept in tables on the side and referred to by index. Atoms borrow
+/// their names from the input, and numbers are only cloned when they
+/// cannot be borrowed from existing `Token`s.
+#[derive(Clone, Default)]
+pub(crate) struct TokenStream<'a> {
+    tokens: Vec<PackedToken>,
+    numbers: Vec<Cow<'a, Value>>,
+    atoms: Vec<&'a str>,
+}
+
+/// A `Token` whose number or atom is stored in the tables of a
+/// `TokenStream`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum PackedToken {
+    Plus,
+    Minus,
+    Divide,
+    Multiply,
+    Exponent,
+    Square,
+    Cube,
+    BitWiseAnd,
+    BitWiseOr,
+    BitWiseXor,
+    BitWiseNot,
+    BitWiseRShift,
+    BitWiseLShift,
+    Modulo,
+    OpenParen,
+    CloseParen,
+    Comma,
+    Assign,
+    Number(u32),
+    Atom(u32),
+    /// A registered operator, whose symbol is kept with the atoms.
+    Operator(u32),
+    Placeholder(u32),
+}
+
+impl<'a> TokenStream<'a> {
+    /// Tokenizes an infix expression like `tokenize`.
+    pub(crate) fn new(input: &'a str) -> Result<Self, CalcError> {
+        TokenStream::tokenize(input, &TokenizerOptions::default())
+    }
+
+    /// Tokenizes an infix expression like `tokenize_with_options`.
+    pub(crate) fn tokenize(
+        input: &'a str,
+        options: &TokenizerOptions,
+    ) -> Result<Self, CalcError> {
+        TokenStream::tokenize_with(input, options, &[])
+    }
+
+    /// Tokenizes an infix expression like `tokenize`, recognizing the
+    /// symbols of `operators` as well.
+    pub(crate) fn with_operators(
+        input: &'a str,
+        operators: &'a [Operator],
+    ) -> Result<Self, CalcError> {
+        TokenStream::tokenize_with(
+            input,
+            &TokenizerOptions::default(),
+            operators,
+        )
+    }
+
+    /// Tokenizes an infix expression like `tokenize_with_options`,
+    /// recognizing the symbols of `operators` as well.
+    pub(crate) fn tokenize_with(
+        input: &'a str,
+        options: &TokenizerOptions,
+        operators: &'a [Operator],
+    ) -> Result<Self, CalcError> {
+        TokenStream::pack(input, options, operators, None)
+    }
+
+    /// Tokenizes an infix expression like `with_operators`, pushing the
+    /// byte range of each token onto `spans`. After an error about a token,
+    /// such as an unrecognized one, the last range is that of the token.
+    pub(crate) fn spanned(
+        input: &'a str,
+        operators: &'a [Operator],
+        spans: &mut Vec<Range<usize>>,
+    ) -> Result<Self, CalcError> {
+        let options = TokenizerOptions::default();
+        TokenStream::pack(input, &options, operators, Some(spans))
+    }
+
+    fn pack(
+        input: &'a str,
+        options: &TokenizerOptions,
+        operators: &'a [Operator],
+        mut spans: Option<&mut Vec<Range<usize>>>,
+    ) -> Result<Self, CalcError> {
+        check_input_len(input, options)?;
+        let mut stream = TokenStream::default();
+        if *options == TokenizerOptions::default() {
+            stream.tokens.reserve(input.len());
+        }
+        let mut tokenizer =
+            Tokenizer::with_operators(input, operators).with_options(options);
+        while let Some(token) = tokenizer.next() {
+            if let Some(ref mut spans) = spans {
+                spans.push(tokenizer.last.clone());
+            }
+            let token = match token? {
+                RawToken::Plus => PackedToken::Plus,
+                RawToken::Minus => PackedToken::Minus,
+                RawToken::Divide => PackedToken::Divide,
+                RawToken::Multiply => PackedToken::Multiply,
+                RawToken::Exponent => PackedToken::Exponent,
+                RawToken::Square => PackedToken::Square,
+                RawToken::Cube => PackedToken::Cube,
+                RawToken::BitWiseAnd => PackedToken::BitWiseAnd,
+                RawToken::BitWiseOr => PackedToken::BitWiseOr,
+                RawToken::BitWiseXor => PackedToken::BitWiseXor,
+                RawToken::BitWiseNot => PackedToken::BitWiseNot,
+                RawToken::BitWiseRShift => PackedToken::BitWiseRShift,
+                RawToken::BitWiseLShift => PackedToken::BitWiseLShift,
+                RawToken::Modulo => PackedToken::Modulo,
+                RawToken::OpenParen => PackedToken::OpenParen,
+                RawToken::CloseParen => PackedToken::CloseParen,
+                RawToken::Comma => PackedToken::Comma,
+                RawToken::Assign => PackedToken::Assign,
+                RawToken::Number(text) => {
+                    let number = parse_with_options(text, options)?;
+                    stream.push_number(Cow::Owned(number))
+                }
+                RawToken::Atom(name) => stream.push_atom(name),
+                RawToken::Operator(symbol) => stream.push_operator(symbol),
+                RawToken::Placeholder(index) => {
+                    PackedToken::Placeholder(index as u32)
+                }
+            };
+            stream.tokens.push(token);
+            check_token_count(stream.tokens.len(), options)?;
+        }
+        debug!("tokenized {:?} into {:?}", input, stream);
+        Ok(stream)
+    }
+
+    /// Packs existing tokens, borrowing their numbers and atoms.
+    pub(crate) fn from_tokens(tokens: &'a [Token]) -> Self {
+        let mut stream = TokenStream::default();
+        stream.tokens.reserve(tokens.len());
+        for token in tokens {
+            let token = match *token {
+                Token::Plus => PackedToken::Plus,
+                Token::Minus => PackedToken::Minus,
+                Token::Divide => PackedToken::Divide,
+                Token::Multiply => PackedToken::Multiply,
+                Token::Exponent => PackedToken::Exponent,
+                Token::Square => PackedToken::Square,
+                Token::Cube => PackedToken::Cube,
+                Token::BitWiseAnd => PackedToken::BitWiseAnd,
+                Token::BitWiseOr => PackedToken::BitWiseOr,
+                Token::BitWiseXor => PackedToken::BitWiseXor,
+                Token::BitWiseNot => PackedToken::BitWiseNot,
+                Token::BitWiseRShift => PackedToken::BitWiseRShift,
+                Token::BitWiseLShift => PackedToken::BitWiseLShift,
+                Token::Modulo => PackedToken::Modulo,
+                Token::OpenParen => PackedToken::OpenParen,
+                Token::CloseParen => PackedToken::CloseParen,
+                Token::Comma => PackedToken::Comma,
+                Token::Assign => PackedToken::Assign,
+                Token::Number(ref n) => stream.push_number(Cow::Borrowed(n)),
+                Token::Atom(ref name) => stream.push_atom(name),
+                Token::Operator(ref symbol) => stream.push_operator(symbol),
+                Token::Placeholder(index) => {
+                    PackedToken::Placeholder(index as u32)
+                }
+            };
+            stream.tokens.push(token);
+        }
+        stream
+    }
+
+    fn push_number(&mut self, n: Cow<'a, Value>) -> PackedToken {
+        self.numbers.push(n);
+        PackedToken::Number(self.numbers.len() as u32 - 1)
+    }
+
+    fn push_atom(&mut self, name: &'a str) -> PackedToken {
+        self.atoms.push(name);
+        PackedToken::Atom(self.atoms.len() as u32 - 1)
+    }
+
+    fn push_operator(&mut self, symbol: &'a str) -> PackedToken {
+        self.atoms.push(symbol);
+        PackedToken::Operator(self.atoms.len() as u32 - 1)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    /// The number of parentheses still open after the last token, or zero
+    /// if a `)` closes one that was never opened.
+    pub(crate) fn unclosed_parens(&self) -> usize {
+        let mut open = 0;
+        for token in &self.tokens {
+            match *token {
+                PackedToken::OpenParen => open += 1,
+                PackedToken::CloseParen if open == 0 => return 0,
+                PackedToken::CloseParen => open -= 1,
+                _ => {}
+            }
+        }
+        open
+    }
+
+    /// Appends `count` closing parentheses.
+    pub(crate) fn close_parens(&mut self, count: usize) {
+        let len = self.tokens.len() + count;
+        self.tokens.resize(len, PackedToken::CloseParen);
+    }
+
+    pub(crate) fn get(&self, pos: usize) -> Option<PackedToken> {
+        self.tokens.get(pos).cloned()
+    }
+
+    /// Looks up the value of a `PackedToken::Number`.
+    pub(crate) fn number(&self, index: u32) -> &Value {
+        &self.numbers[index as usize]
+    }
+
+    /// Looks up the name of a `PackedToken::Atom`.
+    pub(crate) fn atom(&self, index: u32) -> &'a str {
+        self.atoms[index as usize]
+    }
+
+    /// Hashes the tokens with 64-bit FNV-1a. Every token is written out as
+    /// its `Display` text followed by a zero byte, so the hash only depends
+    /// on the tokens and stays the same across runs and platforms.
+    pub(crate) fn fingerprint(&self) -> u64 {
+        const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+        const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+        let mut hash = FNV_OFFSET;
+        for pos in 0..self.len() {
+            let text = self.token(pos).to_string();
+            for &byte in text.as_bytes().iter().chain(&[0]) {
+                hash = (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME);
+            }
+        }
+        hash
+    }
+
+    /// Unpacks the token at `pos`, e.g. to report it in an error.
+    pub(crate) fn token(&self, pos: usize) -> Token {
+        match self.tokens[pos] {
+            PackedToken::Plus => Token::Plus,
+            PackedToken::Minus => Token::Minus,
+            PackedToken::Divide => Token::Divide,
+            PackedToken::Multiply => Token::Multiply,
+            PackedToken::Exponent => Token::Exponent,
+            PackedToken::Square => Token::Square,
+            PackedToken::Cube => Token::Cube,
+            PackedToken::BitWiseAnd => Token::BitWiseAnd,
+            PackedToken::BitWiseOr => Token::BitWiseOr,
+            PackedToken::BitWiseXor => Token::BitWiseXor,
+            PackedToken::BitWiseNot => Token::BitWiseNot,
+            PackedToken::BitWiseRShift => Token::BitWiseRShift,
+            PackedToken::BitWiseLShift => Token::BitWiseLShift,
+            PackedToken::Modulo => Token::Modulo,
+            PackedToken::OpenParen => Token::OpenParen,
+            PackedToken::CloseParen => Token::CloseParen,
+            PackedToken::Comma => Token::Comma,
+            PackedToken::Assign => Token::Assign,
+            PackedToken::Number(index) => {
+                Token::Number(self.number(index).clone())
+            }
+            PackedToken::Atom(index) => Token::Atom(self.atom(index).into()),
+            PackedToken::Operator(index) => {
+                Token::Operator(self.atom(index).into())
+            }
+            PackedToken::Placeholder(index) => {
+                Token::Placeholder(index as usize)
+            }
+        }
+    }
+}
+
+/// Formats like the equivalent list of `Token`s.
+impl<'a> fmt::Debug for TokenStream<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries((0..self.len()).map(|pos| self.token(pos)))
+            .finish()
+    }
+}
+
+/// A token that borrows its text from the input.
+///
+/// Unlike `Token`, numbers are kept as written and only parsed when the
+/// token is converted with `to_token`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RawToken<'a> {
+    Plus,
+    Minus,
+    Divide,
+    Multiply,
+    Exponent,
+    Square,
+    Cube,
+    BitWiseAnd,
+    BitWiseOr,
+    BitWiseXor,
+    BitWiseNot,
+    BitWiseRShift,
+    BitWiseLShift,
+    Modulo,
+    OpenParen,
+    CloseParen,
+    Comma,
+    Assign,
+    Number(&'a str),
+    Atom(&'a str),
+    Operator(&'a str),
+    /// A placeholder, with bare `?`s numbered in order by the `Tokenizer`.
+    Placeholder(usize),
+}
+
+impl<'a> RawToken<'a> {
+    /// Converts into an owned `Token`, parsing numbers.
+    pub fn to_token(self) -> Result<Token, CalcError> {
+        Ok(match self {
+            RawToken::Plus => Token::Plus,
+            RawToken::Minus => Token::Minus,
+            RawToken::Divide => Token::Divide,
+            RawToken::Multiply => Token::Multiply,
+            RawToken::Exponent => Token::Exponent,
+            RawToken::Square => Token::Square,
+            RawToken::Cube => Token::Cube,
+            RawToken::BitWiseAnd => Token::BitWiseAnd,
+            RawToken::BitWiseOr => Token::BitWiseOr,
+            RawToken::BitWiseXor => Token::BitWiseXor,
+            RawToken::BitWiseNot => Token::BitWiseNot,
+            RawToken::BitWiseRShift => Token::BitWiseRShift,
+            RawToken::BitWiseLShift => Token::BitWiseLShift,
+            RawToken::Modulo => Token::Modulo,
+            RawToken::OpenParen => Token::OpenParen,
+            RawToken::CloseParen => Token::CloseParen,
+            RawToken::Comma => Token::Comma,
+            RawToken::Assign => Token::Assign,
+            RawToken::Number(text) => Token::Number(parse_number(text)?),
+            RawToken::Atom(name) => Token::Atom(name.to_owned()),
+            RawToken::Operator(symbol) => Token::Operator(symbol.to_owned()),
+            RawToken::Placeholder(index) => Token::Placeholder(index),
+        })
+    }
+}
+
+fn raw_operator(c: char) -> Option<RawToken<'static>> {
+    match c {
+        '+' => Some(RawToken::Plus),
+        '-' | '−' => Some(RawToken::Minus),
+        '/' | '÷' => Some(RawToken::Divide),
+        '*' | '×' => Some(RawToken::Multiply),
+        '^' => Some(RawToken::BitWiseXor),
+        '²' => Some(RawToken::Square),
+        '³' => Some(RawToken::Cube),
+        '&' => Some(RawToken::BitWiseAnd),
+        '|' => Some(RawToken::BitWiseOr),
+        '~' => Some(RawToken::BitWiseNot),
+        '%' => Some(RawToken::Modulo),
+        '(' => Some(RawToken::OpenParen),
+        ')' => Some(RawToken::CloseParen),
+        ',' => Some(RawToken::Comma),
+        '=' => Some(RawToken::Assign),
+        _ => None,
+    }
+}
+
+fn raw_operator_pair(pair: [char; 2]) -> Option<RawToken<'static>> {
+    match pair {
+        ['*', '*'] => Some(RawToken::Exponent),
+        ['<', '<'] => Some(RawToken::BitWiseLShift),
+        ['>', '>'] => Some(RawToken::BitWiseRShift),
+        _ => None,
+    }
+}
+
+/// Splits an infix expression into `RawToken`s without allocating.
+///
+/// Each call to `next` scans one token. Only an error allocates, and the
+/// iterator ends after returning one.
+///
+/// ```
+/// # extern crate calc;
+/// use calc::token::{RawToken, Tokenizer};
+///
+/// let tokens: Result<Vec<_>, _> = Tokenizer::new("2 * x").collect();
+/// assert_eq!(
+///     tokens,
+///     Ok(vec![RawToken::Number("2"), RawToken::Multiply, RawToken::Atom("x")])
+/// );
+/// ```
+#[derive(Clone, Debug)]
+pub struct Tokenizer<'a> {
+    input: &'a str,
+    pos: usize,
+    operators: &'a [Operator],
+    /// The number of bare `?` placeholders scanned so far.
+    placeholders: usize,
+    /// The byte range of the token scanned last, or of the text an error
+    /// was about.
+    last: Range<usize>,
+    /// Whether `,` is a decimal separator, as set by `with_options`.
+    decimal_comma: bool,
+    /// Whether `.` is a thousands separator rather than a decimal point.
+    thousands_point: bool,
+}
+
+impl<'a> Tokenizer<'a> {
+    pub fn new(input: &'a str) -> Self {
+        Tokenizer::with_operators(input, &[])
+    }
+
+    /// Splits the input like `new`, recognizing the symbols of `operators`
+    /// as well. Where both a registered and a builtin symbol match, the
+    /// longer one is taken.
+    pub fn with_operators(input: &'a str, operators: &'a [Operator]) -> Self {
+        Tokenizer {
+            input,
+            pos: 0,
+            operators,
+            placeholders: 0,
+            last: 0..0,
+            decimal_comma: false,
+            thousands_point: false,
+        }
+    }
+
+    /// Scans numbers with the separators chosen in `options`. The limits
+    /// of the options are left to the caller.
+    pub fn with_options(mut self, options: &TokenizerOptions) -> Self {
+        self.decimal_comma = options.decimal_comma;
+        self.thousands_point = options.decimal_comma && options.thousands_point;
+        self
+    }
+
+    /// Scans the next token like `next`, along with the byte range of the
+    /// input it was read from.
+    pub(crate) fn next_spanned(
+        &mut self,
+    ) -> Option<Result<(RawToken<'a>, Range<usize>), CalcError>> {
+        let token = self.next()?;
+        Some(token.map(|token| (token, self.last.clone())))
+    }
+
+    /// Advances past the longest prefix of the remaining input whose
+    /// characters match `pred`, and returns it.
+    fn take_while<P: Fn(char) -> bool>(&mut self, pred: P) -> &'a str {
+        let rest = &self.input[self.pos..];
+        let len = rest.find(|c| !pred(c)).unwrap_or(rest.len());
+        self.pos += len;
+        &rest[..len]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.input[self.pos..].chars().next()
+    }
+
+    /// Advances past ASCII bytes for which `keep` holds. The bytes of
+    /// multi-byte characters are never ASCII, so scanning bytes instead of
+    /// characters finds the same end, without decoding the input.
+    fn skip_ascii(&mut self, keep: fn(&u8) -> bool) {
+        let bytes = &self.input.as_bytes()[self.pos..];
+        let len = bytes.iter().position(|b| !keep(b)).unwrap_or(bytes.len());
+        self.pos += len;
+    }
+
+    fn skip_digits(&mut self) {
+        self.skip_ascii(u8::is_ascii_digit);
+    }
+
+    /// Scans a number: an integer with a radix prefix, or decimal digits
+    /// with an optional fractional part and exponent. A prefixed integer
+    /// runs on over all letters and digits, so that `parse_number` rejects
+    /// `0b12` as a whole rather than it being read as `0b1` followed by
+    /// `2`. So does an exponent, after its sign. Any `_` separators are
+    /// scanned along with the digits, to be checked by `parse_number`, as
+    /// are the separators of `with_options` that are followed by a digit.
+    fn number(&mut self) -> &'a str {
+        let start = self.pos;
+        let rest = &self.input.as_bytes()[start..];
+        if rest.len() > 1 && rest[0] == b'0' && is_radix_prefix(rest[1] as char)
+        {
+            self.pos += 2;
+            self.skip_ascii(is_alphanumeric_or_separator);
         } else {
-            match c.check_operator() {
-                OperatorState::Complete => {
-                    tokens.push(
-                        c.operator_type().ok_or_else(|| InvalidOperator(c))?,
-                    );
-                    chars.next();
+            self.skip_ascii(is_digit_or_separator);
+            while self.thousands_point && self.separator(b'.') {
+                self.pos += 1;
+                self.skip_ascii(is_digit_or_separator);
+            }
+            let point = match self.input.as_bytes().get(self.pos) {
+                Some(b'.') => !self.thousands_point,
+                Some(b',') => self.decimal_comma && self.separator(b','),
+                _ => false,
+            };
+            if point {
+                self.pos += 1;
+                self.skip_ascii(is_digit_or_separator);
+            }
+            if let Some(b'e') | Some(b'E') = self.input.as_bytes().get(self.pos)
+            {
+                self.pos += 1;
+                if let Some(b'+') | Some(b'-') =
+                    self.input.as_bytes().get(self.pos)
+                {
+                    self.pos += 1;
                 }
-                OperatorState::PotentiallyIncomplete => {
-                    chars.next();
-                    match chars.peek() {
-                        Some(&next_char) if next_char.is_operator() => {
-                            tokens.push(
-                                [c, next_char]
-                                    .operator_type()
-                                    .ok_or_else(|| InvalidOperator(c))?,
-                            );
-                            chars.next();
-                        }
-                        _ => {
-                            tokens.push(
-                                c.operator_type()
-                                    .ok_or_else(|| InvalidOperator(c))?,
-                            );
-                        }
-                    }
+                self.skip_ascii(is_alphanumeric_or_separator);
+            }
+        }
+        &self.input[start..self.pos]
+    }
+
+    /// Whether the input continues with `separator` and then a digit.
+    fn separator(&self, separator: u8) -> bool {
+        let bytes = &self.input.as_bytes()[self.pos..];
+        bytes.first() == Some(&separator)
+            && bytes.get(1).is_some_and(u8::is_ascii_digit)
+    }
+
+    /// Scans a placeholder after its `?`. A bare `?` stands for the
+    /// argument after that of the previous bare `?`, while `?N` stands for
+    /// the `N`th argument, counting from 1.
+    fn placeholder(&mut self) -> Result<RawToken<'a>, CalcError> {
+        let start = self.pos - 1;
+        let digits_start = self.pos;
+        self.skip_digits();
+        if self.pos == digits_start {
+            self.placeholders += 1;
+            return Ok(RawToken::Placeholder(self.placeholders));
+        }
+        match self.input[digits_start..self.pos].parse() {
+            Ok(index) if index > 0 && index <= u32::MAX as usize => {
+                Ok(RawToken::Placeholder(index))
+            }
+            _ => Err(UnrecognizedToken(self.input[start..self.pos].into())),
+        }
+    }
+
+    /// Advances past whitespace, a byte at a time while it is ASCII.
+    fn skip_whitespace(&mut self) {
+        let bytes = self.input.as_bytes();
+        while let Some(&b) = bytes.get(self.pos) {
+            match b {
+                b' ' | b'\t' | b'\n' | b'\x0b' | b'\x0c' | b'\r' => {
+                    self.pos += 1
                 }
-                OperatorState::NotAnOperator => {
-                    if c.is_whitespace() {
-                        chars.next();
-                    } else {
-                        let token_string = consume_until_new_token(&mut chars);
-                        return Err(CalcError::UnrecognizedToken(token_string));
+                0..=0x7f => return,
+                _ => {
+                    self.take_while(char::is_whitespace);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// The longest registered symbol at the current position, if it is
+    /// longer than the builtin operator there.
+    fn registered_operator(&self) -> Option<&'a str> {
+        let rest = &self.input[self.pos..];
+        let symbol = self
+            .operators
+            .iter()
+            .map(|op| op.symbol.as_str())
+            .filter(|symbol| rest.starts_with(symbol))
+            .max_by_key(|symbol| symbol.len())?;
+        let mut chars = rest.chars();
+        let builtin = match (chars.next(), chars.next()) {
+            (Some(a), Some(b)) if raw_operator_pair([a, b]).is_some() => 2,
+            (Some(a), _) if raw_operator(a).is_some() => a.len_utf8(),
+            _ => 0,
+        };
+        if symbol.len() > builtin {
+            Some(&rest[..symbol.len()])
+        } else {
+            None
+        }
+    }
+
+    fn scan(&mut self) -> Option<Result<RawToken<'a>, CalcError>> {
+        // Numbers are by far the most common tokens, so recognize them
+        // before decoding the next character.
+        match self.input.as_bytes().get(self.pos) {
+            Some(b'0'..=b'9') | Some(b'.') => {
+                return Some(Ok(RawToken::Number(self.number())));
+            }
+            _ => {}
+        }
+        let c = self.peek()?;
+        if c.is_alphabetic() {
+            // `²` and `³` are numeric characters, but end the atom as the
+            // postfix operators they are, so that `x²` squares `x`.
+            let atom = self.take_while(|c| {
+                c.is_alphanumeric() && !c.is_operator() || c == '_'
+            });
+            // `π` is another name for `pi`, like `√` is for `sqrt`.
+            let atom = if atom == "π" { "pi" } else { atom };
+            return Some(Ok(RawToken::Atom(atom)));
+        } else if c == '√' {
+            self.pos += c.len_utf8();
+            return Some(Ok(RawToken::Atom("sqrt")));
+        }
+        if let Some(symbol) = self.registered_operator() {
+            self.pos += symbol.len();
+            return Some(Ok(RawToken::Operator(symbol)));
+        }
+        if c == '?' {
+            self.pos += 1;
+            return Some(self.placeholder());
+        }
+        let token = match c.check_operator() {
+            OperatorState::Complete => raw_operator(c),
+            OperatorState::PotentiallyIncomplete => {
+                // The characters that may start a pair are all ASCII.
+                let next = self.input[self.pos + 1..].chars().next();
+                match next.and_then(|next| raw_operator_pair([c, next])) {
+                    Some(pair) => {
+                        self.pos += 2;
+                        return Some(Ok(pair));
                     }
+                    None => raw_operator(c),
                 }
             }
+            OperatorState::NotAnOperator => {
+                let token = self.take_while(|c| {
+                    !(c.is_whitespace()
+                        || c.is_operator()
+                        || c.is_ascii_digit())
+                });
+                return Some(Err(UnrecognizedToken(token.to_owned())));
+            }
+        };
+        self.pos += c.len_utf8();
+        Some(token.ok_or(InvalidOperator(c)))
+    }
+}
+
+impl<'a> Iterator for Tokenizer<'a> {
+    type Item = Result<RawToken<'a>, CalcError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.skip_whitespace();
+        let start = self.pos;
+        let token = self.scan();
+        self.last = start..self.pos;
+        if let Some(Err(_)) = token {
+            self.pos = self.input.len();
         }
+        token
     }
-    Ok(tokens)
 }
 
 /// Tokenizes a mathematical expression written with a polish (prefix) notation.
@@ -375,16 +1148,19 @@ pub fn tokenize_polish(input: &str) -> Result<Vec<Token>, CalcError> {
         }
     }
 
+    debug!("tokenized polish {:?} into {:?}", input, tokens);
     Ok(tokens)
 }
 
+/// Takes the digits in the given radix at the front of `input`, along with
+/// any `_` separators between them.
 fn digits<I>(input: &mut Peekable<I>, radix: u32) -> String
 where
     I: Iterator<Item = char>,
 {
     let mut number = String::new();
     while let Some(&c) = input.peek() {
-        if c.is_digit(radix) {
+        if c.is_digit(radix) || c == '_' {
             number.push(c);
         } else {
             break;
@@ -398,15 +1174,15 @@ fn consume_number<I>(input: &mut Peekable<I>) -> Result<Value, CalcError>
 where
     I: Iterator<Item = char>,
 {
+    let mut text = String::new();
     match input.peek() {
         Some(&'0') => {
             input.next();
+            text.push('0');
             match input.peek() {
-                Some(&'x') | Some(&'X') => {
-                    input.next();
-                    let digits = digits(input, 16);
-                    let num = Integral::from_str_radix(&digits, 16)?;
-                    return Ok(Value::hex(num));
+                Some(&c) if is_radix_prefix(c) => {
+                    push_alphanumeric(input, &mut text);
+                    return parse_number(&text);
                 }
                 Some(&_) => (),
                 None => return Ok(Value::dec(0)),
@@ -415,21 +1191,178 @@ where
         Some(_) => (),
         None => return Err(CalcError::UnexpectedEndOfInput),
     }
-    let whole = digits(input, 10);
+    text.push_str(&digits(input, 10));
     if let Some(&'.') = input.peek() {
         input.next();
-        let frac = digits(input, 10);
-        let num = [whole, ".".into(), frac]
-            .concat()
-            .parse::<d128>()
-            .map_err(|_| CalcError::InvalidNumber("invalid float".into()))?;
-        Ok(Value::Float(num))
+        text.push('.');
+        text.push_str(&digits(input, 10));
+    }
+    if let Some(&e @ 'e') | Some(&e @ 'E') = input.peek() {
+        input.next();
+        text.push(e);
+        if let Some(&sign @ '+') | Some(&sign @ '-') = input.peek() {
+            input.next();
+            text.push(sign);
+        }
+        push_alphanumeric(input, &mut text);
+    }
+    parse_number(&text)
+}
+
+/// Moves the ASCII letters and digits at the front of `input` to `text`,
+/// along with any `_` separators.
+fn push_alphanumeric<I>(input: &mut Peekable<I>, text: &mut String)
+where
+    I: Iterator<Item = char>,
+{
+    while let Some(&c) = input.peek() {
+        if !(c.is_ascii_alphanumeric() || c == '_') {
+            break;
+        }
+        text.push(c);
+        input.next();
+    }
+}
+
+/// Whether `c` makes a leading `0` the start of a radix prefix. Only the
+/// lowercase prefixes are valid, but the uppercase ones are scanned too, to
+/// be rejected rather than read as `0` followed by an atom.
+fn is_radix_prefix(c: char) -> bool {
+    matches!(c, 'x' | 'X' | 'o' | 'O' | 'b' | 'B')
+}
+
+fn is_digit_or_separator(b: &u8) -> bool {
+    b.is_ascii_digit() || *b == b'_'
+}
+
+fn is_alphanumeric_or_separator(b: &u8) -> bool {
+    b.is_ascii_alphanumeric() || *b == b'_'
+}
+
+/// Parses a number scanned by `Tokenizer::number`. Integers may have the
+/// prefix `0x` for hexadecimal, `0o` for octal or `0b` for binary digits.
+/// Hexadecimal integers keep their format, the others are shown in
+/// decimal. Numbers with an exponent, such as `1.5e3` or `2E-4`, are
+/// floats. Digits may be grouped with `_`, as in `1_000_000`. Floats
+/// beyond the range of a `d128` fail with `CalcError::LiteralOutOfRange`.
+fn parse_number(text: &str) -> Result<Value, CalcError> {
+    let invalid = || CalcError::InvalidNumber(text.into());
+    if is_prefixed(text) {
+        let radix = match &text[..2] {
+            "0x" => 16,
+            "0o" => 8,
+            "0b" => 2,
+            _ => return Err(invalid()),
+        };
+        let digits = strip_separators(&text[2..], radix).ok_or_else(invalid)?;
+        if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+            return Err(invalid());
+        }
+        let num = Integral::from_str_radix(&digits, radix)?;
+        return Ok(match radix {
+            16 => Value::hex(num),
+            _ => Value::dec(num),
+        });
+    }
+    let number = strip_separators(text, 10).ok_or_else(invalid)?;
+    if let Some(e) = number.find(['e', 'E']) {
+        let mantissa = &number[..e];
+        let exponent = &number[e + 1..];
+        let exponent = exponent.strip_prefix(['+', '-']).unwrap_or(exponent);
+        let valid = mantissa.bytes().any(|b| b.is_ascii_digit())
+            && !exponent.is_empty()
+            && exponent.bytes().all(|b| b.is_ascii_digit());
+        match number.parse::<d128>() {
+            Ok(num) if valid && num.is_infinite() => {
+                Err(CalcError::LiteralOutOfRange(text.into()))
+            }
+            Ok(num) if valid => Ok(Value::Float(num)),
+            _ => Err(invalid()),
+        }
+    } else if number.contains('.') {
+        // decNumber reads text without digits, like a lone `.`, as NaN.
+        match number.parse::<d128>() {
+            Ok(num) if num.is_infinite() => {
+                Err(CalcError::LiteralOutOfRange(text.into()))
+            }
+            Ok(num) if !num.is_nan() => Ok(Value::Float(num)),
+            _ => Err(CalcError::InvalidNumber("invalid float".into())),
+        }
     } else {
-        let res: Integral = whole.parse()?;
+        let res: Integral = number.parse()?;
         Ok(Value::dec(res))
     }
 }
 
+fn is_prefixed(text: &str) -> bool {
+    text.starts_with('0') && text.chars().nth(1).is_some_and(is_radix_prefix)
+}
+
+/// Parses a number scanned by a `Tokenizer` with the given options, by
+/// rewriting a decimal comma and thousands points to the form that
+/// `parse_number` reads.
+fn parse_with_options(
+    text: &str,
+    options: &TokenizerOptions,
+) -> Result<Value, CalcError> {
+    if !options.decimal_comma || is_prefixed(text) {
+        return parse_number(text);
+    }
+    let end = text.find([',', 'e', 'E']).unwrap_or(text.len());
+    let (integer, rest) = text.split_at(end);
+    let integer = if options.thousands_point {
+        ungroup(integer)
+    } else {
+        Some(Cow::Borrowed(integer))
+    };
+    let number = integer
+        .map(|integer| format!("{}{}", integer, rest.replacen(',', ".", 1)));
+    match number.as_ref().map(|number| parse_number(number)) {
+        Some(Err(CalcError::InvalidNumber(_))) | None => {
+            Err(CalcError::InvalidNumber(text.into()))
+        }
+        Some(result) => result,
+    }
+}
+
+/// Removes the `.` thousands separators from the integer part of a number,
+/// or returns `None` if they don't separate groups of three digits after a
+/// first group of one to three.
+fn ungroup(integer: &str) -> Option<Cow<'_, str>> {
+    if !integer.contains('.') {
+        return Some(Cow::Borrowed(integer));
+    }
+    let grouped = integer.split('.').enumerate().all(|(i, group)| {
+        let len = group.len();
+        (len == 3 || i == 0 && (1..3).contains(&len))
+            && group.bytes().all(|b| b.is_ascii_digit())
+    });
+    if grouped {
+        Some(Cow::Owned(integer.replace('.', "")))
+    } else {
+        None
+    }
+}
+
+/// Removes the `_` separators from the digits of a number, or returns
+/// `None` if one of them isn't between two digits in the given radix.
+fn strip_separators(digits: &str, radix: u32) -> Option<Cow<'_, str>> {
+    if !digits.contains('_') {
+        return Some(Cow::Borrowed(digits));
+    }
+    let bytes = digits.as_bytes();
+    let is_digit =
+        |i: usize| bytes.get(i).is_some_and(|&b| (b as char).is_digit(radix));
+    let separated = bytes.iter().enumerate().all(|(i, &b)| {
+        b != b'_' || (i > 0 && is_digit(i - 1) && is_digit(i + 1))
+    });
+    if separated {
+        Some(Cow::Owned(digits.replace('_', "")))
+    } else {
+        None
+    }
+}
+
 /// Consume a valid atom. An atom is defined by:
 /// - Starting with an alphabetic character
 /// - Consisting of alphanumeric characters or underscores
@@ -492,6 +1425,30 @@ mod tests {
         assert_eq!(tokenize(line), Ok(expected));
     }
 
+    #[test]
+    fn pi_glyph() {
+        assert_eq!(tokenize("2 * π * r"), tokenize("2 * pi * r"));
+        assert_eq!(tokenize("π²"), tokenize("pi²"));
+        // Only a whole atom is renamed.
+        assert_eq!(tokenize("πr"), Ok(vec![Token::Atom("πr".into())]));
+    }
+
+    #[test]
+    fn adjacent_operators() {
+        let line = "2*(3**-1)";
+        let expected = vec![
+            Token::Number(Value::dec(2)),
+            Token::Multiply,
+            Token::OpenParen,
+            Token::Number(Value::dec(3)),
+            Token::Exponent,
+            Token::Minus,
+            Token::Number(Value::dec(1)),
+            Token::CloseParen,
+        ];
+        assert_eq!(tokenize(line), Ok(expected));
+    }
+
     #[test]
     fn hexadecimals() {
         let line = "0xDEADBEEF | 0xC0FFEE";
@@ -503,4 +1460,363 @@ mod tests {
         assert_eq!(tokenize(line), Ok(expected));
     }
 
+    #[test]
+    fn radix_literals() {
+        let line = "0o755 & 0b1010 + 0x0f";
+        let expected = vec![
+            Token::Number(Value::dec(0o755)),
+            Token::BitWiseAnd,
+            Token::Number(Value::dec(0b1010)),
+            Token::Plus,
+            Token::Number(Value::hex(0x0f)),
+        ];
+        assert_eq!(tokenize(line), Ok(expected));
+        assert_eq!(tokenize_polish("& 0b110 0o7"), tokenize("0b110 & 0o7"));
+
+        for &input in &["0x", "0b12", "0o8", "0xfg", "0XFF", "0B1", "0o"] {
+            assert_eq!(
+                tokenize(&format!("1 + {} * 2", input)),
+                Err(CalcError::InvalidNumber(input.into()))
+            );
+            assert_eq!(
+                tokenize_polish(&format!("+ 1 {}", input)),
+                Err(CalcError::InvalidNumber(input.into()))
+            );
+        }
+
+        // A leading zero is still decimal without a prefix.
+        assert_eq!(
+            tokenize("007 + 0.5"),
+            Ok(vec![
+                Token::Number(Value::dec(7)),
+                Token::Plus,
+                Token::Number(Value::Float(d128!(0.5))),
+            ])
+        );
+    }
+
+    #[test]
+    fn scientific_literals() {
+        let line = "1.5e3 * 2E-4 + 1e+2";
+        let expected = vec![
+            Token::Number(Value::Float(d128!(1500))),
+            Token::Multiply,
+            Token::Number(Value::Float(d128!(0.0002))),
+            Token::Plus,
+            Token::Number(Value::Float(d128!(100))),
+        ];
+        assert_eq!(tokenize(line), Ok(expected));
+        assert_eq!(tokenize_polish("* 1.5e3 2E-4"), tokenize("1.5e3 * 2E-4"));
+        assert_eq!(
+            tokenize("0e5"),
+            Ok(vec![Token::Number(Value::Float(d128!(0)))])
+        );
+
+        for &input in &["1e", "2E+", "1e-3x", "1e3e", ".e1"] {
+            assert_eq!(
+                tokenize(&format!("1 + {} * 2", input)),
+                Err(CalcError::InvalidNumber(input.into()))
+            );
+            assert_eq!(
+                tokenize_polish(&format!("+ 1 {}", input)),
+                Err(CalcError::InvalidNumber(input.into()))
+            );
+        }
+
+        // Hexadecimal digits are not exponents.
+        assert_eq!(
+            tokenize("0x1e3"),
+            Ok(vec![Token::Number(Value::hex(0x1e3))])
+        );
+
+        // The largest d128 has 34 nines and the exponent 6144.
+        let max = format!("9.{}e6144", "9".repeat(33));
+        assert!(tokenize(&max).is_ok());
+        for over in &[format!("9.{}e6144", "9".repeat(34)), "1e6145".into()] {
+            assert_eq!(
+                tokenize(&format!("-{}", over)),
+                Err(CalcError::LiteralOutOfRange(over.clone()))
+            );
+        }
+        let long = format!("1{}.5", "0".repeat(6145));
+        assert_eq!(tokenize(&long), Err(CalcError::LiteralOutOfRange(long)));
+    }
+
+    #[test]
+    fn lone_point() {
+        for &input in &[".", "1 + .", ". * 2"] {
+            assert_eq!(
+                tokenize(input),
+                Err(CalcError::InvalidNumber("invalid float".into())),
+                "{}",
+                input
+            );
+        }
+        assert_eq!(
+            tokenize(".5"),
+            Ok(vec![Token::Number(Value::Float(d128!(0.5)))])
+        );
+    }
+
+    #[test]
+    fn digit_separators() {
+        assert_eq!(tokenize("1_000_000 * 3"), tokenize("1000000 * 3"));
+        assert_eq!(
+            tokenize("0xff_ff"),
+            Ok(vec![Token::Number(Value::hex(0xffff))])
+        );
+        assert_eq!(tokenize("0b1_01"), tokenize("5"));
+        assert_eq!(tokenize("1_0.2_5e1_0"), tokenize("10.25e10"));
+        assert_eq!(tokenize_polish("* 1_000 3"), tokenize("1000 * 3"));
+
+        for &input in &["1_", "1__0", "0x_f", "1_.5", "1._5", "1e_5", "2_e3"] {
+            assert_eq!(
+                tokenize(&format!("1 + {} * 2", input)),
+                Err(CalcError::InvalidNumber(input.into()))
+            );
+            assert_eq!(
+                tokenize_polish(&format!("+ 1 {}", input)),
+                Err(CalcError::InvalidNumber(input.into()))
+            );
+        }
+    }
+
+    #[test]
+    fn decimal_comma() {
+        let comma = TokenizerOptions {
+            decimal_comma: true,
+            ..TokenizerOptions::default()
+        };
+        let tokens = |input, options| tokenize_with_options(input, options);
+        assert_eq!(tokens("3,14 * 2", &comma), tokenize("3.14 * 2"));
+        assert_eq!(tokens("1,5e3 + 2.5", &comma), tokenize("1.5e3 + 2.5"));
+        assert_eq!(tokens("max(1,5, 2)", &comma), tokenize("max(1.5, 2)"));
+        assert_eq!(tokens("max(x,5)", &comma), tokenize("max(x, 5)"));
+        assert_eq!(tokens("cond(1,2,3)", &comma), tokenize("cond(1.2, 3)"));
+        assert_eq!(tokens("1,", &comma), tokenize("1,"));
+        let point = TokenizerOptions::default();
+        assert_eq!(tokens("3,14", &point), tokenize("3, 14"));
+
+        let points = TokenizerOptions {
+            thousands_point: true,
+            ..comma.clone()
+        };
+        assert_eq!(tokens("1.000.000,5", &points), tokenize("1000000.5"));
+        assert_eq!(tokens("12.345 * 2", &points), tokenize("12345 * 2"));
+        for &input in &["1.5", "1234.567", "1.00,5", ".5", "1.000.0000"] {
+            assert_eq!(
+                tokens(input, &points),
+                Err(CalcError::InvalidNumber(input.into()))
+            );
+        }
+        // Points only group thousands along with a decimal comma.
+        let points_only = TokenizerOptions {
+            thousands_point: true,
+            ..TokenizerOptions::default()
+        };
+        assert_eq!(tokens("1.5", &points_only), tokenize("1.5"));
+
+        let stream = TokenStream::tokenize("1.000,25", &points).unwrap();
+        assert_eq!(stream.number(0), &Value::Float(d128!(1000.25)));
+    }
+
+    #[test]
+    fn packed_tokens() {
+        assert!(::std::mem::size_of::<PackedToken>() <= 8);
+        let input = "(x + 0x1f) * -2.5 ** y²";
+        let tokens = tokenize(input).unwrap();
+        let stream = TokenStream::new(input).unwrap();
+        let unpacked: Vec<Token> =
+            (0..stream.len()).map(|pos| stream.token(pos)).collect();
+        assert_eq!(unpacked, tokens);
+        assert_eq!(format!("{:?}", stream), format!("{:?}", tokens));
+        let stream = TokenStream::from_tokens(&tokens);
+        assert_eq!(stream.get(1), Some(PackedToken::Atom(0)));
+        assert_eq!(stream.atom(0), "x");
+        assert_eq!(stream.get(3), Some(PackedToken::Number(0)));
+        assert_eq!(stream.number(0), &Value::hex(0x1f));
+        assert_eq!(stream.get(stream.len()), None);
+
+        let options = TokenizerOptions {
+            max_tokens: Some(2),
+            ..TokenizerOptions::default()
+        };
+        assert_eq!(
+            TokenStream::tokenize("1 + 2", &options).map(|s| s.len()),
+            Err(CalcError::InputTooLarge {
+                limit: 2,
+                actual: 3,
+            })
+        );
+    }
+
+    #[test]
+    fn raw_tokens() {
+        let line = "0x1F<<2.5 + log_2 x";
+        let expected = vec![
+            RawToken::Number("0x1F"),
+            RawToken::BitWiseLShift,
+            RawToken::Number("2.5"),
+            RawToken::Plus,
+            RawToken::Atom("log_2"),
+            RawToken::Atom("x"),
+        ];
+        let tokens: Result<Vec<_>, _> = Tokenizer::new(line).collect();
+        assert_eq!(tokens, Ok(expected));
+
+        let mut tokens = Tokenizer::new("1 $$ + 2");
+        assert_eq!(tokens.next(), Some(Ok(RawToken::Number("1"))));
+        assert_eq!(
+            tokens.next(),
+            Some(Err(CalcError::UnrecognizedToken("$$".into())))
+        );
+        assert_eq!(tokens.next(), None);
+        assert_eq!(
+            RawToken::Number("0x").to_token(),
+            tokenize("0x").map(|mut tokens| tokens.remove(0))
+        );
+    }
+
+    #[test]
+    fn placeholders() {
+        let tokens: Result<Vec<_>, _> = Tokenizer::new("?*?2+?").collect();
+        let expected = vec![
+            RawToken::Placeholder(1),
+            RawToken::Multiply,
+            RawToken::Placeholder(2),
+            RawToken::Plus,
+            RawToken::Placeholder(2),
+        ];
+        assert_eq!(tokens, Ok(expected));
+        assert_eq!(tokenize("?3"), Ok(vec![Token::Placeholder(3)]));
+        assert_eq!(
+            tokenize("? + ?0"),
+            Err(CalcError::UnrecognizedToken("?0".into()))
+        );
+    }
+
+    #[test]
+    fn unicode() {
+        let line = "12\u{a0}*\u{2003}3.5²\n³";
+        let expected = vec![
+            Token::Number(Value::dec(12)),
+            Token::Multiply,
+            Token::Number(Value::Float(d128!(3.5))),
+            Token::Square,
+            Token::Cube,
+        ];
+        assert_eq!(tokenize(line), Ok(expected));
+        assert_eq!(
+            tokenize("1 + ３"),
+            Err(CalcError::UnrecognizedToken("３".into()))
+        );
+    }
+
+    #[test]
+    fn input_len_limit() {
+        let options = TokenizerOptions {
+            max_input_len: Some(5),
+            ..TokenizerOptions::default()
+        };
+        assert!(tokenize_with_options("1 + 2", &options).is_ok());
+        assert_eq!(
+            tokenize_with_options("1 + 23", &options),
+            Err(CalcError::InputTooLarge {
+                limit: 5,
+                actual: 6,
+            })
+        );
+    }
+
+    #[test]
+    fn token_limit() {
+        let options = TokenizerOptions {
+            max_tokens: Some(3),
+            ..TokenizerOptions::default()
+        };
+        assert!(tokenize_with_options("1 + 2", &options).is_ok());
+        assert!(tokenize_with_options("(1)", &options).is_ok());
+        assert_eq!(
+            tokenize_with_options("1 + 2 + 3", &options),
+            Err(CalcError::InputTooLarge {
+                limit: 3,
+                actual: 4,
+            })
+        );
+        assert_eq!(
+            tokenize_with_options("(1))", &options),
+            Err(CalcError::InputTooLarge {
+                limit: 3,
+                actual: 4,
+            })
+        );
+    }
+
+    #[test]
+    fn lossless() {
+        let corpus = [
+            "(3 + 7) >> 10 * (7 % 2)",
+            "log 4 / log 2",
+            "2*(3**-1)",
+            "12\u{a0}*\u{2003}3.5²\n³",
+            "0xFF & ~0x0f | 1 << 2 ^ 3",
+            "√2 − 4 ÷ 2 × x_1",
+            "f(?, ?2) = ?1 + .5",
+            "\t let x = 2 in x ** 2 \r\n",
+            "",
+            "   ",
+        ];
+        let whitespace = [" ", "\t", "\n", "\r\n", "\u{a0}", "\u{2003}"];
+        // A xorshift generator, so that the test is the same on every run.
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut random = |n: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as usize % n
+        };
+        for input in &corpus {
+            for _ in 0..50 {
+                let mut text = input.to_string();
+                for _ in 0..random(4) {
+                    let boundaries: Vec<_> = text
+                        .char_indices()
+                        .map(|(i, _)| i)
+                        .chain(Some(text.len()))
+                        .collect();
+                    let at = boundaries[random(boundaries.len())];
+                    text.insert_str(at, whitespace[random(whitespace.len())]);
+                }
+                // Whitespace may split a token, e.g. `**`, and then the
+                // input fails to tokenize or means something else.
+                let tokens = match tokenize_lossless(&text) {
+                    Ok(tokens) => tokens,
+                    Err(_) => continue,
+                };
+                assert_eq!(reconstruct(&tokens), text);
+                let stripped: Result<Vec<_>, _> = tokens
+                    .iter()
+                    .filter_map(|token| token.token)
+                    .map(RawToken::to_token)
+                    .collect();
+                assert_eq!(stripped, tokenize(&text), "{:?}", text);
+                for token in &tokens {
+                    assert_eq!(&text[token.span.clone()], token.text);
+                }
+            }
+        }
+
+        let tokens = tokenize_lossless(" 1 −\t2 ").unwrap();
+        let leading: Vec<_> =
+            tokens.iter().map(|token| token.leading).collect();
+        assert_eq!(leading, [" ", " ", "\t", " "]);
+        assert_eq!(tokens[1].text, "−");
+        assert_eq!(tokens[1].span, 3..6);
+        assert_eq!(tokens[3].token, None);
+        assert_eq!(
+            tokenize_lossless("1 + $"),
+            Err(CalcError::UnrecognizedToken("$".into()))
+        );
+    }
+
 }
@@ -0,0 +1,347 @@
+//! Measurements with error bars, such as `(5.0 ± 0.1) * (2.0 ± 0.05)`.
+//!
+//! Every number carries a standard uncertainty, which the operators
+//! propagate to first order, assuming that the errors of their operands
+//! are independent. Plain numbers are exact. A variable that occurs twice,
+//! as in `x * x`, therefore counts as two independent measurements.
+
+use error::CalcError;
+use number::CalcNum;
+use parse::{Assoc, DefaultEnvironment, Environment, Operator};
+use std::cmp::Ordering;
+use std::fmt;
+use value::Value;
+
+/// The symbols that join a value and its uncertainty.
+const PLUS_MINUS: [&str; 2] = ["±", "+-"];
+
+/// A value with an uncertainty, produced by `eval_uncertain`.
+///
+/// `Display` rounds the uncertainty to two significant digits, and the
+/// value to the same decimal place, e.g. `10.00 ± 0.32`. Exact values are
+/// shown as plain numbers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Uncertain {
+    mean: f64,
+    sigma: f64,
+}
+
+impl Uncertain {
+    /// A value of `mean` with the standard uncertainty `sigma`. A negative
+    /// `sigma` means the same as its magnitude.
+    pub fn new(mean: f64, sigma: f64) -> Self {
+        Uncertain {
+            mean,
+            sigma: sigma.abs(),
+        }
+    }
+
+    /// A value without uncertainty.
+    pub fn exact(mean: f64) -> Self {
+        Uncertain::new(mean, 0.0)
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.mean
+    }
+
+    pub fn sigma(&self) -> f64 {
+        self.sigma
+    }
+
+    /// Joins `value ± sigma`, both of which must be exact.
+    fn join(value: Self, sigma: Self) -> Result<Self, CalcError> {
+        if value.sigma != 0.0 || sigma.sigma != 0.0 {
+            return Err(CalcError::InvalidArgument(format!(
+                "'{} ± {}' needs an exact value and uncertainty",
+                value, sigma
+            )));
+        }
+        if sigma.mean < 0.0 || !sigma.mean.is_finite() {
+            return Err(CalcError::InvalidArgument(format!(
+                "an uncertainty must be finite and not negative, got {}",
+                sigma.mean
+            )));
+        }
+        Ok(Uncertain::new(value.mean, sigma.mean))
+    }
+}
+
+impl fmt::Display for Uncertain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.sigma == 0.0 || !self.sigma.is_finite() {
+            return write!(f, "{}", self.mean);
+        }
+        let decimals = (1 - self.sigma.log10().floor() as i32).max(0);
+        let decimals = decimals as usize;
+        write!(
+            f,
+            "{:.*} ± {:.*}",
+            decimals, self.mean, decimals, self.sigma
+        )
+    }
+}
+
+/// Propagates the uncertainties of independent operands into that of a
+/// result, given the partial derivatives of the operation. Fails if one
+/// for an uncertain operand is not finite, as that of `x ** 0.5` at 0,
+/// where a first-order estimate is meaningless.
+fn quadrature(terms: &[(f64, f64)]) -> Result<f64, CalcError> {
+    let mut sum = 0.0;
+    for &(slope, sigma) in terms.iter().filter(|&&(_, sigma)| sigma != 0.0) {
+        if !slope.is_finite() {
+            return Err(CalcError::InvalidArgument(
+                "the uncertainty is undefined where the derivative is not \
+                 finite"
+                    .into(),
+            ));
+        }
+        sum += (slope * sigma).powi(2);
+    }
+    Ok(sum.sqrt())
+}
+
+impl CalcNum for Uncertain {
+    fn from_value(value: &Value) -> Result<Self, CalcError> {
+        f64::from_value(value).map(Uncertain::exact)
+    }
+
+    fn from_literal(value: &Value) -> Result<Self, CalcError> {
+        f64::from_literal(value).map(Uncertain::exact)
+    }
+
+    fn literal_warning(value: &Value) -> Option<String> {
+        f64::literal_warning(value)
+    }
+
+    /// Only the mean makes it into the `Value`.
+    fn to_value(&self) -> Value {
+        self.mean.to_value()
+    }
+
+    /// Joins a value and its uncertainty for `±`, and otherwise
+    /// linearizes the function around the means of the arguments, taking
+    /// its slopes by central differences.
+    fn call<F>(
+        name: &str,
+        args: Vec<Self>,
+        mut resolve: F,
+    ) -> Result<Self, CalcError>
+    where
+        F: FnMut(&[Value]) -> Result<Value, CalcError>,
+    {
+        if PLUS_MINUS.contains(&name) && args.len() == 2 {
+            return Uncertain::join(args[0], args[1]);
+        }
+        let mut means: Vec<Value> = args.iter().map(|a| a.to_value()).collect();
+        let mean = resolve(&means)?.as_f64();
+        let mut terms = Vec::with_capacity(args.len());
+        for (i, arg) in args.iter().enumerate() {
+            if arg.sigma == 0.0 {
+                continue;
+            }
+            let h = f64::EPSILON.cbrt() * arg.mean.abs().max(1.0);
+            means[i] = Value::from_f64(arg.mean + h);
+            let above = resolve(&means)?.as_f64();
+            means[i] = Value::from_f64(arg.mean - h);
+            let below = resolve(&means)?.as_f64();
+            means[i] = arg.to_value();
+            terms.push(((above - below) / (2.0 * h), arg.sigma));
+        }
+        Ok(Uncertain::new(mean, quadrature(&terms)?))
+    }
+
+    fn add(self, that: Self) -> Result<Self, CalcError> {
+        let sigma = quadrature(&[(1.0, self.sigma), (1.0, that.sigma)])?;
+        Ok(Uncertain::new(self.mean + that.mean, sigma))
+    }
+
+    fn sub(self, that: Self) -> Result<Self, CalcError> {
+        let sigma = quadrature(&[(1.0, self.sigma), (1.0, that.sigma)])?;
+        Ok(Uncertain::new(self.mean - that.mean, sigma))
+    }
+
+    fn mul(self, that: Self) -> Result<Self, CalcError> {
+        let sigma =
+            quadrature(&[(that.mean, self.sigma), (self.mean, that.sigma)])?;
+        Ok(Uncertain::new(self.mean * that.mean, sigma))
+    }
+
+    fn div(self, that: Self) -> Result<Self, CalcError> {
+        if that.mean == 0.0 {
+            return Err(CalcError::DivideByZero);
+        }
+        let mean = self.mean / that.mean;
+        let sigma = quadrature(&[
+            (1.0 / that.mean, self.sigma),
+            (mean / that.mean, that.sigma),
+        ])?;
+        Ok(Uncertain::new(mean, sigma))
+    }
+
+    fn rem(self, that: Self) -> Result<Self, CalcError> {
+        if that.mean == 0.0 {
+            return Err(CalcError::DivideByZero);
+        }
+        let quotient = (self.mean / that.mean).trunc();
+        let sigma = quadrature(&[(1.0, self.sigma), (quotient, that.sigma)])?;
+        Ok(Uncertain::new(self.mean % that.mean, sigma))
+    }
+
+    fn neg(self) -> Result<Self, CalcError> {
+        Ok(Uncertain::new(-self.mean, self.sigma))
+    }
+
+    fn powf(self, that: Self) -> Result<Self, CalcError> {
+        let mean = self.mean.powf(that.mean);
+        // A power of 0 stays 0 as the exponent changes.
+        let by_exponent = if mean == 0.0 {
+            0.0
+        } else {
+            mean * self.mean.ln()
+        };
+        let sigma = quadrature(&[
+            (that.mean * self.mean.powf(that.mean - 1.0), self.sigma),
+            (by_exponent, that.sigma),
+        ])?;
+        Ok(Uncertain::new(mean, sigma))
+    }
+
+    /// The result is exact, as `floor` is flat between the integers.
+    fn floor(self) -> Result<Self, CalcError> {
+        Ok(Uncertain::exact(self.mean.floor()))
+    }
+
+    fn compare(&self, that: &Self) -> Option<Ordering> {
+        self.mean.partial_cmp(&that.mean)
+    }
+
+    /// Uncertain sums aren't compensated, as the correction would count
+    /// as another measurement.
+    fn sum_error(&self, _: &Self, _: &Self) -> Option<Self> {
+        None
+    }
+
+    fn to_bits_integer(&self) -> Option<i64> {
+        if self.sigma == 0.0 {
+            self.mean.to_bits_integer()
+        } else {
+            None
+        }
+    }
+
+    fn from_bits_integer(n: i64) -> Self {
+        Uncertain::exact(n as f64)
+    }
+}
+
+/// The `DefaultEnvironment` with `±` and `+-` as operators that bind like
+/// `+`, for `eval_uncertain`.
+pub(crate) struct UncertainEnvironment;
+
+impl Environment for UncertainEnvironment {
+    fn arity(&self, atom: &str) -> Option<usize> {
+        DefaultEnvironment.arity(atom)
+    }
+
+    fn resolve(
+        &mut self,
+        atom: &str,
+        args: &[Value],
+    ) -> Result<Value, CalcError> {
+        DefaultEnvironment.resolve(atom, args)
+    }
+
+    fn operators(&self) -> Vec<Operator> {
+        PLUS_MINUS
+            .iter()
+            .map(|&symbol| Operator {
+                symbol: symbol.into(),
+                precedence: 2,
+                assoc: Assoc::Left,
+            })
+            .collect()
+    }
+
+    fn names(&self) -> Vec<String> {
+        DefaultEnvironment.names()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eval_uncertain;
+
+    fn assert_close(input: &str, mean: f64, sigma: f64) {
+        let n = eval_uncertain(input).unwrap();
+        assert!((n.mean() - mean).abs() < 1e-9, "{}: {}", input, n.mean());
+        assert!((n.sigma() - sigma).abs() < 1e-9, "{}: {}", input, n.sigma());
+    }
+
+    #[test]
+    fn basic_operations() {
+        assert_close("(5 ± 0.3) + (2 ± 0.4)", 7.0, 0.5);
+        assert_close("(5 ± 0.3) - (2 ± 0.4)", 3.0, 0.5);
+        // 10 * sqrt((0.1 / 5) ** 2 + (0.05 / 2) ** 2)
+        assert_close("(5.0 ± 0.1) * (2.0 ± 0.05)", 10.0, 0.320156211871642);
+        // 2.5 * sqrt((0.2 / 10) ** 2 + (0.1 / 4) ** 2)
+        assert_close("(10 ± 0.2) / (4 ± 0.1)", 2.5, 0.080039052967910);
+        assert_close("-(5 ± 0.3)", -5.0, 0.3);
+    }
+
+    #[test]
+    fn powers() {
+        // 3 * 2 ** 2 * 0.1
+        assert_close("(2 ± 0.1) ** 3", 8.0, 1.2);
+        // 2 ** 3 * ln(2) * 0.1
+        assert_close("2 ** (3 ± 0.1)", 8.0, 0.554517744447956);
+    }
+
+    #[test]
+    fn exact_numbers() {
+        assert_close("2 * (3 ± 0.1)", 6.0, 0.2);
+        assert_close("2 * 3 ± 0.1", 6.0, 0.1);
+        assert_close("1 + 2 * 3", 7.0, 0.0);
+        assert_close("(5.0 +- 0.1) * (2.0 +- 0.05)", 10.0, 0.320156211871642);
+        assert_close("5±0.1", 5.0, 0.1);
+    }
+
+    #[test]
+    fn functions() {
+        let n = eval_uncertain("ln(10 ± 0.5)").unwrap();
+        assert!((n.mean() - 10f64.ln()).abs() < 1e-9);
+        assert!((n.sigma() - 0.05).abs() < 1e-6);
+        let n = eval_uncertain("sin(0 ± 0.1) + pi").unwrap();
+        assert!((n.sigma() - 0.1).abs() < 1e-6);
+    }
+
+    #[test]
+    fn display() {
+        let product = eval_uncertain("(5.0 ± 0.1) * (2.0 ± 0.05)").unwrap();
+        assert_eq!(product.to_string(), "10.00 ± 0.32");
+        assert_eq!(Uncertain::new(1234.5, 32.0).to_string(), "1234 ± 32");
+        assert_eq!(Uncertain::new(9.81, 0.02).to_string(), "9.810 ± 0.020");
+        assert_eq!(Uncertain::new(100.0, 4.3).to_string(), "100.0 ± 4.3");
+        assert_eq!(Uncertain::exact(2.5).to_string(), "2.5");
+    }
+
+    #[test]
+    fn errors() {
+        assert!(eval_uncertain("1 ± -0.1").is_err());
+        assert!(eval_uncertain("(1 ± 0.1) ± 0.1").is_err());
+        assert!(eval_uncertain("1 ± (0.1 ± 0.1)").is_err());
+        assert_eq!(
+            eval_uncertain("(1 ± 0.1) / (0 ± 0.1)"),
+            Err(CalcError::DivideByZero)
+        );
+        assert!(eval_uncertain("(3 ± 0.1) & 1").is_err());
+        // The square root is infinitely steep at 0.
+        assert!(matches!(
+            eval_uncertain("(0 ± 0.1) ** 0.5"),
+            Err(CalcError::InvalidArgument(_))
+        ));
+        assert_close("(0 ± 0.1) ** 2", 0.0, 0.0);
+        assert_close("0 ** (2 ± 0.1)", 0.0, 0.0);
+    }
+}
@@ -1,6 +1,9 @@
 use decimal::d128;
 use error::{CalcError, PartialComp};
-use num::{BigInt, BigUint, ToPrimitive, Zero};
+use num::bigint::Sign;
+use num::{BigInt, BigUint, Signed, ToPrimitive, Zero};
+use number::check_shift;
+use std::f64;
 use std::fmt;
 use std::ops::*;
 
@@ -43,6 +46,13 @@ impl IntegralFmt {
 }
 
 /// Represents a canonical value that can be calculated by this library
+///
+/// Integer literals and the results of `+`, `-`, `*`, `%` and the bitwise
+/// operators on integers stay exact integers of any size, so that
+/// `0xfffffffffffffff | 1` keeps its low bits. An operation with a float
+/// operand gives a float. `/` gives an integer if it divides evenly, as in
+/// `6 / 2`, and a float otherwise, as in `7 / 2`. The bitwise operators
+/// reject floats with `CalcError::BadTypes`.
 #[derive(Clone, Debug, PartialEq)]
 pub enum Value {
     /// An integral value. The format of this value (hexadecimal versus
@@ -55,8 +65,12 @@ pub enum Value {
 pub mod ops {
     use super::{CalcError, Integral, PartialComp, UIntegral};
     use decimal::d128;
-    use num::{Integer, ToPrimitive, Zero};
-    use std::iter::repeat;
+    use num::{BigRational, Integer, Signed, ToPrimitive, Zero};
+
+    /// The size, in bits, of the largest integer an operation may produce.
+    /// Anything bigger is reported as an overflow rather than exhausting
+    /// memory.
+    pub const MAX_BITS: usize = 1 << 20;
 
     macro_rules! bitwise_op {
         ($name:ident, $fun:expr) => {
@@ -66,16 +80,20 @@ pub mod ops {
         };
     }
 
+    /// Sign-extends the shorter of two little-endian two's-complement byte
+    /// strings to the length of the other.
     fn equalize(left: &mut Vec<u8>, right: &mut Vec<u8>) {
-        if left.len() > right.len() {
-            for it in repeat(Zero::zero()).take(left.len() - right.len()) {
-                right.push(it);
-            }
-        } else if right.len() > left.len() {
-            for it in repeat(Zero::zero()).take(right.len() - left.len()) {
-                left.push(it);
-            }
-        }
+        let (short, len) = if left.len() > right.len() {
+            (right, left.len())
+        } else {
+            let len = right.len();
+            (left, len)
+        };
+        let fill = match short.last() {
+            Some(byte) if byte & 0x80 != 0 => 0xff,
+            _ => 0,
+        };
+        short.resize(len, fill);
     }
 
     pub fn bitwise<F: Fn(u8, u8) -> u8>(
@@ -98,10 +116,9 @@ pub mod ops {
     bitwise_op!(or, |n, m| n | m);
     bitwise_op!(xor, |n, m| n ^ m);
 
+    /// Bitwise complement in two's complement, i.e. `-n - 1`.
     pub fn not(n: Integral) -> Integral {
-        let bytes = n.to_signed_bytes_le();
-        let res: Vec<u8> = bytes.iter().map(|n| !n).collect();
-        Integral::from_signed_bytes_le(&res)
+        -n - Integral::from(1)
     }
 
     pub fn int_powu(n: &Integral, m: &UIntegral) -> Integral {
@@ -114,12 +131,95 @@ pub mod ops {
         }
     }
 
+    /// Raises `n` to the power of `m`. Returns `None` if `m` is negative or
+    /// the result would be larger than `MAX_BITS`.
     pub fn int_pow(n: &Integral, m: &Integral) -> Option<Integral> {
-        if *m >= Zero::zero() {
-            Some(int_powu(n, &m.to_biguint().unwrap()))
+        let m = m.to_biguint()?;
+        // Only 0, 1 and -1 can be raised to huge powers without growing.
+        if n.bits() > 1 {
+            let exp = m.to_usize()?;
+            if (n.bits() - 1).checked_mul(exp)? >= MAX_BITS {
+                return None;
+            }
+        }
+        Some(int_powu(n, &m))
+    }
+
+    /// Shifts `n` left by `m` bits. Returns `None` if `m` is negative or the
+    /// result would be larger than `MAX_BITS`.
+    pub fn shl(n: &Integral, m: &Integral) -> Option<Integral> {
+        if m.is_negative() {
+            None
+        } else if n.is_zero() {
+            Some(Zero::zero())
+        } else {
+            let amount = m.to_usize()?;
+            if n.bits().checked_add(amount)? > MAX_BITS {
+                return None;
+            }
+            Some(n << amount)
+        }
+    }
+
+    /// Shifts `n` right by `m` bits. Returns `None` if `m` is negative.
+    pub fn shr(n: &Integral, m: &Integral) -> Option<Integral> {
+        if m.is_negative() {
+            return None;
+        }
+        // Shifting by more than the width of `n` leaves nothing behind.
+        Some(m.to_usize().map_or_else(Zero::zero, |amount| n >> amount))
+    }
+
+    /// Shifts the `width`-bit two's-complement pattern of `n` right by `m`
+    /// bits, filling with zeros. Returns `None` if `m` is negative, `n` does
+    /// not fit into `width` bits or `width` exceeds `MAX_BITS`.
+    pub fn logical_shr(
+        n: &Integral,
+        m: &Integral,
+        width: u32,
+    ) -> Option<Integral> {
+        if m.is_negative() || width as usize > MAX_BITS {
+            return None;
+        }
+        let modulus = Integral::from(1) << width as usize;
+        if *n >= modulus || *n < -(&modulus >> 1) {
+            return None;
+        }
+        let pattern = if n.is_negative() {
+            n + &modulus
         } else {
-            unimplemented!()
+            n.clone()
+        };
+        let amount = m.to_usize();
+        Some(amount.map_or_else(Zero::zero, |amount| pattern >> amount))
+    }
+
+    /// Converts a finite decimal, such as `-1.25E-7`, into the exact
+    /// rational it denotes.
+    pub fn to_rational(n: d128) -> Option<BigRational> {
+        if !n.is_finite() {
+            return None;
         }
+        let text = n.to_string();
+        let (mantissa, exponent) = match text.find(['E', 'e']) {
+            Some(pos) => (&text[..pos], text[pos + 1..].parse::<i64>().ok()?),
+            None => (&text[..], 0),
+        };
+        let (digits, fraction_len) = match mantissa.find('.') {
+            Some(pos) => (
+                format!("{}{}", &mantissa[..pos], &mantissa[pos + 1..]),
+                (mantissa.len() - pos - 1) as i64,
+            ),
+            None => (mantissa.to_owned(), 0),
+        };
+        let digits: Integral = digits.parse().ok()?;
+        let shift = exponent - fraction_len;
+        let power = int_powu(&10.into(), &shift.unsigned_abs().into());
+        Some(if shift < 0 {
+            BigRational::new(digits, power)
+        } else {
+            BigRational::from_integer(digits * power)
+        })
     }
 
     pub fn to_float(n: &Integral) -> Result<d128, CalcError> {
@@ -146,6 +246,21 @@ impl Value {
         }
     }
 
+    /// Whether the value is an integer, which floats such as `2.0` are.
+    pub fn is_whole(&self) -> bool {
+        self.to_integral().is_some()
+    }
+
+    /// The value as an integer, if it is one.
+    pub fn to_integral(&self) -> Option<Integral> {
+        match self {
+            Value::Integral(ref n, _) => Some(n.clone()),
+            Value::Float(ref f) => ops::to_rational(*f)
+                .filter(|n| n.is_integer())
+                .map(|n| n.to_integer()),
+        }
+    }
+
     pub fn as_float(&self) -> Result<d128, CalcError> {
         match self {
             Value::Integral(ref n, _) => ops::to_float(n),
@@ -153,6 +268,29 @@ impl Value {
         }
     }
 
+    /// Creates a decimal float from a binary floating point number.
+    pub fn from_f64(f: f64) -> Self {
+        let n = format!("{:e}", f)
+            .parse()
+            .expect("formatted f64 is a valid decimal");
+        Value::Float(n)
+    }
+
+    /// Converts the value into the nearest binary floating point number.
+    /// Integers too large for an `f64` become infinite.
+    pub fn as_f64(&self) -> f64 {
+        match self {
+            Value::Integral(ref n, _) => n.to_f64().unwrap_or_else(|| {
+                if n.sign() == Sign::Minus {
+                    f64::NEG_INFINITY
+                } else {
+                    f64::INFINITY
+                }
+            }),
+            Value::Float(ref n) => n.to_string().parse().unwrap_or(f64::NAN),
+        }
+    }
+
     /// Represents a computation that can only operate on, and return,
     /// integer values
     pub fn intmap<F, T>(
@@ -212,6 +350,11 @@ impl Value {
             (&Value::Integral(ref n, _), &Value::Float(m)) => {
                 Value::Float(ops::to_float(n)?.pow(m))
             }
+            (Value::Integral(n, _), Value::Integral(m, _))
+                if m.is_negative() =>
+            {
+                Value::Float(ops::to_float(n)?.pow(ops::to_float(m)?))
+            }
             (&Value::Integral(ref n, t1), &Value::Integral(ref m, t2)) => {
                 match ops::int_pow(&n, &m) {
                     Some(v) => Value::Integral(v, t1 + t2),
@@ -227,10 +370,31 @@ impl Value {
     }
 }
 
+impl From<i32> for Value {
+    fn from(n: i32) -> Self {
+        Value::dec(n)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Self {
+        Value::dec(n)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(f: f64) -> Self {
+        Value::from_f64(f)
+    }
+}
+
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Value::Integral(ref n, IntegralFmt::Dec) => write!(f, "{}", n),
+            Value::Integral(ref n, IntegralFmt::Hex) if n.is_negative() => {
+                write!(f, "-0x{:X}", -n)
+            }
             Value::Integral(ref n, IntegralFmt::Hex) => write!(f, "0x{:X}", n),
             Value::Float(ref n) => write!(f, "{}", n),
         }
@@ -241,13 +405,13 @@ impl fmt::Display for Value {
 /// - `value` represents the current computed data
 /// - `tokens` represents the number of tokens that have been consumed
 #[derive(Clone, Debug, PartialEq)]
-pub struct IR {
-    pub value: Value,
+pub struct IR<N = Value> {
+    pub value: N,
     pub tokens: usize,
 }
 
-impl IR {
-    pub fn new<T: Into<Option<usize>>>(value: Value, tokens: T) -> Self {
+impl<N> IR<N> {
+    pub fn new<T: Into<Option<usize>>>(value: N, tokens: T) -> Self {
         IR {
             value,
             tokens: tokens.into().unwrap_or(0),
@@ -369,18 +533,11 @@ impl Shl<Value> for Value {
     type Output = Result<Self, CalcError>;
 
     fn shl(self, that: Value) -> Self::Output {
+        check_shift("<<", &that)?;
         self.intmap(&that, "<<", |n, m| {
-            m.to_i64()
-                .map(|m| {
-                    if m < 0 {
-                        n >> ((-m) as usize)
-                    } else {
-                        n << (m as usize)
-                    }
-                })
-                .ok_or(CalcError::WouldOverflow(PartialComp::binary(
-                    "<<", &self, &that,
-                )))
+            ops::shl(n, m).ok_or(CalcError::WouldOverflow(PartialComp::binary(
+                "<<", &self, &that,
+            )))
         })
     }
 }
@@ -389,18 +546,11 @@ impl Shr<Value> for Value {
     type Output = Result<Self, CalcError>;
 
     fn shr(self, that: Value) -> Self::Output {
-        self.intmap(&that, "<<", |n, m| {
-            m.to_i64()
-                .map(|m| {
-                    if m < 0 {
-                        n << ((-m) as usize)
-                    } else {
-                        n >> (m as usize)
-                    }
-                })
-                .ok_or(CalcError::WouldOverflow(PartialComp::binary(
-                    ">>", &self, &that,
-                )))
+        check_shift(">>", &that)?;
+        self.intmap(&that, ">>", |n, m| {
+            ops::shr(n, m).ok_or(CalcError::WouldOverflow(PartialComp::binary(
+                ">>", &self, &that,
+            )))
         })
     }
 }
@@ -433,6 +583,14 @@ mod tests {
         }
     }
 
+    #[test]
+    fn complement() {
+        for &(n, expected) in &[(0, -1), (-1, 0), (127, -128), (128, -129)] {
+            assert_eq!(ops::not(Integral::from(n)), Integral::from(expected));
+        }
+        assert_eq!(ops::not(Integral::from(-256)), Integral::from(255));
+    }
+
     #[test]
     fn hex_override() {
         let cases = vec![
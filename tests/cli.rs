@@ -0,0 +1,123 @@
+//! Runs the `calc` binary with a controlled environment.
+
+use std::process::{Command, Output};
+
+fn calc(input: &str, vars: &[(&str, &str)]) -> Output {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_calc"));
+    command
+        .arg(input)
+        .env_remove("CALC_UNSET")
+        .env_remove("NO_COLOR")
+        .env_remove("CLICOLOR_FORCE");
+    for &(name, value) in vars {
+        command.env(name, value);
+    }
+    command.output().expect("calc runs")
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).trim().to_owned()
+}
+
+#[test]
+fn environment_variables() {
+    let vars = [("COUNT", "4"), ("BASE", "-2"), ("RATE", "0.5")];
+    let output = calc("$COUNT * 1.5 + ${BASE}", &vars);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "4.0");
+    let output = calc("${RATE}*$COUNT", &vars);
+    assert_eq!(stdout(&output), "2.0");
+}
+
+#[test]
+fn environment_errors() {
+    let output = calc("$CALC_UNSET + 1", &[]);
+    assert!(!output.status.success());
+    assert_eq!(
+        stdout(&output),
+        "calc: environment variable CALC_UNSET is not set"
+    );
+
+    // Values are numbers, never pieces of the expression.
+    let output = calc("$CALC_VALUE * 2", &[("CALC_VALUE", "1 + 1")]);
+    assert!(!output.status.success());
+    assert_eq!(
+        stdout(&output),
+        "calc: environment variable CALC_VALUE is not a number: \"1 + 1\""
+    );
+
+    let output = calc("${CALC_VALUE + 1", &[("CALC_VALUE", "1")]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn colored_errors() {
+    let output = calc("1 / 0", &[]);
+    assert_eq!(stdout(&output), "calc: attempted to divide by zero");
+
+    let output = calc("1 / 0", &[("CLICOLOR_FORCE", "1")]);
+    assert_eq!(
+        stdout(&output),
+        "\x1b[1;31mcalc:\x1b[0m attempted to divide by zero"
+    );
+
+    let forced = [("CLICOLOR_FORCE", "1"), ("NO_COLOR", "1")];
+    let output = calc("1 / 0", &forced);
+    assert_eq!(stdout(&output), "calc: attempted to divide by zero");
+}
+
+#[test]
+fn suggestions() {
+    let output = calc("coss 0", &[]);
+    assert_eq!(
+        stdout(&output),
+        "calc: unknown variable or function 'coss'\nhelp: did you mean 'cos'?"
+    );
+}
+
+#[test]
+fn json_errors() {
+    let output = Command::new(env!("CARGO_BIN_EXE_calc"))
+        .args(["--json-errors", "coss 0"])
+        .output()
+        .expect("calc runs");
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr).trim(),
+        "{\"kind\":\"UnknownAtom\",\
+         \"message\":\"unknown variable or function 'coss'\",\
+         \"span\":null,\"found\":\"coss\",\"expected\":null,\
+         \"suggestion\":\"did you mean 'cos'?\"}"
+    );
+}
+
+#[test]
+fn bc_mode() {
+    let output = Command::new(env!("CARGO_BIN_EXE_calc"))
+        .args(["--bc", "scale=2; 2/3; obase=16; 255"])
+        .output()
+        .expect("calc runs");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), ".66\nFF");
+}
+
+#[test]
+fn all_bases() {
+    let output = Command::new(env!("CARGO_BIN_EXE_calc"))
+        .args(["--all-bases", "--width", "8", "0 - 3"])
+        .output()
+        .expect("calc runs");
+    assert!(output.status.success());
+    assert_eq!(
+        stdout(&output),
+        "dec       -3\nhex       0xfd\noct       0o375\nbin       0b11111101\n\
+         signed    -3\nunsigned  253"
+    );
+
+    let output = Command::new(env!("CARGO_BIN_EXE_calc"))
+        .args(["--all-bases", "1.5"])
+        .output()
+        .expect("calc runs");
+    assert!(!output.status.success());
+}